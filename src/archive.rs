@@ -0,0 +1,409 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+// Deterministic ustar archives of a directory tree. Entries are written in sorted order with
+// uid/gid 0 and no user or group names, so the same tree always packs to the same bytes.
+// File modes and modification times are kept; sub-second times, long paths and huge files go
+// into PAX extended headers, which any modern tar reads.
+
+const BLOCK: usize = 512;
+
+enum EntryKind {
+    File,
+    Dir,
+    Symlink(String),
+}
+
+struct Entry {
+    path: String,
+    kind: EntryKind,
+    mode: u32,
+    size: u64,
+    mtime: Duration,
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode_of(metadata: &fs::Metadata) -> u32 {
+    let base = if metadata.is_dir() { 0o755 } else { 0o644 };
+    if metadata.permissions().readonly() { base & 0o555 } else { base }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(path, permissions)
+}
+
+fn set_mtime(path: &Path, mtime: Duration) -> io::Result<()> {
+    File::options().write(!path.is_dir()).read(path.is_dir()).open(path)?.set_modified(UNIX_EPOCH + mtime)
+}
+
+// Sets the time of the link itself rather than of what it points to
+#[cfg(target_os = "linux")]
+fn set_link_mtime(path: &Path, mtime: Duration) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).map_err(|_| invalid("path contains a NUL byte"))?;
+    let time = libc::timespec { tv_sec: mtime.as_secs() as libc::time_t, tv_nsec: mtime.subsec_nanos() as libc::c_long };
+    let result = unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), [time, time].as_ptr(), libc::AT_SYMLINK_NOFOLLOW) };
+    if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_link_mtime(_path: &Path, _mtime: Duration) -> io::Result<()> {
+    Ok(())
+}
+
+fn collect(root: &Path, relative: &Path, entries: &mut Vec<Entry>) -> io::Result<()> {
+    let mut children: Vec<_> = fs::read_dir(root.join(relative))?.collect::<io::Result<_>>()?;
+    children.sort_by_key(|c| c.file_name());
+    for child in children {
+        let rel = relative.join(child.file_name());
+        let metadata = fs::symlink_metadata(child.path())?;
+        let name = rel.to_string_lossy().replace('\\', "/");
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+        if metadata.file_type().is_symlink() {
+            let target = fs::read_link(child.path())?.to_string_lossy().into_owned();
+            entries.push(Entry { path: name, kind: EntryKind::Symlink(target), mode: 0o777, size: 0, mtime });
+        } else if metadata.is_dir() {
+            entries.push(Entry { path: format!("{}/", name), kind: EntryKind::Dir, mode: mode_of(&metadata), size: 0, mtime });
+            collect(root, &rel, entries)?;
+        } else {
+            entries.push(Entry { path: name, kind: EntryKind::File, mode: mode_of(&metadata), size: metadata.len(), mtime });
+        }
+    }
+    Ok(())
+}
+
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+fn header(name: &str, typeflag: u8, mode: u32, size: u64, mtime: u64, link: &str) -> [u8; BLOCK] {
+    let mut block = [0u8; BLOCK];
+    let name = name.as_bytes();
+    block[..name.len().min(100)].copy_from_slice(&name[..name.len().min(100)]);
+    write_octal(&mut block[100..108], mode as u64);
+    write_octal(&mut block[108..116], 0);
+    write_octal(&mut block[116..124], 0);
+    write_octal(&mut block[124..136], size.min(0o77777777777));
+    write_octal(&mut block[136..148], mtime.min(0o77777777777));
+    block[156] = typeflag;
+    let link = link.as_bytes();
+    block[157..157 + link.len().min(100)].copy_from_slice(&link[..link.len().min(100)]);
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+
+    block[148..156].copy_from_slice(b"        ");
+    let checksum: u64 = block.iter().map(|&b| b as u64).sum();
+    let digits = format!("{:06o}\0 ", checksum);
+    block[148..156].copy_from_slice(digits.as_bytes());
+    block
+}
+
+// One "len key=value\n" PAX record; len counts itself
+fn pax_record(key: &str, value: &str) -> String {
+    let body = format!(" {}={}\n", key, value);
+    let mut len = body.len() + 1;
+    while format!("{}{}", len, body).len() != len {
+        len += 1;
+    }
+    format!("{}{}", len, body)
+}
+
+fn padding(len: u64) -> usize {
+    (BLOCK - (len % BLOCK as u64) as usize) % BLOCK
+}
+
+fn write_entry(out: &mut impl Write, entry: &Entry) -> io::Result<()> {
+    let link = match &entry.kind {
+        EntryKind::Symlink(target) => target.as_str(),
+        _ => "",
+    };
+    let mut pax = String::new();
+    if entry.path.len() > 100 {
+        pax.push_str(&pax_record("path", &entry.path));
+    }
+    if link.len() > 100 {
+        pax.push_str(&pax_record("linkpath", link));
+    }
+    if entry.size > 0o77777777777 {
+        pax.push_str(&pax_record("size", &entry.size.to_string()));
+    }
+    if entry.mtime.subsec_nanos() != 0 || entry.mtime.as_secs() > 0o77777777777 {
+        pax.push_str(&pax_record("mtime", &format!("{}.{:09}", entry.mtime.as_secs(), entry.mtime.subsec_nanos())));
+    }
+    if !pax.is_empty() {
+        out.write_all(&header("././@PaxHeader", b'x', 0o644, pax.len() as u64, 0, ""))?;
+        out.write_all(pax.as_bytes())?;
+        out.write_all(&vec![0u8; padding(pax.len() as u64)])?;
+    }
+    let typeflag = match entry.kind {
+        EntryKind::File => b'0',
+        EntryKind::Dir => b'5',
+        EntryKind::Symlink(_) => b'2',
+    };
+    out.write_all(&header(&entry.path, typeflag, entry.mode, entry.size, entry.mtime.as_secs(), link))
+}
+
+pub fn pack(dir: &Path, archive_path: &Path) -> io::Result<()> {
+    let mut entries = Vec::new();
+    collect(dir, Path::new(""), &mut entries)?;
+
+    let mut out = BufWriter::new(File::create(archive_path)?);
+    for entry in &entries {
+        write_entry(&mut out, entry)?;
+        if let EntryKind::File = entry.kind {
+            let copied = io::copy(&mut File::open(dir.join(&entry.path))?.take(entry.size), &mut out)?;
+            if copied != entry.size {
+                return Err(io::Error::other(format!("{} changed size while being archived", entry.path)));
+            }
+            out.write_all(&vec![0u8; padding(entry.size)])?;
+        }
+    }
+    out.write_all(&[0u8; BLOCK * 2])?;
+    out.flush()?;
+    out.get_ref().sync_all()
+}
+
+fn parse_octal(field: &[u8]) -> io::Result<u64> {
+    let text: String = field.iter().take_while(|&&b| b != 0).map(|&b| b as char).collect();
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| invalid("bad octal field in archive header"))
+}
+
+fn parse_string(field: &[u8]) -> String {
+    String::from_utf8_lossy(&field[..field.iter().position(|&b| b == 0).unwrap_or(field.len())]).into_owned()
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Refuses absolute paths and "..", so an archive can only ever write inside the target
+fn safe_join(root: &Path, name: &str) -> io::Result<PathBuf> {
+    let relative = Path::new(name.trim_end_matches('/'));
+    if relative.components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return Err(invalid("archive entry escapes the target directory"));
+    }
+    Ok(root.join(relative))
+}
+
+fn parse_pax(data: &[u8]) -> Vec<(String, String)> {
+    let text = String::from_utf8_lossy(data);
+    let mut records = Vec::new();
+    let mut rest: &str = &text;
+    while let Some((len, _)) = rest.split_once(' ') {
+        let Ok(len) = len.parse::<usize>() else { break };
+        if len == 0 || len > rest.len() {
+            break;
+        }
+        let record = &rest[..len];
+        if let Some((key, value)) = record.split_once(' ').and_then(|(_, kv)| kv.trim_end_matches('\n').split_once('=')) {
+            records.push((key.to_string(), value.to_string()));
+        }
+        rest = &rest[len..];
+    }
+    records
+}
+
+fn parse_mtime(value: &str) -> Duration {
+    let (secs, frac) = value.split_once('.').unwrap_or((value, ""));
+    let secs = secs.parse().unwrap_or(0);
+    let nanos = format!("{:0<9}", &frac[..frac.len().min(9)]).parse().unwrap_or(0);
+    Duration::new(secs, nanos)
+}
+
+pub fn unpack(archive_path: &Path, dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let mut input = BufReader::new(File::open(archive_path)?);
+    let mut block = [0u8; BLOCK];
+    let mut pax: Vec<(String, String)> = Vec::new();
+    // Directory times are applied last, since creating their contents changes them
+    let mut dir_times: Vec<(PathBuf, Duration, u32)> = Vec::new();
+
+    loop {
+        input.read_exact(&mut block)?;
+        if block.iter().all(|&b| b == 0) {
+            break;
+        }
+        let mut name = parse_string(&block[..100]);
+        let prefix = parse_string(&block[345..500]);
+        if &block[257..262] == b"ustar" && !prefix.is_empty() {
+            name = format!("{}/{}", prefix, name);
+        }
+        let mut link = parse_string(&block[157..257]);
+        let mode = parse_octal(&block[100..108])? as u32;
+        let mut size = parse_octal(&block[124..136])?;
+        let mut mtime = Duration::from_secs(parse_octal(&block[136..148])?);
+        let typeflag = block[156];
+        for (key, value) in pax.drain(..) {
+            match key.as_str() {
+                "path" => name = value,
+                "linkpath" => link = value,
+                "size" => size = value.parse().map_err(|_| invalid("bad PAX size"))?,
+                "mtime" => mtime = parse_mtime(&value),
+                _ => {}
+            }
+        }
+
+        match typeflag {
+            // PAX and GNU long-name entries describe the entry after them
+            b'x' | b'g' | b'L' | b'K' => {
+                let mut data = vec![0u8; size as usize];
+                input.read_exact(&mut data)?;
+                io::copy(&mut (&mut input).take(padding(size) as u64), &mut io::sink())?;
+                match typeflag {
+                    b'x' => pax = parse_pax(&data),
+                    b'L' => pax.push(("path".to_string(), parse_string(&data))),
+                    b'K' => pax.push(("linkpath".to_string(), parse_string(&data))),
+                    _ => {}
+                }
+            }
+            b'5' => {
+                let path = safe_join(dir, &name)?;
+                fs::create_dir_all(&path)?;
+                dir_times.push((path, mtime, mode));
+            }
+            b'2' => {
+                let path = safe_join(dir, &name)?;
+                #[cfg(unix)]
+                {
+                    std::os::unix::fs::symlink(&link, &path)?;
+                    // Best effort, like directories
+                    let _ = set_link_mtime(&path, mtime);
+                }
+                #[cfg(not(unix))]
+                eprintln!("Warning: skipping symlink {} -> {}", path.display(), link);
+            }
+            b'0' | 0 => {
+                let path = safe_join(dir, &name)?;
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                {
+                    let mut file = BufWriter::new(File::create(&path)?);
+                    let copied = io::copy(&mut (&mut input).take(size), &mut file)?;
+                    if copied != size {
+                        return Err(invalid("archive is truncated"));
+                    }
+                    file.flush()?;
+                }
+                io::copy(&mut (&mut input).take(padding(size) as u64), &mut io::sink())?;
+                set_mtime(&path, mtime)?;
+                set_mode(&path, mode)?;
+            }
+            other => return Err(invalid(&format!("unsupported archive entry type {}", other as char))),
+        }
+    }
+
+    for (path, mtime, mode) in dir_times.into_iter().rev() {
+        // Best effort: not every platform can set a directory's time
+        let _ = set_mtime(&path, mtime);
+        set_mode(&path, mode)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(what: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sdfs_archive_test_{}_{:016x}", what, rand::random::<u64>()));
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    // A tree with the things that need more than a plain ustar header: a path over 100 bytes,
+    // a sub-second modification time, an empty file, odd modes and a symlink
+    fn sample_tree() -> PathBuf {
+        let dir = temp_dir("src");
+        let long_dir = dir.join("a".repeat(60)).join("b".repeat(60));
+        fs::create_dir_all(&long_dir).unwrap();
+        fs::write(long_dir.join("deep.txt"), b"far down").unwrap();
+        fs::write(dir.join("notes.txt"), vec![7u8; 1000]).unwrap();
+        fs::write(dir.join("empty"), b"").unwrap();
+        File::options().write(true).open(dir.join("notes.txt")).unwrap().set_modified(UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789)).unwrap();
+        set_mode(&dir.join("empty"), 0o600).unwrap();
+        // Only Linux restores the time of a link, which the repacking test needs
+        #[cfg(target_os = "linux")]
+        std::os::unix::fs::symlink("notes.txt", dir.join("link")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn same_tree_packs_to_same_bytes() {
+        let dir = sample_tree();
+        let first = dir.with_extension("1.tar");
+        let second = dir.with_extension("2.tar");
+        pack(&dir, &first).unwrap();
+        pack(&dir, &second).unwrap();
+        assert_eq!(fs::read(&first).unwrap(), fs::read(&second).unwrap());
+        assert_eq!(fs::metadata(&first).unwrap().len() % BLOCK as u64, 0);
+        for path in [&first, &second] {
+            fs::remove_file(path).unwrap();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unpacked_tree_matches_and_packs_the_same() {
+        let dir = sample_tree();
+        let archive = dir.with_extension("tar");
+        pack(&dir, &archive).unwrap();
+        let restored = temp_dir("dst");
+        unpack(&archive, &restored).unwrap();
+
+        let long_file = Path::new(&"a".repeat(60)).join("b".repeat(60)).join("deep.txt");
+        assert_eq!(fs::read(restored.join(&long_file)).unwrap(), b"far down");
+        assert_eq!(fs::read(restored.join("notes.txt")).unwrap(), vec![7u8; 1000]);
+        assert_eq!(fs::read(restored.join("empty")).unwrap(), b"");
+        let mtime = fs::metadata(restored.join("notes.txt")).unwrap().modified().unwrap();
+        assert_eq!(mtime, UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789));
+        assert_eq!(mode_of(&fs::metadata(restored.join("empty")).unwrap()), mode_of(&fs::metadata(dir.join("empty")).unwrap()));
+        #[cfg(target_os = "linux")]
+        assert_eq!(fs::read_link(restored.join("link")).unwrap(), Path::new("notes.txt"));
+
+        // Everything that goes into the archive came back, so packing again changes nothing
+        let again = restored.with_extension("tar");
+        pack(&restored, &again).unwrap();
+        assert!(fs::read(&archive).unwrap() == fs::read(&again).unwrap());
+        for path in [&archive, &again] {
+            fs::remove_file(path).unwrap();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&restored).unwrap();
+    }
+
+    #[test]
+    fn refuses_entries_outside_the_target() {
+        let dir = temp_dir("escape");
+        let archive = dir.join("evil.tar");
+        let mut data = header("../evil.txt", b'0', 0o644, 0, 0, "").to_vec();
+        data.extend_from_slice(&[0u8; BLOCK * 2]);
+        fs::write(&archive, data).unwrap();
+        assert!(unpack(&archive, &dir.join("out")).is_err());
+        assert!(!dir.join("evil.txt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,14 +2,12 @@ use std::io::{self, Write};
 use std::fs;
 use std::path::Path;
 use std::process::exit;
-use bcrypt::{hash, verify, DEFAULT_COST};
 use rpassword::read_password;
 use serde::{Deserialize, Serialize};
 use rand::rngs::OsRng;
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use hmac::{Hmac, Mac};
 use sha2::Sha256;
-use hex;
 
 type HmacSha256 = Hmac<Sha256>;
 
@@ -30,10 +28,17 @@ pub struct LoginAttempts {
 pub fn prompt_password(prompt: &str) -> String{
     print!("{}", prompt);
     io::stdout().flush().unwrap();
-    return read_password().unwrap();
+    read_password().unwrap()
 }
 
-fn write_to_file(pass_file: &str, pass_data: &PassData) -> () {
+// For commands whose stdout is meant to be parsed
+pub fn prompt_password_stderr(prompt: &str) -> String {
+    eprint!("{}", prompt);
+    io::stderr().flush().unwrap();
+    read_password().unwrap()
+}
+
+fn write_to_file(pass_file: &str, pass_data: &PassData) {
     if let Some(parent) = Path::new(pass_file).parent() {
         if !parent.exists() {
             fs::create_dir_all(parent).expect("Could not create directory");
@@ -47,7 +52,7 @@ fn write_to_file(pass_file: &str, pass_data: &PassData) -> () {
 fn read_from_file(pass_file: &str) -> PassData {
     let json_data = fs::read_to_string(pass_file).expect("Could not read from file");
     let pass_data: PassData = serde_json::from_str(&json_data).expect("Could not cast to PassData");
-    return pass_data;
+    pass_data
 }
 
 pub fn compute_mac(attempts: u32, key: &[u8]) -> String {
@@ -64,7 +69,7 @@ fn read_attempts(attempts_file: &str) -> Result<LoginAttempts, Box<dyn std::erro
     Ok(attempts)
 }
 
-pub fn write_attempts(attempts_file: &str, attempts: &LoginAttempts) -> () {
+pub fn write_attempts(attempts_file: &str, attempts: &LoginAttempts) {
     let data = serde_json::to_string_pretty(attempts).expect("Could not get data");
     fs::write(attempts_file, data).expect("Could not write data");
 }
@@ -87,33 +92,30 @@ fn increment_attempts(attempts_file: &str) -> bool {
     write_attempts(attempts_file, &attempts);
     println!("Failed login attempts: {}", attempts.attempts);
 
-    return false;
+    false
 }
 
-fn reset_attempts(attempts_file: &str) -> () {
-    let mut attempts: LoginAttempts = LoginAttempts { attempts: 0, mac: compute_mac(0, KEY), };
+pub fn reset_attempts(attempts_file: &str) {
+    let attempts: LoginAttempts = LoginAttempts { attempts: 0, mac: compute_mac(0, KEY), };
 
     write_attempts(attempts_file, &attempts);
 }
 
-pub fn setup_password(pass_file: &str) -> (){
+pub fn setup_password(pass_file: &str) {
 
 
-    let mut password: String = String::new();
-    let mut confirm: String = String::new();
-
-    loop {
-        password = prompt_password("Enter new password: ");
-        confirm = prompt_password("Confirm new password: ");
+    let password: String = loop {
+        let password = prompt_password("Enter new password: ");
+        let confirm = prompt_password("Confirm new password: ");
 
         if password == confirm {
-            break;
+            break password;
         }
         else {
             println!("Passwords do not match!");
             continue;
         }
-    }
+    };
     
     let salt = argon2::password_hash::SaltString::generate(&mut OsRng);
 
@@ -128,29 +130,6 @@ pub fn setup_password(pass_file: &str) -> (){
 
 }
 
-pub fn login(pass_file: &str, attempts_file: &str) -> bool {
-    let pass_data = read_from_file(pass_file);
-    let parsed_hash = PasswordHash::new(&pass_data.password_hash).expect("Stored hash is invalid");
-    let argon2obj = Argon2::default();
-    let mut inp_password: String = String::new();
-
-
-    loop {
-        inp_password = prompt_password("Enter password: ");
-
-        if argon2obj.verify_password(inp_password.as_bytes(), &parsed_hash).is_ok() {
-            reset_attempts(attempts_file);
-            break true;
-        } else {
-            println!("Incorrect password entered!");
-            if increment_attempts(attempts_file){
-                println!("Too many failed attempts! Self-destruct initiated.");
-                exit(1);
-            }
-        }
-    }
-}
-
 pub fn login_and_get_password(pass_file: &str, attempts_file: &str) -> Option<String> {
     let pass_data = read_from_file(pass_file);
     let parsed_hash = PasswordHash::new(&pass_data.password_hash).expect("Stored hash is invalid");
@@ -174,4 +153,21 @@ pub fn login_and_get_password(pass_file: &str, attempts_file: &str) -> Option<St
 
 pub fn get_password_from_user() -> String {
     prompt_password("Enter password for encryption/decryption: ")
-}
\ No newline at end of file
+}
+
+// Integrity checks used by the verify command; these never prompt or modify the files
+pub fn check_pass_file(pass_file: &str) -> Result<(), String> {
+    let json_data = fs::read_to_string(pass_file).map_err(|e| format!("cannot read: {}", e))?;
+    let pass_data: PassData = serde_json::from_str(&json_data).map_err(|e| format!("malformed: {}", e))?;
+    PasswordHash::new(&pass_data.password_hash).map_err(|e| format!("invalid password hash: {}", e))?;
+    Ok(())
+}
+
+pub fn check_attempts_file(attempts_file: &str) -> Result<u32, String> {
+    let data = fs::read_to_string(attempts_file).map_err(|e| format!("cannot read: {}", e))?;
+    let attempts: LoginAttempts = serde_json::from_str(&data).map_err(|e| format!("malformed: {}", e))?;
+    if attempts.mac != compute_mac(attempts.attempts, KEY) {
+        return Err("MAC mismatch, attempts counter has been tampered with".to_string());
+    }
+    Ok(attempts.attempts)
+}
@@ -0,0 +1,138 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use crate::vhd;
+
+// Byte-addressed access to the disk inside a locker image, whatever the image format. The
+// filesystem code only ever talks to this, so it works the same on a raw image, a fixed VHD
+// or a dynamic VHD, and on a single partition of any of them.
+pub trait BlockDevice {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()>;
+    // Size of the disk in bytes
+    fn size(&self) -> u64;
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+fn out_of_range(offset: u64, len: usize, size: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("Access at {}..{} is past the end of a {} byte disk", offset, offset + len as u64, size),
+    )
+}
+
+pub fn check_range(offset: u64, len: usize, size: u64) -> io::Result<()> {
+    match offset.checked_add(len as u64) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(out_of_range(offset, len, size)),
+    }
+}
+
+// A raw image (loop backend) or the data area of a fixed VHD, which is the same thing
+// followed by a footer that is never touched here
+pub struct RawImage {
+    file: File,
+    size: u64,
+}
+
+impl RawImage {
+    pub fn new(file: File, size: u64) -> Self {
+        Self { file, size }
+    }
+}
+
+impl BlockDevice for RawImage {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_range(offset, buf.len(), self.size)?;
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buf)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        check_range(offset, buf.len(), self.size)?;
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(buf)
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+// A window onto part of another device, used for a partition
+pub struct Slice<D: BlockDevice> {
+    inner: D,
+    start: u64,
+    len: u64,
+}
+
+impl<D: BlockDevice> Slice<D> {
+    pub fn new(inner: D, start: u64, len: u64) -> io::Result<Self> {
+        check_range(start, len as usize, inner.size())?;
+        Ok(Self { inner, start, len })
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Slice<D> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_range(offset, buf.len(), self.len)?;
+        self.inner.read_at(self.start + offset, buf)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        check_range(offset, buf.len(), self.len)?;
+        self.inner.write_at(self.start + offset, buf)
+    }
+
+    fn size(&self) -> u64 {
+        self.len
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl BlockDevice for Box<dyn BlockDevice> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        (**self).read_at(offset, buf)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        (**self).write_at(offset, buf)
+    }
+
+    fn size(&self) -> u64 {
+        (**self).size()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+}
+
+// Opens the disk inside an image file, picking the format from the file itself: a VHD footer
+// means a fixed or dynamic VHD, anything else is treated as a raw disk
+pub fn open_image(path: &Path, writable: bool) -> Result<Box<dyn BlockDevice>, Box<dyn std::error::Error>> {
+    if vhd::is_vhdx(path) {
+        return Err("VHDX images can only be opened by attaching them".into());
+    }
+    let mut file = OpenOptions::new().read(true).write(writable).open(path)?;
+    let len = file.metadata()?.len();
+    match vhd::read_footer(&mut file) {
+        Ok(footer) if footer.disk_type == vhd::DISK_TYPE_FIXED => {
+            vhd::validate(path)?;
+            Ok(Box::new(RawImage::new(file, footer.current_size)))
+        }
+        Ok(footer) if footer.disk_type == vhd::DISK_TYPE_DYNAMIC => {
+            vhd::validate(path)?;
+            Ok(Box::new(vhd::DynamicDisk::open(file, footer)?))
+        }
+        Ok(footer) => Err(format!("VHD disk type {} is not supported", footer.disk_type).into()),
+        Err(_) => Ok(Box::new(RawImage::new(file, len))),
+    }
+}
@@ -0,0 +1,626 @@
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::time::{SystemTime, UNIX_EPOCH};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+// File formats a fragment can be wrapped in, so it passes for an ordinary picture or archive
+// rather than a file of random bytes. The payload, i.e. the fragment's chunks exactly as they
+// would be stored raw, is kept uncompressed and in order, so any stretch of it sits at a known
+// offset in the carrier file and can still be fetched on its own.
+//  - png: a small noise image, with the payload in private ancillary chunks that viewers skip
+//  - zip: an archive holding the payload as its one stored entry
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Carrier {
+    #[default]
+    Raw,
+    Png,
+    Zip,
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const PNG_SIDE: usize = 32;
+const PNG_ROW: usize = 1 + PNG_SIDE * 3;
+const PNG_PIXELS: usize = PNG_ROW * PNG_SIDE;
+// Signature, IHDR, and an IDAT holding the RGB pixels in a single stored zlib block
+const PNG_HEADER: u64 = 8 + (12 + 13) + (12 + 2 + 5 + PNG_PIXELS as u64 + 4);
+// Private, ancillary, safe-to-copy chunk type the payload is kept in
+const PNG_PAYLOAD: &[u8; 4] = b"daTa";
+// Payload bytes per PNG chunk, well under the format's limit of 2^31 - 1
+const PNG_PIECE: u64 = 1 << 30;
+// Length, type and CRC around the data of every PNG chunk
+const PNG_FRAMING: u64 = 12;
+
+const ZIP_ENTRY: &[u8] = b"data.bin";
+// Entries this big need the ZIP64 extensions
+const ZIP64_LIMIT: u64 = 0xFFFF_FFFF;
+
+impl Carrier {
+    pub fn parse(input: &str) -> Result<Self, String> {
+        match input.trim().to_lowercase().as_str() {
+            "" | "1" | "raw" | "none" => Ok(Self::Raw),
+            "2" | "png" => Ok(Self::Png),
+            "3" | "zip" => Ok(Self::Zip),
+            other => Err(format!("Unknown carrier format: {}", other)),
+        }
+    }
+
+    // The extension a wrapped fragment should have
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Self::Raw => None,
+            Self::Png => Some("png"),
+            Self::Zip => Some("zip"),
+        }
+    }
+
+    fn header_len(self, payload_len: u64) -> u64 {
+        match self {
+            Self::Raw => 0,
+            Self::Png => PNG_HEADER,
+            Self::Zip => zip_local_header(payload_len, 0, 0).len() as u64,
+        }
+    }
+
+    fn trailer_len(self, payload_len: u64) -> u64 {
+        match self {
+            Self::Raw => 0,
+            Self::Png => PNG_FRAMING,
+            Self::Zip => zip_trailer(payload_len, 0, 0, 0).len() as u64,
+        }
+    }
+
+    // Size of the file a payload of `payload_len` bytes is stored in
+    pub fn wrapped_len(self, payload_len: u64) -> u64 {
+        let framing = match self {
+            Self::Png => payload_len.div_ceil(PNG_PIECE) * PNG_FRAMING,
+            Self::Raw | Self::Zip => 0,
+        };
+        self.header_len(payload_len) + payload_len + framing + self.trailer_len(payload_len)
+    }
+
+    // Where bytes `offset..offset + len` of the payload are in the file, as (offset, length) runs
+    pub fn locate(self, payload_len: u64, offset: u64, len: u64) -> Vec<(u64, u64)> {
+        match self {
+            Self::Raw | Self::Zip if len == 0 => Vec::new(),
+            Self::Raw | Self::Zip => vec![(self.header_len(payload_len) + offset, len)],
+            Self::Png => {
+                let mut runs = Vec::new();
+                let (mut offset, mut len) = (offset, len);
+                while len > 0 {
+                    let piece = offset / PNG_PIECE;
+                    let within = offset % PNG_PIECE;
+                    let n = len.min(PNG_PIECE - within);
+                    runs.push((PNG_HEADER + piece * (PNG_PIECE + PNG_FRAMING) + 8 + within, n));
+                    offset += n;
+                    len -= n;
+                }
+                runs
+            }
+        }
+    }
+
+    // The carrier file for `payload`, which must be exactly `payload_len` bytes long
+    pub fn wrap<'a>(self, payload: impl Read + 'a, payload_len: u64) -> Box<dyn Read + 'a> {
+        if self == Self::Raw {
+            return Box::new(payload);
+        }
+        let (time, date) = dos_timestamp();
+        let header = match self {
+            Self::Png => png_header(),
+            _ => zip_local_header(payload_len, time, date),
+        };
+        Box::new(Wrapper {
+            carrier: self,
+            inner: payload,
+            payload_len,
+            sent: 0,
+            piece_left: 0,
+            crc: Crc32::new(),
+            framing: header,
+            framing_at: 0,
+            done: false,
+            time,
+            date,
+        })
+    }
+
+    // The payload of a carrier file read from the start. Nothing is checked beyond the layout:
+    // the chunks are authenticated by the Merkle manifest. A truncated file gives a short
+    // payload, and anything after the end of the carrier is passed on as if it were payload,
+    // so either way the fragment reads as the wrong size.
+    pub fn unwrap<'a>(self, file: impl Read + 'a, payload_len: u64) -> Box<dyn Read + 'a> {
+        if self == Self::Raw {
+            return Box::new(file);
+        }
+        Box::new(Unwrapper {
+            inner: file,
+            runs: self.locate(payload_len, 0, payload_len).into(),
+            position: 0,
+            end: self.wrapped_len(payload_len),
+        })
+    }
+}
+
+struct Crc32(u32);
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+impl Crc32 {
+    fn new() -> Self {
+        Self(0xFFFF_FFFF)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = CRC_TABLE[((self.0 ^ byte as u32) & 0xFF) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = Crc32::new();
+    parts.iter().for_each(|part| crc.update(part));
+    crc.finish()
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&crc32(&[kind, data]).to_be_bytes());
+}
+
+fn png_header() -> Vec<u8> {
+    let mut out = PNG_SIGNATURE.to_vec();
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(PNG_SIDE as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(PNG_SIDE as u32).to_be_bytes());
+    // 8-bit RGB, no interlacing
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+    png_chunk(&mut out, b"IHDR", &ihdr);
+
+    // Every row starts with filter type 0
+    let mut pixels = vec![0u8; PNG_PIXELS];
+    rand::thread_rng().fill_bytes(&mut pixels);
+    for row in pixels.chunks_mut(PNG_ROW) {
+        row[0] = 0;
+    }
+    let mut zlib = vec![0x78, 0x01, 0x01];
+    zlib.extend_from_slice(&(PNG_PIXELS as u16).to_le_bytes());
+    zlib.extend_from_slice(&(!(PNG_PIXELS as u16)).to_le_bytes());
+    zlib.extend_from_slice(&pixels);
+    zlib.extend_from_slice(&adler32(&pixels).to_be_bytes());
+    png_chunk(&mut out, b"IDAT", &zlib);
+    out
+}
+
+fn png_trailer() -> Vec<u8> {
+    let mut out = Vec::new();
+    png_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+// The current time as a DOS time and date, which is what ZIP records
+fn dos_timestamp() -> (u16, u16) {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, time) = ((seconds / 86400) as i64, seconds % 86400);
+    // Civil date from the day number
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let m = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * m + 2) / 5 + 1;
+    let month = if m < 10 { m + 3 } else { m - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    let dos_time = ((time / 3600) << 11) | ((time % 3600 / 60) << 5) | ((time % 60) / 2);
+    let dos_date = ((year - 1980).clamp(0, 127) << 9) | (month << 5) | day;
+    (dos_time as u16, dos_date as u16)
+}
+
+fn put16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+// The CRC isn't known until the payload has gone by, so it follows in a data descriptor
+// (flag bit 3) and the local header leaves it and the sizes as zero
+fn zip_local_header(payload_len: u64, time: u16, date: u16) -> Vec<u8> {
+    let zip64 = payload_len >= ZIP64_LIMIT;
+    let mut out = Vec::new();
+    put32(&mut out, 0x0403_4B50);
+    put16(&mut out, if zip64 { 45 } else { 20 });
+    put16(&mut out, 0x0008);
+    // Stored, not compressed
+    put16(&mut out, 0);
+    put16(&mut out, time);
+    put16(&mut out, date);
+    put32(&mut out, 0);
+    let size = if zip64 { 0xFFFF_FFFF } else { 0 };
+    put32(&mut out, size);
+    put32(&mut out, size);
+    put16(&mut out, ZIP_ENTRY.len() as u16);
+    put16(&mut out, if zip64 { 20 } else { 0 });
+    out.extend_from_slice(ZIP_ENTRY);
+    if zip64 {
+        put16(&mut out, 0x0001);
+        put16(&mut out, 16);
+        put64(&mut out, 0);
+        put64(&mut out, 0);
+    }
+    out
+}
+
+// Data descriptor, central directory and end of central directory records
+fn zip_trailer(payload_len: u64, crc: u32, time: u16, date: u16) -> Vec<u8> {
+    let zip64 = payload_len >= ZIP64_LIMIT;
+    let version = if zip64 { 45 } else { 20 };
+    let mut out = Vec::new();
+    put32(&mut out, 0x0807_4B50);
+    put32(&mut out, crc);
+    if zip64 {
+        put64(&mut out, payload_len);
+        put64(&mut out, payload_len);
+    } else {
+        put32(&mut out, payload_len as u32);
+        put32(&mut out, payload_len as u32);
+    }
+
+    let directory_offset = zip_local_header(payload_len, time, date).len() as u64 + payload_len + out.len() as u64;
+    let directory_start = out.len();
+    put32(&mut out, 0x0201_4B50);
+    put16(&mut out, version);
+    put16(&mut out, version);
+    put16(&mut out, 0x0008);
+    put16(&mut out, 0);
+    put16(&mut out, time);
+    put16(&mut out, date);
+    put32(&mut out, crc);
+    let size = if zip64 { 0xFFFF_FFFF } else { payload_len as u32 };
+    put32(&mut out, size);
+    put32(&mut out, size);
+    put16(&mut out, ZIP_ENTRY.len() as u16);
+    put16(&mut out, if zip64 { 20 } else { 0 });
+    // Comment, disk, internal and external attributes, local header offset
+    put16(&mut out, 0);
+    put16(&mut out, 0);
+    put16(&mut out, 0);
+    put32(&mut out, 0);
+    put32(&mut out, 0);
+    out.extend_from_slice(ZIP_ENTRY);
+    if zip64 {
+        put16(&mut out, 0x0001);
+        put16(&mut out, 16);
+        put64(&mut out, payload_len);
+        put64(&mut out, payload_len);
+    }
+    let directory_len = (out.len() - directory_start) as u64;
+
+    if zip64 {
+        let record_offset = directory_offset + directory_len;
+        put32(&mut out, 0x0606_4B50);
+        put64(&mut out, 44);
+        put16(&mut out, version);
+        put16(&mut out, version);
+        put32(&mut out, 0);
+        put32(&mut out, 0);
+        put64(&mut out, 1);
+        put64(&mut out, 1);
+        put64(&mut out, directory_len);
+        put64(&mut out, directory_offset);
+        put32(&mut out, 0x0706_4B50);
+        put32(&mut out, 0);
+        put64(&mut out, record_offset);
+        put32(&mut out, 1);
+    }
+    put32(&mut out, 0x0605_4B50);
+    put16(&mut out, 0);
+    put16(&mut out, 0);
+    put16(&mut out, 1);
+    put16(&mut out, 1);
+    put32(&mut out, directory_len as u32);
+    put32(&mut out, if zip64 { 0xFFFF_FFFF } else { directory_offset as u32 });
+    put16(&mut out, 0);
+    out
+}
+
+// Streams a carrier file: the header, the payload with any framing it is split by, then the
+// trailer, which can only be written once the payload's CRC is known
+struct Wrapper<R> {
+    carrier: Carrier,
+    inner: R,
+    payload_len: u64,
+    sent: u64,
+    // Payload bytes left in the current PNG chunk, or in the whole ZIP entry
+    piece_left: u64,
+    crc: Crc32,
+    // Bytes of the carrier format waiting to go out before more payload
+    framing: Vec<u8>,
+    framing_at: usize,
+    done: bool,
+    time: u16,
+    date: u16,
+}
+
+impl<R: Read> Read for Wrapper<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.framing_at < self.framing.len() {
+                let n = (self.framing.len() - self.framing_at).min(buf.len());
+                buf[..n].copy_from_slice(&self.framing[self.framing_at..self.framing_at + n]);
+                self.framing_at += n;
+                return Ok(n);
+            }
+            if self.piece_left > 0 {
+                let want = self.piece_left.min(buf.len() as u64) as usize;
+                let n = self.inner.read(&mut buf[..want])?;
+                if n == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Payload ended after {} of {} bytes", self.sent, self.payload_len)));
+                }
+                self.crc.update(&buf[..n]);
+                self.sent += n as u64;
+                self.piece_left -= n as u64;
+                if self.carrier == Carrier::Png && self.piece_left == 0 {
+                    self.set_framing(self.crc.finish().to_be_bytes().to_vec());
+                }
+                return Ok(n);
+            }
+            if self.done {
+                return Ok(0);
+            }
+            let left = self.payload_len - self.sent;
+            if left == 0 {
+                // The source has to end here too, rather than being cut off
+                if self.inner.read(&mut [0u8; 1])? != 0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Payload is longer than {} bytes", self.payload_len)));
+                }
+                let trailer = match self.carrier {
+                    Carrier::Png => png_trailer(),
+                    _ => zip_trailer(self.payload_len, self.crc.finish(), self.time, self.date),
+                };
+                self.set_framing(trailer);
+                self.done = true;
+            } else if self.carrier == Carrier::Png {
+                self.piece_left = left.min(PNG_PIECE);
+                let mut framing = (self.piece_left as u32).to_be_bytes().to_vec();
+                framing.extend_from_slice(PNG_PAYLOAD);
+                self.set_framing(framing);
+                self.crc = Crc32::new();
+                self.crc.update(PNG_PAYLOAD);
+            } else {
+                self.piece_left = left;
+            }
+        }
+    }
+}
+
+impl<R> Wrapper<R> {
+    fn set_framing(&mut self, framing: Vec<u8>) {
+        self.framing = framing;
+        self.framing_at = 0;
+    }
+}
+
+// Reads the payload runs out of a carrier file, skipping everything around them
+struct Unwrapper<R> {
+    inner: R,
+    runs: VecDeque<(u64, u64)>,
+    position: u64,
+    // Where the carrier file should end
+    end: u64,
+}
+
+impl<R: Read> Unwrapper<R> {
+    // False if the file ended first
+    fn skip_to(&mut self, target: u64) -> io::Result<bool> {
+        let skipped = io::copy(&mut (&mut self.inner).take(target - self.position), &mut io::sink())?;
+        self.position += skipped;
+        Ok(self.position == target)
+    }
+}
+
+impl<R: Read> Read for Unwrapper<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(&(start, len)) = self.runs.front() {
+            if self.position < start && !self.skip_to(start)? {
+                return Ok(0);
+            }
+            let want = (start + len - self.position).min(buf.len() as u64) as usize;
+            let n = self.inner.read(&mut buf[..want])?;
+            self.position += n as u64;
+            if self.position == start + len {
+                self.runs.pop_front();
+            }
+            return Ok(n);
+        }
+        if self.position < self.end && !self.skip_to(self.end)? {
+            return Ok(0);
+        }
+        self.inner.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 13 % 256) as u8).collect()
+    }
+
+    fn wrapped(carrier: Carrier, payload: &[u8]) -> Vec<u8> {
+        let mut file = Vec::new();
+        carrier.wrap(payload, payload.len() as u64).read_to_end(&mut file).unwrap();
+        assert_eq!(file.len() as u64, carrier.wrapped_len(payload.len() as u64));
+        file
+    }
+
+    fn be32(data: &[u8], at: usize) -> u32 {
+        u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    fn le16(data: &[u8], at: usize) -> usize {
+        u16::from_le_bytes(data[at..at + 2].try_into().unwrap()) as usize
+    }
+
+    fn le32(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    // Walks the chunks of a PNG file, checking every CRC and the zlib stream of the image, and
+    // returns the chunk types in order
+    fn png_chunks(file: &[u8]) -> Vec<[u8; 4]> {
+        assert_eq!(file[..8], PNG_SIGNATURE);
+        let mut kinds = Vec::new();
+        let mut at = 8;
+        while at < file.len() {
+            let len = be32(file, at) as usize;
+            let kind: [u8; 4] = file[at + 4..at + 8].try_into().unwrap();
+            let data = &file[at + 8..at + 8 + len];
+            assert_eq!(be32(file, at + 8 + len), crc32(&[&kind, data]), "CRC of {:?}", kind);
+            if &kind == b"IDAT" {
+                assert_eq!(u16::from_be_bytes([data[0], data[1]]) % 31, 0);
+                let stored = le16(data, 3);
+                assert_eq!(stored, !le16(data, 5) & 0xFFFF);
+                assert_eq!(stored, PNG_PIXELS);
+                let pixels = &data[7..7 + stored];
+                assert!(pixels.chunks(PNG_ROW).all(|row| row[0] == 0));
+                assert_eq!(be32(data, 7 + stored), adler32(pixels));
+            }
+            kinds.push(kind);
+            at += PNG_FRAMING as usize + len;
+        }
+        assert_eq!(at, file.len());
+        kinds
+    }
+
+    // Reads a ZIP file from its central directory the way an unzip tool does, checks the one
+    // entry against its local header and CRC, and returns its name and data
+    fn zip_entry(file: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let end = file.len() - 22;
+        assert_eq!(le32(file, end), 0x0605_4B50);
+        assert_eq!((le16(file, end + 8), le16(file, end + 10)), (1, 1));
+        let directory = le32(file, end + 16) as usize;
+        assert_eq!(directory + le32(file, end + 12) as usize, end);
+        assert_eq!(le32(file, directory), 0x0201_4B50);
+        let crc = le32(file, directory + 16);
+        let size = le32(file, directory + 20) as usize;
+        assert_eq!(le32(file, directory + 24) as usize, size);
+        let name = file[directory + 46..directory + 46 + le16(file, directory + 28)].to_vec();
+        let local = le32(file, directory + 42) as usize;
+        assert_eq!(le32(file, local), 0x0403_4B50);
+        assert_eq!(le16(file, local + 8), 0);
+        assert_eq!(file[local + 30..local + 30 + le16(file, local + 26)], name);
+        let start = local + 30 + le16(file, local + 26) + le16(file, local + 28);
+        let data = file[start..start + size].to_vec();
+        assert_eq!(crc32(&[&data]), crc);
+        // The data descriptor repeats the CRC and sizes the local header left out
+        assert_eq!(le32(file, start + size), 0x0807_4B50);
+        assert_eq!(le32(file, start + size + 4), crc);
+        (name, data)
+    }
+
+    #[test]
+    fn checksums_match_the_standard_ones() {
+        assert_eq!(crc32(&[b"1234", b"56789"]), 0xCBF4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn wrapped_files_round_trip() {
+        for carrier in [Carrier::Raw, Carrier::Png, Carrier::Zip] {
+            for len in [0, 1, 1000, 100_000] {
+                let data = payload(len);
+                let file = wrapped(carrier, &data);
+                let mut unwrapped = Vec::new();
+                carrier.unwrap(file.as_slice(), len as u64).read_to_end(&mut unwrapped).unwrap();
+                assert!(unwrapped == data, "{:?} with {} bytes", carrier, len);
+
+                // Every stretch of the payload can be found in the file on its own
+                for (offset, part) in [(0, len), (len / 3, len / 2), (len.saturating_sub(1), 1.min(len))] {
+                    let runs = carrier.locate(len as u64, offset as u64, part as u64);
+                    let found: Vec<u8> = runs.iter().flat_map(|&(at, n)| file[at as usize..(at + n) as usize].to_vec()).collect();
+                    assert!(found == data[offset..offset + part]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn png_carrier_is_a_valid_png() {
+        let data = payload(5000);
+        let file = wrapped(Carrier::Png, &data);
+        assert_eq!(png_chunks(&file), vec![*b"IHDR", *b"IDAT", *PNG_PAYLOAD, *b"IEND"]);
+        assert_eq!(be32(&file, 16), PNG_SIDE as u32);
+        // Lower case first letter: ancillary, so viewers skip it. Lower case last: safe to copy.
+        assert!(PNG_PAYLOAD[0].is_ascii_lowercase() && PNG_PAYLOAD[3].is_ascii_lowercase());
+        assert_eq!(png_chunks(&wrapped(Carrier::Png, &[])), vec![*b"IHDR", *b"IDAT", *b"IEND"]);
+    }
+
+    #[test]
+    fn zip_carrier_is_a_valid_zip() {
+        for len in [0, 5000] {
+            let data = payload(len);
+            let (name, stored) = zip_entry(&wrapped(Carrier::Zip, &data));
+            assert_eq!(name, ZIP_ENTRY);
+            assert!(stored == data);
+        }
+    }
+
+    #[test]
+    fn damaged_files_read_as_the_wrong_size() {
+        let data = payload(1000);
+        for carrier in [Carrier::Png, Carrier::Zip] {
+            let file = wrapped(carrier, &data);
+            let mut short = Vec::new();
+            carrier.unwrap(&file[..file.len() - 500], 1000).read_to_end(&mut short).unwrap();
+            assert!(short.len() < data.len());
+            let mut long = Vec::new();
+            carrier.unwrap([file.as_slice(), b"extra"].concat().as_slice(), 1000).read_to_end(&mut long).unwrap();
+            assert_eq!(long.len(), data.len() + 5);
+            // A payload that doesn't match its stated length is refused while wrapping
+            assert!(carrier.wrap(&data[..999], 1000).read_to_end(&mut Vec::new()).is_err());
+            assert!(carrier.wrap(data.as_slice(), 999).read_to_end(&mut Vec::new()).is_err());
+        }
+    }
+}
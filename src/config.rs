@@ -0,0 +1,74 @@
+use std::fs;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+// Unencrypted settings in files/config.json. They are needed before any password has been
+// entered (to tell whether the locker is attached), so nothing secret belongs here.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VaultConfig {
+    // "vhd" (Windows VHD through diskpart), "loop" (raw image on a Linux loop device) or
+    // "directory" (plain working directory, archived on lock)
+    pub backend: String,
+    // Drive letter for "vhd", mount directory for "loop", working directory for "directory"
+    #[serde(default)]
+    pub mount_point: String,
+    // Filesystem the image is formatted with, e.g. "ntfs" or "ext4"
+    #[serde(default)]
+    pub filesystem: String,
+    // "fixed", "dynamic" or "vhdx" for the "vhd" backend; empty means fixed
+    #[serde(default)]
+    pub disk_format: String,
+    // Keep the unlocked locker in memory (a tmpfs directory) instead of under files/
+    #[serde(default)]
+    pub ram_only: bool,
+    // The in-memory directory used when ram_only is set, picked during setup
+    #[serde(default)]
+    pub ram_dir: String,
+}
+
+impl VaultConfig {
+    // What an install made before config.json existed was using
+    pub fn platform_default() -> Self {
+        if cfg!(windows) {
+            Self {
+                backend: "vhd".to_string(),
+                mount_point: String::new(),
+                filesystem: "ntfs".to_string(),
+                disk_format: "fixed".to_string(),
+                ram_only: false,
+                ram_dir: String::new(),
+            }
+        } else {
+            Self {
+                backend: "loop".to_string(),
+                mount_point: String::new(),
+                filesystem: "ext4".to_string(),
+                disk_format: String::new(),
+                ram_only: false,
+                ram_dir: String::new(),
+            }
+        }
+    }
+
+    pub fn image_name(&self) -> &'static str {
+        match self.backend.as_str() {
+            "vhd" if self.disk_format == "vhdx" => "locker.vhdx",
+            "vhd" => "locker.vhd",
+            "directory" => "locker.tar",
+            _ => "locker.img",
+        }
+    }
+}
+
+pub fn load(config_file: &str) -> Result<VaultConfig, Box<dyn std::error::Error>> {
+    if !Path::new(config_file).exists() {
+        return Ok(VaultConfig::platform_default());
+    }
+    let config: VaultConfig = serde_json::from_str(&fs::read_to_string(config_file)?)?;
+    Ok(config)
+}
+
+pub fn save(config_file: &str, config: &VaultConfig) -> Result<(), Box<dyn std::error::Error>> {
+    fs::write(config_file, serde_json::to_string_pretty(config)?)?;
+    Ok(())
+}
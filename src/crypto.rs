@@ -1,7 +1,12 @@
-use std::fs::{File};
-use std::io::{Read, Write, BufReader, BufWriter};
+use std::io::{self, Read, Write};
 use aes_gcm::{Aes256Gcm, Key, Nonce};
 use aes_gcm::aead::{Aead, KeyInit};
+use aes::Aes256;
+use aes::cipher::{BlockEncrypt, generic_array::GenericArray};
+use ghash::GHash;
+use ghash::universal_hash::UniversalHash;
+use rand::rngs::OsRng;
+use rand::RngCore;
 use sha2::{Sha256, Digest};
 
 // Derive a 32-byte key from password and KEY
@@ -14,48 +19,396 @@ fn derive_key(password: &str, key_const: &[u8]) -> [u8; 32] {
     key
 }
 
-pub fn encrypt_file(input_path: &str, output_path: &str, password: &str, key_const: &[u8]) -> std::io::Result<()> {
+// Nonce everything was encrypted with before each encryption got a random one
+pub const LEGACY_NONCE: &[u8; NONCE_SIZE] = b"nonce_aesgcm";
+pub const NONCE_SIZE: usize = 12;
+
+pub fn random_nonce() -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+// Encrypts under a fresh random nonce, which goes in front of the ciphertext. The same
+// password encrypts the fragment info again on every save, so a fixed nonce would give away the
+// XOR of the versions and the GHASH key.
+pub fn encrypt_bytes(data: &[u8], password: &str, key_const: &[u8]) -> Vec<u8> {
     let key_bytes = derive_key(password, key_const);
     let key = Key::<Aes256Gcm>::from_slice(&key_bytes);
     let cipher = Aes256Gcm::new(key);
-    // Use a 12-byte nonce as required by AES-GCM
-    let nonce = Nonce::from_slice(b"nonce_aesgcm"); // 12 bytes
-
-    let mut input = BufReader::new(File::open(input_path)?);
-    let mut buffer = Vec::new();
-    input.read_to_end(&mut buffer)?;
-    let ciphertext = cipher.encrypt(nonce, buffer.as_ref()).expect("encryption failure!");
-    let mut output = BufWriter::new(File::create(output_path)?);
-    output.write_all(&ciphertext)?;
-    Ok(())
+    let nonce = random_nonce();
+    let mut sealed = nonce.to_vec();
+    sealed.extend(cipher.encrypt(Nonce::from_slice(&nonce), data).expect("encryption failure!"));
+    sealed
 }
 
-pub fn decrypt_file(input_path: &str, output_path: &str, password: &str, key_const: &[u8]) -> std::io::Result<()> {
+// A wrong password is reported as an error instead of a panic. Data encrypted before the nonce
+// was stored in front of it was encrypted with LEGACY_NONCE, and is still accepted.
+pub fn decrypt_bytes(data: &[u8], password: &str, key_const: &[u8]) -> std::io::Result<Vec<u8>> {
     let key_bytes = derive_key(password, key_const);
     let key = Key::<Aes256Gcm>::from_slice(&key_bytes);
     let cipher = Aes256Gcm::new(key);
-    let nonce = Nonce::from_slice(b"nonce_aesgcm");
-    let mut input = BufReader::new(File::open(input_path)?);
-    let mut buffer = Vec::new();
-    input.read_to_end(&mut buffer)?;
-    match cipher.decrypt(nonce, buffer.as_ref()) {
-        Ok(plaintext) => {
-            let mut output = BufWriter::new(File::create(output_path)?);
-            output.write_all(&plaintext)?;
-            Ok(())
-        },
-        Err(e) => {
-            panic!("decryption failure!: {:?}", e);
+    data.split_at_checked(NONCE_SIZE)
+        .and_then(|(nonce, ciphertext)| cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok())
+        .or_else(|| cipher.decrypt(Nonce::from_slice(LEGACY_NONCE), data).ok())
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "decryption failure (wrong password or corrupted data)"))
+}
+
+// Decrypts any byte range of a ciphertext made by GcmWriter without reading the rest. GCM
+// encrypts with AES in counter mode: for a 12-byte nonce, byte i of the plaintext is XORed with
+// the keystream block AES(nonce || i / 16 + 2). The tag is not checked, so the caller has to
+// authenticate the ciphertext another way, e.g. against the Merkle manifest.
+pub struct Keystream {
+    cipher: Aes256,
+    nonce: [u8; NONCE_SIZE],
+}
+
+impl Keystream {
+    pub fn new(password: &str, key_const: &[u8], nonce: &[u8; NONCE_SIZE]) -> Self {
+        let key_bytes = derive_key(password, key_const);
+        Self { cipher: Aes256::new(GenericArray::from_slice(&key_bytes)), nonce: *nonce }
+    }
+
+    fn encrypt_block(&self, block: [u8; 16]) -> [u8; 16] {
+        let mut block = GenericArray::from(block);
+        self.cipher.encrypt_block(&mut block);
+        block.into()
+    }
+
+    // AES(nonce || counter)
+    fn counter_block(&self, counter: u32) -> [u8; 16] {
+        let mut block = [0u8; 16];
+        block[..12].copy_from_slice(&self.nonce);
+        block[12..].copy_from_slice(&counter.to_be_bytes());
+        self.encrypt_block(block)
+    }
+
+    // XORs `data`, which starts at `offset` in the ciphertext, with the keystream
+    pub fn apply(&self, offset: u64, data: &mut [u8]) {
+        let mut position = offset;
+        let mut done = 0;
+        while done < data.len() {
+            let block = self.counter_block(((position / 16) as u32).wrapping_add(2));
+            let within = (position % 16) as usize;
+            let n = (16 - within).min(data.len() - done);
+            for (byte, key) in data[done..done + n].iter_mut().zip(&block[within..within + n]) {
+                *byte ^= key;
+            }
+            done += n;
+            position += n as u64;
+        }
+    }
+
+    // Identifies the key without revealing anything usable: counter block 0 is never part of
+    // the keystream or the tag for a 12-byte nonce. It is always taken under LEGACY_NONCE, so it
+    // stays the same whatever nonce the image was last encrypted under.
+    pub fn check_value(&self) -> String {
+        let mut block = [0u8; 16];
+        block[..12].copy_from_slice(LEGACY_NONCE);
+        hex::encode(self.encrypt_block(block))
+    }
+
+    // How the tag of a `len`-byte ciphertext changes when `delta` is XORed into it at `offset`.
+    // GHASH is linear, so ciphertext block j contributes block_j * H^(n + 1 - j) for n blocks,
+    // and a few rewritten bytes only need their own blocks hashed, not the whole image.
+    pub fn tag_delta(&self, len: u64, offset: u64, delta: &[u8]) -> [u8; 16] {
+        let h = u128::from_be_bytes(self.encrypt_block([0u8; 16]));
+        let first = offset / 16;
+        let lead = (offset % 16) as usize;
+        let mut padded = vec![0u8; (lead + delta.len()).div_ceil(16) * 16];
+        padded[lead..lead + delta.len()].copy_from_slice(delta);
+        let mut sum = 0u128;
+        for block in padded.chunks_exact(16) {
+            sum = gf_mul(sum, h) ^ u128::from_be_bytes(block.try_into().expect("16-byte block"));
+        }
+        let last = first + (padded.len() / 16) as u64 - 1;
+        gf_mul(sum, gf_pow(h, len.div_ceil(16) + 1 - last)).to_be_bytes()
+    }
+}
+
+// Multiplication in GHASH's field, with its reflected bit order (NIST SP 800-38D, algorithm 1)
+fn gf_mul(x: u128, y: u128) -> u128 {
+    let mut product = 0u128;
+    let mut v = y;
+    for i in 0..128 {
+        if (x >> (127 - i)) & 1 == 1 {
+            product ^= v;
+        }
+        v = if v & 1 == 1 { (v >> 1) ^ (0xE1 << 120) } else { v >> 1 };
+    }
+    product
+}
+
+fn gf_pow(base: u128, mut exponent: u64) -> u128 {
+    let mut result = 1u128 << 127;
+    let mut square = base;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = gf_mul(result, square);
+        }
+        square = gf_mul(square, square);
+        exponent >>= 1;
+    }
+    result
+}
+
+// Bytes the tag adds to the end of every encrypted image
+pub const GCM_TAG_SIZE: u64 = 16;
+const GCM_MAX_PLAINTEXT: u64 = ((1u64 << 32) - 2) * 16;
+
+// GHASH over a ciphertext fed to it in pieces of any size
+struct TagHasher {
+    ghash: GHash,
+    // Ciphertext not yet hashed because it doesn't fill a 16-byte block
+    partial: Vec<u8>,
+    len: u64,
+}
+
+impl TagHasher {
+    fn new(keystream: &Keystream) -> Self {
+        let hash_key = keystream.encrypt_block([0u8; 16]);
+        Self { ghash: GHash::new(&hash_key.into()), partial: Vec::with_capacity(16), len: 0 }
+    }
+
+    fn update(&mut self, ciphertext: &[u8]) {
+        // Hash whole blocks straight away and keep the remainder for the next piece
+        self.partial.extend_from_slice(ciphertext);
+        let whole = self.partial.len() / 16 * 16;
+        self.ghash.update_padded(&self.partial[..whole]);
+        self.partial.drain(..whole);
+        self.len += ciphertext.len() as u64;
+    }
+
+    fn finish(mut self, keystream: &Keystream) -> [u8; 16] {
+        self.ghash.update_padded(&self.partial);
+        let mut lengths = [0u8; 16];
+        lengths[8..].copy_from_slice(&(self.len * 8).to_be_bytes());
+        self.ghash.update(&[lengths.into()]);
+        let mut tag: [u8; 16] = self.ghash.finalize().into();
+        for (byte, mask) in tag.iter_mut().zip(keystream.counter_block(1)) {
+            *byte ^= mask;
+        }
+        tag
+    }
+}
+
+// Encrypts a stream into `inner` under `nonce`, producing exactly what AES-256-GCM would for the
+// same data but without ever holding all of it: each write is encrypted with the keystream and
+// folded into GHASH as it passes through, and finish() appends the tag. Writing more than 64 GB,
+// where the GCM block counter would wrap, is refused. Every image has to get its own nonce.
+pub struct GcmWriter<W: Write> {
+    inner: W,
+    keystream: Keystream,
+    tag: TagHasher,
+}
+
+impl<W: Write> GcmWriter<W> {
+    pub fn new(inner: W, password: &str, key_const: &[u8], nonce: &[u8; NONCE_SIZE]) -> Self {
+        let keystream = Keystream::new(password, key_const, nonce);
+        Self { inner, tag: TagHasher::new(&keystream), keystream }
+    }
+
+    // Writes the tag and hands back the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(&self.tag.finish(&self.keystream))?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for GcmWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.tag.len + buf.len() as u64 > GCM_MAX_PLAINTEXT {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "AES-GCM can't encrypt more than 64 GB under one nonce"));
         }
+        let mut data = buf.to_vec();
+        self.keystream.apply(self.tag.len, &mut data);
+        self.inner.write_all(&data)?;
+        self.tag.update(&data);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
 }
 
-// Encrypt a file (e.g., fragment_info.json) with the same key logic as VHD
-pub fn encrypt_json(input_path: &str, output_path: &str, password: &str, key_const: &[u8]) -> std::io::Result<()> {
-    encrypt_file(input_path, output_path, password, key_const)
+// The other direction: decrypts `len` bytes of ciphertext and tag from `inner`. The tag is
+// checked when the end is reached, so the read that would return end of file fails instead
+// if the password is wrong or the data was altered. Whatever was read before then must be
+// thrown away in that case.
+pub struct GcmReader<R: Read> {
+    inner: R,
+    keystream: Keystream,
+    tag: Option<TagHasher>,
+    ciphertext_len: u64,
 }
 
-// Decrypt a file (e.g., fragment_info.json) with the same key logic as VHD
-pub fn decrypt_json(input_path: &str, output_path: &str, password: &str, key_const: &[u8]) -> std::io::Result<()> {
-    decrypt_file(input_path, output_path, password, key_const)
+impl<R: Read> GcmReader<R> {
+    pub fn new(inner: R, len: u64, password: &str, key_const: &[u8], nonce: &[u8; NONCE_SIZE]) -> io::Result<Self> {
+        let ciphertext_len = len
+            .checked_sub(GCM_TAG_SIZE)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Encrypted image is too short"))?;
+        let keystream = Keystream::new(password, key_const, nonce);
+        Ok(Self { inner, tag: Some(TagHasher::new(&keystream)), keystream, ciphertext_len })
+    }
+}
+
+impl<R: Read> Read for GcmReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(tag) = self.tag.as_mut() else {
+            return Ok(0);
+        };
+        let remaining = self.ciphertext_len - tag.len;
+        if remaining == 0 {
+            let mut expected = [0u8; 16];
+            self.inner.read_exact(&mut expected)?;
+            let tag = self.tag.take().expect("checked above").finish(&self.keystream);
+            if tag != expected {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "decryption failure (wrong password or corrupted data)"));
+            }
+            return Ok(0);
+        }
+        let want = remaining.min(buf.len() as u64) as usize;
+        let n = self.inner.read(&mut buf[..want])?;
+        if n == 0 && want > 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Encrypted image ended early"));
+        }
+        let position = tag.len;
+        tag.update(&buf[..n]);
+        self.keystream.apply(position, &mut buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_CONST: &[u8] = b"test key constant";
+
+    #[test]
+    fn every_encryption_gets_its_own_nonce() {
+        let first = encrypt_bytes(b"fragment info", "pw", KEY_CONST);
+        let second = encrypt_bytes(b"fragment info", "pw", KEY_CONST);
+        assert_ne!(first[..NONCE_SIZE], second[..NONCE_SIZE]);
+        assert_ne!(first[NONCE_SIZE..], second[NONCE_SIZE..]);
+        assert_eq!(decrypt_bytes(&first, "pw", KEY_CONST).unwrap(), b"fragment info");
+        assert_eq!(decrypt_bytes(&second, "pw", KEY_CONST).unwrap(), b"fragment info");
+        assert!(decrypt_bytes(&first, "wrong", KEY_CONST).is_err());
+    }
+
+    #[test]
+    fn decrypts_data_from_before_the_stored_nonce() {
+        let key_bytes = derive_key("pw", KEY_CONST);
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key_bytes));
+        let legacy = cipher.encrypt(Nonce::from_slice(LEGACY_NONCE), &b"old fragment info"[..]).unwrap();
+        assert_eq!(decrypt_bytes(&legacy, "pw", KEY_CONST).unwrap(), b"old fragment info");
+        assert!(decrypt_bytes(&legacy, "wrong", KEY_CONST).is_err());
+        assert!(decrypt_bytes(b"short", "pw", KEY_CONST).is_err());
+    }
+
+    fn encrypt_image(data: &[u8], nonce: &[u8; NONCE_SIZE]) -> Vec<u8> {
+        let mut writer = GcmWriter::new(Vec::new(), "pw", KEY_CONST, nonce);
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn images_under_new_nonces_share_no_keystream() {
+        let data = vec![0u8; 100];
+        let (first_nonce, second_nonce) = (random_nonce(), random_nonce());
+        let (first, second) = (encrypt_image(&data, &first_nonce), encrypt_image(&data, &second_nonce));
+        // Zeros encrypt to the keystream itself
+        assert_ne!(first[..100], second[..100]);
+        let mut decrypted = Vec::new();
+        GcmReader::new(&first[..], first.len() as u64, "pw", KEY_CONST, &first_nonce).unwrap().read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, data);
+        assert!(GcmReader::new(&first[..], first.len() as u64, "pw", KEY_CONST, &second_nonce).unwrap().read_to_end(&mut Vec::new()).is_err());
+        // The password check doesn't depend on the nonce
+        assert_eq!(Keystream::new("pw", KEY_CONST, &first_nonce).check_value(), Keystream::new("pw", KEY_CONST, &second_nonce).check_value());
+        assert_ne!(Keystream::new("pw", KEY_CONST, &first_nonce).check_value(), Keystream::new("other", KEY_CONST, &first_nonce).check_value());
+    }
+
+    fn aes_gcm(data: &[u8], nonce: &[u8; NONCE_SIZE]) -> Vec<u8> {
+        let key_bytes = derive_key("pw", KEY_CONST);
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key_bytes)).encrypt(Nonce::from_slice(nonce), data).unwrap()
+    }
+
+    fn decrypt_image(image: &[u8], nonce: &[u8; NONCE_SIZE], password: &str) -> io::Result<Vec<u8>> {
+        let mut reader = GcmReader::new(image, image.len() as u64, password, KEY_CONST, nonce)?;
+        // Small uneven reads, so blocks are split across them
+        let mut decrypted = Vec::new();
+        let mut piece = [0u8; 7];
+        loop {
+            match reader.read(&mut piece)? {
+                0 => return Ok(decrypted),
+                n => decrypted.extend_from_slice(&piece[..n]),
+            }
+        }
+    }
+
+    #[test]
+    fn streamed_encryption_is_aes_gcm() {
+        let nonce = random_nonce();
+        for len in [0, 1, 15, 16, 17, 1000, 70_000] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 253) as u8).collect();
+            // Written in pieces that don't line up with the 16-byte blocks
+            let mut writer = GcmWriter::new(Vec::new(), "pw", KEY_CONST, &nonce);
+            for piece in data.chunks(1 + len / 5) {
+                writer.write_all(piece).unwrap();
+            }
+            let streamed = writer.finish().unwrap();
+            assert!(streamed == aes_gcm(&data, &nonce), "{} bytes", len);
+            assert_eq!(streamed.len() as u64, len as u64 + GCM_TAG_SIZE);
+            assert!(decrypt_image(&streamed, &nonce, "pw").unwrap() == data);
+
+            // Any range decrypts on its own
+            if len > 20 {
+                let mut range = streamed[5..len - 3].to_vec();
+                Keystream::new("pw", KEY_CONST, &nonce).apply(5, &mut range);
+                assert!(range == data[5..len - 3]);
+            }
+        }
+    }
+
+    #[test]
+    fn reader_rejects_altered_images() {
+        let nonce = random_nonce();
+        let data = vec![42u8; 1000];
+        let image = encrypt_image(&data, &nonce);
+        for at in [0, 500, 999, 1000, 1015] {
+            let mut altered = image.clone();
+            altered[at] ^= 0x80;
+            assert!(decrypt_image(&altered, &nonce, "pw").is_err(), "flipped byte {}", at);
+        }
+        assert!(decrypt_image(&image, &nonce, "wrong").is_err());
+        assert!(decrypt_image(&image[..image.len() - 1], &nonce, "pw").is_err());
+        let mut reader = GcmReader::new(&image[..500], image.len() as u64, "pw", KEY_CONST, &nonce).unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+        assert!(GcmReader::new(&image[..10], 10, "pw", KEY_CONST, &nonce).is_err());
+    }
+
+    #[test]
+    fn tag_delta_patches_the_tag_of_a_rewrite() {
+        let nonce = random_nonce();
+        let keystream = Keystream::new("pw", KEY_CONST, &nonce);
+        let data: Vec<u8> = (0..1000).map(|i| (i * 7 % 256) as u8).collect();
+        let image = encrypt_image(&data, &nonce);
+        // Aligned, unaligned, across block boundaries, at both ends and into the short last block
+        for (offset, len) in [(0, 1), (0, 16), (5, 3), (14, 4), (100, 300), (990, 10), (995, 5), (0, 1000)] {
+            let mut rewritten = data.clone();
+            rewritten[offset..offset + len].iter_mut().for_each(|b| *b = b.wrapping_add(1));
+            let expected = encrypt_image(&rewritten, &nonce);
+
+            // Plaintext and ciphertext change by the same bytes
+            let delta: Vec<u8> = data[offset..offset + len].iter().zip(&rewritten[offset..offset + len]).map(|(a, b)| a ^ b).collect();
+            let mut patched = image.clone();
+            patched[offset..offset + len].iter_mut().zip(&delta).for_each(|(byte, d)| *byte ^= d);
+            let change = keystream.tag_delta(data.len() as u64, offset as u64, &delta);
+            patched[1000..].iter_mut().zip(change).for_each(|(byte, c)| *byte ^= c);
+            assert!(patched == expected, "{} bytes at {}", len, offset);
+        }
+        assert_eq!(keystream.tag_delta(1000, 40, &[0; 20]), [0; 16]);
+    }
 }
@@ -0,0 +1,90 @@
+use std::io;
+use rand::{CryptoRng, Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+use crate::filesys;
+use crate::keysetup;
+use crate::metadata::VaultMetadata;
+use crate::naming::{self, Namer};
+use crate::padding::Filler;
+use crate::store;
+
+// Decoy fragments: files of random bytes, named and sized like the real fragments and spread
+// over random directories the same way, so finding one fragment and searching for similar
+// files turns up chaff as well. They are only listed in the encrypted fragment info and are
+// never read back. Every lock rewrites them so their sizes and timestamps keep up with the
+// real fragments.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Decoy {
+    pub filename: String,
+    pub location: String,
+}
+
+// Brings the vault up to `decoy_count` decoys. With `renew`, or if the count changed, all of
+// them get new names and directories. Returns the decoys that were replaced, which the caller
+// deletes once the fragment info listing the new ones has been saved.
+pub fn plan(metadata: &mut VaultMetadata, base_path: &str, renew: bool, rng: &mut (impl RngCore + CryptoRng + ?Sized)) -> Result<Vec<Decoy>, String> {
+    if !renew && metadata.decoys.len() == metadata.decoy_count {
+        return Ok(Vec::new());
+    }
+    let old = std::mem::take(&mut metadata.decoys);
+    if metadata.decoy_count == 0 {
+        return Ok(old);
+    }
+    let directories = filesys::get_random_directories(metadata.decoy_count, base_path, rng);
+    if directories.is_empty() {
+        return Err(format!("No directories found under {} for decoy fragments", base_path));
+    }
+    // Named under the vault's policy like the fragments, from a token that stands for nothing
+    let mut namer = Namer::new(metadata.naming, metadata.carrier);
+    for fragment in &metadata.fragments {
+        namer.reserve(&fragment.location, &fragment.filename);
+    }
+    for i in 0..metadata.decoy_count {
+        let location = directories[i % directories.len()].clone();
+        let mut filename = None;
+        for _ in 0..naming::TOKEN_ATTEMPTS {
+            let token = keysetup::random_token(metadata.max_chunks, metadata.fragments.len(), rng);
+            filename = namer.name(&location, &token, rng).map_err(|e| format!("Listing {} failed: {}", location, e))?;
+            if filename.is_some() {
+                break;
+            }
+        }
+        let filename = filename.ok_or_else(|| format!("No free name for a decoy fragment in {}", location))?;
+        metadata.decoys.push(Decoy { filename, location });
+    }
+    Ok(old)
+}
+
+// Fills every decoy with fresh random bytes. Each one takes the size of a randomly chosen real
+// fragment give or take 20%, so the sizes follow whatever the chunk layout produces. Padded
+// fragments come in sizes drawn from a known range, so decoys are drawn from the same one.
+pub fn write(metadata: &VaultMetadata, rng: &mut (impl RngCore + CryptoRng + ?Sized)) -> io::Result<()> {
+    let sizes = metadata.padded_sizes();
+    if sizes.is_empty() {
+        return Ok(());
+    }
+    let largest = metadata.fragment_sizes().into_iter().max().unwrap_or(0);
+    for decoy in &metadata.decoys {
+        let size = match &metadata.padding {
+            Some(padding) => padding.target(largest, rng),
+            None => sizes[rng.gen_range(0..sizes.len())] * rng.gen_range(80..=120) / 100,
+        };
+        let chaff = Filler::new(ChaCha20Rng::from_rng(&mut *rng).map_err(io::Error::other)?, size);
+        store::open_store(&decoy.location)?.put(&decoy.filename, &mut metadata.carrier.wrap(chaff, size), metadata.carrier.wrapped_len(size))?;
+    }
+    Ok(())
+}
+
+// Securely deletes `decoys`, skipping any that are already gone
+pub fn delete(decoys: &[Decoy]) {
+    for decoy in decoys {
+        let result = store::open_store(&decoy.location).and_then(|s| match s.exists(&decoy.filename)? {
+            true => s.delete(&decoy.filename),
+            false => Ok(()),
+        });
+        if let Err(e) = result {
+            eprintln!("Warning: failed to delete decoy {}: {}", store::describe(&decoy.location, &decoy.filename), e);
+        }
+    }
+}
@@ -0,0 +1,1134 @@
+use std::collections::{BTreeSet, HashSet};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rand::RngCore;
+use crate::blockdev::{self, BlockDevice, Slice};
+
+// FAT32 written from scratch so the locker's contents can be formatted, listed, added to,
+// extracted and deleted without attaching the image. Follows the Microsoft FAT specification
+// (fatgen103): 512-byte sectors, two FATs, FSInfo and a backup boot sector, and long file names.
+
+const SECTOR: u64 = 512;
+const RESERVED_SECTORS: u32 = 32;
+const NUM_FATS: u32 = 2;
+// Partitions start at 1 MB, like every current partitioning tool
+const PARTITION_START: u64 = 2048;
+const MIN_CLUSTERS: u64 = 65525;
+const FAT_MASK: u32 = 0x0FFF_FFFF;
+const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+const ENTRY_SIZE: usize = 32;
+// A directory may hold at most 65536 entries
+const MAX_DIR_ENTRIES: usize = 65536;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+const PARTITION_TYPE_FAT32_LBA: u8 = 0x0C;
+
+type FatResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+// Cluster size by volume size, from the table in the specification
+fn sectors_per_cluster(total_sectors: u64) -> u64 {
+    match total_sectors {
+        0..=532_480 => 1,
+        532_481..=16_777_216 => 8,
+        16_777_217..=33_554_432 => 16,
+        33_554_433..=67_108_864 => 32,
+        _ => 64,
+    }
+}
+
+fn fat_sectors_for(total_sectors: u64, sectors_per_cluster: u64) -> u64 {
+    let tmp1 = total_sectors - RESERVED_SECTORS as u64;
+    let tmp2 = (256 * sectors_per_cluster + NUM_FATS as u64) / 2;
+    tmp1.div_ceil(tmp2)
+}
+
+fn write_mbr(dev: &mut dyn BlockDevice, start: u64, sectors: u64) -> io::Result<()> {
+    let mut mbr = [0u8; SECTOR as usize];
+    let mut signature = [0u8; 4];
+    rand::thread_rng().fill_bytes(&mut signature);
+    mbr[440..444].copy_from_slice(&signature);
+    let entry = &mut mbr[446..462];
+    // CHS fields set to the "use LBA" values
+    entry[1..4].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[4] = PARTITION_TYPE_FAT32_LBA;
+    entry[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[8..12].copy_from_slice(&(start as u32).to_le_bytes());
+    entry[12..16].copy_from_slice(&(sectors as u32).to_le_bytes());
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+    dev.write_at(0, &mbr)
+}
+
+fn write_zeros(dev: &mut dyn BlockDevice, offset: u64, len: u64) -> io::Result<()> {
+    let zeros = vec![0u8; 64 * 1024];
+    let mut done = 0;
+    while done < len {
+        let n = (len - done).min(zeros.len() as u64) as usize;
+        dev.write_at(offset + done, &zeros[..n])?;
+        done += n as u64;
+    }
+    Ok(())
+}
+
+// Formats the whole device as FAT32. With `partitioned` an MBR with a single FAT32 partition is
+// written first, which Windows needs for a fixed disk; without it the filesystem starts at
+// sector 0, which Linux can mount straight from a loop device.
+pub fn format(dev: &mut dyn BlockDevice, partitioned: bool, label: &str) -> FatResult<()> {
+    let disk_sectors = dev.size() / SECTOR;
+    let (start, total_sectors) = if partitioned {
+        if disk_sectors <= PARTITION_START {
+            return Err("Disk is too small to partition".into());
+        }
+        (PARTITION_START, disk_sectors - PARTITION_START)
+    } else {
+        (0, disk_sectors)
+    };
+    if total_sectors > u32::MAX as u64 {
+        return Err("FAT32 volumes are limited to 2 TB".into());
+    }
+
+    // Drop to smaller clusters if the table's choice leaves too few of them to count as FAT32
+    let mut spc = sectors_per_cluster(total_sectors);
+    let (fat_sectors, clusters) = loop {
+        let fat_sectors = fat_sectors_for(total_sectors, spc);
+        let data_sectors = total_sectors.saturating_sub(RESERVED_SECTORS as u64 + NUM_FATS as u64 * fat_sectors);
+        let clusters = data_sectors / spc;
+        if clusters < MIN_CLUSTERS && spc > 1 {
+            spc /= 2;
+            continue;
+        }
+        break (fat_sectors, clusters);
+    };
+    if clusters < MIN_CLUSTERS {
+        return Err("FAT32 needs a volume of at least 33 MB".into());
+    }
+
+    if partitioned {
+        write_mbr(dev, start, total_sectors)?;
+    }
+    let base = start * SECTOR;
+
+    let mut boot = [0u8; SECTOR as usize];
+    boot[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    boot[3..11].copy_from_slice(b"MSWIN4.1");
+    boot[11..13].copy_from_slice(&(SECTOR as u16).to_le_bytes());
+    boot[13] = spc as u8;
+    boot[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+    boot[16] = NUM_FATS as u8;
+    boot[21] = 0xF8;
+    boot[24..26].copy_from_slice(&63u16.to_le_bytes());
+    boot[26..28].copy_from_slice(&255u16.to_le_bytes());
+    boot[28..32].copy_from_slice(&(start as u32).to_le_bytes());
+    boot[32..36].copy_from_slice(&(total_sectors as u32).to_le_bytes());
+    boot[36..40].copy_from_slice(&(fat_sectors as u32).to_le_bytes());
+    boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+    boot[48..50].copy_from_slice(&1u16.to_le_bytes());
+    boot[50..52].copy_from_slice(&6u16.to_le_bytes());
+    boot[64] = 0x80;
+    boot[66] = 0x29;
+    let mut volume_id = [0u8; 4];
+    rand::thread_rng().fill_bytes(&mut volume_id);
+    boot[67..71].copy_from_slice(&volume_id);
+    let label = volume_label(label);
+    boot[71..82].copy_from_slice(&label);
+    boot[82..90].copy_from_slice(b"FAT32   ");
+    boot[510] = 0x55;
+    boot[511] = 0xAA;
+
+    let mut fsinfo = [0u8; SECTOR as usize];
+    fsinfo[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+    fsinfo[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+    // The root directory takes the first cluster
+    fsinfo[488..492].copy_from_slice(&((clusters - 1) as u32).to_le_bytes());
+    fsinfo[492..496].copy_from_slice(&3u32.to_le_bytes());
+    fsinfo[508..512].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+
+    write_zeros(dev, base, RESERVED_SECTORS as u64 * SECTOR)?;
+    for copy in [0, 6] {
+        dev.write_at(base + copy * SECTOR, &boot)?;
+        dev.write_at(base + (copy + 1) * SECTOR, &fsinfo)?;
+    }
+
+    let mut first_fat_sector = [0u8; SECTOR as usize];
+    first_fat_sector[0..4].copy_from_slice(&0x0FFF_FFF8u32.to_le_bytes());
+    first_fat_sector[4..8].copy_from_slice(&END_OF_CHAIN.to_le_bytes());
+    first_fat_sector[8..12].copy_from_slice(&END_OF_CHAIN.to_le_bytes());
+    for fat in 0..NUM_FATS as u64 {
+        let fat_start = base + (RESERVED_SECTORS as u64 + fat * fat_sectors) * SECTOR;
+        write_zeros(dev, fat_start, fat_sectors * SECTOR)?;
+        dev.write_at(fat_start, &first_fat_sector)?;
+    }
+
+    let root_start = base + (RESERVED_SECTORS as u64 + NUM_FATS as u64 * fat_sectors) * SECTOR;
+    write_zeros(dev, root_start, spc * SECTOR)?;
+    let mut label_entry = [0u8; ENTRY_SIZE];
+    label_entry[0..11].copy_from_slice(&label);
+    label_entry[11] = ATTR_VOLUME_ID;
+    let (date, time) = dos_datetime(SystemTime::now());
+    label_entry[22..24].copy_from_slice(&time.to_le_bytes());
+    label_entry[24..26].copy_from_slice(&date.to_le_bytes());
+    dev.write_at(root_start, &label_entry)?;
+    dev.flush()?;
+    Ok(())
+}
+
+// Opens an image file and formats the disk inside it
+pub fn format_image(path: &Path, partitioned: bool) -> FatResult<()> {
+    let mut dev = blockdev::open_image(path, true)?;
+    format(&mut *dev, partitioned, "Locker")
+}
+
+fn volume_label(label: &str) -> [u8; 11] {
+    let mut bytes = [b' '; 11];
+    for (slot, c) in bytes.iter_mut().zip(label.chars().filter(|c| c.is_ascii_alphanumeric() || *c == ' ')) {
+        *slot = c.to_ascii_uppercase() as u8;
+    }
+    bytes
+}
+
+fn is_fat32_boot_sector(sector: &[u8]) -> bool {
+    sector[510] == 0x55 && sector[511] == 0xAA && &sector[82..87] == b"FAT32"
+}
+
+// Days since 1970-01-01 to (year, month, day), proleptic Gregorian
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// FAT timestamps have no time zone; UTC is used both ways. Two-second resolution, 1980-2107.
+fn dos_datetime(time: SystemTime) -> (u16, u16) {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    let of_day = secs.rem_euclid(86_400);
+    if year < 1980 {
+        return ((1 << 5) | 1, 0);
+    }
+    let year = year.min(2107);
+    let date = (((year - 1980) as u16) << 9) | ((month as u16) << 5) | day as u16;
+    let time = (((of_day / 3600) as u16) << 11) | ((((of_day / 60) % 60) as u16) << 5) | ((of_day % 60) / 2) as u16;
+    (date, time)
+}
+
+fn system_time_from_dos(date: u16, time: u16) -> SystemTime {
+    let year = 1980 + (date >> 9) as i64;
+    let month = ((date >> 5) & 0x0F).clamp(1, 12) as u32;
+    let day = (date & 0x1F).max(1) as u32;
+    let secs = days_from_civil(year, month, day) * 86_400
+        + (time >> 11) as i64 * 3600
+        + ((time >> 5) & 0x3F) as i64 * 60
+        + (time & 0x1F) as i64 * 2;
+    UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64)
+}
+
+fn lfn_checksum(short: &[u8; 11]) -> u8 {
+    short.iter().fold(0u8, |sum, &b| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b))
+}
+
+fn short_name_string(short: &[u8; 11], case_flags: u8) -> String {
+    let mut base: String = short[..8].iter().map(|&b| b as char).collect::<String>().trim_end().to_string();
+    let mut ext: String = short[8..].iter().map(|&b| b as char).collect::<String>().trim_end().to_string();
+    if base.starts_with('\u{5}') {
+        base.replace_range(..1, "\u{e5}");
+    }
+    // Windows NT stores all-lowercase 8.3 names without a long name, using these flags
+    if case_flags & 0x08 != 0 {
+        base = base.to_lowercase();
+    }
+    if case_flags & 0x10 != 0 {
+        ext = ext.to_lowercase();
+    }
+    if ext.is_empty() { base } else { format!("{}.{}", base, ext) }
+}
+
+const SHORT_NAME_SPECIALS: &[u8] = b"$%'-_@~`!(){}^#&";
+
+fn is_short_char(b: u8) -> bool {
+    b.is_ascii_uppercase() || b.is_ascii_digit() || SHORT_NAME_SPECIALS.contains(&b)
+}
+
+// The 8.3 name itself when `name` is already a valid upper-case 8.3 name
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) => (base, ext),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || !base.bytes().chain(ext.bytes()).all(is_short_char) {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(short)
+}
+
+// Numeric-tail short name ("LONGFI~1.TXT") for a name that needs a long name entry
+fn generated_short_name(name: &str, taken: &HashSet<[u8; 11]>) -> FatResult<[u8; 11]> {
+    let clean = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|c| *c != ' ' && *c != '.')
+            .map(|c| {
+                let upper = c.to_ascii_uppercase();
+                if upper.is_ascii() && is_short_char(upper as u8) { upper as u8 } else { b'_' }
+            })
+            .collect()
+    };
+    let (base, ext) = match name.trim_start_matches('.').rsplit_once('.') {
+        Some((base, ext)) => (clean(base), clean(ext)),
+        None => (clean(name), Vec::new()),
+    };
+    let base = if base.is_empty() { b"_".to_vec() } else { base };
+    for n in 1..1_000_000u32 {
+        let tail = format!("~{}", n);
+        let keep = base.len().min(8 - tail.len());
+        let mut short = [b' '; 11];
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        let ext_len = ext.len().min(3);
+        short[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+        if !taken.contains(&short) {
+            return Ok(short);
+        }
+    }
+    Err(format!("No short name left for {}", name).into())
+}
+
+fn check_long_name(name: &str) -> FatResult<()> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(format!("\"{}\" is not a valid file name", name).into());
+    }
+    if name.ends_with('.') || name.ends_with(' ') {
+        return Err(format!("File names can't end with a dot or space: \"{}\"", name).into());
+    }
+    if let Some(c) = name.chars().find(|&c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c)) {
+        return Err(format!("File names can't contain {:?}: \"{}\"", c, name).into());
+    }
+    if name.encode_utf16().count() > 255 {
+        return Err(format!("File name is longer than 255 characters: \"{}\"", name).into());
+    }
+    Ok(())
+}
+
+fn lfn_entries(name: &str, short: &[u8; 11]) -> Vec<[u8; ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    if !units.len().is_multiple_of(13) {
+        units.push(0);
+        while !units.len().is_multiple_of(13) {
+            units.push(0xFFFF);
+        }
+    }
+    let checksum = lfn_checksum(short);
+    let count = units.len() / 13;
+    // Stored last part first
+    (0..count)
+        .rev()
+        .map(|i| {
+            let mut entry = [0u8; ENTRY_SIZE];
+            entry[0] = (i + 1) as u8 | if i + 1 == count { 0x40 } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            let part = &units[i * 13..(i + 1) * 13];
+            let offsets = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2));
+            for (offset, unit) in offsets.zip(part) {
+                entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            entry
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    short: [u8; 11],
+    pub attr: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+    date: u16,
+    time: u16,
+    // Slot of the 8.3 entry and how many long name slots come before it
+    slot: usize,
+    lfn_slots: usize,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    pub fn modified(&self) -> SystemTime {
+        system_time_from_dos(self.date, self.time)
+    }
+
+    pub fn modified_string(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}",
+            1980 + (self.date >> 9),
+            (self.date >> 5) & 0x0F,
+            self.date & 0x1F,
+            self.time >> 11,
+            (self.time >> 5) & 0x3F
+        )
+    }
+}
+
+fn parse_dir(data: &[u8]) -> Vec<DirEntry> {
+    let mut entries = Vec::new();
+    let mut lfn: Vec<(u8, Vec<u16>)> = Vec::new();
+    let mut lfn_checksum_seen = 0u8;
+    for (slot, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+        match raw[0] {
+            0x00 => break,
+            0xE5 => {
+                lfn.clear();
+                continue;
+            }
+            _ => {}
+        }
+        let attr = raw[11];
+        if attr & 0x3F == ATTR_LONG_NAME {
+            if raw[0] & 0x40 != 0 {
+                lfn.clear();
+            }
+            lfn_checksum_seen = raw[13];
+            let units: Vec<u16> = (1..11)
+                .step_by(2)
+                .chain((14..26).step_by(2))
+                .chain((28..32).step_by(2))
+                .map(|o| u16::from_le_bytes([raw[o], raw[o + 1]]))
+                .collect();
+            lfn.push((raw[0] & 0x1F, units));
+            continue;
+        }
+        let short: [u8; 11] = raw[..11].try_into().unwrap();
+        let lfn_parts = std::mem::take(&mut lfn);
+        if attr & ATTR_VOLUME_ID != 0 || short[0] == b'.' {
+            continue;
+        }
+        // The long name only counts if its parts are complete, in order and match this entry
+        let lfn_ok = !lfn_parts.is_empty()
+            && lfn_checksum_seen == lfn_checksum(&short)
+            && lfn_parts.iter().rev().enumerate().all(|(i, (ordinal, _))| *ordinal as usize == i + 1);
+        let name = if lfn_ok {
+            let units: Vec<u16> = lfn_parts.iter().rev().flat_map(|(_, u)| u.iter().copied()).take_while(|&u| u != 0).collect();
+            String::from_utf16_lossy(&units)
+        } else {
+            short_name_string(&short, raw[12])
+        };
+        entries.push(DirEntry {
+            name,
+            short,
+            attr,
+            first_cluster: (u16::from_le_bytes([raw[20], raw[21]]) as u32) << 16 | u16::from_le_bytes([raw[26], raw[27]]) as u32,
+            size: u32::from_le_bytes(raw[28..32].try_into().unwrap()),
+            time: u16::from_le_bytes([raw[22], raw[23]]),
+            date: u16::from_le_bytes([raw[24], raw[25]]),
+            slot,
+            lfn_slots: if lfn_ok { lfn_parts.len() } else { 0 },
+        });
+    }
+    entries
+}
+
+fn short_entry(short: &[u8; 11], attr: u8, first_cluster: u32, size: u32, modified: SystemTime) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0u8; ENTRY_SIZE];
+    entry[..11].copy_from_slice(short);
+    entry[11] = attr;
+    let (date, time) = dos_datetime(modified);
+    for at in [14, 22] {
+        entry[at..at + 2].copy_from_slice(&time.to_le_bytes());
+    }
+    for at in [16, 18, 24] {
+        entry[at..at + 2].copy_from_slice(&date.to_le_bytes());
+    }
+    entry[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    path.split(['/', '\\']).filter(|p| !p.is_empty() && *p != ".").collect()
+}
+
+pub struct Fat32 {
+    dev: Box<dyn BlockDevice>,
+    sectors_per_cluster: u64,
+    reserved_sectors: u64,
+    fat_sectors: u64,
+    num_fats: u64,
+    root_cluster: u32,
+    fsinfo_sector: u64,
+    cluster_count: u32,
+    fat: Vec<u32>,
+    // FAT sectors changed since the last flush
+    dirty: BTreeSet<u64>,
+    next_free: u32,
+}
+
+impl Fat32 {
+    // Finds the FAT32 filesystem on the disk, either at sector 0 or in an MBR partition
+    pub fn open(mut dev: Box<dyn BlockDevice>) -> FatResult<Self> {
+        let mut sector = [0u8; SECTOR as usize];
+        dev.read_at(0, &mut sector)?;
+        let mut dev: Box<dyn BlockDevice> = if is_fat32_boot_sector(&sector) {
+            dev
+        } else {
+            if sector[510] != 0x55 || sector[511] != 0xAA {
+                return Err("No FAT32 filesystem or partition table found in the image".into());
+            }
+            let partition = (0..4)
+                .map(|i| &sector[446 + i * 16..462 + i * 16])
+                .find(|e| [0x0B, 0x0C, 0x1B, 0x1C].contains(&e[4]))
+                .ok_or("The image has no FAT32 partition")?;
+            let start = u32::from_le_bytes(partition[8..12].try_into().unwrap()) as u64 * SECTOR;
+            let len = u32::from_le_bytes(partition[12..16].try_into().unwrap()) as u64 * SECTOR;
+            Box::new(Slice::new(dev, start, len)?)
+        };
+
+        dev.read_at(0, &mut sector)?;
+        if !is_fat32_boot_sector(&sector) {
+            return Err("Partition does not hold a FAT32 filesystem".into());
+        }
+        let le16 = |at: usize| u16::from_le_bytes([sector[at], sector[at + 1]]) as u64;
+        let le32 = |at: usize| u32::from_le_bytes(sector[at..at + 4].try_into().unwrap()) as u64;
+        if le16(11) != SECTOR {
+            return Err(format!("Only 512-byte sectors are supported, this volume uses {}", le16(11)).into());
+        }
+        let sectors_per_cluster = sector[13] as u64;
+        let reserved_sectors = le16(14);
+        let num_fats = sector[16] as u64;
+        let total_sectors = le32(32);
+        let fat_sectors = le32(36);
+        if sectors_per_cluster == 0 || !sectors_per_cluster.is_power_of_two() || num_fats == 0 || fat_sectors == 0 {
+            return Err("FAT32 boot sector is corrupt".into());
+        }
+        let data_start = reserved_sectors + num_fats * fat_sectors;
+        let cluster_count = (total_sectors.saturating_sub(data_start) / sectors_per_cluster)
+            .min(fat_sectors * SECTOR / 4 - 2) as u32;
+        if data_start * SECTOR >= dev.size() || cluster_count == 0 {
+            return Err("FAT32 boot sector describes a volume larger than the disk".into());
+        }
+
+        let mut raw_fat = vec![0u8; ((cluster_count as u64 + 2) * 4) as usize];
+        dev.read_at(reserved_sectors * SECTOR, &mut raw_fat)?;
+        let fat = raw_fat.chunks_exact(4).map(|e| u32::from_le_bytes(e.try_into().unwrap())).collect();
+
+        let fs = Self {
+            dev,
+            sectors_per_cluster,
+            reserved_sectors,
+            fat_sectors,
+            num_fats,
+            root_cluster: le32(44) as u32,
+            fsinfo_sector: le16(48),
+            cluster_count,
+            fat,
+            dirty: BTreeSet::new(),
+            next_free: 2,
+        };
+        if !fs.is_valid_cluster(fs.root_cluster) {
+            return Err("FAT32 root directory cluster is out of range".into());
+        }
+        Ok(fs)
+    }
+
+    fn cluster_bytes(&self) -> u64 {
+        self.sectors_per_cluster * SECTOR
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        (self.reserved_sectors + self.num_fats * self.fat_sectors) * SECTOR + (cluster as u64 - 2) * self.cluster_bytes()
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+
+    fn next_in_chain(&self, cluster: u32) -> u32 {
+        self.fat[cluster as usize] & FAT_MASK
+    }
+
+    fn set_fat(&mut self, cluster: u32, value: u32) {
+        let entry = &mut self.fat[cluster as usize];
+        // The top four bits are reserved and must be kept as they are
+        *entry = (*entry & !FAT_MASK) | (value & FAT_MASK);
+        self.dirty.insert(cluster as u64 * 4 / SECTOR);
+    }
+
+    fn chain(&self, first: u32) -> FatResult<Vec<u32>> {
+        let mut clusters = Vec::new();
+        let mut cluster = first;
+        while self.is_valid_cluster(cluster) {
+            if clusters.len() > self.cluster_count as usize {
+                return Err("Cluster chain loops back on itself".into());
+            }
+            clusters.push(cluster);
+            cluster = self.next_in_chain(cluster);
+        }
+        if cluster < 0x0FFF_FFF8 && !(first == 0 && clusters.is_empty()) {
+            return Err(format!("Cluster chain starting at {} is broken", first).into());
+        }
+        Ok(clusters)
+    }
+
+    fn free_count(&self) -> u32 {
+        self.fat[2..].iter().filter(|&&e| e & FAT_MASK == 0).count() as u32
+    }
+
+    // Allocates `count` clusters linked into one chain and returns them in order
+    fn allocate(&mut self, count: usize) -> FatResult<Vec<u32>> {
+        let mut picked = Vec::with_capacity(count);
+        let mut cluster = self.next_free;
+        for _ in 0..self.cluster_count {
+            if picked.len() == count {
+                break;
+            }
+            if !self.is_valid_cluster(cluster) {
+                cluster = 2;
+            }
+            if self.next_in_chain(cluster) == 0 {
+                picked.push(cluster);
+            }
+            cluster += 1;
+        }
+        if picked.len() < count {
+            return Err("Not enough free space in the locker".into());
+        }
+        for pair in picked.windows(2) {
+            self.set_fat(pair[0], pair[1]);
+        }
+        if let Some(&last) = picked.last() {
+            self.set_fat(last, END_OF_CHAIN);
+        }
+        self.next_free = cluster;
+        Ok(picked)
+    }
+
+    fn free_chain(&mut self, first: u32) -> FatResult<()> {
+        for cluster in self.chain(first)? {
+            self.set_fat(cluster, 0);
+        }
+        Ok(())
+    }
+
+    fn read_chain(&mut self, first: u32) -> FatResult<(Vec<u32>, Vec<u8>)> {
+        let clusters = self.chain(first)?;
+        let cluster_bytes = self.cluster_bytes() as usize;
+        let mut data = vec![0u8; clusters.len() * cluster_bytes];
+        for (i, &cluster) in clusters.iter().enumerate() {
+            let offset = self.cluster_offset(cluster);
+            self.dev.read_at(offset, &mut data[i * cluster_bytes..(i + 1) * cluster_bytes])?;
+        }
+        Ok((clusters, data))
+    }
+
+    fn read_dir(&mut self, cluster: u32) -> FatResult<Vec<DirEntry>> {
+        Ok(parse_dir(&self.read_chain(cluster)?.1))
+    }
+
+    fn find(&mut self, dir: u32, name: &str) -> FatResult<Option<DirEntry>> {
+        let name_upper = name.to_uppercase();
+        Ok(self
+            .read_dir(dir)?
+            .into_iter()
+            .find(|e| e.name.to_uppercase() == name_upper || short_name_string(&e.short, 0) == name_upper))
+    }
+
+    // Cluster of the directory at `parts`, the root for an empty path
+    fn dir_cluster(&mut self, parts: &[&str]) -> FatResult<u32> {
+        let mut cluster = self.root_cluster;
+        for (i, part) in parts.iter().enumerate() {
+            match self.find(cluster, part)? {
+                Some(entry) if entry.is_dir() => cluster = entry.first_cluster,
+                Some(_) => return Err(format!("{} is not a directory", parts[..=i].join("/")).into()),
+                None => return Err(format!("{} does not exist", parts[..=i].join("/")).into()),
+            }
+        }
+        // ".." entries pointing at the root store cluster 0
+        Ok(if cluster == 0 { self.root_cluster } else { cluster })
+    }
+
+    fn write_slots(&mut self, clusters: &[u32], slot: usize, entries: &[[u8; ENTRY_SIZE]]) -> FatResult<()> {
+        let per_cluster = self.cluster_bytes() as usize / ENTRY_SIZE;
+        for (i, entry) in entries.iter().enumerate() {
+            let index = slot + i;
+            let offset = self.cluster_offset(clusters[index / per_cluster]) + ((index % per_cluster) * ENTRY_SIZE) as u64;
+            self.dev.write_at(offset, entry)?;
+        }
+        Ok(())
+    }
+
+    fn zero_cluster(&mut self, cluster: u32) -> FatResult<()> {
+        let zeros = vec![0u8; self.cluster_bytes() as usize];
+        self.dev.write_at(self.cluster_offset(cluster), &zeros)?;
+        Ok(())
+    }
+
+    // Adds an entry (plus long name entries when needed) to the directory, growing it by a
+    // cluster if there is no run of free slots long enough
+    fn add_entry(&mut self, dir: u32, name: &str, attr: u8, first_cluster: u32, size: u32, modified: SystemTime) -> FatResult<()> {
+        check_long_name(name)?;
+        let (mut clusters, data) = self.read_chain(dir)?;
+        let existing = parse_dir(&data);
+        let taken: HashSet<[u8; 11]> = existing.iter().map(|e| e.short).collect();
+        let (short, lfn) = match exact_short_name(name) {
+            Some(short) if !taken.contains(&short) => (short, Vec::new()),
+            _ => {
+                let short = generated_short_name(name, &taken)?;
+                (short, lfn_entries(name, &short))
+            }
+        };
+        let mut entries = lfn;
+        entries.push(short_entry(&short, attr, first_cluster, size, modified));
+
+        let mut total_slots = data.len() / ENTRY_SIZE;
+        let mut run_start = None;
+        let mut run = 0;
+        for (slot, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+            if raw[0] == 0x00 || raw[0] == 0xE5 {
+                if run == 0 {
+                    run_start = Some(slot);
+                }
+                run += 1;
+                if run == entries.len() {
+                    break;
+                }
+            } else {
+                run = 0;
+                run_start = None;
+            }
+        }
+        if run < entries.len() {
+            let per_cluster = self.cluster_bytes() as usize / ENTRY_SIZE;
+            let extra = (entries.len() - run).div_ceil(per_cluster);
+            if total_slots + extra * per_cluster > MAX_DIR_ENTRIES {
+                return Err("Directory is full".into());
+            }
+            let new_clusters = self.allocate(extra)?;
+            for &cluster in &new_clusters {
+                self.zero_cluster(cluster)?;
+            }
+            let last = *clusters.last().ok_or("Directory has no clusters")?;
+            self.set_fat(last, new_clusters[0]);
+            clusters.extend(new_clusters);
+            if run == 0 {
+                run_start = Some(total_slots);
+            }
+            total_slots += extra * per_cluster;
+        }
+        let start = run_start.ok_or("No free directory slot")?;
+        debug_assert!(start + entries.len() <= total_slots);
+        self.write_slots(&clusters, start, &entries)
+    }
+
+    fn remove_entry(&mut self, dir: u32, entry: &DirEntry) -> FatResult<()> {
+        let clusters = self.chain(dir)?;
+        let first = entry.slot - entry.lfn_slots;
+        // Marking the first byte is all it takes to delete a slot
+        let per_cluster = self.cluster_bytes() as usize / ENTRY_SIZE;
+        for index in first..=entry.slot {
+            let offset = self.cluster_offset(clusters[index / per_cluster]) + ((index % per_cluster) * ENTRY_SIZE) as u64;
+            self.dev.write_at(offset, &[0xE5])?;
+        }
+        Ok(())
+    }
+
+    fn make_dir(&mut self, parent: u32, name: &str) -> FatResult<u32> {
+        let cluster = self.allocate(1)?[0];
+        self.zero_cluster(cluster)?;
+        let now = SystemTime::now();
+        let dot = short_entry(b".          ", ATTR_DIRECTORY, cluster, 0, now);
+        let parent_ref = if parent == self.root_cluster { 0 } else { parent };
+        let dotdot = short_entry(b"..         ", ATTR_DIRECTORY, parent_ref, 0, now);
+        self.write_slots(&[cluster], 0, &[dot, dotdot])?;
+        if let Err(e) = self.add_entry(parent, name, ATTR_DIRECTORY, cluster, 0, now) {
+            self.set_fat(cluster, 0);
+            return Err(e);
+        }
+        Ok(cluster)
+    }
+
+    // Walks `parts`, creating any directory that doesn't exist yet
+    fn make_dirs(&mut self, parts: &[&str]) -> FatResult<u32> {
+        let mut cluster = self.root_cluster;
+        for part in parts {
+            cluster = match self.find(cluster, part)? {
+                Some(entry) if entry.is_dir() => entry.first_cluster,
+                Some(_) => return Err(format!("{} exists and is not a directory", part).into()),
+                None => self.make_dir(cluster, part)?,
+            };
+        }
+        Ok(cluster)
+    }
+
+    pub fn is_dir(&mut self, path: &str) -> bool {
+        self.dir_cluster(&split_path(path)).is_ok()
+    }
+
+    // Entries of a directory, or the entry itself for a file
+    pub fn list(&mut self, path: &str) -> FatResult<Vec<DirEntry>> {
+        let parts = split_path(path);
+        let Some((name, parents)) = parts.split_last() else {
+            let root = self.root_cluster;
+            return self.read_dir(root);
+        };
+        let parent = self.dir_cluster(parents)?;
+        match self.find(parent, name)? {
+            Some(entry) if entry.is_dir() => {
+                let cluster = self.dir_cluster(&parts)?;
+                self.read_dir(cluster)
+            }
+            Some(entry) => Ok(vec![entry]),
+            None => Err(format!("{} does not exist", path).into()),
+        }
+    }
+
+    // Directory entry of a file or directory; the root has none
+    pub fn entry(&mut self, path: &str) -> FatResult<DirEntry> {
+        let parts = split_path(path);
+        let (name, parents) = parts.split_last().ok_or("No file name given")?;
+        let parent = self.dir_cluster(parents)?;
+        Ok(self.find(parent, name)?.ok_or_else(|| format!("{} does not exist", path))?)
+    }
+
+    pub fn read_file(&mut self, path: &str, out: &mut dyn Write) -> FatResult<DirEntry> {
+        let entry = self.entry(path)?;
+        if entry.is_dir() {
+            return Err(format!("{} is a directory", path).into());
+        }
+        let clusters = self.chain(entry.first_cluster)?;
+        let cluster_bytes = self.cluster_bytes();
+        if (clusters.len() as u64) < (entry.size as u64).div_ceil(cluster_bytes) {
+            return Err(format!("{} is shorter on disk than its recorded size", path).into());
+        }
+        let mut buffer = vec![0u8; cluster_bytes as usize];
+        let mut remaining = entry.size as u64;
+        for cluster in clusters {
+            if remaining == 0 {
+                break;
+            }
+            let n = remaining.min(cluster_bytes) as usize;
+            self.dev.read_at(self.cluster_offset(cluster), &mut buffer[..n])?;
+            out.write_all(&buffer[..n])?;
+            remaining -= n as u64;
+        }
+        Ok(entry)
+    }
+
+    // Reads part of a file into `buf` and returns the number of bytes read, which is short only
+    // at the end of the file. Walks the cluster chain in the cached FAT, so only the clusters
+    // holding the range are read from the disk.
+    pub fn read_at(&mut self, entry: &DirEntry, offset: u64, buf: &mut [u8]) -> FatResult<usize> {
+        let end = (entry.size as u64).min(offset.saturating_add(buf.len() as u64));
+        if offset >= end {
+            return Ok(0);
+        }
+        let cluster_bytes = self.cluster_bytes();
+        let mut cluster = entry.first_cluster;
+        for _ in 0..offset / cluster_bytes {
+            if !self.is_valid_cluster(cluster) {
+                break;
+            }
+            cluster = self.next_in_chain(cluster);
+        }
+        let mut position = offset;
+        while position < end {
+            if !self.is_valid_cluster(cluster) {
+                return Err(format!("{} is shorter on disk than its recorded size", entry.name).into());
+            }
+            let within = position % cluster_bytes;
+            let n = (cluster_bytes - within).min(end - position);
+            let done = (position - offset) as usize;
+            self.dev.read_at(self.cluster_offset(cluster) + within, &mut buf[done..done + n as usize])?;
+            position += n;
+            cluster = self.next_in_chain(cluster);
+        }
+        Ok((end - offset) as usize)
+    }
+
+    // Writes `size` bytes from `input` to `path`, replacing an existing file and creating
+    // missing parent directories
+    pub fn write_file(&mut self, path: &str, input: &mut dyn Read, size: u64, modified: SystemTime) -> FatResult<()> {
+        let size = u32::try_from(size).map_err(|_| "FAT32 can't hold files of 4 GB or more")?;
+        let parts = split_path(path);
+        let (name, parents) = parts.split_last().ok_or("No file name given")?;
+        check_long_name(name)?;
+        let parent = self.make_dirs(parents)?;
+        let existing = self.find(parent, name)?;
+        let cluster_bytes = self.cluster_bytes();
+        let needed = (size as u64).div_ceil(cluster_bytes) as usize;
+        let reclaimable = match &existing {
+            Some(entry) if entry.is_dir() => return Err(format!("{} is a directory", path).into()),
+            Some(entry) => self.chain(entry.first_cluster)?.len(),
+            None => 0,
+        };
+        if needed > self.free_count() as usize + reclaimable {
+            return Err(format!("Not enough free space in the locker for {}", path).into());
+        }
+        if let Some(entry) = &existing {
+            self.remove_entry(parent, entry)?;
+            self.free_chain(entry.first_cluster)?;
+        }
+
+        let clusters = self.allocate(needed)?;
+        let mut buffer = vec![0u8; cluster_bytes as usize];
+        let mut remaining = size as u64;
+        for &cluster in &clusters {
+            let n = remaining.min(cluster_bytes) as usize;
+            buffer.fill(0);
+            if let Err(e) = input.read_exact(&mut buffer[..n]) {
+                self.free_chain(clusters[0])?;
+                return Err(format!("Reading input for {} failed: {}", path, e).into());
+            }
+            self.dev.write_at(self.cluster_offset(cluster), &buffer)?;
+            remaining -= n as u64;
+        }
+        let first = clusters.first().copied().unwrap_or(0);
+        if let Err(e) = self.add_entry(parent, name, ATTR_ARCHIVE, first, size, modified) {
+            if first != 0 {
+                self.free_chain(first)?;
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, path: &str, recursive: bool) -> FatResult<()> {
+        let parts = split_path(path);
+        let (name, parents) = parts.split_last().ok_or("The root directory can't be removed")?;
+        let parent = self.dir_cluster(parents)?;
+        let entry = self.find(parent, name)?.ok_or_else(|| format!("{} does not exist", path))?;
+        if entry.is_dir() {
+            let children = self.read_dir(entry.first_cluster)?;
+            if !children.is_empty() && !recursive {
+                return Err(format!("{} is not empty (use -r to remove it with its contents)", path).into());
+            }
+            for child in children {
+                self.remove(&format!("{}/{}", parts.join("/"), child.name), true)?;
+            }
+        }
+        self.remove_entry(parent, &entry)?;
+        if entry.first_cluster != 0 {
+            self.free_chain(entry.first_cluster)?;
+        }
+        Ok(())
+    }
+
+    // Writes changed FAT sectors to every FAT copy and refreshes FSInfo
+    pub fn flush(&mut self) -> FatResult<()> {
+        let dirty = std::mem::take(&mut self.dirty);
+        for sector in dirty {
+            // The last FAT sector can extend past the last cluster; keep what's there
+            let mut bytes = [0u8; SECTOR as usize];
+            self.dev.read_at((self.reserved_sectors + sector) * SECTOR, &mut bytes)?;
+            let first_entry = (sector * SECTOR / 4) as usize;
+            for (i, chunk) in bytes.chunks_exact_mut(4).enumerate() {
+                if let Some(entry) = self.fat.get(first_entry + i) {
+                    chunk.copy_from_slice(&entry.to_le_bytes());
+                }
+            }
+            for fat in 0..self.num_fats {
+                let offset = (self.reserved_sectors + fat * self.fat_sectors + sector) * SECTOR;
+                self.dev.write_at(offset, &bytes)?;
+            }
+        }
+        let mut fsinfo = [0u8; SECTOR as usize];
+        self.dev.read_at(self.fsinfo_sector * SECTOR, &mut fsinfo)?;
+        if fsinfo[0..4] == 0x4161_5252u32.to_le_bytes() {
+            fsinfo[488..492].copy_from_slice(&self.free_count().to_le_bytes());
+            fsinfo[492..496].copy_from_slice(&self.next_free.to_le_bytes());
+            self.dev.write_at(self.fsinfo_sector * SECTOR, &fsinfo)?;
+        }
+        self.dev.flush()?;
+        Ok(())
+    }
+
+    pub fn free_space(&self) -> u64 {
+        self.free_count() as u64 * self.cluster_bytes()
+    }
+
+    pub fn total_space(&self) -> u64 {
+        self.cluster_count as u64 * self.cluster_bytes()
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        self.cluster_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Shares its bytes with every clone, so a volume can be opened again after it is flushed
+    #[derive(Clone)]
+    struct MemoryDisk {
+        data: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl BlockDevice for MemoryDisk {
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+            blockdev::check_range(offset, buf.len(), self.size())?;
+            buf.copy_from_slice(&self.data.borrow()[offset as usize..offset as usize + buf.len()]);
+            Ok(())
+        }
+
+        fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+            blockdev::check_range(offset, buf.len(), self.size())?;
+            self.data.borrow_mut()[offset as usize..offset as usize + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn size(&self) -> u64 {
+            self.data.borrow().len() as u64
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Just big enough for FAT32, which gives one-sector clusters
+    fn formatted(partitioned: bool) -> MemoryDisk {
+        let size = 40 * 1024 * 1024 + if partitioned { PARTITION_START * SECTOR } else { 0 };
+        let mut disk = MemoryDisk { data: Rc::new(RefCell::new(vec![0u8; size as usize])) };
+        format(&mut disk, partitioned, "Test").unwrap();
+        disk
+    }
+
+    fn contents(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    fn put(fs: &mut Fat32, path: &str, data: &[u8]) {
+        fs.write_file(path, &mut &data[..], data.len() as u64, UNIX_EPOCH + Duration::from_secs(1_700_000_000)).unwrap();
+    }
+
+    fn get(fs: &mut Fat32, path: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        fs.read_file(path, &mut out).unwrap();
+        out
+    }
+
+    fn names(fs: &mut Fat32, path: &str) -> Vec<String> {
+        let mut names: Vec<String> = fs.list(path).unwrap().into_iter().map(|e| e.name).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn put_list_get_remove() {
+        let disk = formatted(true);
+        let mut fs = Fat32::open(Box::new(disk.clone())).unwrap();
+        assert_eq!(fs.cluster_size(), SECTOR);
+        let empty = fs.free_space();
+        let long_name = "A rather long file name, with spaces.tar.gz";
+        put(&mut fs, "README.TXT", b"short name only");
+        put(&mut fs, long_name, &contents(3000, 1));
+        put(&mut fs, "docs/nested/deep file.bin", &contents(5000, 2));
+        put(&mut fs, "empty", b"");
+        fs.flush().unwrap();
+
+        // Everything is on the disk, not just in the open volume
+        let mut fs = Fat32::open(Box::new(disk.clone())).unwrap();
+        assert_eq!(names(&mut fs, ""), vec![long_name, "README.TXT", "docs", "empty"]);
+        assert_eq!(names(&mut fs, "docs/nested"), vec!["deep file.bin"]);
+        assert!(fs.is_dir("docs") && !fs.is_dir("empty"));
+        assert_eq!(get(&mut fs, "README.TXT"), b"short name only");
+        assert_eq!(get(&mut fs, long_name), contents(3000, 1));
+        assert_eq!(get(&mut fs, "docs/nested/deep file.bin"), contents(5000, 2));
+        assert!(get(&mut fs, "empty").is_empty());
+        let entry = fs.entry(long_name).unwrap();
+        assert_eq!(entry.modified(), UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let mut middle = vec![0u8; 700];
+        assert_eq!(fs.read_at(&entry, 1000, &mut middle).unwrap(), 700);
+        assert_eq!(middle, contents(3000, 1)[1000..1700]);
+        assert!(fs.read_file("missing", &mut Vec::new()).is_err());
+
+        // Replacing a file gives back the clusters it had
+        put(&mut fs, long_name, b"smaller");
+        assert_eq!(get(&mut fs, long_name), b"smaller");
+
+        assert!(fs.remove("docs", false).is_err());
+        fs.remove("docs", true).unwrap();
+        for name in [long_name, "README.TXT", "empty"] {
+            fs.remove(name, false).unwrap();
+        }
+        fs.flush().unwrap();
+        let mut fs = Fat32::open(Box::new(disk)).unwrap();
+        assert!(fs.list("").unwrap().is_empty());
+        assert_eq!(fs.free_space(), empty);
+    }
+
+    #[test]
+    fn directory_spanning_several_clusters() {
+        let disk = formatted(false);
+        let mut fs = Fat32::open(Box::new(disk.clone())).unwrap();
+        // Each name takes three long name slots and a short one, so 100 of them fill 25 clusters
+        let name = |i: usize| format!("many/Holiday photo number {:03}.jpeg", i);
+        for i in 0..100 {
+            put(&mut fs, &name(i), &contents(i * 10, i as u8));
+        }
+        let dir = fs.entry("many").unwrap();
+        assert!(fs.chain(dir.first_cluster).unwrap().len() >= 25);
+
+        // Freed slots are found again when the directory is written to later
+        for i in (0..100).step_by(2) {
+            fs.remove(&name(i), false).unwrap();
+        }
+        for i in 100..120 {
+            put(&mut fs, &name(i), &contents(i * 10, i as u8));
+        }
+        fs.flush().unwrap();
+
+        let mut fs = Fat32::open(Box::new(disk)).unwrap();
+        let listed = fs.list("many").unwrap();
+        assert_eq!(listed.len(), 70);
+        let mut short_names: Vec<[u8; 11]> = listed.iter().map(|e| e.short).collect();
+        short_names.sort();
+        short_names.dedup();
+        assert_eq!(short_names.len(), 70);
+        for i in (1..100).step_by(2).chain(100..120) {
+            assert_eq!(get(&mut fs, &name(i)), contents(i * 10, i as u8));
+        }
+        assert!(fs.entry(&name(0)).is_err());
+    }
+
+    #[test]
+    fn refuses_what_fat_cant_hold() {
+        let mut fs = Fat32::open(Box::new(formatted(false))).unwrap();
+        put(&mut fs, "file", b"data");
+        for bad in ["file/inside", "a:b", "question?"] {
+            assert!(fs.write_file(bad, &mut &b"x"[..], 1, SystemTime::now()).is_err(), "{}", bad);
+        }
+        assert!(fs.write_file("huge", &mut io::empty(), 4 << 30, SystemTime::now()).is_err());
+        assert!(fs.write_file("too big", &mut io::repeat(0), fs.free_space() + 1, SystemTime::now()).is_err());
+        let mut too_small = MemoryDisk { data: Rc::new(RefCell::new(vec![0u8; 16 * 1024 * 1024])) };
+        assert!(format(&mut too_small, false, "Test").is_err());
+    }
+}
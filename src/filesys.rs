@@ -1,56 +1,53 @@
-use vhdrs;
-use windows;
-use core::num;
 use std::vec;
 use std::fs::{self, File};
-use std::io::{self, BufReader, BufWriter, Read, SeekFrom, Seek, Write};
-use std::path::{Path, PathBuf};
-use rand::Rng;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use rand::{CryptoRng, Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
 use walkdir::WalkDir;
+use crate::merkle::{self, MerkleManifest};
+use crate::store;
+#[cfg(windows)]
 use std::process::Command;
+#[cfg(windows)]
 use std::env;
-use std::thread;
-use std::time::Duration;
 
-pub fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let vhd_path = "C:\\Users\\ankit_nk\\Documents\\sdfs\\test.vhd";
-    println!("Attempting to attach/detach VHD at: {}", vhd_path);
-    
-    attach_drive(vhd_path)?;
-    println!("VHD operation completed successfully!");
-    
-    Ok(())
-}
-
-pub fn create_drive(path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    if let Some(parent) = Path::new(path).parent() {
-        if !parent.exists() {
-            fs::create_dir_all(parent)?;
+#[cfg(windows)]
+pub fn create_drive(path: &str, disk_mb: u64, letterstr: &str, disk_format: &str, filesystem: &str) -> Result<(), Box<dyn std::error::Error>> {
+    // VHD images are written natively; diskpart is only needed to create VHDX, which has no
+    // native writer here, and to partition and format with anything but the built-in FAT32
+    let create_line = match disk_format {
+        "vhdx" => {
+            if let Some(parent) = Path::new(path).parent() {
+                fs::create_dir_all(parent)?;
+            }
+            format!("create vdisk file=\"{}\" maximum={} type=expandable\n", path, disk_mb)
         }
-    }
-
-    let mut input = String::new();
-    let mut letter = String::new();
-    print!("Enter disk size in MB: ");
-    io::stdout().flush()?;
-    io::stdin().read_line(&mut input)?;
-
-    print!("Enter drive letter: ");
-    io::stdout().flush()?;
-    io::stdin().read_line(&mut letter)?;
+        "dynamic" => {
+            crate::vhd::create_dynamic(Path::new(path), disk_mb * 1024 * 1024)?;
+            String::new()
+        }
+        _ => {
+            crate::vhd::create_fixed(Path::new(path), disk_mb * 1024 * 1024)?;
+            String::new()
+        }
+    };
+    let filesystem = if filesystem.is_empty() { "ntfs" } else { filesystem };
+    let prepare = if filesystem == "fat32" && disk_format != "vhdx" {
+        crate::fat::format_image(Path::new(path), true)?;
+        "select partition 1\n".to_string()
+    } else {
+        format!("convert mbr\ncreate partition primary\nformat fs={} label=\"Locker\" quick\n", filesystem)
+    };
 
-    let disk_mb: u64 = input.trim().parse()?;
-    let letterstr: &str = letter.trim();
-      let diskpart_script = format!(
-        "create vdisk file=\"{}\" maximum={} type=fixed
-select vdisk file=\"{}\"
+    let diskpart_script = format!(
+        "{}select vdisk file=\"{}\"
 attach vdisk
-create partition primary
-format fs=ntfs label=\"Locker\" quick
-assign letter={}
+{}assign letter={}
 detach vdisk
 ",
-        path, disk_mb, path, letterstr
+        create_line, path, prepare, letterstr
     );
 
     let mut script_path = env::temp_dir();
@@ -75,6 +72,7 @@ detach vdisk
     Ok(())
 }
 
+#[cfg(windows)]
 pub fn is_vhd_attached(path: &str) -> bool {
     let script = format!(
         r#"select vdisk file="{}"
@@ -85,7 +83,7 @@ detail vdisk"#,
     let mut script_path = env::temp_dir();
     script_path.push("diskpart_check.txt");
 
-    if let Ok(_) = fs::write(&script_path, script) {
+    if fs::write(&script_path, script).is_ok() {
         if let Ok(output) = Command::new("diskpart")
             .arg("/s")
             .arg(&script_path)
@@ -103,10 +101,8 @@ detail vdisk"#,
     false
 }
 
+#[cfg(windows)]
 pub fn attach_drive(path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    if !Path::new(path).exists() {
-        create_drive(path)?;
-    }
     let _ = vhdrs::Vhd::detach(path);
     std::thread::sleep(std::time::Duration::from_millis(500));
     let mut vhd = vhdrs::Vhd::new(path, vhdrs::OpenMode::ReadWrite, None)?;
@@ -114,98 +110,182 @@ pub fn attach_drive(path: &str) -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+#[cfg(windows)]
 pub fn detach_drive(path: &str) -> Result<(), Box<dyn std::error::Error>> {
     vhdrs::Vhd::detach(path)?;
     Ok(())
 }
 
-pub fn split_binary_with_key(vhd_path: &str, fragments: &[crate::keysetup::FragmentInfo], total_chunks: usize) -> Result<(), Box<dyn std::error::Error>> {
-    let vhd_file = File::open(vhd_path)?;
-    let metadata = vhd_file.metadata()?;
-    let total_size = metadata.len() as usize;
-    
-    let chunk_size = if total_chunks > 0 {
-        (total_size + total_chunks - 1) / total_chunks
-    } else {
-        return Err("Total chunks must be greater than 0".into());
-    };
-    
-    let mut reader = BufReader::new(vhd_file);
-    let mut chunks = Vec::new();
-    
-    for i in 0..total_chunks {
-        let bytes_to_read = if i < total_chunks - 1 {
-            chunk_size
-        } else {
-            total_size - chunk_size * (total_chunks - 1)
-        };
-        
-        let mut chunk_buffer = vec![0u8; bytes_to_read];
-        reader.read_exact(&mut chunk_buffer)?;
-        chunks.push(chunk_buffer);
+// Byte length of each chunk when an image of total_size is cut into total_chunks pieces
+pub fn chunk_lengths(total_size: usize, total_chunks: usize) -> Vec<usize> {
+    if total_chunks == 0 {
+        return Vec::new();
     }
-    
-    for fragment in fragments {
-        let file_path = Path::new(&fragment.directory).join(&fragment.filename);
-        let mut output_file = BufWriter::new(File::create(&file_path)?);
-        
-        for &chunk_index in &fragment.chunk_indices {
-            if chunk_index < chunks.len() {
-                output_file.write_all(&chunks[chunk_index])?;
+    let chunk_size = total_size.div_ceil(total_chunks);
+    (0..total_chunks)
+        .map(|i| {
+            if i < total_chunks - 1 {
+                chunk_size
+            } else {
+                total_size.saturating_sub(chunk_size * (total_chunks - 1))
             }
+        })
+        .collect()
+}
+
+// Chunks cut to a target size start on multiples of this, so they line up with disk sectors
+// and filesystem clusters
+pub const CHUNK_ALIGNMENT: u64 = 4096;
+const MIN_CHUNK_SIZE: u64 = 64 * 1024;
+
+// A target chunk size as entered during setup: a number of bytes with an optional K, M or G
+// suffix, or MiB without one
+pub fn parse_chunk_size(text: &str) -> Result<u64, String> {
+    let text = text.trim().to_uppercase();
+    let digits = text.trim_end_matches(['K', 'M', 'G', 'I', 'B']);
+    let multiplier = match text[digits.len()..].chars().next() {
+        Some('B') => 1,
+        Some('K') => 1 << 10,
+        Some('G') => 1 << 30,
+        _ => 1 << 20,
+    };
+    let size = digits.trim().parse::<u64>().ok().and_then(|n| n.checked_mul(multiplier)).ok_or_else(|| format!("Not a chunk size: {}", text))?;
+    if !(MIN_CHUNK_SIZE..=crate::keysetup::MAX_CHUNK_SIZE).contains(&size) || size % CHUNK_ALIGNMENT != 0 {
+        return Err(format!("The chunk size must be between 64 KiB and 512 KiB and a multiple of {} bytes", CHUNK_ALIGNMENT));
+    }
+    Ok(size)
+}
+
+// Lengths in whole units of `unit` bytes, as even as possible, with the overshoot past
+// `total_size` taken off the last chunk
+pub fn aligned_chunk_lengths(total_size: u64, total_chunks: usize, unit: u64) -> Vec<u64> {
+    if total_chunks == 0 {
+        return Vec::new();
+    }
+    let units = total_size.div_ceil(unit);
+    let (each, extra) = (units / total_chunks as u64, units % total_chunks as u64);
+    let mut lengths: Vec<u64> = (0..total_chunks as u64).map(|i| (each + u64::from(i < extra)) * unit).collect();
+    trim_last_chunk(&mut lengths, units * unit - total_size);
+    lengths
+}
+
+// Takes `excess` (less than one unit) off the last chunk that isn't empty
+fn trim_last_chunk(lengths: &mut [u64], excess: u64) {
+    if let Some(last) = lengths.iter_mut().rev().find(|len| **len > 0) {
+        *last -= excess;
+    }
+}
+
+// Cutting the image into chunks of pseudo-random lengths instead of equal ones, so fragment
+// sizes don't give away how many chunks each fragment holds. Every chunk falls between
+// `min_percent` and `max_percent` of the average length. The lengths are drawn from ChaCha20
+// seeded with `secret`, the lock counter and the image size, so they change on every lock.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ChunkSizing {
+    pub secret: String,
+    pub min_percent: u64,
+    pub max_percent: u64,
+}
+
+impl ChunkSizing {
+    // Bounds as entered during setup, e.g. "50-150"
+    pub fn parse(bounds: &str, rng: &mut (impl RngCore + CryptoRng + ?Sized)) -> Result<Self, String> {
+        let usage = || format!("Expected the shortest and longest chunk as percentages of the average, like 50-150, not {}", bounds);
+        let (min, max) = bounds.split_once('-').ok_or_else(usage)?;
+        let min_percent: u64 = min.trim().parse().map_err(|_| usage())?;
+        let max_percent: u64 = max.trim().parse().map_err(|_| usage())?;
+        if min_percent > 100 || !(100..=1000).contains(&max_percent) {
+            return Err("The shortest chunk can be at most 100% of the average and the longest between 100% and 1000%".to_string());
         }
-        
-        output_file.flush()?;
+        let mut secret = [0u8; 32];
+        rng.fill_bytes(&mut secret);
+        Ok(Self { secret: hex::encode(secret), min_percent, max_percent })
     }
-    
-    if let Err(e) = fs::remove_file(vhd_path) {
-        eprintln!("Warning: failed to delete VHD file after splitting: {}", e);
+
+    // Lengths are drawn in whole units of `unit` bytes, and the last one is trimmed to fit
+    pub fn lengths(&self, total_size: u64, total_chunks: usize, generation: u64, unit: u64) -> Vec<u64> {
+        if total_chunks == 0 {
+            return Vec::new();
+        }
+        let mut seed = Sha256::new_with_prefix(self.secret.as_bytes());
+        seed.update(generation.to_le_bytes());
+        seed.update(total_size.to_le_bytes());
+        let mut rng = ChaCha20Rng::from_seed(seed.finalize().into());
+
+        let n = total_chunks as u128;
+        let units = total_size.div_ceil(unit);
+        let shortest = (units as u128 * self.min_percent as u128 / (100 * n)) as u64;
+        let longest = (units as u128 * self.max_percent as u128).div_ceil(100 * n) as u64;
+        // Each length is drawn from what still lets the chunks after it stay within the
+        // bounds, and the last one takes whatever is left
+        let mut remaining = units;
+        let mut lengths = Vec::with_capacity(total_chunks);
+        for left in (1..total_chunks as u64).rev() {
+            let low = shortest.max(remaining.saturating_sub(left * longest));
+            let high = longest.min(remaining - left * shortest);
+            let len = rng.gen_range(low..=high);
+            lengths.push(len * unit);
+            remaining -= len;
+        }
+        lengths.push(remaining * unit);
+        trim_last_chunk(&mut lengths, units * unit - total_size);
+        lengths
     }
-    
-    Ok(())
 }
 
-pub fn assemble_binary_with_key(fragments: &[crate::keysetup::FragmentInfo], key: &str, output_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let total_chunks = key.len();
+// With a manifest, every chunk is checked against its Merkle leaf and the first tampered
+// chunk is reported by index and fragment instead of surfacing later as a GCM failure.
+// Only vaults locked before the image size was recorded come this way, and those predate
+// carrier files, so the fragments are always raw; everything newer goes through FragmentReader,
+// which unwraps them.
+pub fn assemble_binary_with_key(fragments: &[crate::keysetup::FragmentInfo], key: &str, output_path: &str, manifest: Option<&MerkleManifest>) -> Result<(), Box<dyn std::error::Error>> {
+    let total_chunks = crate::keysetup::chunk_count(key, fragments.len());
+    let leaves = match manifest {
+        Some(manifest) => {
+            let leaves = manifest.checked_leaves()?;
+            if leaves.len() != total_chunks {
+                return Err(format!("Merkle manifest covers {} chunks but the key has {}", leaves.len(), total_chunks).into());
+            }
+            Some(leaves)
+        }
+        None => None,
+    };
     let mut chunks = vec![Vec::new(); total_chunks];
     
-    let mut total_size = 0;
+    let mut fragment_contents = Vec::with_capacity(fragments.len());
     for fragment in fragments {
-        let file_path = Path::new(&fragment.directory).join(&fragment.filename);
-        if let Ok(metadata) = fs::metadata(&file_path) {
-            total_size += metadata.len() as usize;
-        }
+        let mut fragment_data = Vec::new();
+        store::open_store(&fragment.location)?.get(&fragment.filename)?.read_to_end(&mut fragment_data)?;
+        fragment_contents.push(fragment_data);
     }
+    let total_size: usize = fragment_contents.iter().map(|data| data.len()).sum();
     
-    let chunk_size = if total_chunks > 0 {
-        (total_size + total_chunks - 1) / total_chunks 
-    } else {
+    if total_chunks == 0 {
         return Err("No chunks to assemble".into());
-    };
+    }
+    let lengths = chunk_lengths(total_size, total_chunks);
     
-    for fragment in fragments {
-        let file_path = Path::new(&fragment.directory).join(&fragment.filename);
-        let fragment_file = File::open(&file_path)?;
-        let mut reader = BufReader::new(fragment_file);
-        
-        if fragment.chunk_indices.is_empty() {
-            continue;
-        }
-        
-        let mut fragment_data = Vec::new();
-        reader.read_to_end(&mut fragment_data)?;
+    for (fragment, fragment_data) in fragments.iter().zip(&fragment_contents) {
+        let file_path = store::describe(&fragment.location, &fragment.filename);
         
         let mut offset = 0;
         for &global_chunk_index in &fragment.chunk_indices {
-            let bytes_for_this_chunk = if global_chunk_index < total_chunks - 1 {
-                chunk_size
-            } else {
-                total_size - chunk_size * (total_chunks - 1)
-            };
+            if global_chunk_index >= chunks.len() {
+                continue;
+            }
+            let bytes_for_this_chunk = lengths[global_chunk_index];
             
-            if offset + bytes_for_this_chunk <= fragment_data.len() && global_chunk_index < chunks.len() {
-                chunks[global_chunk_index] = fragment_data[offset..offset + bytes_for_this_chunk].to_vec();
+            if offset + bytes_for_this_chunk <= fragment_data.len() {
+                let chunk = &fragment_data[offset..offset + bytes_for_this_chunk];
+                if let (Some(leaves), Some(manifest)) = (&leaves, manifest) {
+                    if merkle::leaf_hash(manifest.generation, global_chunk_index, chunk) != leaves[global_chunk_index] {
+                        return Err(format!("Chunk {} in fragment {} has been tampered with", global_chunk_index, file_path).into());
+                    }
+                }
+                chunks[global_chunk_index] = chunk.to_vec();
                 offset += bytes_for_this_chunk;
+            } else if leaves.is_some() {
+                return Err(format!("Fragment {} is truncated at chunk {}", file_path, global_chunk_index).into());
             }
         }
     }
@@ -223,69 +303,72 @@ pub fn assemble_binary_with_key(fragments: &[crate::keysetup::FragmentInfo], key
     Ok(())
 }
 
-pub fn split_binary(filepaths: Vec<(&str, &str)>, vhdname: &str) -> (){
-    let numchunks: usize = filepaths.len();
-
-    let vhdpath = Path::new(vhdname);
-
-    let metadata = fs::metadata(&vhdpath).unwrap();
-    let filesize = metadata.len() as usize;
-
-    let chunksize = filesize / numchunks;
-
-    let file = File::open(vhdpath).unwrap();
-    let mut reader = BufReader::new(file);
-
-    for i in 0..numchunks {
-        let bytestoread = if i < numchunks - 1 {
-            chunksize
-        } else {
-            filesize - chunksize * (numchunks - 1)
-        };
-
-        let mut chunk_buff = vec![0u8; bytestoread];
-
-        reader.read_exact(&mut chunk_buff).unwrap();
-
-        let chunkfilename = format!("{}\\{}", filepaths[i].1, filepaths[i].0);
-        let mut chunkfile = File::create(&chunkfilename).expect(&format!("Failed to write to {}", chunkfilename));
-        chunkfile.write_all(&chunk_buff).expect("Couldn't write to buffer");
+// Overwrites the file with random bytes and syncs it before removing it. On SSDs and
+// copy-on-write filesystems the old blocks may survive, but nothing readable is left behind
+// through the filesystem itself.
+pub fn secure_delete(path: &Path) -> io::Result<()> {
+    let len = fs::metadata(path)?.len();
+    let mut file = fs::OpenOptions::new().write(true).open(path)?;
+    let mut rng = rand::thread_rng();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut remaining = len;
+    while remaining > 0 {
+        let n = remaining.min(buffer.len() as u64) as usize;
+        rng.fill(&mut buffer[..n]);
+        file.write_all(&buffer[..n])?;
+        remaining -= n as u64;
     }
+    file.sync_all()?;
+    file.set_len(0)?;
+    drop(file);
+    fs::remove_file(path)
+}
 
-    if let Err(e) = fs::remove_file(vhdpath) {
-        eprintln!("Warning: failed to delete VHD file after splitting: {}", e);
+// secure_delete for every file under `dir`, then removes the emptied tree. Read-only files and
+// directories are made writable first so the overwrite can't be skipped.
+pub fn secure_delete_dir(dir: &Path) -> io::Result<()> {
+    for entry in WalkDir::new(dir).contents_first(true) {
+        let entry = entry.map_err(io::Error::other)?;
+        let path = entry.path();
+        if entry.path_is_symlink() {
+            fs::remove_file(path)?;
+            continue;
+        }
+        let mut permissions = entry.metadata().map_err(io::Error::other)?.permissions();
+        if permissions.readonly() {
+            #[allow(clippy::permissions_set_readonly_false)]
+            permissions.set_readonly(false);
+            fs::set_permissions(path, permissions)?;
+        }
+        if entry.file_type().is_dir() {
+            fs::remove_dir(path)?;
+        } else {
+            secure_delete(path)?;
+        }
     }
+    Ok(())
 }
 
-pub fn assemble_binary(directories: Vec<(&str, &str)>, vhdname: &str) -> (){
-    let outputfile = File::create(vhdname).expect("Couldn't create file");
-    let mut writer = BufWriter::new(outputfile);
-
-    for (filename, directory) in directories {
-        let chunkpath: PathBuf = Path::new(directory).join(filename);
-
-        let chunkfile = File::open(&chunkpath).unwrap();
-        let mut reader = BufReader::new(chunkfile);
-
-        io::copy(&mut reader, &mut writer).expect("Couldn't copy");
+// Where get_random_directories starts looking for fragment directories
+pub fn default_search_root() -> String {
+    if cfg!(windows) {
+        "C:\\".to_string()
+    } else {
+        std::env::var("HOME").unwrap_or_else(|_| "/".to_string())
     }
-
-    writer.flush().expect("Couldn't flush write buffer");
 }
 
 fn normalize_path(path: &str) -> String {
-    if path.len() >= 2 && path.chars().nth(1) == Some(':') {
-        if path.len() == 2 || path.chars().nth(2) != Some('\\') {
+    if path.len() >= 2 && path.chars().nth(1) == Some(':')
+        && (path.len() == 2 || path.chars().nth(2) != Some('\\')) {
             let (drive, rest) = path.split_at(2);
             let rest = rest.trim_start();
             return format!("{}\\{}", drive, rest);
         }
-    }
     path.to_string()
 }
 
-pub fn get_random_directories(n: usize, base_path: &str) -> Vec<String> {
-    let mut rng = rand::thread_rng();
+pub fn get_random_directories(n: usize, base_path: &str, rng: &mut (impl RngCore + CryptoRng + ?Sized)) -> Vec<String> {
     let mut result = Vec::with_capacity(n);
     let accept_prob = 0.1;
     let temp_dir = std::env::temp_dir().to_string_lossy().to_lowercase();
@@ -298,6 +381,9 @@ pub fn get_random_directories(n: usize, base_path: &str) -> Vec<String> {
     for entry in WalkDir::new(base_path)
         .min_depth(2)
         .max_depth(6)
+        // Directory listings come back in no particular order, and a seeded RNG should still
+        // pick the same directories
+        .sort_by_file_name()
         .into_iter()
         .filter_map(Result::ok)
     {
@@ -307,7 +393,14 @@ pub fn get_random_directories(n: usize, base_path: &str) -> Vec<String> {
                 let norm = normalize_path(s);
                 let norm_lower = norm.to_lowercase();
                 
-                if norm_lower.contains(&temp_dir)
+                let hidden = path.components().any(|c| c.as_os_str().to_string_lossy().starts_with('.'));
+                let unix_system = ["/proc", "/sys", "/dev", "/run", "/boot", "/usr", "/lib", "/bin", "/sbin", "/etc", "/var", "/snap"]
+                    .iter()
+                    .any(|prefix| norm_lower.starts_with(prefix));
+                
+                if hidden
+                    || unix_system
+                    || norm_lower.contains(&temp_dir)
                     || norm_lower.contains("windows")
                     || norm_lower.contains("program files")
                     || norm_lower.contains("programdata")
@@ -0,0 +1,394 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use crate::blockdev::{self, BlockDevice};
+use crate::crypto::{self, GcmWriter, Keystream, GCM_TAG_SIZE};
+use crate::fragreader::{ChunkLayout, FragmentReader};
+use crate::fragwriter::{self, FragmentWriter};
+use crate::merkle::{self, Hash, MerkleManifest};
+use crate::metadata::VaultMetadata;
+use crate::padding::Filler;
+use crate::store;
+
+// A locked vault's image opened for reading and writing straight from its fragments. Reads go
+// through a FragmentReader. Writes are kept in memory and written back on flush: every fragment
+// holding a changed chunk is streamed from its store, the changed bytes are re-encrypted in
+// place, and the result is put back under the same name. Because GCM is counter mode, a
+// rewritten byte only changes the same byte of ciphertext, and because GHASH is linear the tag
+// can be patched from the changed blocks alone. The Merkle leaves of the rewritten chunks are
+// recomputed on the way through and the fragment info is saved after each fragment.
+//
+// Patching in place reuses the keystream of the last lock: anyone holding a fragment from before
+// and after a write can XOR the two and learn the XOR of the old and new plaintext of every
+// changed byte. That's why nbd-serve only writes when asked to with --write; the next full lock
+// encrypts everything under a new nonce again.
+//
+// Blocks that were left out of the fragments as sparse holes have nowhere to go, so writing
+// data into them fails with StorageFull; zeros written there are simply dropped.
+
+// Unwritten data above this is written back without waiting for a flush
+const WRITE_BACK_LIMIT: u64 = 64 * 1024 * 1024;
+
+pub struct FragmentDisk {
+    metadata: VaultMetadata,
+    fragment_info: String,
+    passphrase: String,
+    password: String,
+    key_const: &'static [u8],
+    reader: FragmentReader,
+    keystream: Keystream,
+    // [offset in the image, offset in the packed plaintext, length]
+    runs: Vec<[u64; 3]>,
+    packed_size: u64,
+    size: u64,
+    // New plaintext not yet in the fragments, by offset in the packed plaintext; entries never
+    // overlap or touch
+    dirty: BTreeMap<u64, Vec<u8>>,
+    dirty_bytes: u64,
+    // How the tag has to change for the writes so far, and the tag itself once a write-back
+    // has read it
+    tag_change: [u8; 16],
+    tag: Option<[u8; 16]>,
+}
+
+impl FragmentDisk {
+    // `fragment_info` and `passphrase` are where the updated metadata is saved after a write-back
+    pub fn open(
+        metadata: VaultMetadata,
+        password: &str,
+        key_const: &'static [u8],
+        fragment_info: &str,
+        passphrase: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let keystream = Keystream::new(password, key_const, &metadata.image_nonce()?);
+        check_password(&metadata, &keystream)?;
+        let reader = FragmentReader::decrypted(&metadata, password, key_const)?;
+        let packed_size = metadata.image_size - GCM_TAG_SIZE;
+        let (runs, size) = match &metadata.sparse {
+            Some(map) => (map.data_runs(), map.image_size),
+            None => (vec![[0, 0, packed_size]], packed_size),
+        };
+        Ok(Self {
+            metadata,
+            fragment_info: fragment_info.to_string(),
+            passphrase: passphrase.to_string(),
+            password: password.to_string(),
+            key_const,
+            reader,
+            keystream,
+            runs,
+            packed_size,
+            size,
+            dirty: BTreeMap::new(),
+            dirty_bytes: 0,
+            tag_change: [0u8; 16],
+            tag: None,
+        })
+    }
+
+    // Bytes of the image that are sparse holes and can't be written
+    pub fn unallocated(&self) -> u64 {
+        self.size - self.packed_size
+    }
+
+    fn overlay(&self, packed: u64, buf: &mut [u8]) {
+        let end = packed + buf.len() as u64;
+        for (&start, data) in self.dirty.range(..end).rev().take_while(|(&start, data)| start + data.len() as u64 > packed) {
+            let from = start.max(packed);
+            let to = (start + data.len() as u64).min(end);
+            buf[(from - packed) as usize..(to - packed) as usize].copy_from_slice(&data[(from - start) as usize..(to - start) as usize]);
+        }
+    }
+
+    fn insert_dirty(&mut self, offset: u64, data: &[u8]) {
+        let end = offset + data.len() as u64;
+        // Merge with every entry that overlaps or touches the new one
+        let neighbours: Vec<u64> = self.dirty
+            .range(..=end)
+            .rev()
+            .take_while(|(&start, existing)| start + existing.len() as u64 >= offset)
+            .map(|(&start, _)| start)
+            .collect();
+        let merged_start = neighbours.last().map_or(offset, |&start| start.min(offset));
+        let mut merged_end = end;
+        let mut merged = Vec::new();
+        for start in neighbours.into_iter().rev() {
+            let existing = self.dirty.remove(&start).expect("entry was just found");
+            self.dirty_bytes -= existing.len() as u64;
+            merged_end = merged_end.max(start + existing.len() as u64);
+            merged.resize((merged_end - merged_start) as usize, 0);
+            merged[(start - merged_start) as usize..][..existing.len()].copy_from_slice(&existing);
+        }
+        merged.resize((merged_end - merged_start) as usize, 0);
+        merged[(offset - merged_start) as usize..][..data.len()].copy_from_slice(data);
+        self.dirty_bytes += merged.len() as u64;
+        self.dirty.insert(merged_start, merged);
+    }
+
+    // Writes every pending change back into the fragments. The reader is reopened afterwards
+    // whatever happened, since some fragments may have been rewritten even if others failed.
+    fn write_back(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+        let result = self.write_back_fragments();
+        self.reader = FragmentReader::decrypted(&self.metadata, &self.password, self.key_const)?;
+        result
+    }
+
+    fn write_back_fragments(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let layout = ChunkLayout::for_vault(&self.metadata)?;
+        let tag = match self.tag {
+            Some(tag) => tag,
+            None => {
+                let mut tag = [0u8; 16];
+                FragmentReader::encrypted(&self.metadata)?.read_at(self.packed_size, &mut tag)?;
+                tag
+            }
+        };
+        let tag: [u8; 16] = std::array::from_fn(|i| tag[i] ^ self.tag_change[i]);
+        self.tag = Some(tag);
+        self.tag_change = [0u8; 16];
+
+        // The new ciphertext, cut at chunk boundaries and grouped by fragment
+        let mut patches: Vec<Vec<(u64, Vec<u8>)>> = vec![Vec::new(); self.metadata.fragments.len()];
+        let mut add = |offset: u64, bytes: &[u8]| {
+            let mut done = 0;
+            while done < bytes.len() {
+                let position = offset + done as u64;
+                let span = layout.span(layout.chunk_at(position).expect("write-back stays inside the image"));
+                let n = ((span.start + span.len - position) as usize).min(bytes.len() - done);
+                patches[span.fragment].push((span.offset_in_fragment + (position - span.start), bytes[done..done + n].to_vec()));
+                done += n;
+            }
+        };
+        for (&offset, plaintext) in &self.dirty {
+            let mut ciphertext = plaintext.clone();
+            self.keystream.apply(offset, &mut ciphertext);
+            add(offset, &ciphertext);
+        }
+        add(self.packed_size, &tag);
+
+        // The tag goes last, so an interrupted write-back leaves the data readable from the
+        // fragments even though a full unlock would reject it
+        let tag_fragments: Vec<usize> = (self.packed_size..self.metadata.image_size)
+            .filter_map(|offset| layout.chunk_at(offset).map(|i| layout.span(i).fragment))
+            .collect();
+        let mut order: Vec<usize> = (0..patches.len()).filter(|&f| !patches[f].is_empty()).collect();
+        order.sort_by_key(|f| tag_fragments.contains(f));
+
+        let mut leaves = self.metadata.merkle.checked_leaves()?;
+        let generation = self.metadata.merkle.generation;
+        let padded_sizes = self.metadata.padded_sizes();
+        // Padding is regenerated rather than copied, it comes out the same for the same lock
+        let mut fillers: Vec<Option<Filler>> = self.metadata.padding_fillers(generation).into_iter().map(Some).collect();
+        for fragment in order {
+            let mut fragment_patches = std::mem::take(&mut patches[fragment]);
+            fragment_patches.sort_by_key(|&(offset, _)| offset);
+            let info = &self.metadata.fragments[fragment];
+            let chunks: Vec<(usize, u64)> = info.chunk_indices.iter().copied().filter(|&i| i < leaves.len()).map(|i| (i, layout.span(i).len)).collect();
+            let payload_len = padded_sizes[fragment];
+            let filler = fillers[fragment].take().unwrap_or_else(Filler::empty);
+            let carrier = self.metadata.carrier;
+            let fragment_store = store::open_store(&info.location)?;
+            let mut rewrite = Rewrite {
+                inner: carrier.unwrap(fragment_store.get(&info.filename)?, payload_len),
+                describe: fragment_store.describe(&info.filename),
+                chunks,
+                current: 0,
+                within: 0,
+                position: 0,
+                patches: fragment_patches,
+                expected: &leaves,
+                generation,
+                old: None,
+                new: None,
+                leaves: Vec::new(),
+            };
+            // Staged like a lock, so the fragment and the leaves describing it change together
+            let staged = fragwriter::staged_name(&info.filename);
+            fragment_store.put(&staged, &mut carrier.wrap((&mut rewrite).chain(filler), payload_len), carrier.wrapped_len(payload_len))?;
+            for (index, leaf) in std::mem::take(&mut rewrite.leaves) {
+                leaves[index] = leaf;
+            }
+            self.metadata.merkle = MerkleManifest::from_leaves(generation, &leaves);
+            fragwriter::commit(&mut self.metadata, &self.fragment_info, &self.passphrase, self.key_const)?;
+        }
+
+        self.dirty.clear();
+        self.dirty_bytes = 0;
+        self.tag = None;
+        Ok(())
+    }
+}
+
+// A wrong password would scramble everything written with it, so it has to be caught first
+fn check_password(metadata: &VaultMetadata, keystream: &Keystream) -> Result<(), Box<dyn std::error::Error>> {
+    match &metadata.key_check {
+        Some(check) if *check == keystream.check_value() => Ok(()),
+        Some(_) => Err("Wrong image password".into()),
+        None => Err("The image password can't be checked for this vault; lock it again before writing to it from its fragments".into()),
+    }
+}
+
+// Rewrites the fragments with the sparse holes stored as zeros, so every block of the image can
+// be written. The fragments grow to the full size of the image; the next lock packs them again.
+// The new fragments are staged, and the caller puts them in place with fragwriter::commit.
+pub fn allocate(metadata: &mut VaultMetadata, password: &str, key_const: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    check_password(metadata, &Keystream::new(password, key_const, &metadata.image_nonce()?))?;
+    if metadata.sparse.as_ref().is_none_or(|map| map.holes.is_empty()) {
+        return Ok(());
+    }
+    let mut image = FragmentReader::decrypted(metadata, password, key_const)?;
+    let image_size = image.size() + GCM_TAG_SIZE;
+    let generation = metadata.merkle.generation + 1;
+    // The new layout only replaces the old one once every fragment has been written
+    let mut planned = metadata.clone();
+    planned.image_size = image_size;
+    planned.cut_chunks(generation);
+    // Everything is encrypted again, so under a nonce of its own like any lock
+    let nonce = crypto::random_nonce();
+    planned.image_nonce = Some(hex::encode(nonce));
+    let fragments = FragmentWriter::for_vault(&planned, generation)?;
+    let mut encryptor = GcmWriter::new(fragments, password, key_const, &nonce);
+    let written = io::copy(&mut image, &mut encryptor)
+        .and_then(|_| encryptor.finish())
+        .and_then(FragmentWriter::finish);
+    planned.merkle = match written {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            fragwriter::discard_staged(&planned.fragments);
+            return Err(e.into());
+        }
+    };
+    planned.sparse = None;
+    *metadata = planned;
+    Ok(())
+}
+
+impl BlockDevice for FragmentDisk {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        blockdev::check_range(offset, buf.len(), self.size)?;
+        self.reader.read_at(offset, buf)?;
+        for [from, packed, len] in crate::sparse::runs_in(&self.runs, offset, buf.len() as u64) {
+            let at = (from - offset) as usize;
+            self.overlay(packed, &mut buf[at..at + len as usize]);
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        blockdev::check_range(offset, buf.len(), self.size)?;
+        let runs = crate::sparse::runs_in(&self.runs, offset, buf.len() as u64);
+        let stored: u64 = runs.iter().map(|&[_, _, len]| len).sum();
+        if stored < buf.len() as u64 {
+            let mut in_runs = vec![false; buf.len()];
+            for &[from, _, len] in &runs {
+                in_runs[(from - offset) as usize..][..len as usize].fill(true);
+            }
+            if buf.iter().zip(&in_runs).any(|(&byte, &stored)| byte != 0 && !stored) {
+                return Err(io::Error::new(
+                    io::ErrorKind::StorageFull,
+                    "That part of the locker was empty when it was locked and isn't stored in the fragments",
+                ));
+            }
+        }
+
+        let mut current = vec![0u8; buf.len()];
+        self.read_at(offset, &mut current)?;
+        for [from, packed, len] in runs {
+            let at = (from - offset) as usize;
+            let new = &buf[at..at + len as usize];
+            let delta: Vec<u8> = new.iter().zip(&current[at..at + len as usize]).map(|(a, b)| a ^ b).collect();
+            if delta.iter().all(|&b| b == 0) {
+                continue;
+            }
+            let change = self.keystream.tag_delta(self.packed_size, packed, &delta);
+            for (byte, change) in self.tag_change.iter_mut().zip(change) {
+                *byte ^= change;
+            }
+            self.insert_dirty(packed, new);
+        }
+        if self.dirty_bytes > WRITE_BACK_LIMIT {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_back().map_err(|e| io::Error::other(format!("Writing changes back to the fragments failed: {}", e)))
+    }
+}
+
+// Streams one fragment from its store with the patches applied, checking every chunk against
+// its current leaf and working out the new leaves as it goes. Any error aborts the put, so the
+// fragment is either fully rewritten or left as it was.
+struct Rewrite<'a> {
+    inner: Box<dyn Read>,
+    describe: String,
+    // (chunk index, length) in the order the fragment holds them
+    chunks: Vec<(usize, u64)>,
+    current: usize,
+    within: u64,
+    position: u64,
+    // (offset in the fragment, new ciphertext), sorted and non-overlapping
+    patches: Vec<(u64, Vec<u8>)>,
+    expected: &'a [Hash],
+    generation: u64,
+    old: Option<merkle::LeafHasher>,
+    new: Option<merkle::LeafHasher>,
+    leaves: Vec<(usize, Hash)>,
+}
+
+impl Rewrite<'_> {
+    fn end_chunk(&mut self, index: usize) -> io::Result<()> {
+        let old = self.old.take().unwrap_or_else(|| merkle::LeafHasher::new(self.generation, index));
+        if old.finalize() != self.expected[index] {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Chunk {} in fragment {} has been tampered with", index, self.describe)));
+        }
+        let new = self.new.take().unwrap_or_else(|| merkle::LeafHasher::new(self.generation, index));
+        self.leaves.push((index, new.finalize()));
+        self.current += 1;
+        self.within = 0;
+        Ok(())
+    }
+}
+
+impl Read for Rewrite<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Empty chunks have nothing to read but still get a leaf
+        while let Some(&(index, 0)) = self.chunks.get(self.current) {
+            self.end_chunk(index)?;
+        }
+        let Some(&(index, len)) = self.chunks.get(self.current) else {
+            return Ok(0);
+        };
+        let want = ((len - self.within) as usize).min(buf.len());
+        let n = self.inner.read(&mut buf[..want])?;
+        if n == 0 && want > 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{} is shorter than the fragment info says", self.describe)));
+        }
+        let data = &mut buf[..n];
+        self.old.get_or_insert_with(|| merkle::LeafHasher::new(self.generation, index)).update(data);
+
+        let end = self.position + n as u64;
+        let first = self.patches.partition_point(|(offset, bytes)| offset + bytes.len() as u64 <= self.position);
+        for (offset, bytes) in self.patches[first..].iter().take_while(|(offset, _)| *offset < end) {
+            let from = (*offset).max(self.position);
+            let to = (offset + bytes.len() as u64).min(end);
+            data[(from - self.position) as usize..(to - self.position) as usize]
+                .copy_from_slice(&bytes[(from - offset) as usize..(to - offset) as usize]);
+        }
+        self.new.get_or_insert_with(|| merkle::LeafHasher::new(self.generation, index)).update(data);
+
+        self.position = end;
+        self.within += n as u64;
+        if self.within == len {
+            self.end_chunk(index)?;
+        }
+        Ok(n)
+    }
+}
@@ -0,0 +1,463 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use crate::blockdev::{self, BlockDevice, Slice};
+use crate::carrier::Carrier;
+use crate::crypto::{Keystream, GCM_TAG_SIZE};
+use crate::keysetup::FragmentInfo;
+use crate::merkle::{self, Hash};
+use crate::metadata::VaultMetadata;
+use crate::sparse;
+use crate::store::{self, FragmentStore};
+use crate::vhd;
+
+// Random access to a locked vault's image straight from its fragments, without reassembling
+// it. The encrypted view is the image exactly as it was split; the decrypted view is the
+// locker image itself, with the sparse holes filled back in. A read is mapped through the
+// assembly key to the chunks holding those bytes, and only those ranges are fetched from the
+// fragment stores (and, for the decrypted view, decrypted in memory).
+//
+// When the vault has a Merkle manifest, every chunk is checked against its leaf as it is
+// fetched, and reads are only ever served from the bytes that were checked, so tampered data
+// is never returned even by a store that changes its answer between requests. The GCM tag
+// covers the whole image and can't be checked for a partial read, which is why the decrypted
+// view requires a manifest.
+
+// A chunk is fetched whole and kept until a read needs another one. Chunks up to this size are
+// kept in memory; bigger ones, which only vaults not locked since chunks were capped at
+// keysetup::MAX_CHUNK_SIZE still have, are fetched piece by piece into a private temporary file.
+const CHUNK_BUFFER_LIMIT: u64 = 16 * 1024 * 1024;
+const VERIFY_PIECE: u64 = 4 * 1024 * 1024;
+// Decrypted data is cached in blocks of this size, so the many small reads a filesystem makes
+// don't each go to the fragment store
+const CACHE_BLOCK: u64 = 64 * 1024;
+const CACHE_BLOCKS: usize = 256;
+
+#[derive(Clone, Debug)]
+pub struct ChunkSpan {
+    // Offset of the chunk in the encrypted image
+    pub start: u64,
+    pub len: u64,
+    // Index into the vault's fragments, and where the chunk sits inside that fragment
+    pub fragment: usize,
+    pub offset_in_fragment: u64,
+}
+
+// Where every chunk of the encrypted image lives, worked out from the chunk lengths and the
+// order of `chunk_indices` in each fragment, the same way FragmentWriter lays them out
+#[derive(Clone, Debug)]
+pub struct ChunkLayout {
+    chunks: Vec<ChunkSpan>,
+}
+
+impl ChunkLayout {
+    pub fn new(fragments: &[FragmentInfo], lengths: &[u64]) -> Result<Self, String> {
+        let total_chunks = lengths.len();
+        let mut located = vec![None; total_chunks];
+        for (fragment_index, fragment) in fragments.iter().enumerate() {
+            let mut offset = 0u64;
+            for &chunk in fragment.chunk_indices.iter().filter(|&&i| i < total_chunks) {
+                located[chunk] = Some((fragment_index, offset));
+                offset += lengths[chunk];
+            }
+        }
+        let mut chunks = Vec::with_capacity(total_chunks);
+        let mut start = 0u64;
+        for (i, location) in located.into_iter().enumerate() {
+            let (fragment, offset_in_fragment) = location.ok_or_else(|| format!("Chunk {} is not in any fragment", i))?;
+            let len = lengths[i];
+            chunks.push(ChunkSpan { start, len, fragment, offset_in_fragment });
+            start += len;
+        }
+        Ok(Self { chunks })
+    }
+
+    pub fn for_vault(metadata: &VaultMetadata) -> Result<Self, String> {
+        Self::new(&metadata.fragments, &metadata.chunk_sizes())
+    }
+
+    pub fn span(&self, index: usize) -> &ChunkSpan {
+        &self.chunks[index]
+    }
+
+    // Index of the chunk holding byte `offset` of the encrypted image
+    pub fn chunk_at(&self, offset: u64) -> Option<usize> {
+        let index = self.chunks.partition_point(|c| c.start + c.len <= offset);
+        (index < self.chunks.len()).then_some(index)
+    }
+}
+
+// What the decrypted view needs on top of the ciphertext
+struct Plaintext {
+    keystream: Keystream,
+    // Length of the packed plaintext, i.e. the encrypted image without its tag
+    packed_size: u64,
+    // [offset in the image, offset in the packed plaintext, length]; the rest is zeros
+    runs: Vec<[u64; 3]>,
+    cache: HashMap<u64, Vec<u8>>,
+    cache_order: VecDeque<u64>,
+}
+
+// The ciphertext of the chunk last fetched, as checked against its leaf
+enum FetchedChunk {
+    Memory(Vec<u8>),
+    Spilled(SpilledChunk),
+}
+
+// A big chunk's ciphertext in a temporary file that is removed with it
+struct SpilledChunk {
+    file: File,
+    path: PathBuf,
+}
+
+impl Drop for SpilledChunk {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+pub struct FragmentReader {
+    layout: ChunkLayout,
+    locations: Vec<String>,
+    filenames: Vec<String>,
+    // Fragments are wrapped in `carrier` around payloads of these sizes
+    carrier: Carrier,
+    payload_lens: Vec<u64>,
+    // Opened on first use, so fragments that a read never touches are never contacted
+    stores: Vec<Option<Box<dyn FragmentStore>>>,
+    generation: u64,
+    leaves: Option<Vec<Hash>>,
+    // The chunk reads are currently served from, by index
+    current: Option<(usize, FetchedChunk)>,
+    plaintext: Option<Plaintext>,
+    position: u64,
+    len: u64,
+}
+
+impl FragmentReader {
+    fn new(metadata: &VaultMetadata, require_manifest: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        if metadata.image_size == 0 {
+            return Err("The vault has never been locked, so there are no fragments to read".into());
+        }
+        if metadata.swap_pending {
+            return Err("The last lock didn't finish moving its fragments into place; unlock the vault to finish it".into());
+        }
+        let total_chunks = metadata.total_chunks();
+        let leaves = if metadata.merkle.is_empty() {
+            if require_manifest {
+                return Err("No Merkle manifest is recorded, so reads can't be checked; lock the vault again to add one".into());
+            }
+            None
+        } else {
+            let leaves = metadata.merkle.checked_leaves()?;
+            if leaves.len() != total_chunks {
+                return Err(format!("Merkle manifest covers {} chunks but the key has {}", leaves.len(), total_chunks).into());
+            }
+            Some(leaves)
+        };
+        Ok(Self {
+            layout: ChunkLayout::for_vault(metadata)?,
+            locations: metadata.fragments.iter().map(|f| f.location.clone()).collect(),
+            filenames: metadata.fragments.iter().map(|f| f.filename.clone()).collect(),
+            carrier: metadata.carrier,
+            payload_lens: metadata.padded_sizes(),
+            stores: metadata.fragments.iter().map(|_| None).collect(),
+            generation: metadata.merkle.generation,
+            leaves,
+            current: None,
+            plaintext: None,
+            position: 0,
+            len: metadata.image_size,
+        })
+    }
+
+    // The encrypted image as it was split into fragments
+    pub fn encrypted(metadata: &VaultMetadata) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::new(metadata, false)
+    }
+
+    // The plaintext locker image. A wrong password isn't detected here; it just decrypts to noise.
+    pub fn decrypted(metadata: &VaultMetadata, password: &str, key_const: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut reader = Self::new(metadata, true)?;
+        let packed_size = metadata.image_size.checked_sub(GCM_TAG_SIZE).ok_or("Encrypted image is too short")?;
+        let (runs, size) = match &metadata.sparse {
+            Some(map) => {
+                if !map.is_well_formed() || map.image_size - map.hole_bytes() != packed_size {
+                    return Err("Sparse map in the fragment info doesn't match the encrypted image".into());
+                }
+                (map.data_runs(), map.image_size)
+            }
+            None => (vec![[0, 0, packed_size]], packed_size),
+        };
+        reader.plaintext = Some(Plaintext {
+            keystream: Keystream::new(password, key_const, &metadata.image_nonce()?),
+            packed_size,
+            runs,
+            cache: HashMap::new(),
+            cache_order: VecDeque::new(),
+        });
+        reader.len = size;
+        Ok(reader)
+    }
+
+    fn store(&mut self, fragment: usize) -> io::Result<&dyn FragmentStore> {
+        if self.stores[fragment].is_none() {
+            self.stores[fragment] = Some(store::open_store(&self.locations[fragment])?);
+        }
+        Ok(self.stores[fragment].as_deref().expect("store was just opened"))
+    }
+
+    // `len` bytes from `offset` in the fragment's payload
+    fn fetch(&mut self, fragment: usize, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let name = self.filenames[fragment].clone();
+        let runs = self.carrier.locate(self.payload_lens[fragment], offset, len);
+        let store = self.store(fragment)?;
+        if let [(start, len)] = runs[..] {
+            return store.get_range(&name, start, len as usize);
+        }
+        let mut data = Vec::with_capacity(len as usize);
+        for (start, len) in runs {
+            data.extend_from_slice(&store.get_range(&name, start, len as usize)?);
+        }
+        Ok(data)
+    }
+
+    fn tampered(&mut self, index: usize) -> io::Error {
+        let fragment = self.layout.chunks[index].fragment;
+        let name = self.filenames[fragment].clone();
+        let described = self.store(fragment).map_or_else(|_| name.clone(), |s| s.describe(&name));
+        io::Error::new(io::ErrorKind::InvalidData, format!("Chunk {} in fragment {} has been tampered with", index, described))
+    }
+
+    // Fetches a chunk whole and checks it, keeping it in memory or, when it's big, in a
+    // temporary file
+    fn fetch_chunk(&mut self, index: usize) -> io::Result<FetchedChunk> {
+        let chunk = self.layout.chunks[index].clone();
+        let mut hasher = merkle::LeafHasher::new(self.generation, index);
+        let fetched = if chunk.len <= CHUNK_BUFFER_LIMIT {
+            let data = self.fetch(chunk.fragment, chunk.offset_in_fragment, chunk.len)?;
+            hasher.update(&data);
+            FetchedChunk::Memory(data)
+        } else {
+            let path = store::temp_path("chunk");
+            let mut spilled = SpilledChunk { file: store::create_private(&path)?, path };
+            let mut done = 0;
+            while done < chunk.len {
+                let n = (chunk.len - done).min(VERIFY_PIECE);
+                let data = self.fetch(chunk.fragment, chunk.offset_in_fragment + done, n)?;
+                hasher.update(&data);
+                spilled.file.write_all(&data)?;
+                done += n;
+            }
+            FetchedChunk::Spilled(spilled)
+        };
+        if self.leaves.as_ref().is_some_and(|leaves| hasher.finalize() != leaves[index]) {
+            return Err(self.tampered(index));
+        }
+        Ok(fetched)
+    }
+
+    fn read_ciphertext(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut position = offset;
+        let mut done = 0;
+        while done < buf.len() {
+            let index = self.layout.chunk_at(position)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Read past the end of the encrypted image"))?;
+            if self.current.as_ref().is_none_or(|(current, _)| *current != index) {
+                // Dropped first, so at most one big chunk is ever on disk
+                self.current = None;
+                self.current = Some((index, self.fetch_chunk(index)?));
+            }
+            let chunk = &self.layout.chunks[index];
+            let within = position - chunk.start;
+            let n = ((chunk.len - within) as usize).min(buf.len() - done);
+            match &mut self.current.as_mut().expect("chunk was just fetched").1 {
+                FetchedChunk::Memory(data) => buf[done..done + n].copy_from_slice(&data[within as usize..within as usize + n]),
+                FetchedChunk::Spilled(spilled) => {
+                    spilled.file.seek(SeekFrom::Start(within))?;
+                    spilled.file.read_exact(&mut buf[done..done + n])?;
+                }
+            }
+            done += n;
+            position += n as u64;
+        }
+        Ok(())
+    }
+
+    fn read_packed(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut position = offset;
+        let mut done = 0;
+        while done < buf.len() {
+            let block = position / CACHE_BLOCK;
+            let plaintext = self.plaintext.as_ref().expect("only called for the decrypted view");
+            if !plaintext.cache.contains_key(&block) {
+                let start = block * CACHE_BLOCK;
+                let mut data = vec![0u8; (plaintext.packed_size - start).min(CACHE_BLOCK) as usize];
+                self.read_ciphertext(start, &mut data)?;
+                let plaintext = self.plaintext.as_mut().expect("only called for the decrypted view");
+                plaintext.keystream.apply(start, &mut data);
+                if plaintext.cache_order.len() >= CACHE_BLOCKS {
+                    if let Some(oldest) = plaintext.cache_order.pop_front() {
+                        plaintext.cache.remove(&oldest);
+                    }
+                }
+                plaintext.cache.insert(block, data);
+                plaintext.cache_order.push_back(block);
+            }
+            let data = &self.plaintext.as_ref().expect("only called for the decrypted view").cache[&block];
+            let within = (position % CACHE_BLOCK) as usize;
+            let n = (data.len() - within).min(buf.len() - done);
+            buf[done..done + n].copy_from_slice(&data[within..within + n]);
+            done += n;
+            position += n as u64;
+        }
+        Ok(())
+    }
+
+    // Fills `buf` from `offset` in whichever view this reader presents
+    fn read_range(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let Some(plaintext) = &self.plaintext else {
+            return self.read_ciphertext(offset, buf);
+        };
+        buf.fill(0);
+        for [from, packed, len] in sparse::runs_in(&plaintext.runs, offset, buf.len() as u64) {
+            let at = (from - offset) as usize;
+            self.read_packed(packed, &mut buf[at..at + len as usize])?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for FragmentReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (self.len.saturating_sub(self.position)).min(buf.len() as u64) as usize;
+        self.read_range(self.position, &mut buf[..n])?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for FragmentReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        self.position = target.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Seek before the start of the image"))?;
+        Ok(self.position)
+    }
+}
+
+impl BlockDevice for FragmentReader {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        blockdev::check_range(offset, buf.len(), self.len)?;
+        self.read_range(offset, buf)
+    }
+
+    fn write_at(&mut self, _offset: u64, _buf: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "The locker is opened read-only from its fragments"))
+    }
+
+    fn size(&self) -> u64 {
+        self.len
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The disk inside a decrypted image: a fixed VHD is the disk followed by its footer, anything
+// without a footer is a raw disk. Dynamic VHDs are laid out by their block table and need a
+// full unlock.
+pub fn disk<D: BlockDevice + 'static>(mut image: D) -> Result<Box<dyn BlockDevice>, Box<dyn std::error::Error>> {
+    let size = image.size();
+    if size >= vhd::FOOTER_SIZE as u64 {
+        let mut bytes = [0u8; vhd::FOOTER_SIZE];
+        image.read_at(size - vhd::FOOTER_SIZE as u64, &mut bytes)?;
+        if let Ok(footer) = vhd::Footer::parse(&bytes) {
+            if footer.disk_type != vhd::DISK_TYPE_FIXED {
+                return Err("Only raw and fixed VHD lockers can be read from their fragments, unlock this one instead".into());
+            }
+            return Ok(Box::new(Slice::new(image, 0, footer.current_size)?));
+        }
+    }
+    Ok(Box::new(image))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+    use crate::fragwriter::{self, FragmentWriter};
+
+    // A vault of one fragment holding `image` cut into `chunks` equal chunks
+    fn locked(dir: &Path, image: &[u8], chunks: usize) -> VaultMetadata {
+        let location = dir.to_string_lossy().into_owned();
+        let fragment = FragmentInfo {
+            filename: "a.bin".to_string(),
+            token: "a".to_string(),
+            location: location.clone(),
+            chunk_indices: (0..chunks).collect(),
+        };
+        let mut metadata = VaultMetadata::new(chunks, vec![location], "a".repeat(chunks), vec![fragment]);
+        metadata.image_size = image.len() as u64;
+        let mut writer = FragmentWriter::for_vault(&metadata, 1).unwrap();
+        writer.write_all(image).unwrap();
+        metadata.merkle = writer.finish().unwrap();
+        fragwriter::swap_in(&metadata.fragments).unwrap();
+        metadata
+    }
+
+    fn tamper(path: &Path, offset: u64) {
+        let mut data = fs::read(path).unwrap();
+        data[offset as usize] ^= 1;
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn serves_only_the_bytes_it_checked() {
+        let dir = store::temp_path("fragreader_test");
+        fs::create_dir(&dir).unwrap();
+        let image: Vec<u8> = (0..128 * 1024).map(|i| (i % 251) as u8).collect();
+        let metadata = locked(&dir, &image, 2);
+        let mut reader = FragmentReader::encrypted(&metadata).unwrap();
+        let mut buf = [0u8; 100];
+        reader.read_at(10, &mut buf).unwrap();
+        assert_eq!(buf, image[10..110]);
+
+        // Changed behind the reader's back, a chunk it already checked still reads as checked
+        tamper(&dir.join("a.bin"), 20);
+        reader.read_at(10, &mut buf).unwrap();
+        assert_eq!(buf, image[10..110]);
+        // Fetched again after another chunk was read, it is caught
+        reader.read_at(64 * 1024, &mut buf).unwrap();
+        assert_eq!(buf, image[64 * 1024..64 * 1024 + 100]);
+        let err = reader.read_at(10, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn big_chunks_are_read_from_a_checked_copy() {
+        let dir = store::temp_path("fragreader_test");
+        fs::create_dir(&dir).unwrap();
+        let image: Vec<u8> = (0..CHUNK_BUFFER_LIMIT + 4096).map(|i| (i % 251) as u8).collect();
+        let metadata = locked(&dir, &image, 1);
+        let mut reader = FragmentReader::encrypted(&metadata).unwrap();
+        let mut buf = [0u8; 100];
+        let far = CHUNK_BUFFER_LIMIT;
+        reader.read_at(far, &mut buf).unwrap();
+        assert_eq!(buf, image[far as usize..far as usize + 100]);
+
+        tamper(&dir.join("a.bin"), far + 10);
+        reader.read_at(far, &mut buf).unwrap();
+        assert_eq!(buf, image[far as usize..far as usize + 100]);
+        let mut fresh = FragmentReader::encrypted(&metadata).unwrap();
+        assert_eq!(fresh.read_at(0, &mut buf).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
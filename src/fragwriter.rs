@@ -0,0 +1,504 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+use crate::carrier::Carrier;
+use crate::keysetup::FragmentInfo;
+use crate::merkle::{self, Hash, MerkleManifest};
+use crate::metadata::{self, VaultMetadata};
+use crate::padding::Filler;
+use crate::store;
+
+// Fans a byte stream out into fragment files according to an AKIFA layout, the streaming
+// counterpart of FragmentReader. Byte i of the stream belongs to chunk i / chunk length, and
+// each chunk is appended to the fragment that owns it, so nothing is staged on disk and only a
+// few pieces per fragment are ever in memory. Each fragment is uploaded by its own thread
+// through the normal FragmentStore::put, under its staged name so the old fragment stays in
+// place until commit() has saved the metadata that describes the new one. The chunk lengths depend on the total size, which therefore has to be known up
+// front; any stream of known length can be written this way.
+//
+// Fragments hold their chunks in the order of their chunk lists, which is only shuffled within
+// small windows of neighbouring chunks (see keysetup::shuffle_windows), or ascending for older
+// vaults. A chunk that arrives before its turn in a fragment is held back until the chunks
+// ahead of it have been written; those all sit in the same window, so each fragment holds back
+// less than keysetup::SHUFFLE_WINDOW_BYTES, whatever the chunk size.
+//
+// Any padding a fragment gets is appended once its last chunk has been sent.
+
+// Pieces queued per fragment before the writer waits for that upload to catch up
+const QUEUE_DEPTH: usize = 8;
+
+// Name a fragment is written under until the lock that wrote it is committed
+pub fn staged_name(filename: &str) -> String {
+    format!("{}.next", filename)
+}
+
+// Saves metadata describing freshly written fragments, then renames each staged fragment over
+// the one it replaces and deletes the retired_fragments a new layout left behind. The metadata
+// is saved with swap_pending set and the retired fragments listed first, so a crash part-way
+// through is finished by finish_commit on the next lock or unlock instead of leaving fragments
+// of two different locks or old ones nothing points to; until that save the old fragments and
+// metadata are untouched, and failing it throws the staged fragments away.
+pub fn commit(vault_metadata: &mut VaultMetadata, enc_path: &str, passphrase: &str, key_const: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    vault_metadata.swap_pending = true;
+    if let Err(e) = metadata::save(vault_metadata, enc_path, passphrase, key_const) {
+        vault_metadata.swap_pending = false;
+        discard_staged(&vault_metadata.fragments);
+        return Err(e);
+    }
+    finish_commit(vault_metadata, enc_path, passphrase, key_const)
+}
+
+// Finishes the last commit if it was interrupted, and does nothing if it wasn't. Retired
+// fragments that can't be deleted stay recorded and are tried again next time.
+pub fn finish_commit(vault_metadata: &mut VaultMetadata, enc_path: &str, passphrase: &str, key_const: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    if !vault_metadata.swap_pending && vault_metadata.retired_fragments.is_empty() {
+        return Ok(());
+    }
+    if vault_metadata.swap_pending {
+        swap_in(&vault_metadata.fragments)
+            .map_err(|e| format!("Couldn't move every new fragment into place, this is retried on the next lock or unlock: {}", e))?;
+        vault_metadata.swap_pending = false;
+    }
+    let current = vault_metadata.fragment_names();
+    for (location, name) in std::mem::take(&mut vault_metadata.retired_fragments) {
+        if current.contains(&(location.clone(), name.clone())) {
+            continue;
+        }
+        let deleted = store::open_store(&location).and_then(|s| if s.exists(&name)? { s.delete(&name) } else { Ok(()) });
+        if let Err(e) = deleted {
+            eprintln!("Warning: failed to securely delete {}, this is retried on the next lock or unlock: {}", store::describe(&location, &name), e);
+            vault_metadata.retired_fragments.push((location, name));
+        }
+    }
+    metadata::save(vault_metadata, enc_path, passphrase, key_const)
+}
+
+// Moves every staged fragment into place. Fragments that were already moved are skipped, so an
+// interrupted swap can simply be run again.
+pub fn swap_in(fragments: &[FragmentInfo]) -> io::Result<()> {
+    for fragment in fragments {
+        let fragment_store = store::open_store(&fragment.location)?;
+        let staged = staged_name(&fragment.filename);
+        if fragment_store.exists(&staged)? {
+            fragment_store.rename(&staged, &fragment.filename)?;
+        }
+    }
+    Ok(())
+}
+
+// Deletes the staged fragments of a lock that is being abandoned
+pub fn discard_staged(fragments: &[FragmentInfo]) {
+    for fragment in fragments {
+        let staged = staged_name(&fragment.filename);
+        let _ = store::open_store(&fragment.location).and_then(|s| if s.exists(&staged)? { s.delete(&staged) } else { Ok(()) });
+    }
+}
+
+enum Piece {
+    Data(Vec<u8>),
+    End,
+}
+
+// Feeds an upload from the writer. If the writer goes away without finishing, the upload gets
+// an error rather than a clean end, so a partial fragment is never put in place.
+struct PieceReader {
+    receiver: Receiver<Piece>,
+    current: Vec<u8>,
+    offset: usize,
+    ended: bool,
+}
+
+impl Read for PieceReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.offset == self.current.len() {
+            if self.ended {
+                return Ok(0);
+            }
+            match self.receiver.recv() {
+                Ok(Piece::Data(data)) => {
+                    self.current = data;
+                    self.offset = 0;
+                }
+                Ok(Piece::End) => self.ended = true,
+                Err(_) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Fragment writer stopped before the end")),
+            }
+        }
+        let n = (self.current.len() - self.offset).min(buf.len());
+        buf[..n].copy_from_slice(&self.current[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+struct FragmentSink {
+    describe: String,
+    sender: Option<SyncSender<Piece>>,
+    upload: Option<JoinHandle<io::Result<()>>>,
+    // Chunks in the order the fragment holds them, and how many have been sent
+    order: Vec<usize>,
+    next: usize,
+    held_back: HashMap<usize, Vec<u8>>,
+}
+
+impl FragmentSink {
+    // Waits for the upload and returns how it went
+    fn join(&mut self) -> io::Result<()> {
+        self.sender = None;
+        match self.upload.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result.map_err(|e| io::Error::new(e.kind(), format!("Writing {} failed: {}", self.describe, e))),
+            Some(Err(_)) => Err(io::Error::other(format!("Writing {} panicked", self.describe))),
+            None => Ok(()),
+        }
+    }
+
+    fn send(&mut self, piece: Piece) -> io::Result<()> {
+        let sent = self.sender.as_ref().is_some_and(|sender| sender.send(piece).is_ok());
+        if sent {
+            return Ok(());
+        }
+        // The upload has stopped; its own error says why
+        self.join()?;
+        Err(io::Error::other(format!("Writing {} stopped early", self.describe)))
+    }
+}
+
+pub struct FragmentWriter {
+    sinks: Vec<FragmentSink>,
+    // Fragment owning each chunk
+    owners: Vec<usize>,
+    lengths: Vec<u64>,
+    generation: u64,
+    chunk: usize,
+    within: u64,
+    hasher: merkle::LeafHasher,
+    leaves: Vec<Hash>,
+}
+
+impl FragmentWriter {
+    // Starts writing a vault's image into its fragments on lock `generation`, after cut_chunks
+    // has laid it out for that lock
+    pub fn for_vault(metadata: &VaultMetadata, generation: u64) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::new(&metadata.fragments, metadata.chunk_sizes(), generation, metadata.carrier, metadata.padding_fillers(generation))
+    }
+
+    // Starts writing a stream cut into chunks of `lengths` bytes into `fragments`, each followed
+    // by its `padding` and wrapped in `carrier`. `generation` is the lock counter the Merkle
+    // leaves are bound to.
+    pub fn new(
+        fragments: &[FragmentInfo],
+        lengths: Vec<u64>,
+        generation: u64,
+        carrier: Carrier,
+        padding: Vec<Filler>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let total_chunks = lengths.len();
+        if total_chunks == 0 {
+            return Err("Total chunks must be greater than 0".into());
+        }
+        let mut owners = vec![None; total_chunks];
+        for (fragment_index, fragment) in fragments.iter().enumerate() {
+            for &chunk in fragment.chunk_indices.iter().filter(|&&i| i < total_chunks) {
+                if owners[chunk].replace(fragment_index).is_some() {
+                    return Err(format!("Chunk {} is assigned to more than one fragment", chunk).into());
+                }
+            }
+        }
+        let owners = owners
+            .into_iter()
+            .enumerate()
+            .map(|(chunk, owner)| owner.ok_or_else(|| format!("Chunk {} is not in any fragment", chunk)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut sinks = Vec::with_capacity(fragments.len());
+        let mut padding = padding.into_iter();
+        for fragment in fragments {
+            let filler = padding.next().unwrap_or_else(Filler::empty);
+            let (sender, receiver) = mpsc::sync_channel(QUEUE_DEPTH);
+            let location = fragment.location.clone();
+            let filename = staged_name(&fragment.filename);
+            let payload_len: u64 = fragment.chunk_indices.iter().filter(|&&i| i < total_chunks).map(|&i| lengths[i]).sum::<u64>() + filler.remaining();
+            // Stores aren't Send, so each upload opens its own
+            let upload = thread::spawn(move || {
+                let reader = PieceReader { receiver, current: Vec::new(), offset: 0, ended: false };
+                store::open_store(&location)?.put(&filename, &mut carrier.wrap(reader.chain(filler), payload_len), carrier.wrapped_len(payload_len))
+            });
+            sinks.push(FragmentSink {
+                describe: store::describe(&fragment.location, &fragment.filename),
+                sender: Some(sender),
+                upload: Some(upload),
+                order: fragment.chunk_indices.iter().copied().filter(|&i| i < total_chunks).collect(),
+                next: 0,
+                held_back: HashMap::new(),
+            });
+        }
+
+        Ok(Self {
+            sinks,
+            owners,
+            lengths,
+            generation,
+            chunk: 0,
+            within: 0,
+            hasher: merkle::LeafHasher::new(generation, 0),
+            leaves: Vec::with_capacity(total_chunks),
+        })
+    }
+
+    fn deliver(&mut self, data: &[u8]) -> io::Result<()> {
+        let sink = &mut self.sinks[self.owners[self.chunk]];
+        if sink.order[sink.next] == self.chunk {
+            sink.send(Piece::Data(data.to_vec()))
+        } else {
+            sink.held_back.entry(self.chunk).or_default().extend_from_slice(data);
+            Ok(())
+        }
+    }
+
+    fn end_chunk(&mut self) -> io::Result<()> {
+        let hasher = std::mem::replace(&mut self.hasher, merkle::LeafHasher::new(self.generation, self.chunk + 1));
+        self.leaves.push(hasher.finalize());
+        let sink = &mut self.sinks[self.owners[self.chunk]];
+        if sink.order[sink.next] == self.chunk {
+            sink.next += 1;
+            // Every chunk up to this one is complete, so anything held back can follow now
+            while let Some(data) = sink.order.get(sink.next).and_then(|chunk| sink.held_back.remove(chunk)) {
+                sink.send(Piece::Data(data))?;
+                sink.next += 1;
+            }
+        }
+        self.chunk += 1;
+        self.within = 0;
+        Ok(())
+    }
+
+    // Closes every fragment and returns the Merkle manifest over the chunks written. Fails if
+    // the stream was shorter than the size given to new() or any upload failed.
+    pub fn finish(mut self) -> io::Result<MerkleManifest> {
+        // Trailing chunks can be empty when there are more chunks than bytes
+        while self.chunk < self.lengths.len() && self.within == self.lengths[self.chunk] {
+            self.end_chunk()?;
+        }
+        if self.chunk < self.lengths.len() {
+            let written: u64 = self.lengths[..self.chunk].iter().sum::<u64>() + self.within;
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Stream ended after {} of {} bytes", written, self.lengths.iter().sum::<u64>()),
+            ));
+        }
+        let mut result = Ok(());
+        for sink in &mut self.sinks {
+            let sent = sink.send(Piece::End);
+            let joined = sink.join();
+            if result.is_ok() {
+                result = sent.and(joined);
+            }
+        }
+        result?;
+        Ok(MerkleManifest::from_leaves(self.generation, &self.leaves))
+    }
+}
+
+impl Write for FragmentWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut done = 0;
+        while done < buf.len() {
+            if self.chunk >= self.lengths.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "More data than the fragment layout was sized for"));
+            }
+            let remaining = self.lengths[self.chunk] - self.within;
+            if remaining == 0 {
+                self.end_chunk()?;
+                continue;
+            }
+            let n = remaining.min((buf.len() - done) as u64) as usize;
+            let data = &buf[done..done + n];
+            self.hasher.update(data);
+            self.deliver(data)?;
+            self.within += n as u64;
+            done += n;
+            if self.within == self.lengths[self.chunk] {
+                self.end_chunk()?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// An unfinished writer cancels every upload. Fragments that were already complete stay under
+// their staged names for the caller to discard.
+impl Drop for FragmentWriter {
+    fn drop(&mut self) {
+        for sink in &mut self.sinks {
+            let _ = sink.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sdfs_fragwriter_test_{:016x}", rand::random::<u64>()));
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn fragment(dir: &std::path::Path, filename: &str, chunk_indices: Vec<usize>) -> FragmentInfo {
+        FragmentInfo {
+            filename: filename.to_string(),
+            token: String::new(),
+            location: dir.to_string_lossy().into_owned(),
+            chunk_indices,
+        }
+    }
+
+    fn write(fragments: &[FragmentInfo], data: &[u8], lengths: Vec<u64>) {
+        let mut writer = FragmentWriter::new(fragments, lengths, 1, Carrier::Raw, Vec::new()).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn old_fragments_stay_until_swapped_in() {
+        let dir = temp_dir();
+        fs::write(dir.join("a.bin"), b"old").unwrap();
+        let fragments = vec![fragment(&dir, "a.bin", vec![0, 2]), fragment(&dir, "b.bin", vec![1])];
+        write(&fragments, b"aaaabbbbcccc", vec![4, 4, 4]);
+
+        assert_eq!(fs::read(dir.join("a.bin")).unwrap(), b"old");
+        assert!(!dir.join("b.bin").exists());
+        assert_eq!(fs::read(dir.join("a.bin.next")).unwrap(), b"aaaacccc");
+
+        swap_in(&fragments).unwrap();
+        assert_eq!(fs::read(dir.join("a.bin")).unwrap(), b"aaaacccc");
+        assert_eq!(fs::read(dir.join("b.bin")).unwrap(), b"bbbb");
+        assert!(!dir.join("a.bin.next").exists());
+        // Running it again after it completed changes nothing
+        swap_in(&fragments).unwrap();
+        assert_eq!(fs::read(dir.join("b.bin")).unwrap(), b"bbbb");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn abandoned_lock_leaves_old_fragments() {
+        let dir = temp_dir();
+        fs::write(dir.join("a.bin"), b"old").unwrap();
+        let fragments = vec![fragment(&dir, "a.bin", vec![0, 1])];
+        write(&fragments, b"new!", vec![2, 2]);
+        discard_staged(&fragments);
+        assert_eq!(fs::read(dir.join("a.bin")).unwrap(), b"old");
+        assert!(!dir.join("a.bin.next").exists());
+
+        // A writer dropped part-way leaves nothing behind
+        let mut writer = FragmentWriter::new(&fragments, vec![2, 2], 1, Carrier::Raw, Vec::new()).unwrap();
+        writer.write_all(b"ne").unwrap();
+        drop(writer);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn interrupted_commit_is_finished_explicitly() {
+        let dir = temp_dir();
+        let info = dir.join("info.enc").to_string_lossy().into_owned();
+        // A rotating lock that stopped after saving the metadata for its renamed fragment
+        fs::write(dir.join("a.bin"), b"old").unwrap();
+        let mut b = fragment(&dir, "b.bin", vec![0, 1]);
+        b.token = "b".to_string();
+        write(std::slice::from_ref(&b), b"new!", vec![2, 2]);
+        let mut metadata = VaultMetadata::new(2, vec![b.location.clone()], "bb".to_string(), vec![b.clone()]);
+        metadata.swap_pending = true;
+        metadata.retired_fragments = vec![(b.location.clone(), "a.bin".to_string())];
+        metadata::save(&metadata, &info, "passphrase", b"key").unwrap();
+
+        // Loading only reads
+        let mut loaded = metadata::load(&info, "passphrase", b"key").unwrap();
+        assert!(dir.join("b.bin.next").exists());
+        assert!(dir.join("a.bin").exists());
+
+        finish_commit(&mut loaded, &info, "passphrase", b"key").unwrap();
+        assert_eq!(fs::read(dir.join("b.bin")).unwrap(), b"new!");
+        assert!(!dir.join("b.bin.next").exists());
+        assert!(!dir.join("a.bin").exists());
+        let loaded = metadata::load(&info, "passphrase", b"key").unwrap();
+        assert!(!loaded.swap_pending && loaded.retired_fragments.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Writes `lengths` worth of chunks into one fragment holding them in `order`, and returns
+    // the most bytes it ever held back along with what it wrote
+    fn peak_held_back(order: Vec<usize>, lengths: Vec<u64>) -> (u64, Vec<u8>) {
+        let dir = temp_dir();
+        let fragments = vec![fragment(&dir, "a.bin", order)];
+        let mut writer = FragmentWriter::new(&fragments, lengths.clone(), 1, Carrier::Raw, Vec::new()).unwrap();
+        let mut peak = 0;
+        for (chunk, &len) in lengths.iter().enumerate() {
+            writer.write_all(&vec![chunk as u8; len as usize]).unwrap();
+            peak = peak.max(writer.sinks[0].held_back.values().map(|data| data.len() as u64).sum());
+        }
+        writer.finish().unwrap();
+        let written = fs::read(dir.join("a.bin.next")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        (peak, written)
+    }
+
+    #[test]
+    fn holds_back_at_most_one_window() {
+        use crate::keysetup::{shuffle_windows, SHUFFLE_WINDOW_BYTES, SHUFFLE_WINDOW_CHUNKS};
+
+        // Every window reversed keeps as much as possible waiting for its first chunk
+        let lengths = vec![128 * 1024; 64];
+        let chunks: Vec<usize> = (0..lengths.len()).collect();
+        let mut order = chunks.clone();
+        for window in shuffle_windows(&chunks, &lengths) {
+            order[window].reverse();
+        }
+        let (peak, written) = peak_held_back(order.clone(), lengths.clone());
+        assert_eq!(peak, (SHUFFLE_WINDOW_CHUNKS as u64 - 1) * 128 * 1024);
+        assert!(peak < SHUFFLE_WINDOW_BYTES);
+        let expected: Vec<u8> = order.iter().flat_map(|&chunk| vec![chunk as u8; lengths[chunk] as usize]).collect();
+        assert_eq!(written, expected);
+    }
+
+    #[test]
+    fn default_layout_is_stored_shuffled() {
+        use crate::keysetup::{self, SHUFFLE_WINDOW_BYTES};
+        use rand::rngs::OsRng;
+
+        let dir = temp_dir();
+        let dirs = vec![dir.to_string_lossy().into_owned(); 4];
+        let (key, fragments) = keysetup::generate_key_and_fragments(dirs.clone(), 4, &mut OsRng);
+        let mut metadata = VaultMetadata::new(4, dirs, key, fragments);
+        // No target chunk size, so one pass of the key alone would give 4 MiB chunks
+        metadata.image_size = SHUFFLE_WINDOW_BYTES * metadata.key_chunks() as u64;
+        metadata.cut_chunks(1);
+        let lengths = metadata.chunk_sizes();
+        assert!(lengths.iter().all(|&len| len <= keysetup::MAX_CHUNK_SIZE));
+
+        // Every chunk is filled with its own index, so the order on disk can be read back
+        let chunk_data = |chunk: usize| (0..lengths[chunk] as usize).map(move |i| (chunk as u32).to_le_bytes()[i % 4]);
+        let data: Vec<u8> = (0..lengths.len()).flat_map(chunk_data).collect();
+        assert_eq!(data.len() as u64, metadata.image_size);
+        let mut writer = FragmentWriter::for_vault(&metadata, 1).unwrap();
+        writer.write_all(&data).unwrap();
+        writer.finish().unwrap();
+
+        for fragment in &metadata.fragments {
+            let stored = fs::read(dir.join(staged_name(&fragment.filename))).unwrap();
+            let mut ascending = fragment.chunk_indices.clone();
+            ascending.sort_unstable();
+            assert_ne!(fragment.chunk_indices, ascending);
+            assert_eq!(stored, fragment.chunk_indices.iter().flat_map(|&chunk| chunk_data(chunk)).collect::<Vec<u8>>());
+            assert_ne!(stored, ascending.iter().flat_map(|&chunk| chunk_data(chunk)).collect::<Vec<u8>>());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
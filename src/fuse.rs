@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::fat::{DirEntry, Fat32};
+
+// Read-only FUSE filesystem over a FAT32 locker, speaking the kernel protocol on /dev/fuse
+// directly (include/uapi/linux/fuse.h). Requests are handled one at a time in the foreground;
+// the filesystem goes away when it is unmounted with `umount` or `fusermount3 -u`, or when the
+// process gets Ctrl-C.
+
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+const MAX_READ: u32 = 128 * 1024;
+// Attributes never change while mounted, so the kernel may cache them for a long time
+const ATTR_TTL_SECS: u64 = 3600;
+
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_SETATTR: u32 = 4;
+const FUSE_MKNOD: u32 = 8;
+const FUSE_MKDIR: u32 = 9;
+const FUSE_UNLINK: u32 = 10;
+const FUSE_RMDIR: u32 = 11;
+const FUSE_RENAME: u32 = 12;
+const FUSE_LINK: u32 = 13;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_WRITE: u32 = 16;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_CREATE: u32 = 35;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+const FUSE_RENAME2: u32 = 45;
+
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+const IN_HEADER_SIZE: usize = 40;
+const ATTR_SIZE: usize = 88;
+
+struct Node {
+    path: String,
+    // None for the root directory, which has no directory entry
+    entry: Option<DirEntry>,
+}
+
+struct FatFs {
+    volume: Fat32,
+    nodes: Vec<Node>,
+    ids: HashMap<String, u64>,
+    uid: u32,
+    gid: u32,
+    mounted_at: SystemTime,
+}
+
+// Reads a little-endian integer out of a request body
+fn le_u32(body: &[u8], at: usize) -> u32 {
+    body.get(at..at + 4).map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn le_u64(body: &[u8], at: usize) -> u64 {
+    body.get(at..at + 8).map_or(0, |b| u64::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+// Errors from the FAT code are plain strings; a missing path is the only one worth telling apart
+fn errno_for(error: &dyn std::error::Error) -> i32 {
+    let message = error.to_string();
+    if message.ends_with("does not exist") {
+        libc::ENOENT
+    } else if message.ends_with("is not a directory") {
+        libc::ENOTDIR
+    } else {
+        eprintln!("Read failed: {}", message);
+        libc::EIO
+    }
+}
+
+impl FatFs {
+    // Node ids are indexes into `nodes` plus one, so the root is id 1 as FUSE expects
+    fn node(&self, id: u64) -> Result<&Node, i32> {
+        id.checked_sub(1).and_then(|i| self.nodes.get(i as usize)).ok_or(libc::ENOENT)
+    }
+
+    // Node ids stay valid for the whole mount; the tree is read-only, so there is nothing to
+    // reclaim when the kernel forgets one
+    fn id_for(&mut self, path: String, entry: DirEntry) -> u64 {
+        if let Some(&id) = self.ids.get(&path) {
+            return id;
+        }
+        self.nodes.push(Node { path: path.clone(), entry: Some(entry) });
+        let id = self.nodes.len() as u64;
+        self.ids.insert(path, id);
+        id
+    }
+
+    fn attr(&self, id: u64) -> Result<[u8; ATTR_SIZE], i32> {
+        let node = self.node(id)?;
+        let (size, mode, nlink, time) = match &node.entry {
+            None => (0, libc::S_IFDIR | 0o555, 2, self.mounted_at),
+            Some(entry) if entry.is_dir() => (0, libc::S_IFDIR | 0o555, 2, entry.modified()),
+            Some(entry) => (entry.size as u64, libc::S_IFREG | 0o444, 1, entry.modified()),
+        };
+        let mut attr = [0u8; ATTR_SIZE];
+        attr[0..8].copy_from_slice(&id.to_le_bytes());
+        attr[8..16].copy_from_slice(&size.to_le_bytes());
+        attr[16..24].copy_from_slice(&size.div_ceil(512).to_le_bytes());
+        for at in [24, 32, 40] {
+            attr[at..at + 8].copy_from_slice(&unix_time(time).to_le_bytes());
+        }
+        attr[60..64].copy_from_slice(&mode.to_le_bytes());
+        attr[64..68].copy_from_slice(&(nlink as u32).to_le_bytes());
+        attr[68..72].copy_from_slice(&self.uid.to_le_bytes());
+        attr[72..76].copy_from_slice(&self.gid.to_le_bytes());
+        attr[80..84].copy_from_slice(&(self.volume.cluster_size() as u32).to_le_bytes());
+        Ok(attr)
+    }
+
+    fn lookup(&mut self, parent: u64, name: &[u8]) -> Result<Vec<u8>, i32> {
+        let name = std::str::from_utf8(name).map_err(|_| libc::ENOENT)?;
+        let parent_path = self.node(parent)?.path.clone();
+        let entry = self.volume.entry(&format!("{}/{}", parent_path, name)).map_err(|e| errno_for(e.as_ref()))?;
+        // Key the node on the name as stored, so lookups that differ only in case share it
+        let id = self.id_for(format!("{}/{}", parent_path, entry.name), entry);
+        let mut out = Vec::with_capacity(40 + ATTR_SIZE);
+        out.extend_from_slice(&id.to_le_bytes());
+        out.extend_from_slice(&0u64.to_le_bytes());
+        out.extend_from_slice(&ATTR_TTL_SECS.to_le_bytes());
+        out.extend_from_slice(&ATTR_TTL_SECS.to_le_bytes());
+        out.extend_from_slice(&[0u8; 8]);
+        out.extend_from_slice(&self.attr(id)?);
+        Ok(out)
+    }
+
+    fn getattr(&self, id: u64) -> Result<Vec<u8>, i32> {
+        let mut out = Vec::with_capacity(16 + ATTR_SIZE);
+        out.extend_from_slice(&ATTR_TTL_SECS.to_le_bytes());
+        out.extend_from_slice(&[0u8; 8]);
+        out.extend_from_slice(&self.attr(id)?);
+        Ok(out)
+    }
+
+    fn open(&self, id: u64, body: &[u8], directory: bool) -> Result<Vec<u8>, i32> {
+        let is_dir = self.node(id)?.entry.as_ref().is_none_or(DirEntry::is_dir);
+        if is_dir != directory {
+            return Err(if directory { libc::ENOTDIR } else { libc::EISDIR });
+        }
+        if le_u32(body, 0) as i32 & libc::O_ACCMODE != libc::O_RDONLY {
+            return Err(libc::EROFS);
+        }
+        let mut out = vec![0u8; 16];
+        out[8..12].copy_from_slice(&FOPEN_KEEP_CACHE.to_le_bytes());
+        Ok(out)
+    }
+
+    fn read(&mut self, id: u64, body: &[u8]) -> Result<Vec<u8>, i32> {
+        let offset = le_u64(body, 8);
+        let size = le_u32(body, 16).min(MAX_READ) as usize;
+        let entry = self.node(id)?.entry.clone().ok_or(libc::EISDIR)?;
+        let mut data = vec![0u8; size];
+        let n = self.volume.read_at(&entry, offset, &mut data).map_err(|e| errno_for(e.as_ref()))?;
+        data.truncate(n);
+        Ok(data)
+    }
+
+    // Each entry's offset is the index of the next one, so the kernel can resume a listing
+    // where the previous reply ended
+    fn readdir(&mut self, id: u64, body: &[u8]) -> Result<Vec<u8>, i32> {
+        let offset = le_u64(body, 8) as usize;
+        let size = le_u32(body, 16) as usize;
+        let path = self.node(id)?.path.clone();
+        let mut listing = vec![(id, ".".to_string(), libc::DT_DIR), (id, "..".to_string(), libc::DT_DIR)];
+        let entries = self.volume.list(if path.is_empty() { "/" } else { &path }).map_err(|e| errno_for(e.as_ref()))?;
+        for entry in entries {
+            let kind = if entry.is_dir() { libc::DT_DIR } else { libc::DT_REG };
+            let name = entry.name.clone();
+            let child = self.id_for(format!("{}/{}", path, name), entry);
+            listing.push((child, name, kind));
+        }
+        let mut out = Vec::new();
+        for (i, (ino, name, kind)) in listing.into_iter().enumerate().skip(offset) {
+            let record_len = (24 + name.len()).div_ceil(8) * 8;
+            if out.len() + record_len > size {
+                break;
+            }
+            out.extend_from_slice(&ino.to_le_bytes());
+            out.extend_from_slice(&(i as u64 + 1).to_le_bytes());
+            out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(kind as u32).to_le_bytes());
+            out.extend_from_slice(name.as_bytes());
+            out.resize(out.len().div_ceil(8) * 8, 0);
+        }
+        Ok(out)
+    }
+
+    fn statfs(&self) -> Vec<u8> {
+        let block = self.volume.cluster_size();
+        let mut out = vec![0u8; 80];
+        out[0..8].copy_from_slice(&(self.volume.total_space() / block).to_le_bytes());
+        out[8..16].copy_from_slice(&(self.volume.free_space() / block).to_le_bytes());
+        out[16..24].copy_from_slice(&(self.volume.free_space() / block).to_le_bytes());
+        out[40..44].copy_from_slice(&(block as u32).to_le_bytes());
+        out[44..48].copy_from_slice(&255u32.to_le_bytes());
+        out[48..52].copy_from_slice(&(block as u32).to_le_bytes());
+        out
+    }
+}
+
+fn init_reply(body: &[u8]) -> Vec<u8> {
+    let minor = le_u32(body, 4).min(FUSE_KERNEL_MINOR_VERSION);
+    let mut out = vec![0u8; 64];
+    out[0..4].copy_from_slice(&FUSE_KERNEL_VERSION.to_le_bytes());
+    out[4..8].copy_from_slice(&minor.to_le_bytes());
+    out[8..12].copy_from_slice(&le_u32(body, 8).to_le_bytes());
+    out[20..24].copy_from_slice(&MAX_READ.to_le_bytes());
+    out[24..28].copy_from_slice(&1u32.to_le_bytes());
+    out
+}
+
+fn reply(device: &mut File, unique: u64, result: Result<Vec<u8>, i32>) -> io::Result<()> {
+    let (error, body) = match result {
+        Ok(body) => (0, body),
+        Err(errno) => (-errno, Vec::new()),
+    };
+    let mut message = Vec::with_capacity(16 + body.len());
+    message.extend_from_slice(&((16 + body.len()) as u32).to_le_bytes());
+    message.extend_from_slice(&error.to_le_bytes());
+    message.extend_from_slice(&unique.to_le_bytes());
+    message.extend_from_slice(&body);
+    match device.write(&message) {
+        // The request was interrupted and the kernel has already given up on it
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+        other => other.map(|_| ()),
+    }
+}
+
+fn serve(device: &mut File, fs: &mut FatFs) -> io::Result<()> {
+    let mut buffer = vec![0u8; MAX_READ as usize + 4096];
+    loop {
+        let n = match device.read(&mut buffer) {
+            Ok(n) => n,
+            Err(e) => match e.raw_os_error() {
+                // Unmounted
+                Some(libc::ENODEV) => return Ok(()),
+                Some(libc::EINTR) | Some(libc::EAGAIN) | Some(libc::ENOENT) => continue,
+                _ => return Err(e),
+            },
+        };
+        if n < IN_HEADER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Short request from the FUSE device"));
+        }
+        let opcode = le_u32(&buffer, 4);
+        let unique = le_u64(&buffer, 8);
+        let node = le_u64(&buffer, 16);
+        let body = &buffer[IN_HEADER_SIZE..n];
+        let result = match opcode {
+            FUSE_INIT => Ok(init_reply(body)),
+            FUSE_LOOKUP => fs.lookup(node, body.split(|&b| b == 0).next().unwrap_or(body)),
+            FUSE_GETATTR => fs.getattr(node),
+            FUSE_OPEN => fs.open(node, body, false),
+            FUSE_OPENDIR => fs.open(node, body, true),
+            FUSE_READ => fs.read(node, body),
+            FUSE_READDIR => fs.readdir(node, body),
+            FUSE_STATFS => Ok(fs.statfs()),
+            FUSE_RELEASE | FUSE_RELEASEDIR | FUSE_FLUSH => Ok(Vec::new()),
+            FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_INTERRUPT => continue,
+            FUSE_DESTROY => {
+                reply(device, unique, Ok(Vec::new()))?;
+                return Ok(());
+            }
+            FUSE_SETATTR | FUSE_MKNOD | FUSE_MKDIR | FUSE_UNLINK | FUSE_RMDIR | FUSE_RENAME | FUSE_LINK | FUSE_WRITE
+            | FUSE_CREATE | FUSE_RENAME2 => Err(libc::EROFS),
+            _ => Err(libc::ENOSYS),
+        };
+        reply(device, unique, result)?;
+    }
+}
+
+fn c_string(value: &[u8]) -> io::Result<CString> {
+    CString::new(value).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Path contains a NUL byte"))
+}
+
+// As root the filesystem is mounted with mount(2). Everyone else goes through the setuid
+// fusermount3 helper, which mounts it and passes the /dev/fuse descriptor back over a socket.
+fn mount(mountpoint: &Path) -> io::Result<File> {
+    let target = c_string(mountpoint.as_os_str().as_bytes())?;
+    if unsafe { libc::geteuid() } != 0 {
+        return mount_with_fusermount(mountpoint);
+    }
+    let device = OpenOptions::new().read(true).write(true).open("/dev/fuse")?;
+    let options = c_string(format!(
+        "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+        device.as_raw_fd(),
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() }
+    ).as_bytes())?;
+    let flags = libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV;
+    let result = unsafe {
+        libc::mount(c"sdfs".as_ptr(), target.as_ptr(), c"fuse.sdfs".as_ptr(), flags, options.as_ptr().cast())
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(device)
+}
+
+fn mount_with_fusermount(mountpoint: &Path) -> io::Result<File> {
+    let mut sockets = [0; 2];
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, sockets.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (ours, theirs) = unsafe { (File::from_raw_fd(sockets[0]), File::from_raw_fd(sockets[1])) };
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "fusermount3 is not installed");
+    let mut status = None;
+    for helper in ["fusermount3", "fusermount"] {
+        match Command::new(helper)
+            .arg("-o")
+            .arg("ro,nosuid,nodev,default_permissions,fsname=sdfs,subtype=sdfs")
+            .arg("--")
+            .arg(mountpoint)
+            .env("_FUSE_COMMFD", theirs.as_raw_fd().to_string())
+            .status()
+        {
+            Ok(result) => {
+                status = Some(result);
+                break;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => last_error = e,
+        }
+    }
+    drop(theirs);
+    let status = status.ok_or(last_error)?;
+    if !status.success() {
+        return Err(io::Error::other(format!("fusermount3 failed ({})", status)));
+    }
+
+    // The descriptor arrives as SCM_RIGHTS ancillary data alongside a single byte
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec { iov_base: byte.as_mut_ptr().cast(), iov_len: 1 };
+    let mut control = [0u8; 64];
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = control.len() as _;
+    if unsafe { libc::recvmsg(ours.as_raw_fd(), &mut message, 0) } <= 0 {
+        return Err(io::Error::other("fusermount3 did not pass back the FUSE device"));
+    }
+    let header = unsafe { libc::CMSG_FIRSTHDR(&message) };
+    if header.is_null() || unsafe { (*header).cmsg_level != libc::SOL_SOCKET || (*header).cmsg_type != libc::SCM_RIGHTS } {
+        return Err(io::Error::other("fusermount3 did not pass back the FUSE device"));
+    }
+    let fd = unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(header) as *const libc::c_int) };
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn unmount(mountpoint: &Path) {
+    if unsafe { libc::geteuid() } == 0 {
+        if let Ok(target) = c_string(mountpoint.as_os_str().as_bytes()) {
+            unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) };
+        }
+    } else {
+        for helper in ["fusermount3", "fusermount"] {
+            if Command::new(helper).arg("-u").arg("-z").arg(mountpoint).status().is_ok() {
+                break;
+            }
+        }
+    }
+}
+
+// Mounts `volume` read-only at `mountpoint` and serves it until it is unmounted. Ctrl-C and
+// SIGTERM unmount it cleanly instead of leaving a dead mount behind.
+pub fn mount_and_serve(volume: Fat32, mountpoint: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mountpoint: PathBuf = mountpoint.canonicalize()?;
+    let mut device = mount(&mountpoint).map_err(|e| format!("Failed to mount {}: {}", mountpoint.display(), e))?;
+
+    // Block the signals in every thread and pick them up in one that only waits for them
+    let signals = unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        set
+    };
+    let watched = mountpoint.clone();
+    std::thread::spawn(move || {
+        let mut signal = 0;
+        unsafe { libc::sigwait(&signals, &mut signal) };
+        unmount(&watched);
+    });
+
+    let mut fs = FatFs {
+        volume,
+        nodes: vec![Node { path: String::new(), entry: None }],
+        ids: HashMap::new(),
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+        mounted_at: SystemTime::now(),
+    };
+    println!("Locker mounted read-only at {}. Unmount it (or press Ctrl-C) to stop.", mountpoint.display());
+    let result = serve(&mut device, &mut fs);
+    if result.is_err() {
+        unmount(&mountpoint);
+    }
+    result?;
+    println!("Locker unmounted.");
+    Ok(())
+}
@@ -1,160 +1,470 @@
-use rand::Rng;
-use rand::prelude::SliceRandom;
-use std::collections::VecDeque;
-use std::collections::HashMap;
+use hmac::{Hmac, Mac};
+use rand::seq::SliceRandom;
+use rand::{CryptoRng, Rng, RngCore, SeedableRng};
+use rand::rngs::OsRng;
+use rand_chacha::ChaCha20Rng;
+use rand_chacha::rand_core::CryptoRngCore;
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::Path;
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct FragmentInfo {
     pub filename: String,
-    pub directory: String,
+    // The key characters the fragment's chunks were assigned from. The filename used to be
+    // these characters plus .bin; a naming policy can now pick any name, see naming.rs. Empty
+    // in fragment info saved before that, where the filename stem is the token.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub token: String,
+    // Local directory or fragment store URI, see store.rs
+    #[serde(alias = "directory")]
+    pub location: String,
+    // Rebuilt from the key and the tokens when the fragment info is loaded, see
+    // derive_chunk_indices; only stored for vaults whose key can't reproduce it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub chunk_indices: Vec<usize>,
 }
 
-struct FilenameQueue {
-    original: String,
-    queue: VecDeque<char>,
+// Filename characters. A key symbol is a fixed number of them, read as a number in base 62.
+const FILENAME_CHARS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+const MAX_SYMBOL_WIDTH: u32 = 3;
+pub const MAX_FRAGMENTS: usize = 62usize.pow(MAX_SYMBOL_WIDTH);
+// Characters a filename can take up, leaving room for the extension in 255 bytes
+const MAX_FILENAME_CHARS: usize = 250;
+// How generate_key_and_fragments resolves conflicts, recorded with each key, see FilenameQueues
+pub const KEY_LAYOUT: u32 = 1;
+
+// Characters per key symbol: the fewest that give every fragment a front symbol of its own.
+// Up to 62 fragments this is one, so those vaults use single characters as they always have.
+pub fn symbol_width(fragment_count: usize) -> usize {
+    let mut width = 1;
+    let mut symbols = FILENAME_CHARS.len();
+    while symbols < fragment_count {
+        width += 1;
+        symbols *= FILENAME_CHARS.len();
+    }
+    width
 }
 
-impl FilenameQueue {
-    fn new(name: String) -> Self {
-        let queue = name.chars().collect();
-        Self {
-            original: name,
-            queue,
-        }
+// Number of chunks a key splits the image into
+pub fn chunk_count(key: &str, fragment_count: usize) -> usize {
+    key.len() / symbol_width(fragment_count)
+}
+
+// Checks the setup parameters before anything is generated, since resolving conflicts needs a
+// distinct symbol for every fragment and each chunk adds a symbol to a filename
+pub fn check_parameters(fragment_count: usize, max_chunks_per_file: usize) -> Result<(), String> {
+    if fragment_count == 0 {
+        return Err("At least one fragment is needed".to_string());
     }
-    
-    fn pop_front(&mut self) -> Option<char> {
-        self.queue.pop_front()
+    if fragment_count > MAX_FRAGMENTS {
+        return Err(format!("At most {} fragments are supported", MAX_FRAGMENTS));
     }
-    
-    fn front(&self) -> Option<&char> {
-        self.queue.front()
+    if max_chunks_per_file == 0 {
+        return Err("Each fragment needs at least one chunk".to_string());
     }
-    
-    fn is_empty(&self) -> bool {
-        self.queue.is_empty()
+    let width = symbol_width(fragment_count);
+    if max_chunks_per_file * width > MAX_FILENAME_CHARS {
+        return Err(format!(
+            "With {} fragments every chunk takes {} characters of a filename, so at most {} chunks per file fit",
+            fragment_count, width, MAX_FILENAME_CHARS / width
+        ));
     }
+    Ok(())
 }
 
-fn generate_random_filename(max_length: usize) -> String {
-    let mut rng = rand::thread_rng();
-    
-    let length = rng.gen_range(1..=max_length);
-    
-    let chars = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
-    let chars: Vec<char> = chars.chars().collect();
-    
-    let mut filename = String::new();
-    for _ in 0..length {
-        let ch = chars[rng.gen_range(0..chars.len())];
-        filename.push(ch);
+fn encode_symbol(mut symbol: u32, width: usize, out: &mut String) {
+    let base = FILENAME_CHARS.len() as u32;
+    let mut chars = vec![0u8; width];
+    for ch in chars.iter_mut().rev() {
+        *ch = FILENAME_CHARS[(symbol % base) as usize];
+        symbol /= base;
     }
-    
-    filename
+    out.extend(chars.into_iter().map(char::from));
 }
 
-fn adjust_char_for_conflict(ch: char) -> char {
-    let mut rng = rand::thread_rng();
-    let direction = if rng.gen_bool(0.5) { 1 } else { -1 };
-    
-    let chars = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
-    let chars: Vec<char> = chars.chars().collect();
-    
-    if let Some(current_pos) = chars.iter().position(|&c| c == ch) {
-        let new_pos = if direction == 1 {
-            (current_pos + 1) % chars.len()
-        } else {
-            if current_pos == 0 {
-                chars.len() - 1
-            } else {
-                current_pos - 1
-            }
-        };
-        chars[new_pos]
-    } else {
-        '0'
+// Splits a filename or key into symbols of `width` characters
+fn decode_symbols(text: &str, width: usize) -> Option<Vec<u32>> {
+    if !text.len().is_multiple_of(width) {
+        return None;
     }
+    text.as_bytes()
+        .chunks(width)
+        .map(|symbol| {
+            symbol.iter().try_fold(0u32, |value, ch| {
+                let digit = FILENAME_CHARS.iter().position(|c| c == ch)?;
+                Some(value * FILENAME_CHARS.len() as u32 + digit as u32)
+            })
+        })
+        .collect()
 }
 
-fn resolve_conflicts(queues: &mut [FilenameQueue]) {
-    loop {
-        let mut conflicts = HashMap::new();
-        
-        for (i, queue) in queues.iter().enumerate() {
-            if let Some(&ch) = queue.front() {
-                conflicts.entry(ch).or_insert(Vec::new()).push(i);
+// The filename queues with a distinct symbol at every front. When a queue exposes a new front
+// (at the start, in fragment order, and after each pop) that another queue already shows, it
+// is moved to the next symbol, wrapping around, until it is free; the adjusted symbol is what
+// the key records. Fronts are looked up in a map, so a step costs the same with thousands of
+// fragments as with three, and a free symbol is always found because the width leaves at
+// least as many symbols as there are fragments.
+//
+// Keys of layout 0 were made by resolving every queue again after each pop: of the queues
+// sharing a front, all but the first moved on, over and over until the fronts differed. That
+// is still replayed for those vaults, which never have more than 62 fragments.
+struct FilenameQueues {
+    queues: Vec<VecDeque<u32>>,
+    fronts: HashMap<u32, usize>,
+    symbol_count: u32,
+    full_pass: bool,
+}
+
+impl FilenameQueues {
+    fn new(queues: Vec<VecDeque<u32>>, width: usize, layout: u32) -> Self {
+        let symbol_count = (FILENAME_CHARS.len() as u32).pow(width as u32);
+        let mut queues = Self { fronts: HashMap::with_capacity(queues.len()), queues, symbol_count, full_pass: layout == 0 };
+        if queues.full_pass {
+            queues.resolve_all_conflicts();
+        } else {
+            for i in 0..queues.queues.len() {
+                queues.resolve_conflict(i);
             }
         }
-        
-        let mut has_conflicts = false;
-        
-        for (_ch, indices) in conflicts {
-            if indices.len() > 1 {
+        queues
+    }
+    
+    fn resolve_conflict(&mut self, i: usize) {
+        if let Some(front) = self.queues[i].front_mut() {
+            while self.fronts.contains_key(front) {
+                *front = (*front + 1) % self.symbol_count;
+            }
+            self.fronts.insert(*front, i);
+        }
+    }
+    
+    fn resolve_all_conflicts(&mut self) {
+        loop {
+            let mut conflicts: BTreeMap<u32, Vec<usize>> = BTreeMap::new();
+            for (i, queue) in self.queues.iter().enumerate() {
+                if let Some(&front) = queue.front() {
+                    conflicts.entry(front).or_default().push(i);
+                }
+            }
+            let mut has_conflicts = false;
+            for indices in conflicts.values().filter(|indices| indices.len() > 1) {
                 has_conflicts = true;
-                for &idx in &indices[1..] {
-                    if let Some(front_char) = queues[idx].queue.pop_front() {
-                        let new_char = adjust_char_for_conflict(front_char);
-                        queues[idx].queue.push_front(new_char);
+                for &i in &indices[1..] {
+                    if let Some(front) = self.queues[i].front_mut() {
+                        *front = (*front + 1) % self.symbol_count;
                     }
                 }
             }
+            if !has_conflicts {
+                self.fronts = conflicts.into_iter().map(|(front, indices)| (front, indices[0])).collect();
+                break;
+            }
         }
-        
-        if !has_conflicts {
-            break;
+    }
+    
+    // The queue showing `symbol` at its front
+    fn owner(&self, symbol: u32) -> Option<usize> {
+        self.fronts.get(&symbol).copied()
+    }
+    
+    fn pop_front(&mut self, i: usize) -> Option<u32> {
+        let symbol = self.queues[i].pop_front()?;
+        self.fronts.remove(&symbol);
+        if self.full_pass {
+            self.resolve_all_conflicts();
+        } else {
+            self.resolve_conflict(i);
         }
+        Some(symbol)
+    }
+}
+
+// The randomness behind a fragment layout. Everything that picks a key, filenames or
+// directories takes the RNG as a parameter, so tests can pass a seeded one and get the same
+// layout every time; a real vault only ever gets the OS RNG.
+pub fn layout_rng() -> Box<dyn CryptoRngCore> {
+    Box::new(OsRng)
+}
+
+fn generate_random_filename(max_length: usize, width: usize, rng: &mut (impl RngCore + CryptoRng + ?Sized)) -> String {
+    let length = rng.gen_range(1..=max_length) * width;
+    
+    let mut filename = String::new();
+    for _ in 0..length {
+        let ch = FILENAME_CHARS[rng.gen_range(0..FILENAME_CHARS.len())];
+        filename.push(ch as char);
     }
+    
+    filename
+}
+
+// A token like the ones generate_key_and_fragments gives a vault of `fragment_count`
+// fragments, for files that should pass for fragments
+pub fn random_token(max_chunks_per_file: usize, fragment_count: usize, rng: &mut (impl RngCore + CryptoRng + ?Sized)) -> String {
+    generate_random_filename(max_chunks_per_file, symbol_width(fragment_count), rng)
 }
 
-pub fn generate_key_and_fragments(directories: Vec<String>, max_chunks_per_file: usize) -> (String, Vec<FragmentInfo>) {
-    let mut rng = rand::thread_rng();
+// `directories` and `max_chunks_per_file` must pass check_parameters
+pub fn generate_key_and_fragments(directories: Vec<String>, max_chunks_per_file: usize, rng: &mut (impl RngCore + CryptoRng + ?Sized)) -> (String, Vec<FragmentInfo>) {
+    let width = symbol_width(directories.len());
     
-    let mut filename_queues: Vec<FilenameQueue> = directories
+    let filenames: Vec<String> = directories
         .iter()
-        .map(|_| {
-            let filename = generate_random_filename(max_chunks_per_file);
-            FilenameQueue::new(filename)
-        })
+        .map(|_| generate_random_filename(max_chunks_per_file, width, rng))
         .collect();
+    let mut filename_queues = FilenameQueues::new(
+        filenames.iter().map(|name| decode_symbols(name, width).expect("generated from FILENAME_CHARS").into()).collect(),
+        width,
+        KEY_LAYOUT,
+    );
     
     let mut key = String::new();
     let mut chunk_assignments: Vec<Vec<usize>> = vec![Vec::new(); directories.len()];
     let mut chunk_index = 0;
+    let mut non_empty: Vec<usize> = (0..directories.len()).collect();
     
-    while filename_queues.iter().any(|q| !q.is_empty()) {
-        resolve_conflicts(&mut filename_queues);
-        
-        let non_empty: Vec<usize> = filename_queues
-            .iter()
-            .enumerate()
-            .filter_map(|(i, q)| if !q.is_empty() { Some(i) } else { None })
-            .collect();
-        
-        if let Some(&selected_idx) = non_empty.choose(&mut rng) {
-            if let Some(ch) = filename_queues[selected_idx].pop_front() {
-                key.push(ch);
-                chunk_assignments[selected_idx].push(chunk_index);
-                chunk_index += 1;
-            }
+    while !non_empty.is_empty() {
+        let pick = rng.gen_range(0..non_empty.len());
+        let selected_idx = non_empty[pick];
+        if let Some(symbol) = filename_queues.pop_front(selected_idx) {
+            encode_symbol(symbol, width, &mut key);
+            chunk_assignments[selected_idx].push(chunk_index);
+            chunk_index += 1;
+        }
+        if filename_queues.queues[selected_idx].is_empty() {
+            non_empty.swap_remove(pick);
         }
     }
     
     let fragments: Vec<FragmentInfo> = directories
         .into_iter()
-        .enumerate()
-        .zip(filename_queues.iter())
-        .map(|((i, dir), queue)| FragmentInfo {
-            filename: format!("{}.bin", queue.original),
-            directory: dir,
-            chunk_indices: chunk_assignments[i].clone(),
+        .zip(filenames)
+        .zip(chunk_assignments)
+        .map(|((dir, name), chunk_indices)| FragmentInfo {
+            filename: format!("{}.bin", name),
+            token: name,
+            location: dir,
+            chunk_indices,
         })
         .collect();
     
     (key, fragments)
 }
 
-pub fn generate_key(directories: Vec<String>, max_chunks: usize) -> String {
-    let (key, _) = generate_key_and_fragments(directories, max_chunks);
-    key
-}
\ No newline at end of file
+impl FragmentInfo {
+    // The queue the fragment stands for: its token, or for older fragment info its filename
+    // without the extension
+    pub fn key_symbols(&self) -> &str {
+        if !self.token.is_empty() {
+            return &self.token;
+        }
+        Path::new(&self.filename).file_stem().and_then(|stem| stem.to_str()).unwrap_or(&self.filename)
+    }
+}
+
+// Replays `key` against the filename queues of `fragments`, making the same conflict
+// adjustments generate_key_and_fragments made, and returns the chunks each fragment holds.
+// Fails if the key and tokens don't fit together: a token that couldn't have been
+// generated, two fragments stored as the same file, a key symbol no queue offers, or
+// symbols left over on either side.
+pub fn derive_chunk_indices(key: &str, layout: u32, fragments: &[FragmentInfo]) -> Result<Vec<Vec<usize>>, String> {
+    if fragments.len() > MAX_FRAGMENTS {
+        return Err(format!("{} fragments can't all be told apart, at most {} can", fragments.len(), MAX_FRAGMENTS));
+    }
+    let width = symbol_width(fragments.len());
+    let mut seen = HashSet::new();
+    let mut queues = Vec::with_capacity(fragments.len());
+    for fragment in fragments {
+        let symbols = decode_symbols(fragment.key_symbols(), width)
+            .filter(|symbols| !symbols.is_empty())
+            .ok_or_else(|| format!("Fragment {} has a token that isn't made of {}-character key symbols", fragment.filename, width))?;
+        if !seen.insert((&fragment.location, &fragment.filename)) {
+            return Err(format!("Fragment {} in {} is listed twice", fragment.filename, fragment.location));
+        }
+        queues.push(VecDeque::from(symbols));
+    }
+    let key_symbols = decode_symbols(key, width).ok_or_else(|| format!("The key isn't made of {}-character symbols", width))?;
+    let filename_length: usize = queues.iter().map(|q| q.len()).sum();
+    if filename_length != key_symbols.len() {
+        return Err(format!("The key has {} symbols but the tokens have {}", key_symbols.len(), filename_length));
+    }
+    
+    let mut filename_queues = FilenameQueues::new(queues, width, layout);
+    let mut chunk_assignments: Vec<Vec<usize>> = vec![Vec::new(); fragments.len()];
+    
+    for (chunk_index, &symbol) in key_symbols.iter().enumerate() {
+        // Fronts are distinct, so at most one queue can match
+        let Some(selected_idx) = filename_queues.owner(symbol) else {
+            let text = &key[chunk_index * width..(chunk_index + 1) * width];
+            return Err(format!("Key symbol {} at position {} doesn't match any fragment", text, chunk_index));
+        };
+        filename_queues.pop_front(selected_idx);
+        chunk_assignments[selected_idx].push(chunk_index);
+    }
+    
+    Ok(chunk_assignments)
+}
+
+// Fills in the chunk lists of fragments loaded without them. Vaults fragmented before conflicts
+// were resolved the same way every time have keys that may not replay, so their stored lists
+// are kept as they are.
+pub fn resolve_chunk_indices(key: &str, layout: u32, fragments: &mut [FragmentInfo]) -> Result<(), String> {
+    if fragments.iter().any(|f| !f.chunk_indices.is_empty()) {
+        return Ok(());
+    }
+    let derived = derive_chunk_indices(key, layout, fragments).map_err(|e| format!("The key doesn't match the fragment tokens: {}", e))?;
+    for (fragment, chunks) in fragments.iter_mut().zip(derived) {
+        fragment.chunk_indices = chunks;
+    }
+    Ok(())
+}
+
+// Whether the chunk lists can be left out when the fragment info is saved
+pub fn chunk_indices_derivable(key: &str, layout: u32, fragments: &[FragmentInfo]) -> bool {
+    derive_chunk_indices(key, layout, fragments)
+        .is_ok_and(|derived| fragments.iter().zip(&derived).all(|(f, chunks)| &f.chunk_indices == chunks))
+}
+
+// Chunks are only shuffled among neighbours in a fragment: runs of at most this many of its
+// chunks, holding at most this many bytes between them. Writing a fragment holds back chunks
+// that arrive before their turn, so this is all it ever has to keep in memory.
+pub const SHUFFLE_WINDOW_CHUNKS: usize = 16;
+pub const SHUFFLE_WINDOW_BYTES: u64 = 4 * 1024 * 1024;
+// Longest chunk cut_chunks makes, so that every window holds at least eight chunks to shuffle
+// however big the image is
+pub const MAX_CHUNK_SIZE: u64 = SHUFFLE_WINDOW_BYTES / 8;
+
+// Splits a fragment's ascending chunk list into the windows its chunks are shuffled within,
+// given the length of every chunk. A chunk bigger than SHUFFLE_WINDOW_BYTES, which only vaults
+// locked before chunks were capped at MAX_CHUNK_SIZE have, is a window on its own.
+pub fn shuffle_windows(chunks: &[usize], lengths: &[u64]) -> Vec<std::ops::Range<usize>> {
+    let mut windows = Vec::new();
+    let mut start = 0;
+    let mut bytes = 0;
+    for (position, &chunk) in chunks.iter().enumerate() {
+        let len = lengths.get(chunk).copied().unwrap_or(0);
+        if position > start && (position - start == SHUFFLE_WINDOW_CHUNKS || bytes + len > SHUFFLE_WINDOW_BYTES) {
+            windows.push(start..position);
+            start = position;
+            bytes = 0;
+        }
+        bytes += len;
+    }
+    if start < chunks.len() {
+        windows.push(start..chunks.len());
+    }
+    windows
+}
+
+// Puts a fragment's chunks in the order it stores them: ascending, with the chunks inside each
+// of its shuffle_windows shuffled by a generator seeded with HMAC-SHA256 of the fragment's
+// token under the key. Every fragment gets its own order, nothing about it is stored, and it
+// changes whenever the key or the chunk lengths do.
+pub fn shuffle_chunks(key: &str, lengths: &[u64], fragment: &mut FragmentInfo) {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(b"chunk order");
+    mac.update(fragment.key_symbols().as_bytes());
+    let mut rng = ChaCha20Rng::from_seed(mac.finalize().into_bytes().into());
+    fragment.chunk_indices.sort_unstable();
+    for window in shuffle_windows(&fragment.chunk_indices, lengths) {
+        fragment.chunk_indices[window].shuffle(&mut rng);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use crate::carrier::Carrier;
+    use crate::filesys;
+    use crate::naming::{self, NamingPolicy};
+
+    // A small tree of plausible directories with a few files in each. It goes under target/
+    // because the directory picker skips anything in the temp directory.
+    fn directory_tree() -> PathBuf {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("target").join(format!("sdfs_layout_test_{:016x}", OsRng.next_u64()));
+        for top in ["documents", "projects", "music", "media"] {
+            for sub in 0..8 {
+                let dir = root.join(top).join(format!("folder{}", sub));
+                fs::create_dir_all(&dir).unwrap();
+                fs::write(dir.join("holiday.jpg"), b"").unwrap();
+                fs::write(dir.join("notes.txt"), b"").unwrap();
+            }
+        }
+        root
+    }
+
+    fn layout(root: &Path, policy: NamingPolicy, seed: u64) -> (Vec<String>, String, Vec<FragmentInfo>) {
+        let mut rng = ChaCha20Rng::seed_from_u64(seed);
+        let dirs = filesys::get_random_directories(4, &root.to_string_lossy(), &mut rng);
+        let (key, fragments) = naming::generate_named_fragments(&dirs, 4, policy, Carrier::Raw, &mut rng).unwrap();
+        (dirs, key, fragments)
+    }
+
+    fn summary(fragments: &[FragmentInfo]) -> Vec<(String, String, String, Vec<usize>)> {
+        fragments
+            .iter()
+            .map(|f| (f.location.clone(), f.filename.clone(), f.token.clone(), f.chunk_indices.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_gives_same_layout() {
+        let root = directory_tree();
+        for policy in [NamingPolicy::Random, NamingPolicy::Dictionary, NamingPolicy::Siblings] {
+            let (dirs, key, fragments) = layout(&root, policy, 7);
+            let (again_dirs, again_key, again) = layout(&root, policy, 7);
+            assert_eq!(dirs, again_dirs);
+            assert_eq!(key, again_key);
+            assert_eq!(summary(&fragments), summary(&again));
+            assert_eq!(fragments.len(), dirs.len());
+            assert!(fragments.iter().all(|f| !f.chunk_indices.is_empty()));
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn different_seeds_give_different_layouts() {
+        let root = directory_tree();
+        let (_, key, fragments) = layout(&root, NamingPolicy::Dictionary, 7);
+        let (_, other_key, other) = layout(&root, NamingPolicy::Dictionary, 8);
+        assert_ne!(key, other_key);
+        assert_ne!(summary(&fragments), summary(&other));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn chunks_stay_within_their_windows() {
+        let mut fragment = FragmentInfo {
+            filename: String::new(),
+            token: "f".to_string(),
+            location: String::new(),
+            chunk_indices: (0..200).rev().collect(),
+        };
+        // Small chunks fill windows by count, big ones by bytes, and the biggest stand alone
+        let lengths: Vec<u64> = (0..200u64).map(|i| match i {
+            0..=99 => 1024,
+            100..=179 => 1024 * 1024,
+            _ => SHUFFLE_WINDOW_BYTES * 2,
+        }).collect();
+        let windows = shuffle_windows(&(0..200).collect::<Vec<_>>(), &lengths);
+        for window in &windows {
+            let bytes: u64 = window.clone().map(|i| lengths[i]).sum();
+            assert!(window.len() <= SHUFFLE_WINDOW_CHUNKS);
+            assert!(window.len() == 1 || bytes <= SHUFFLE_WINDOW_BYTES);
+        }
+        assert_eq!(windows.iter().map(|w| w.len()).sum::<usize>(), 200);
+
+        shuffle_chunks("ABCD", &lengths, &mut fragment);
+        assert_ne!(fragment.chunk_indices, (0..200).collect::<Vec<_>>());
+        for window in windows {
+            let mut inside = fragment.chunk_indices[window.clone()].to_vec();
+            inside.sort_unstable();
+            assert_eq!(inside, window.collect::<Vec<_>>());
+        }
+        assert_eq!(&fragment.chunk_indices[180..], &(180..200).collect::<Vec<_>>()[..]);
+    }
+
+}
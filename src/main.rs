@@ -1,12 +1,37 @@
+mod archive;
 mod auth;
+mod carrier;
+mod blockdev;
+mod config;
 mod filesys;
-mod json_manip;
 mod keysetup;
 mod crypto;
+mod decoy;
+mod fat;
+#[cfg(target_os = "linux")]
+mod fragdisk;
+mod fragreader;
+mod fragwriter;
+#[cfg(target_os = "linux")]
+mod fuse;
+mod merkle;
+#[cfg(target_os = "linux")]
+mod nbd;
+mod metadata;
+mod naming;
+mod padding;
+mod relocate;
+mod sparse;
+mod store;
+mod verify;
+mod vhd;
+mod volatile;
+mod volume;
 
 use std::fs;
 use std::path::Path;
 use std::env;
+use rand_chacha::rand_core::CryptoRngCore;
 
 const KEY: &[u8] = b"thisisatest";
 
@@ -14,17 +39,295 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let current_dir = env::current_dir().expect("Couldn't get current directory");
     let pass_file = "files/pass.json";
     let attempts_file = "files/attempts.json";
-    let fragment_info_file = "files/fragment_info.json";
-    let locker_pathbuf = current_dir.join(["files", "locker.vhd"].iter().collect::<std::path::PathBuf>());
-    let locker = locker_pathbuf.to_str().expect("Couldn't cast to string");
+    let config_file = "files/config.json";
+    let mut vault_config = config::load(config_file)?;
+    let mut locker_pathbuf = locker_path(&current_dir, &vault_config);
+    let search_root = filesys::default_search_root();
+    let fragment_info_enc = current_dir.join(["files", "fragment_info.json.enc"].iter().collect::<std::path::PathBuf>());
+    let fragment_info_enc_str = fragment_info_enc.to_str().unwrap();
     fs::create_dir_all("files").expect("Couldn't create files directory");
+
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("verify") {
+        let json_output = args.iter().any(|a| a == "--json");
+        let only_fragment = args.iter().position(|a| a == "--fragment").and_then(|i| args.get(i + 1)).map(String::as_str);
+        // Monitoring can hand the passphrase over through the environment instead of a prompt
+        let passphrase = match env::var("SDFS_PASSPHRASE") {
+            Ok(passphrase) => passphrase,
+            Err(_) => auth::prompt_password_stderr("Enter passphrase for fragment info decryption: "),
+        };
+        let vault_metadata = metadata::load(fragment_info_enc_str, &passphrase, KEY).map_err(|e| e.to_string());
+        let report = verify::verify_vault(pass_file, attempts_file, fragment_info_enc_str, vault_metadata.as_ref().map_err(Clone::clone), only_fragment);
+        if json_output {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            verify::print_report(&report);
+        }
+        if !report.ok {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if args.get(1).map(String::as_str) == Some("relocate") {
+        let passphrase = auth::prompt_password("Enter passphrase for fragment info decryption: ");
+        let mut vault_metadata = metadata::load(fragment_info_enc_str, &passphrase, KEY)?;
+        fragwriter::finish_commit(&mut vault_metadata, fragment_info_enc_str, &passphrase, KEY)?;
+        let mut selected = Vec::new();
+        for name in &args[2..] {
+            match vault_metadata.fragments.iter().position(|f| &f.filename == name) {
+                Some(index) => selected.push(index),
+                None => return Err(format!("No fragment named {}", name).into()),
+            }
+        }
+        println!("Relocating fragments...");
+        let mut rng = keysetup::layout_rng();
+        relocate::relocate_fragments(&mut vault_metadata, &selected, &search_root, &mut *rng, |updated| {
+            metadata::save(updated, fragment_info_enc_str, &passphrase, KEY)
+        })?;
+        // Moving everything moves the decoys too; they are simply written afresh elsewhere
+        if selected.is_empty() && !vault_metadata.decoys.is_empty() {
+            let old_decoys = decoy::plan(&mut vault_metadata, &search_root, true, &mut *rng)?;
+            decoy::write(&vault_metadata, &mut *rng)?;
+            metadata::save(&vault_metadata, fragment_info_enc_str, &passphrase, KEY)?;
+            decoy::delete(&old_decoys);
+            println!("  {} decoy fragments moved", vault_metadata.decoys.len());
+        }
+        println!("Relocation complete.");
+        return Ok(());
+    }
+
+    // Deletes everything for good: the unlocked locker if there is one, every fragment and
+    // decoy, and the vault's own files
+    if args.get(1).map(String::as_str) == Some("destroy") {
+        use std::io::{self, Write};
+        let passphrase = auth::prompt_password("Enter passphrase for fragment info decryption: ");
+        let vault_metadata = metadata::load(fragment_info_enc_str, &passphrase, KEY)?;
+        print!("This permanently deletes the locker, its {} fragments and {} decoys. Type DESTROY to continue: ", vault_metadata.fragments.len(), vault_metadata.decoys.len());
+        io::stdout().flush()?;
+        let mut confirm = String::new();
+        io::stdin().read_line(&mut confirm)?;
+        if confirm.trim() != "DESTROY" {
+            println!("Nothing was deleted.");
+            return Ok(());
+        }
+        let locker = locker_pathbuf.to_str().expect("Couldn't cast to string");
+        let backend = volume::backend_for(&vault_config)?;
+        if backend.is_attached(locker) {
+            backend.detach(locker)?;
+        }
+        if Path::new(locker).exists() {
+            filesys::secure_delete(Path::new(locker))?;
+        }
+        // Along with whatever an interrupted lock left under staged or retired names
+        fragwriter::discard_staged(&vault_metadata.fragments);
+        for (location, name) in vault_metadata.fragment_names().into_iter().chain(vault_metadata.retired_fragments.clone()) {
+            if let Err(e) = store::open_store(&location).and_then(|s| s.delete(&name)) {
+                eprintln!("Warning: failed to delete {}: {}", store::describe(&location, &name), e);
+            }
+        }
+        decoy::delete(&vault_metadata.decoys);
+        for file in [fragment_info_enc_str, pass_file, attempts_file, config_file] {
+            if Path::new(file).exists() {
+                filesys::secure_delete(Path::new(file))?;
+            }
+        }
+        println!("Vault destroyed.");
+        return Ok(());
+    }
+
+    // Image tools that don't touch the vault, e.g. to check a VHD on a machine without diskpart
+    if args.get(1).map(String::as_str) == Some("vhd") {
+        match (args.get(2).map(String::as_str), args.get(3)) {
+            (Some("create"), Some(path)) => {
+                let size_mb: u64 = args.get(4).ok_or("Usage: sdfs vhd create <file> <size in MB> [--dynamic]")?.parse()?;
+                let footer = if args.iter().any(|a| a == "--dynamic") {
+                    vhd::create_dynamic(Path::new(path), size_mb * 1024 * 1024)?
+                } else {
+                    vhd::create_fixed(Path::new(path), size_mb * 1024 * 1024)?
+                };
+                println!("Created {} ({} bytes, CHS {}/{}/{})", path, footer.current_size, footer.geometry.cylinders, footer.geometry.heads, footer.geometry.sectors);
+            }
+            (Some("check"), Some(path)) => match vhd::validate(Path::new(path))? {
+                Some(footer) => {
+                    let kind = if footer.disk_type == vhd::DISK_TYPE_DYNAMIC { "dynamic" } else { "fixed" };
+                    println!("{} is a valid {} VHD of {} bytes", path, kind, footer.current_size);
+                }
+                None => println!("{} has valid VHDX headers", path),
+            },
+            _ => return Err("Usage: sdfs vhd create <file> <size in MB> [--dynamic] | sdfs vhd check <file>".into()),
+        }
+        return Ok(());
+    }
+
+    // Offline access to the files inside an unlocked image that isn't attached, so scripts can
+    // work with the locker on any OS without mounting it
+    if let Some(command @ ("ls" | "put" | "get" | "rm")) = args.get(1).map(String::as_str) {
+        let locker = locker_pathbuf.to_str().expect("Couldn't cast to string");
+        if vault_config.backend == "directory" {
+            return Err(format!("The directory locker needs no mounting, use {} directly", vault_config.mount_point).into());
+        }
+        let writable = command == "put" || command == "rm";
+        let mut volume = if !Path::new(locker).exists() {
+            if writable {
+                return Err("The locker is locked. Run `sdfs --no-attach` to unlock it without attaching it".into());
+            }
+            // Listing and extracting work on a locked locker, straight from the fragments
+            match open_locked_volume(pass_file, attempts_file, fragment_info_enc_str)? {
+                Some(volume) => volume,
+                None => return Ok(()),
+            }
+        } else {
+            if volume::backend_for(&vault_config)?.is_attached(locker) {
+                return Err("The locker is attached. Lock it and unlock it with `sdfs --no-attach` to work on it offline".into());
+            }
+            fat::Fat32::open(blockdev::open_image(Path::new(locker), writable)?)?
+        };
+        let operands: Vec<&String> = args[2..].iter().filter(|a| !a.starts_with('-')).collect();
+        match command {
+            "ls" => {
+                let path = operands.first().map(|p| p.as_str()).unwrap_or("/");
+                let mut entries = volume.list(path)?;
+                entries.sort_by_key(|e| e.name.to_lowercase());
+                for entry in entries {
+                    let suffix = if entry.is_dir() { "/" } else { "" };
+                    println!("{:>12}  {}  {}{}", entry.size, entry.modified_string(), entry.name, suffix);
+                }
+                println!("{} bytes free", volume.free_space());
+            }
+            "put" => {
+                let source = operands.first().ok_or("Usage: sdfs put <local file> [path in locker]")?;
+                let file_name = Path::new(source.as_str()).file_name().ok_or("Not a file")?.to_string_lossy().into_owned();
+                let target = operands.get(1).map(|t| t.to_string()).unwrap_or(file_name.clone());
+                // A target ending in a slash, or naming a directory, receives the file under its own name
+                let target = if target.ends_with('/') || volume.is_dir(&target) {
+                    format!("{}/{}", target.trim_end_matches('/'), file_name)
+                } else {
+                    target
+                };
+                let mut file = fs::File::open(source.as_str())?;
+                let file_metadata = file.metadata()?;
+                volume.write_file(&target, &mut file, file_metadata.len(), file_metadata.modified()?)?;
+                volume.flush()?;
+                println!("Added {}", target);
+            }
+            "get" => {
+                let source = operands.first().ok_or("Usage: sdfs get <path in locker> [local file]")?;
+                let file_name = source.rsplit(['/', '\\']).next().unwrap_or(source).to_string();
+                let target = operands.get(1).map(|t| t.to_string()).unwrap_or(file_name);
+                let mut file = fs::File::create(&target)?;
+                let entry = match volume.read_file(source, &mut file) {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        drop(file);
+                        let _ = fs::remove_file(&target);
+                        return Err(e);
+                    }
+                };
+                file.set_modified(entry.modified())?;
+                println!("Extracted {} to {}", source, target);
+            }
+            _ => {
+                let path = operands.first().ok_or("Usage: sdfs rm [-r] <path in locker>")?;
+                let recursive = args.iter().any(|a| a == "-r");
+                volume.remove(path, recursive)?;
+                volume.flush()?;
+                println!("Removed {}", path);
+            }
+        }
+        return Ok(());
+    }
+
+    // Browses a locked FAT32 locker through FUSE. Reads are served from the fragments as they
+    // come in, so no plaintext image is ever written to disk.
+    #[cfg(target_os = "linux")]
+    if args.get(1).map(String::as_str) == Some("mount") {
+        let locker = locker_pathbuf.to_str().expect("Couldn't cast to string");
+        if vault_config.backend == "directory" {
+            return Err("The directory locker has no disk image to mount".into());
+        }
+        if Path::new(locker).exists() {
+            return Err("The locker is unlocked. Use it directly, or run `sdfs lock` first to mount it from its fragments".into());
+        }
+        let mountpoint = match args.get(2) {
+            Some(dir) => std::path::PathBuf::from(dir),
+            None => current_dir.join(["files", "mnt"].iter().collect::<std::path::PathBuf>()),
+        };
+        fs::create_dir_all(&mountpoint)?;
+        if let Some(volume) = open_locked_volume(pass_file, attempts_file, fragment_info_enc_str)? {
+            fuse::mount_and_serve(volume, &mountpoint)?;
+        }
+        return Ok(());
+    }
+    #[cfg(not(target_os = "linux"))]
+    if args.get(1).map(String::as_str) == Some("mount") {
+        return Err("`sdfs mount` uses FUSE and is only available on Linux".into());
+    }
+
+    // Exports the locked locker as a network block device. Reads, and with --write writes, go to
+    // the fragments chunk by chunk, so the plaintext image never exists as a file.
+    #[cfg(target_os = "linux")]
+    if args.get(1).map(String::as_str) == Some("nbd-serve") {
+        let locker = locker_pathbuf.to_str().expect("Couldn't cast to string");
+        if vault_config.backend == "directory" {
+            return Err("The directory locker has no disk image to serve".into());
+        }
+        if Path::new(locker).exists() {
+            return Err("The locker is unlocked. Use it directly, or run `sdfs lock` first to serve it from its fragments".into());
+        }
+        let option = |name: &str| args.iter().position(|a| a == name).and_then(|i| args.get(i + 1));
+        let address = match (option("--socket"), option("--port")) {
+            (Some(path), _) => nbd::Address::Unix(std::path::PathBuf::from(path)),
+            (None, Some(port)) => nbd::Address::Tcp(port.parse().map_err(|_| format!("Invalid port: {}", port))?),
+            (None, None) => nbd::Address::Tcp(nbd::DEFAULT_PORT),
+        };
+        // Writes re-encrypt bytes in place under the keystream of the last lock, which gives away
+        // what changed to anyone holding copies from before and after, so they have to be asked for
+        let read_only = !args.iter().any(|a| a == "--write");
+        if read_only && args.iter().any(|a| a == "--allocate") {
+            return Err("--allocate is only needed with --write".into());
+        }
+        let password = match auth::login_and_get_password(pass_file, attempts_file) {
+            Some(pwd) => pwd,
+            None => return Ok(()),
+        };
+        let mut vault_metadata = metadata::load(fragment_info_enc_str, &password, KEY)?;
+        if !read_only {
+            fragwriter::finish_commit(&mut vault_metadata, fragment_info_enc_str, &password, KEY)?;
+        }
+        let vhd_password = auth::get_password_from_user();
+        let mut disk = if read_only {
+            fragreader::disk(fragreader::FragmentReader::decrypted(&vault_metadata, &vhd_password, KEY)?)?
+        } else {
+            if args.iter().any(|a| a == "--allocate") {
+                println!("Storing the empty parts of the locker in the fragments...");
+                fragdisk::allocate(&mut vault_metadata, &vhd_password, KEY)?;
+                fragwriter::commit(&mut vault_metadata, fragment_info_enc_str, &password, KEY)?;
+            }
+            let image = fragdisk::FragmentDisk::open(vault_metadata, &vhd_password, KEY, fragment_info_enc_str, &password)?;
+            println!(
+                "Warning: writes are encrypted with the same keystream as the bytes they replace. Anyone who keeps copies of a fragment from before and after can tell what changed. Lock the locker again to encrypt it under a new nonce."
+            );
+            if image.unallocated() > 0 {
+                println!(
+                    "Note: {} MB of the locker were empty when it was locked and can't be written over NBD. Run with --write --allocate to make room for them.",
+                    image.unallocated() / (1024 * 1024)
+                );
+            }
+            fragreader::disk(image)?
+        };
+        nbd::serve(&mut disk, &address, read_only)?;
+        return Ok(());
+    }
+    #[cfg(not(target_os = "linux"))]
+    if args.get(1).map(String::as_str) == Some("nbd-serve") {
+        return Err("`sdfs nbd-serve` is only available on Linux".into());
+    }
+
     if !Path::new(pass_file).exists() {
         use std::io::{self, Write};
         auth::setup_password(pass_file);
-        auth::write_attempts(attempts_file, &auth::LoginAttempts {
-            attempts: 0,
-            mac: auth::compute_mac(0, KEY)
-        });
+        auth::reset_attempts(attempts_file);
         print!("Enter number of VHD fragments: ");
         io::stdout().flush()?;
         let mut frag_input = String::new();
@@ -35,91 +338,285 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         let mut chunk_input = String::new();
         io::stdin().read_line(&mut chunk_input)?;
         let max_chunks: usize = chunk_input.trim().parse().expect("Invalid number");
-        let random_dirs = filesys::get_random_directories(fragment_count, "C:\\");
+        keysetup::check_parameters(fragment_count, max_chunks)?;
+        if keysetup::symbol_width(fragment_count) > 1 {
+            println!("With more than 62 fragments, each chunk is named by {} characters in the key and filenames.", keysetup::symbol_width(fragment_count));
+        }
+        print!("Enter fragment store URIs to use, comma-separated (leave blank for local directories only): ");
+        io::stdout().flush()?;
+        let mut store_input = String::new();
+        io::stdin().read_line(&mut store_input)?;
+        let mut random_dirs: Vec<String> = store_input
+            .split(',')
+            .map(|uri| uri.trim().to_string())
+            .filter(|uri| !uri.is_empty())
+            .take(fragment_count)
+            .collect();
+        for uri in &random_dirs {
+            store::open_store(uri)?;
+        }
+        let mut rng = keysetup::layout_rng();
+        random_dirs.extend(filesys::get_random_directories(fragment_count - random_dirs.len(), &search_root, &mut *rng));
+        if random_dirs.len() < fragment_count {
+            return Err(format!("Only found {} suitable directories under {} for {} fragments", random_dirs.len(), search_root, fragment_count).into());
+        }
         
-        println!("\nFragment directories selected:");
+        println!("\nFragment locations selected:");
         for (i, dir) in random_dirs.iter().enumerate() {
             println!("  Fragment {}: {}", i, dir);
         }
         println!();
         
-        let (key, fragments) = keysetup::generate_key_and_fragments(random_dirs.clone(), max_chunks);
+        print!("Fragment names: 1) random characters with a .bin extension  2) common filenames like IMG_4821.jpg  3) variations on the files already in each directory (default 1): ");
+        io::stdout().flush()?;
+        let mut naming_input = String::new();
+        io::stdin().read_line(&mut naming_input)?;
+        let naming_policy = naming::NamingPolicy::parse(&naming_input)?;
+        print!("Fragment files: 1) raw encrypted data  2) wrapped in a PNG image  3) wrapped in a ZIP archive (default 1): ");
+        io::stdout().flush()?;
+        let mut carrier_input = String::new();
+        io::stdin().read_line(&mut carrier_input)?;
+        let carrier = carrier::Carrier::parse(&carrier_input)?;
+        let (key, fragments) = naming::generate_named_fragments(&random_dirs, max_chunks, naming_policy, carrier, &mut *rng)?;
         
         println!("Generated filenames:");
         for (i, fragment) in fragments.iter().enumerate() {
-            println!("  File {}: {} in {} (chunks: {:?})", i, fragment.filename, fragment.directory, fragment.chunk_indices);
+            println!("  File {}: {} in {} (token: {}, chunks: {:?})", i, fragment.filename, fragment.location, fragment.token, fragment.chunk_indices);
         }
         println!("Assembly key: {}", key);
         println!();
         
-        let fragment_info = serde_json::json!({
-            "fragment_count": fragment_count,
-            "max_chunks": max_chunks,
-            "dirs": random_dirs,
-            "key": key,
-            "fragments": fragments
-        });
-        fs::write(fragment_info_file, serde_json::to_string_pretty(&fragment_info)?).expect("Couldn't write fragment info");
+        print!("Move fragments to new directories on every lock? (y/n): ");
+        io::stdout().flush()?;
+        let mut relocate_input = String::new();
+        io::stdin().read_line(&mut relocate_input)?;
+        print!("Generate a new assembly key and filenames on every lock? (y/n): ");
+        io::stdout().flush()?;
+        let mut rotate_input = String::new();
+        io::stdin().read_line(&mut rotate_input)?;
+        print!("Target chunk size between 64K and 512K, e.g. 256K (leave blank for 512K): ");
+        io::stdout().flush()?;
+        let mut chunk_size_input = String::new();
+        io::stdin().read_line(&mut chunk_size_input)?;
+        let chunk_size = match chunk_size_input.trim() {
+            "" => 0,
+            size => filesys::parse_chunk_size(size)?,
+        };
+        print!("Cut the image into chunks of random lengths? Enter the shortest and longest as a percentage of the average, e.g. 50-150 (leave blank for equal chunks): ");
+        io::stdout().flush()?;
+        let mut sizing_input = String::new();
+        io::stdin().read_line(&mut sizing_input)?;
+        let chunk_sizing = match sizing_input.trim() {
+            "" => None,
+            bounds => Some(filesys::ChunkSizing::parse(bounds, &mut *rng)?),
+        };
+        print!("Pad the fragments so their sizes don't show how many chunks they hold? Enter 0 to pad them all to the same size, or a percentage to pad each to a random size up to that much above the largest, e.g. 20% (leave blank for no padding): ");
+        io::stdout().flush()?;
+        let mut padding_input = String::new();
+        io::stdin().read_line(&mut padding_input)?;
+        let padding = match padding_input.trim() {
+            "" => None,
+            spread => Some(padding::Padding::parse(spread, &mut *rng)?),
+        };
+        if let Some(padding) = &padding {
+            let chunk_counts: Vec<usize> = fragments.iter().map(|f| f.chunk_indices.len()).collect();
+            println!("Padding will make the fragments about {}% bigger than the locker", padding.overhead_percent(&chunk_counts));
+        }
+        print!("Number of decoy fragments of random bytes to hide alongside the real ones (default 0): ");
+        io::stdout().flush()?;
+        let mut decoy_input = String::new();
+        io::stdin().read_line(&mut decoy_input)?;
+        let decoy_count: usize = match decoy_input.trim() {
+            "" => 0,
+            count => count.parse().map_err(|_| format!("Not a number of decoys: {}", count))?,
+        };
+        
+        let mut vault_metadata = metadata::VaultMetadata::new(max_chunks, random_dirs, key, fragments);
+        vault_metadata.relocate_on_lock = relocate_input.trim().eq_ignore_ascii_case("y");
+        vault_metadata.rotate_on_lock = rotate_input.trim().eq_ignore_ascii_case("y");
+        vault_metadata.chunk_size = chunk_size;
+        vault_metadata.decoy_count = decoy_count;
+        vault_metadata.chunk_sizing = chunk_sizing;
+        vault_metadata.naming = naming_policy;
+        vault_metadata.carrier = carrier;
+        vault_metadata.padding = padding;
         let passphrase = auth::prompt_password("Enter passphrase for fragment info encryption: ");
-        let fragment_info_enc = current_dir.join(["files", "fragment_info.json.enc"].iter().collect::<std::path::PathBuf>());
-        let fragment_info_enc_str = fragment_info_enc.to_str().unwrap();
-        crypto::encrypt_json(fragment_info_file, fragment_info_enc_str, &passphrase, KEY)?;
-        let _ = fs::remove_file(fragment_info_file);
-        filesys::attach_drive(locker)?;
+        metadata::save(&vault_metadata, fragment_info_enc_str, &passphrase, KEY)?;
+        
+        print!("Locker type: 1) disk image (needs admin/root)  2) plain directory (default 1): ");
+        io::stdout().flush()?;
+        let mut backend_input = String::new();
+        io::stdin().read_line(&mut backend_input)?;
+        if backend_input.trim() == "2" {
+            vault_config.backend = "directory".to_string();
+        }
+        
+        if vault_config.backend == "directory" {
+            vault_config.mount_point = current_dir.join(["files", "locker"].iter().collect::<std::path::PathBuf>()).to_string_lossy().into_owned();
+        } else if vault_config.backend == "vhd" {
+            print!("Disk type: fixed, dynamic or vhdx (default dynamic): ");
+            io::stdout().flush()?;
+            let mut format_input = String::new();
+            io::stdin().read_line(&mut format_input)?;
+            vault_config.disk_format = match format_input.trim().to_lowercase().as_str() {
+                "" | "dynamic" => "dynamic".to_string(),
+                "fixed" => "fixed".to_string(),
+                "vhdx" => "vhdx".to_string(),
+                other => return Err(format!("Unknown disk type {}", other).into()),
+            };
+            print!("Enter drive letter: ");
+            io::stdout().flush()?;
+            let mut letter = String::new();
+            io::stdin().read_line(&mut letter)?;
+            vault_config.mount_point = letter.trim().to_string();
+        } else {
+            vault_config.mount_point = current_dir.join(["files", "mnt"].iter().collect::<std::path::PathBuf>()).to_string_lossy().into_owned();
+        }
+        if vault_config.backend != "directory" {
+            print!("Enter filesystem for the locker (default {}, fat32 uses the built-in formatter): ", vault_config.filesystem);
+            io::stdout().flush()?;
+            let mut filesystem = String::new();
+            io::stdin().read_line(&mut filesystem)?;
+            if !filesystem.trim().is_empty() {
+                vault_config.filesystem = filesystem.trim().to_lowercase();
+            }
+        }
+        print!("Keep the unlocked locker in memory only, so it never touches the disk? (y/N): ");
+        io::stdout().flush()?;
+        let mut ram_input = String::new();
+        io::stdin().read_line(&mut ram_input)?;
+        if ram_input.trim().eq_ignore_ascii_case("y") {
+            let ram_dir = volatile::default_dir(&current_dir)?;
+            volatile::prepare(&ram_dir)?;
+            vault_config.ram_only = true;
+            vault_config.ram_dir = ram_dir.to_string_lossy().into_owned();
+            if vault_config.backend == "directory" {
+                vault_config.mount_point = ram_dir.join("locker").to_string_lossy().into_owned();
+            }
+            println!("The unlocked locker will be kept in {}", ram_dir.display());
+        }
+        config::save(config_file, &vault_config)?;
+        locker_pathbuf = locker_path(&current_dir, &vault_config);
+        let locker = locker_pathbuf.to_str().expect("Couldn't cast to string");
+        let backend = volume::backend_for(&vault_config)?;
+        backend.create(locker)?;
+        backend.attach(locker)?;
         return Ok(());
     }
     
-    if Path::new(locker).exists() && filesys::is_vhd_attached(locker) {
-        filesys::detach_drive(locker)?;
-        let fragment_info_enc = current_dir.join(["files", "fragment_info.json.enc"].iter().collect::<std::path::PathBuf>());
-        let fragment_info_enc_str = fragment_info_enc.to_str().unwrap();
-        let fragment_info_dec = current_dir.join(["files", "fragment_info.json"].iter().collect::<std::path::PathBuf>());
-        let fragment_info_dec_str = fragment_info_dec.to_str().unwrap();
+    let locker = locker_pathbuf.to_str().expect("Couldn't cast to string");
+    let backend = volume::backend_for(&vault_config)?;
+    
+    // The directory backend has no image while attached, so only ask the backend here
+    // `sdfs lock` also locks an image that was unlocked with --no-attach
+    let lock_offline = args.get(1).map(String::as_str) == Some("lock") && Path::new(locker).exists();
+    let no_attach = args.iter().any(|a| a == "--no-attach");
+    if backend.is_attached(locker) || lock_offline {
+        // Both passwords are checked while the locker is still attached, so a typo doesn't
+        // leave it detached and unlocked
         let passphrase = auth::prompt_password("Enter passphrase for fragment info decryption: ");
-        crypto::decrypt_json(fragment_info_enc_str, fragment_info_dec_str, &passphrase, KEY)?;
-        let fragment_info: serde_json::Value = serde_json::from_str(&fs::read_to_string(fragment_info_dec_str)?)?;
-        let _ = fs::remove_file(fragment_info_dec_str);
-        let key = fragment_info["key"].as_str().unwrap();
-        let fragments: Vec<keysetup::FragmentInfo> = serde_json::from_value(fragment_info["fragments"].clone())?;
-        
-        let total_chunks = key.len();
-        
-        let enc_vhd = current_dir.join(["files", "locker_encrypted.vhd"].iter().collect::<std::path::PathBuf>());
-        let enc_vhd_str = enc_vhd.to_str().unwrap();
+        let mut vault_metadata = metadata::load(fragment_info_enc_str, &passphrase, KEY)?;
         let password = auth::get_password_from_user();
-        crypto::encrypt_file(locker, enc_vhd_str, &password, KEY).expect("Encryption failed");
-        let _ = fs::remove_file(locker);
-        filesys::split_binary_with_key(enc_vhd_str, &fragments, total_chunks).expect("Failed to split binary");
-        let _ = fs::remove_file(enc_vhd_str);
+        // The first lock sets the image password; after that it has to match
+        if let Some(check) = &vault_metadata.key_check {
+            if crypto::Keystream::new(&password, KEY, &vault_metadata.image_nonce()?).check_value() != *check {
+                return Err("Wrong image password, the locker is still unlocked".into());
+            }
+        }
+        if backend.is_attached(locker) {
+            backend.detach(locker)?;
+        }
+        // An earlier lock that was cut short is finished before this one changes anything
+        fragwriter::finish_commit(&mut vault_metadata, fragment_info_enc_str, &passphrase, KEY)?;
+        
+        // Only blocks holding data are encrypted and fragmented, the zero blocks go in the metadata
+        let sparse_map = sparse::scan(Path::new(locker))?;
+        let packed_size = sparse_map.image_size - sparse_map.hole_bytes();
+        println!("Packed {} of {} bytes ({} bytes of empty blocks skipped)", packed_size, sparse_map.image_size, sparse_map.hole_bytes());
+        vault_metadata.image_size = packed_size + crypto::GCM_TAG_SIZE;
+        let mut rng = keysetup::layout_rng();
+        let old_fragment_names = plan_lock_layout(&mut vault_metadata, &search_root, &mut *rng)?;
+        let old_decoys = decoy::plan(&mut vault_metadata, &search_root, !old_fragment_names.is_empty(), &mut *rng)?;
+        let generation = vault_metadata.merkle.generation + 1;
+        vault_metadata.cut_chunks(generation);
+        if vault_metadata.padding.is_some() {
+            println!("Padding the fragments with {} bytes", vault_metadata.fragment_padding.iter().sum::<u64>());
+        }
+        // The image is encrypted and cut into fragments in one pass, nothing is staged on disk.
+        // The fragments are written under staged names and the old ones stay untouched until
+        // the metadata for the new set has been saved.
+        // A fresh nonce on every lock, so no two versions of the image share a keystream
+        let nonce = crypto::random_nonce();
+        vault_metadata.image_nonce = Some(hex::encode(nonce));
+        let fragments = fragwriter::FragmentWriter::for_vault(&vault_metadata, generation)?;
+        let mut encryptor = crypto::GcmWriter::new(fragments, &password, KEY, &nonce);
+        let written = sparse::write_packed(Path::new(locker), &sparse_map, &mut encryptor)
+            .and_then(|_| encryptor.finish())
+            .and_then(fragwriter::FragmentWriter::finish);
+        match written {
+            Ok(manifest) => vault_metadata.merkle = manifest,
+            Err(e) => {
+                fragwriter::discard_staged(&vault_metadata.fragments);
+                return Err(format!("Failed to write the fragments, the locker is still unlocked: {}", e).into());
+            }
+        }
+        vault_metadata.sparse = Some(sparse_map);
+        vault_metadata.key_check = Some(crypto::Keystream::new(&password, KEY, &nonce).check_value());
+        if !old_fragment_names.is_empty() {
+            // A new layout is read back before anything depends on it
+            let mut staged = vault_metadata.clone();
+            staged.fragments.iter_mut().for_each(|f| f.filename = fragwriter::staged_name(&f.filename));
+            if let Err(e) = verify::check_layout(&staged) {
+                fragwriter::discard_staged(&vault_metadata.fragments);
+                return Err(format!("New fragment layout failed verification, the locker is still unlocked: {}", e).into());
+            }
+        }
+        if let Err(e) = decoy::write(&vault_metadata, &mut *rng) {
+            eprintln!("Warning: failed to write the decoy fragments: {}", e);
+        }
+        let new_fragment_names = vault_metadata.fragment_names();
+        vault_metadata.retired_fragments.extend(old_fragment_names.into_iter().filter(|old| !new_fragment_names.contains(old)));
+        fragwriter::commit(&mut vault_metadata, fragment_info_enc_str, &passphrase, KEY)
+            .map_err(|e| format!("Failed to save the new fragments, the locker is still unlocked: {}", e))?;
+        if vault_config.ram_only {
+            let runs = vault_metadata.sparse.as_ref().map(sparse::SparseMap::data_runs).unwrap_or_default();
+            if let Err(e) = volatile::wipe(Path::new(locker), &runs) {
+                eprintln!("Warning: failed to wipe {}: {}", locker, e);
+                let _ = fs::remove_file(locker);
+            }
+        } else {
+            let _ = fs::remove_file(locker);
+        }
+        decoy::delete(&old_decoys);
         return Ok(());
     }
     
-    if Path::new(locker).exists() && !filesys::is_vhd_attached(locker) {
-        println!("VHD file found but not attached. Attaching drive...");
-        filesys::attach_drive(locker)?;
+    if Path::new(locker).exists() && !backend.is_attached(locker) {
+        if no_attach {
+            println!("Locker image is unlocked but not attached. Run `sdfs lock` to lock it.");
+            return Ok(());
+        }
+        println!("Locker image found but not attached. Attaching drive...");
+        backend.attach(locker)?;
         return Ok(());
     }
     
     if !Path::new(locker).exists() {
+        let target = unlock_target(&current_dir, &vault_config, Path::new(locker), args.iter().any(|a| a == "--allow-disk"))?;
+        let locker = target.to_str().expect("Couldn't cast to string");
         let password = match auth::login_and_get_password(pass_file, attempts_file) {
             Some(pwd) => pwd,
             None => return Ok(()),
         };
         
-        let fragment_info_enc = current_dir.join(["files", "fragment_info.json.enc"].iter().collect::<std::path::PathBuf>());
-        let fragment_info_enc_str = fragment_info_enc.to_str().unwrap();
-        let fragment_info_dec = current_dir.join(["files", "fragment_info.json"].iter().collect::<std::path::PathBuf>());
-        let fragment_info_dec_str = fragment_info_dec.to_str().unwrap();
-        crypto::decrypt_json(fragment_info_enc_str, fragment_info_dec_str, &password, KEY)?;
-        let fragment_info: serde_json::Value = serde_json::from_str(&fs::read_to_string(fragment_info_dec_str)?)?;
-        let _ = fs::remove_file(fragment_info_dec_str);
-        let key = fragment_info["key"].as_str().unwrap();
-        let fragments: Vec<keysetup::FragmentInfo> = serde_json::from_value(fragment_info["fragments"].clone())?;
+        let mut vault_metadata = metadata::load(fragment_info_enc_str, &password, KEY)?;
+        fragwriter::finish_commit(&mut vault_metadata, fragment_info_enc_str, &password, KEY)?;
+        let key = vault_metadata.key.as_str();
+        let fragments = &vault_metadata.fragments;
         
         let mut missing = false;
-        for fragment in &fragments {
-            let fpath = std::path::Path::new(&fragment.directory).join(&fragment.filename);
-            if !fpath.exists() {
+        for fragment in fragments {
+            if !store::open_store(&fragment.location)?.exists(&fragment.filename)? {
                 missing = true;
                 break;
             }
@@ -131,21 +628,135 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         
         println!("Reassembling VHD from fragments...");
         println!("Fragment files:");
+        let key_chunks = vault_metadata.key_chunks();
         for (i, fragment) in fragments.iter().enumerate() {
-            println!("  File {}: {} in {} (chunks: {:?})", i, fragment.filename, fragment.directory, fragment.chunk_indices);
+            let once: Vec<usize> = fragment.chunk_indices.iter().copied().filter(|&c| c < key_chunks).collect();
+            println!("  File {}: {} in {} (chunks: {:?})", i, fragment.filename, fragment.location, once);
         }
         println!("Assembly key: {}", key);
+        if vault_metadata.key_rounds > 1 {
+            println!("Key repeated {} times for {} chunks of about {} KiB", vault_metadata.key_rounds, vault_metadata.total_chunks(), vault_metadata.image_size / vault_metadata.total_chunks() as u64 / 1024);
+        }
         
-        let enc_vhd = current_dir.join(["files", "locker_encrypted.vhd"].iter().collect::<std::path::PathBuf>());
-        let enc_vhd_str = enc_vhd.to_str().unwrap();
-        filesys::assemble_binary_with_key(&fragments, key, enc_vhd_str).expect("Failed to assemble binary");
         let vhd_password = auth::get_password_from_user();
-        crypto::decrypt_file(enc_vhd_str, locker, &vhd_password, KEY).expect("Decryption failed");
-        let _ = fs::remove_file(enc_vhd_str);
-        filesys::attach_drive(locker)?;
+        let encrypted_temp = Path::new(locker).with_file_name("locker_encrypted.vhd");
+        let (encrypted, encrypted_len): (Box<dyn std::io::Read>, u64) = if vault_metadata.image_size > 0 {
+            // Streams the image chunk by chunk instead of holding every fragment in memory
+            (Box::new(fragreader::FragmentReader::encrypted(&vault_metadata)?), vault_metadata.image_size)
+        } else {
+            // Locked before the image size was recorded; the chunk sizes come from the fragments
+            let manifest = if vault_metadata.merkle.is_empty() { None } else { Some(&vault_metadata.merkle) };
+            let encrypted_temp_str = encrypted_temp.to_str().expect("Couldn't cast to string");
+            filesys::assemble_binary_with_key(fragments, key, encrypted_temp_str, manifest).expect("Failed to assemble binary");
+            (Box::new(std::io::BufReader::new(fs::File::open(&encrypted_temp)?)), fs::metadata(&encrypted_temp)?.len())
+        };
+        // Decrypted straight into the locker image; the tag is checked once the last byte is in
+        let mut plaintext = crypto::GcmReader::new(encrypted, encrypted_len, &vhd_password, KEY, &vault_metadata.image_nonce()?)?;
+        let decrypted = match &vault_metadata.sparse {
+            Some(sparse_map) => sparse::unpack(&mut plaintext, sparse_map, Path::new(locker)),
+            // Locked before sparse packing existed, the whole image was encrypted
+            None => fs::File::create(locker).and_then(|file| {
+                let mut writer = std::io::BufWriter::new(file);
+                std::io::copy(&mut plaintext, &mut writer)?;
+                std::io::Write::flush(&mut writer)
+            }),
+        };
+        let _ = fs::remove_file(&encrypted_temp);
+        if let Err(e) = decrypted {
+            let _ = fs::remove_file(locker);
+            return Err(format!("Failed to decrypt the locker: {}", e).into());
+        }
+        if no_attach {
+            println!("Locker unlocked without attaching it. Use `sdfs ls`, `put`, `get` and `rm` to work with its files, then `sdfs lock`.");
+            return Ok(());
+        }
+        backend.attach(locker)?;
         return Ok(());
     }
     
     println!("Error: Unexpected system state. Please check your files.");
     Ok(())
 }
+
+// Where the unlocked image lives: in memory with ram_only, unless an --allow-disk unlock left it
+// under files/
+fn locker_path(current_dir: &Path, vault_config: &config::VaultConfig) -> std::path::PathBuf {
+    let on_disk = current_dir.join("files").join(vault_config.image_name());
+    if vault_config.ram_only && !vault_config.ram_dir.is_empty() && !on_disk.exists() {
+        return Path::new(&vault_config.ram_dir).join(vault_config.image_name());
+    }
+    on_disk
+}
+
+// Where to unlock to. With ram_only that has to be in memory; if it isn't (the tmpfs is gone, or
+// this isn't Linux) the image only goes under files/ when --allow-disk is given.
+fn unlock_target(current_dir: &Path, vault_config: &config::VaultConfig, locker: &Path, allow_disk: bool) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+    if !vault_config.ram_only {
+        return Ok(locker.to_path_buf());
+    }
+    let ram_dir = locker.parent().ok_or("The locker image has no directory")?;
+    let reason = match volatile::is_volatile(ram_dir) {
+        Ok(true) => {
+            volatile::prepare(ram_dir)?;
+            return Ok(locker.to_path_buf());
+        }
+        Ok(false) => format!("{} is not in memory, so the unlocked locker would end up on persistent storage", ram_dir.display()),
+        Err(e) => e,
+    };
+    if !allow_disk {
+        return Err(format!("{}. Run with --allow-disk to unlock it to files/ anyway", reason).into());
+    }
+    println!("Warning: {}, unlocking to files/ instead", reason);
+    Ok(current_dir.join("files").join(vault_config.image_name()))
+}
+
+// Logs in and opens the FAT32 filesystem of a locked locker read-only from its fragments.
+// None if the login was refused.
+fn open_locked_volume(pass_file: &str, attempts_file: &str, fragment_info_enc: &str) -> Result<Option<fat::Fat32>, Box<dyn std::error::Error>> {
+    let password = match auth::login_and_get_password(pass_file, attempts_file) {
+        Some(pwd) => pwd,
+        None => return Ok(None),
+    };
+    let vault_metadata = metadata::load(fragment_info_enc, &password, KEY)?;
+    let vhd_password = auth::get_password_from_user();
+    let image = fragreader::FragmentReader::decrypted(&vault_metadata, &vhd_password, KEY)?;
+    // A wrong image password decrypts to noise, which shows up here as a missing filesystem
+    let volume = fat::Fat32::open(fragreader::disk(image)?)
+        .map_err(|e| format!("{}. Check the image password; only FAT32 lockers can be read from their fragments", e))?;
+    Ok(Some(volume))
+}
+
+// Applies the relocate/rotate settings before the locker is fragmented. Returns the
+// (location, filename) of the previous fragments, which the caller retires once the new set
+// has been verified, or nothing if the layout did not change.
+fn plan_lock_layout(vault_metadata: &mut metadata::VaultMetadata, search_root: &str, rng: &mut dyn CryptoRngCore) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    if !vault_metadata.relocate_on_lock && !vault_metadata.rotate_on_lock {
+        return Ok(Vec::new());
+    }
+    let old_names = vault_metadata.fragment_names();
+    
+    let locations = if vault_metadata.relocate_on_lock {
+        let all: Vec<usize> = (0..vault_metadata.fragments.len()).collect();
+        relocate::pick_new_locations(vault_metadata, &all, search_root, rng)?
+    } else {
+        vault_metadata.fragments.iter().map(|f| f.location.clone()).collect()
+    };
+    
+    if vault_metadata.rotate_on_lock {
+        let (key, fragments) = naming::generate_named_fragments(&locations, vault_metadata.max_chunks, vault_metadata.naming, vault_metadata.carrier, rng)?;
+        vault_metadata.key = key;
+        vault_metadata.key_layout = keysetup::KEY_LAYOUT;
+        vault_metadata.fragments = fragments;
+    } else {
+        let mut namer = naming::Namer::new(vault_metadata.naming, vault_metadata.carrier);
+        for (fragment, location) in vault_metadata.fragments.iter_mut().zip(&locations) {
+            if fragment.location != *location {
+                fragment.filename = namer.name_moved(fragment, location, rng)?;
+                fragment.location = location.clone();
+            }
+        }
+    }
+    vault_metadata.fragment_count = vault_metadata.fragments.len();
+    vault_metadata.dirs = locations;
+    Ok(old_names)
+}
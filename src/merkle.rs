@@ -0,0 +1,180 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Merkle tree over the chunk ciphertexts of the encrypted image.
+//
+// Leaves are bound to the chunk index and the lock generation, so a chunk cannot be moved to
+// another position and a fragment left over from an earlier lock cannot be swapped back in.
+// Leaf and node hashes use different prefixes so a node can never be passed off as a leaf.
+// A node without a sibling is promoted to the next level unchanged.
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+pub type Hash = [u8; 32];
+
+// Stored in the encrypted vault metadata
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MerkleManifest {
+    pub generation: u64,
+    pub root: String,
+    pub leaves: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum MerkleError {
+    RootMismatch,
+    Malformed(String),
+}
+
+impl std::fmt::Display for MerkleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MerkleError::RootMismatch => write!(f, "Merkle leaves do not match the stored root"),
+            MerkleError::Malformed(msg) => write!(f, "malformed Merkle manifest: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for MerkleError {}
+
+pub struct LeafHasher {
+    hasher: Sha256,
+}
+
+impl LeafHasher {
+    pub fn new(generation: u64, index: usize) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update([LEAF_PREFIX]);
+        hasher.update(generation.to_le_bytes());
+        hasher.update((index as u64).to_le_bytes());
+        Self { hasher }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    pub fn finalize(self) -> Hash {
+        self.hasher.finalize().into()
+    }
+}
+
+pub fn leaf_hash(generation: u64, index: usize, data: &[u8]) -> Hash {
+    let mut hasher = LeafHasher::new(generation, index);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+fn next_level(level: &[Hash]) -> Vec<Hash> {
+    level
+        .chunks(2)
+        .map(|pair| if pair.len() == 2 { node_hash(&pair[0], &pair[1]) } else { pair[0] })
+        .collect()
+}
+
+pub fn root(leaves: &[Hash]) -> Hash {
+    if leaves.is_empty() {
+        return Sha256::digest([]).into();
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+impl MerkleManifest {
+    pub fn from_leaves(generation: u64, leaves: &[Hash]) -> Self {
+        Self {
+            generation,
+            root: hex::encode(root(leaves)),
+            leaves: leaves.iter().map(hex::encode).collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    fn decode_hash(value: &str) -> Result<Hash, MerkleError> {
+        let bytes = hex::decode(value).map_err(|e| MerkleError::Malformed(e.to_string()))?;
+        bytes.try_into().map_err(|_| MerkleError::Malformed("hash is not 32 bytes".to_string()))
+    }
+
+    pub fn decoded_leaves(&self) -> Result<Vec<Hash>, MerkleError> {
+        self.leaves.iter().map(|leaf| Self::decode_hash(leaf)).collect()
+    }
+
+    // Decodes the leaves after checking that they actually fold up to the stored root, so
+    // callers checking many chunks can compare leaves directly
+    pub fn checked_leaves(&self) -> Result<Vec<Hash>, MerkleError> {
+        let leaves = self.decoded_leaves()?;
+        if root(&leaves) != Self::decode_hash(&self.root)? {
+            return Err(MerkleError::RootMismatch);
+        }
+        Ok(leaves)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: usize) -> Vec<Hash> {
+        (0..count).map(|i| leaf_hash(3, i, format!("chunk {}", i).as_bytes())).collect()
+    }
+
+    #[test]
+    fn root_pairs_nodes_and_promotes_the_odd_one() {
+        let l = leaves(3);
+        assert_eq!(root(&l[..1]), l[0]);
+        assert_eq!(root(&l[..2]), node_hash(&l[0], &l[1]));
+        assert_eq!(root(&l), node_hash(&node_hash(&l[0], &l[1]), &l[2]));
+        assert_eq!(root(&[]), <[u8; 32]>::from(Sha256::digest([])));
+    }
+
+    #[test]
+    fn leaves_are_bound_to_index_and_generation() {
+        assert_ne!(leaf_hash(3, 0, b"data"), leaf_hash(3, 1, b"data"));
+        assert_ne!(leaf_hash(3, 0, b"data"), leaf_hash(4, 0, b"data"));
+        // A node can't pass for a leaf of the same bytes
+        let l = leaves(2);
+        assert_ne!(leaf_hash(3, 0, &[&l[0][..], &l[1][..]].concat()), node_hash(&l[0], &l[1]));
+        let mut hasher = LeafHasher::new(3, 5);
+        hasher.update(b"da");
+        hasher.update(b"ta");
+        assert_eq!(hasher.finalize(), leaf_hash(3, 5, b"data"));
+    }
+
+    #[test]
+    fn checked_leaves_catch_tampering() {
+        for count in [1, 2, 5, 8] {
+            let manifest = MerkleManifest::from_leaves(3, &leaves(count));
+            assert_eq!(manifest.checked_leaves().unwrap(), leaves(count));
+
+            for i in 0..count {
+                let mut tampered = manifest.clone();
+                tampered.leaves[i] = hex::encode(leaf_hash(3, i, b"something else"));
+                assert!(matches!(tampered.checked_leaves(), Err(MerkleError::RootMismatch)));
+            }
+            let mut dropped = manifest.clone();
+            dropped.leaves.pop();
+            assert!(matches!(dropped.checked_leaves(), Err(MerkleError::RootMismatch)));
+            let mut bad_root = manifest.clone();
+            bad_root.root = hex::encode([0u8; 32]);
+            assert!(matches!(bad_root.checked_leaves(), Err(MerkleError::RootMismatch)));
+        }
+        let mut malformed = MerkleManifest::from_leaves(3, &leaves(2));
+        malformed.leaves[1].truncate(10);
+        assert!(matches!(malformed.checked_leaves(), Err(MerkleError::Malformed(_))));
+    }
+}
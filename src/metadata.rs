@@ -0,0 +1,289 @@
+use std::fs;
+use serde::{Deserialize, Serialize};
+use crate::carrier::Carrier;
+use crate::crypto;
+use crate::decoy::Decoy;
+use crate::filesys::{self, ChunkSizing};
+use crate::keysetup::{self, FragmentInfo};
+use crate::merkle::MerkleManifest;
+use crate::naming::NamingPolicy;
+use crate::padding::{Filler, Padding};
+use crate::sparse::SparseMap;
+
+// Contents of fragment_info.json.enc
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VaultMetadata {
+    pub fragment_count: usize,
+    pub max_chunks: usize,
+    pub dirs: Vec<String>,
+    pub key: String,
+    // keysetup::KEY_LAYOUT at the time the key was generated; 0 for older vaults
+    #[serde(default)]
+    pub key_layout: u32,
+    pub fragments: Vec<FragmentInfo>,
+    // Size of the encrypted image that was split, 0 until the first lock
+    #[serde(default)]
+    pub image_size: u64,
+    // Authenticates every chunk of the encrypted image, regenerated on each lock
+    #[serde(default)]
+    pub merkle: MerkleManifest,
+    // Move every fragment to new directories each time the locker is fragmented
+    #[serde(default)]
+    pub relocate_on_lock: bool,
+    // Generate a new key and new filenames each time the locker is fragmented
+    #[serde(default)]
+    pub rotate_on_lock: bool,
+    // Zero blocks left out of the encrypted image on the last lock; None if the whole image
+    // was encrypted
+    #[serde(default)]
+    pub sparse: Option<SparseMap>,
+    // Keystream::check_value of the image password, so a wrong one is caught before anything
+    // is written to the fragments; set on each lock
+    #[serde(default)]
+    pub key_check: Option<String>,
+    // Target chunk size in bytes; 0 for chunks of up to keysetup::MAX_CHUNK_SIZE, which is
+    // also the most any chunk gets
+    #[serde(default)]
+    pub chunk_size: u64,
+    // How many times the key's chunk schedule was repeated on the last lock to get chunks of
+    // about chunk_size: chunk r * k + j, for a key of k chunks, goes where key chunk j goes
+    #[serde(default)]
+    pub key_rounds: u64,
+    // Set to cut the image into chunks of varying length on every lock
+    #[serde(default)]
+    pub chunk_sizing: Option<ChunkSizing>,
+    // Length of every chunk on the last lock when they vary; empty for equal chunks
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunk_lengths: Vec<u64>,
+    // Number of decoy fragments to keep next to the real ones, and where they are
+    #[serde(default)]
+    pub decoy_count: usize,
+    #[serde(default)]
+    pub decoys: Vec<Decoy>,
+    // How fragment and decoy files are named when they are created or moved
+    #[serde(default)]
+    pub naming: NamingPolicy,
+    // File format fragment and decoy files are wrapped in
+    #[serde(default)]
+    pub carrier: Carrier,
+    // Set to pad the fragments after their chunks on every lock, and how much padding each
+    // fragment got on the last one
+    #[serde(default)]
+    pub padding: Option<Padding>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fragment_padding: Vec<u64>,
+    // Fragments store their chunks in a secret order derived from the key, see
+    // keysetup::shuffle_chunks, rather than in ascending order. Older vaults switch over on
+    // their next lock.
+    #[serde(default)]
+    pub shuffled_chunks: bool,
+    // Nonce the image was encrypted under on the last lock, in hex; every lock picks a new one.
+    // None for vaults last locked before that, whose images used crypto::LEGACY_NONCE.
+    #[serde(default)]
+    pub image_nonce: Option<String>,
+    // Set while the fragments of the last lock may still sit under their staged names (see
+    // fragwriter::commit); the next lock or unlock moves them into place
+    #[serde(default)]
+    pub swap_pending: bool,
+    // (location, filename) of fragments a relocating or rotating lock left behind, kept until
+    // they have been deleted so an interrupted lock can't orphan them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub retired_fragments: Vec<(String, String)>,
+}
+
+impl VaultMetadata {
+    pub fn new(max_chunks: usize, dirs: Vec<String>, key: String, fragments: Vec<FragmentInfo>) -> Self {
+        Self {
+            fragment_count: fragments.len(),
+            max_chunks,
+            dirs,
+            key,
+            key_layout: keysetup::KEY_LAYOUT,
+            fragments,
+            image_size: 0,
+            merkle: MerkleManifest::default(),
+            relocate_on_lock: false,
+            rotate_on_lock: false,
+            sparse: None,
+            key_check: None,
+            chunk_size: 0,
+            key_rounds: 1,
+            chunk_sizing: None,
+            chunk_lengths: Vec::new(),
+            decoy_count: 0,
+            decoys: Vec::new(),
+            naming: NamingPolicy::Random,
+            carrier: Carrier::Raw,
+            padding: None,
+            fragment_padding: Vec::new(),
+            shuffled_chunks: true,
+            image_nonce: None,
+            swap_pending: false,
+            retired_fragments: Vec::new(),
+        }
+    }
+
+    pub fn total_chunks(&self) -> usize {
+        self.key_chunks() * self.key_rounds.max(1) as usize
+    }
+
+    // Chunks one pass of the key describes
+    pub fn key_chunks(&self) -> usize {
+        keysetup::chunk_count(&self.key, self.fragments.len())
+    }
+
+    // Repeats each fragment's chunks from the key `rounds` times, in ascending order; the caller
+    // orders them once the chunk lengths are settled
+    fn repeat_key(&mut self, rounds: u64) {
+        let key_chunks = self.key_chunks();
+        for fragment in &mut self.fragments {
+            let mut once: Vec<usize> = fragment.chunk_indices.iter().copied().filter(|&i| i < key_chunks).collect();
+            once.sort_unstable();
+            fragment.chunk_indices = (0..rounds as usize).flat_map(|round| once.iter().map(move |&i| round * key_chunks + i)).collect();
+        }
+        self.key_rounds = rounds;
+    }
+
+    // Puts every fragment's chunk list in the order the fragment stores them, which depends on
+    // the chunk lengths
+    fn order_chunks(&mut self) {
+        let lengths = self.chunk_sizes();
+        for fragment in &mut self.fragments {
+            match self.shuffled_chunks {
+                true => keysetup::shuffle_chunks(&self.key, &lengths, fragment),
+                false => fragment.chunk_indices.sort_unstable(),
+            }
+        }
+    }
+
+    // Byte length of every chunk of the encrypted image, as recorded on the last lock
+    pub fn chunk_sizes(&self) -> Vec<u64> {
+        if !self.chunk_lengths.is_empty() {
+            self.chunk_lengths.clone()
+        } else if self.chunk_size > 0 {
+            filesys::aligned_chunk_lengths(self.image_size, self.total_chunks(), filesys::CHUNK_ALIGNMENT)
+        } else {
+            filesys::chunk_lengths(self.image_size as usize, self.total_chunks()).into_iter().map(|len| len as u64).collect()
+        }
+    }
+
+    // Decides how an image of `image_size` about to be fragmented is cut: how often the key is
+    // repeated, and the chunk lengths when they vary
+    pub fn cut_chunks(&mut self, generation: u64) {
+        // Every fragment is rewritten after this, so older vaults can take up shuffled chunks
+        self.shuffled_chunks = true;
+        // Chunks are kept short enough that each shuffle window holds several of them, even the
+        // longest chunk random lengths can give
+        let longest_percent = self.chunk_sizing.as_ref().map_or(100, |sizing| sizing.max_percent.max(100));
+        let limit = keysetup::MAX_CHUNK_SIZE * 100 / longest_percent;
+        let size = match self.chunk_size {
+            0 => limit,
+            size => size.min(limit),
+        };
+        let rounds = self.image_size.div_ceil(size * self.key_chunks() as u64).max(1);
+        self.repeat_key(rounds);
+        let unit = if self.chunk_size > 0 { filesys::CHUNK_ALIGNMENT } else { 1 };
+        self.chunk_lengths = match &self.chunk_sizing {
+            Some(sizing) => sizing.lengths(self.image_size, self.total_chunks(), generation, unit),
+            None => Vec::new(),
+        };
+        self.order_chunks();
+        self.fragment_padding = match &self.padding {
+            Some(padding) => padding.lengths(&self.fragment_sizes(), generation),
+            None => Vec::new(),
+        };
+    }
+
+    // Bytes of chunk data in every fragment as of the last lock; the files are a little bigger
+    // when they are wrapped in a carrier
+    pub fn fragment_sizes(&self) -> Vec<u64> {
+        let lengths = self.chunk_sizes();
+        self.fragments
+            .iter()
+            .map(|f| f.chunk_indices.iter().filter_map(|&i| lengths.get(i)).sum())
+            .collect()
+    }
+
+    // Bytes each fragment holds, chunks and padding, before any carrier is wrapped around them
+    pub fn padded_sizes(&self) -> Vec<u64> {
+        let mut sizes = self.fragment_sizes();
+        for (size, padding) in sizes.iter_mut().zip(&self.fragment_padding) {
+            *size += padding;
+        }
+        sizes
+    }
+
+    // The padding that follows the chunks of every fragment written on lock `generation`
+    pub fn padding_fillers(&self, generation: u64) -> Vec<Filler> {
+        (0..self.fragments.len())
+            .map(|i| match (&self.padding, self.fragment_padding.get(i)) {
+                (Some(padding), Some(&len)) => padding.filler(generation, i, len),
+                _ => Filler::empty(),
+            })
+            .collect()
+    }
+
+    fn check_chunk_lengths(&self) -> Result<(), String> {
+        if self.chunk_lengths.is_empty() {
+            return Ok(());
+        }
+        if self.chunk_lengths.len() != self.total_chunks() || self.chunk_lengths.iter().sum::<u64>() != self.image_size {
+            return Err(format!("The recorded chunk lengths don't add up to {} chunks of {} bytes", self.total_chunks(), self.image_size));
+        }
+        Ok(())
+    }
+
+    pub fn image_nonce(&self) -> Result<[u8; crypto::NONCE_SIZE], String> {
+        match &self.image_nonce {
+            Some(nonce) => hex::decode(nonce).ok().and_then(|bytes| bytes.try_into().ok()).ok_or_else(|| format!("Invalid image nonce {}", nonce)),
+            None => Ok(*crypto::LEGACY_NONCE),
+        }
+    }
+
+    fn check_padding(&self) -> Result<(), String> {
+        if !self.fragment_padding.is_empty() && self.fragment_padding.len() != self.fragments.len() {
+            return Err(format!("Padding is recorded for {} fragments, but there are {}", self.fragment_padding.len(), self.fragments.len()));
+        }
+        Ok(())
+    }
+
+    // (location, filename) of every fragment
+    pub fn fragment_names(&self) -> Vec<(String, String)> {
+        self.fragments.iter().map(|f| (f.location.clone(), f.filename.clone())).collect()
+    }
+}
+
+pub fn load(enc_path: &str, passphrase: &str, key_const: &[u8]) -> Result<VaultMetadata, Box<dyn std::error::Error>> {
+    let ciphertext = fs::read(enc_path)?;
+    let plaintext = crypto::decrypt_bytes(&ciphertext, passphrase, key_const)?;
+    let mut metadata: VaultMetadata = serde_json::from_slice(&plaintext)?;
+    keysetup::resolve_chunk_indices(&metadata.key, metadata.key_layout, &mut metadata.fragments)?;
+    if metadata.key_rounds > 1 {
+        metadata.repeat_key(metadata.key_rounds);
+    }
+    metadata.check_chunk_lengths()?;
+    metadata.order_chunks();
+    metadata.check_padding()?;
+    metadata.image_nonce()?;
+    Ok(metadata)
+}
+
+pub fn save(metadata: &VaultMetadata, enc_path: &str, passphrase: &str, key_const: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    // Only one pass of the key is stored, and the chunk layout is left for the key to describe
+    // whenever it can
+    let mut stored = metadata.clone();
+    stored.repeat_key(1);
+    stored.key_rounds = metadata.key_rounds;
+    // The stored order is worked out again on load
+    stored.fragments.iter_mut().for_each(|f| f.chunk_indices.sort_unstable());
+    if keysetup::chunk_indices_derivable(&stored.key, stored.key_layout, &stored.fragments) {
+        stored.fragments.iter_mut().for_each(|f| f.chunk_indices.clear());
+    }
+    let plaintext = serde_json::to_vec_pretty(&stored)?;
+    let ciphertext = crypto::encrypt_bytes(&plaintext, passphrase, key_const);
+    // Write next to the target first so a crash never leaves a truncated metadata file
+    let tmp_path = format!("{}.tmp", enc_path);
+    fs::write(&tmp_path, ciphertext)?;
+    fs::rename(&tmp_path, enc_path)?;
+    Ok(())
+}
@@ -0,0 +1,280 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::path::Path;
+use rand::{CryptoRng, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use crate::carrier::Carrier;
+use crate::keysetup::{self, FragmentInfo};
+use crate::store;
+
+// How fragments and decoys are named where they are stored. Only a fragment's token matters to
+// the key, so the visible name is free to blend in with the files around it; the token stays
+// in the encrypted fragment info.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NamingPolicy {
+    // The token with a .bin extension
+    #[default]
+    Random,
+    // Common filenames such as IMG_4821.jpg, preferring the extension most files in the
+    // directory have
+    Dictionary,
+    // Variations on the names of files already in the directory, or Dictionary names where
+    // there are none
+    Siblings,
+}
+
+impl NamingPolicy {
+    pub fn parse(input: &str) -> Result<Self, String> {
+        match input.trim().to_lowercase().as_str() {
+            "" | "1" | "random" => Ok(Self::Random),
+            "2" | "dictionary" => Ok(Self::Dictionary),
+            "3" | "siblings" => Ok(Self::Siblings),
+            other => Err(format!("Unknown naming policy: {}", other)),
+        }
+    }
+}
+
+// Common filename stems and the extension they usually come with; every '#' becomes a random digit
+const DICTIONARY: &[(&str, &str)] = &[
+    ("IMG_####", "jpg"),
+    ("DSC#####", "jpg"),
+    ("Screenshot_####", "png"),
+    ("VID_####", "mp4"),
+    ("Scan_####", "pdf"),
+    ("invoice_####", "pdf"),
+    ("report_####", "pdf"),
+    ("Document", "docx"),
+    ("draft_##", "docx"),
+    ("notes", "txt"),
+    ("budget_####", "xlsx"),
+    ("export_####", "csv"),
+    ("presentation", "pptx"),
+    ("track##", "mp3"),
+    ("backup_####", "zip"),
+    ("archive_####", "zip"),
+    ("data_###", "dat"),
+    ("cache_####", "tmp"),
+];
+
+// Candidate names tried before giving up on a location
+const ATTEMPTS: usize = 64;
+// New tokens, and for fragments new keys, tried before giving up on finding free names. A
+// location crowded enough to use them all up won't clear up by trying longer.
+pub const TOKEN_ATTEMPTS: usize = 32;
+
+// Hands out names under a policy, none of which clash with a file already in the location or
+// with a name handed out before. Each location is listed once, so remote stores aren't queried
+// per candidate. Files wrapped in a carrier always get its extension.
+pub struct Namer {
+    policy: NamingPolicy,
+    extension: Option<&'static str>,
+    // Files in each location, sorted so a seeded RNG picks the same ones every time
+    listings: HashMap<String, Vec<String>>,
+    // (location, lowercased name), as some filesystems ignore case
+    taken: HashSet<(String, String)>,
+}
+
+impl Namer {
+    pub fn new(policy: NamingPolicy, carrier: Carrier) -> Self {
+        Self { policy, extension: carrier.extension(), listings: HashMap::new(), taken: HashSet::new() }
+    }
+
+    fn listing(&mut self, location: &str) -> io::Result<&[String]> {
+        if !self.listings.contains_key(location) {
+            let mut names = store::open_store(location)?.list()?;
+            names.sort();
+            for name in &names {
+                self.taken.insert((location.to_string(), name.to_lowercase()));
+            }
+            self.listings.insert(location.to_string(), names);
+        }
+        Ok(&self.listings[location])
+    }
+
+    // Marks a name as in use without handing it out
+    pub fn reserve(&mut self, location: &str, name: &str) {
+        self.taken.insert((location.to_string(), name.to_lowercase()));
+    }
+
+    fn is_free(&self, location: &str, name: &str) -> bool {
+        !self.taken.contains(&(location.to_string(), name.to_lowercase()))
+    }
+
+    // A free name in `location` for a file with `token`, or None if the policy couldn't come up
+    // with one, in which case the caller tries again with another token
+    pub fn name(&mut self, location: &str, token: &str, rng: &mut (impl RngCore + CryptoRng + ?Sized)) -> io::Result<Option<String>> {
+        let mut siblings: Vec<String> = self
+            .listing(location)?
+            .iter()
+            .filter(|name| !name.starts_with('.') && store::is_portable_name(name))
+            .cloned()
+            .collect();
+        // Siblings that already have the carrier's extension make the best models
+        if let Some(extension) = self.extension {
+            let matching: Vec<String> = siblings.iter().filter(|name| has_extension(name, extension)).cloned().collect();
+            if !matching.is_empty() {
+                siblings = matching;
+            }
+        }
+        let candidates = match self.policy {
+            NamingPolicy::Random => 1,
+            NamingPolicy::Dictionary | NamingPolicy::Siblings => ATTEMPTS,
+        };
+        for attempt in 0..candidates {
+            let mut name = match self.policy {
+                NamingPolicy::Random => format!("{}.bin", token),
+                NamingPolicy::Siblings if !siblings.is_empty() => variation(&siblings[rng.gen_range(0..siblings.len())], rng),
+                NamingPolicy::Dictionary | NamingPolicy::Siblings => dictionary_name(self.extension.or(dominant_extension(&siblings)), rng),
+            };
+            // Names without digits only come in one version, so later attempts vary them too
+            if attempt >= ATTEMPTS / 2 {
+                name = variation(&name, rng);
+            }
+            if let Some(extension) = self.extension {
+                name = with_extension(&name, extension);
+            }
+            if self.is_free(location, &name) {
+                self.reserve(location, &name);
+                return Ok(Some(name));
+            }
+        }
+        Ok(None)
+    }
+
+    // A name for `fragment` in the location it is moving to. Its token stays the same, so
+    // under the random policy the name does too.
+    pub fn name_moved(&mut self, fragment: &FragmentInfo, location: &str, rng: &mut (impl RngCore + CryptoRng + ?Sized)) -> io::Result<String> {
+        self.name(location, fragment.key_symbols(), rng)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::AlreadyExists, format!("No free name for fragment {} in {}", fragment.filename, location)))
+    }
+}
+
+fn has_extension(name: &str, extension: &str) -> bool {
+    Path::new(name).extension().and_then(|e| e.to_str()).is_some_and(|e| e.eq_ignore_ascii_case(extension))
+}
+
+fn with_extension(name: &str, extension: &str) -> String {
+    if has_extension(name, extension) {
+        return name.to_string();
+    }
+    let stem = Path::new(name).file_stem().and_then(|s| s.to_str()).unwrap_or(name);
+    format!("{}.{}", stem, extension)
+}
+
+// The most common extension among `names`, spelled the way it first appears
+fn dominant_extension(names: &[String]) -> Option<&str> {
+    let mut counts: BTreeMap<String, (usize, &str)> = BTreeMap::new();
+    for extension in names.iter().filter_map(|name| Path::new(name).extension()?.to_str()) {
+        counts.entry(extension.to_lowercase()).or_insert((0, extension)).0 += 1;
+    }
+    counts.into_values().max_by_key(|&(count, _)| count).map(|(_, extension)| extension)
+}
+
+fn fill_digits(pattern: &str, rng: &mut (impl RngCore + CryptoRng + ?Sized)) -> String {
+    pattern
+        .chars()
+        .map(|c| if c == '#' { char::from(b'0' + rng.gen_range(0..10u8)) } else { c })
+        .collect()
+}
+
+// A dictionary name, with an entry that usually has `extension` if there is one
+fn dictionary_name(extension: Option<&str>, rng: &mut (impl RngCore + CryptoRng + ?Sized)) -> String {
+    let matching: Vec<&(&str, &str)> = DICTIONARY
+        .iter()
+        .filter(|(_, usual)| extension.is_some_and(|e| e.eq_ignore_ascii_case(usual)))
+        .collect();
+    let (stem, usual) = match matching.is_empty() {
+        true => DICTIONARY[rng.gen_range(0..DICTIONARY.len())],
+        false => *matching[rng.gen_range(0..matching.len())],
+    };
+    format!("{}.{}", fill_digits(stem, rng), extension.unwrap_or(usual))
+}
+
+// A name that could be the next in a series with `sibling`: its digits redrawn, or if it has
+// none, a copy number added
+fn variation(sibling: &str, rng: &mut (impl RngCore + CryptoRng + ?Sized)) -> String {
+    let path = Path::new(sibling);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or(sibling);
+    let stem = if stem.chars().any(|c| c.is_ascii_digit()) {
+        fill_digits(&stem.chars().map(|c| if c.is_ascii_digit() { '#' } else { c }).collect::<String>(), rng)
+    } else {
+        match rng.gen_range(0..3) {
+            0 => format!("{} ({})", stem, rng.gen_range(1..10)),
+            1 => format!("{}_{}", stem, rng.gen_range(1..100)),
+            _ => format!("{}-{}", stem, rng.gen_range(1..100)),
+        }
+    };
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(extension) => format!("{}.{}", stem, extension),
+        None => stem,
+    }
+}
+
+// Generates a key and fragments for `locations` and names the fragments under `policy`,
+// drawing a new key until every name is free, TOKEN_ATTEMPTS times at most
+pub fn generate_named_fragments(
+    locations: &[String],
+    max_chunks: usize,
+    policy: NamingPolicy,
+    carrier: Carrier,
+    rng: &mut (impl RngCore + CryptoRng + ?Sized),
+) -> io::Result<(String, Vec<FragmentInfo>)> {
+    'generate: for _ in 0..TOKEN_ATTEMPTS {
+        let (key, mut fragments) = keysetup::generate_key_and_fragments(locations.to_vec(), max_chunks, rng);
+        let mut namer = Namer::new(policy, carrier);
+        for fragment in &mut fragments {
+            match namer.name(&fragment.location, &fragment.token, rng)? {
+                Some(name) => fragment.filename = name,
+                None => continue 'generate,
+            }
+        }
+        return Ok((key, fragments));
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("No free names for the fragments after {} keys; the chosen directories are too crowded", TOKEN_ATTEMPTS),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    fn temp_dir() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("sdfs_naming_test_{:016x}", rand::random::<u64>()));
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn names_are_free_and_distinct() {
+        let dir = temp_dir();
+        fs::write(dir.join("IMG_1234.jpg"), b"").unwrap();
+        let location = dir.to_string_lossy().into_owned();
+        let mut rng = ChaCha20Rng::seed_from_u64(1);
+        let mut namer = Namer::new(NamingPolicy::Siblings, Carrier::Raw);
+        let names: HashSet<String> = (0..20).map(|i| namer.name(&location, &i.to_string(), &mut rng).unwrap().unwrap()).collect();
+        assert_eq!(names.len(), 20);
+        assert!(!names.contains("IMG_1234.jpg"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gives_up_when_every_name_is_taken() {
+        // One fragment of one chunk gets a single-character token, so with a file for every
+        // character no key can be named
+        let dir = temp_dir();
+        for &c in b"0123456789abcdefghijklmnopqrstuvwxyz" {
+            fs::write(dir.join(format!("{}.bin", char::from(c))), b"").unwrap();
+        }
+        let locations = vec![dir.to_string_lossy().into_owned()];
+        let mut rng = ChaCha20Rng::seed_from_u64(1);
+        let error = generate_named_fragments(&locations, 1, NamingPolicy::Random, Carrier::Raw, &mut rng).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
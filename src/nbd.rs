@@ -0,0 +1,583 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use crate::blockdev::{self, BlockDevice};
+
+// Exports a BlockDevice over the network block device protocol (fixed newstyle handshake,
+// simple replies), so the kernel's nbd client or qemu can use it as a disk:
+//
+//   nbd-client -N locker 127.0.0.1 10809 /dev/nbd0
+//   nbd-client -unix /path/to/socket -N locker /dev/nbd0
+//
+// One client is served at a time. Writes and flushes go straight to the device, and the device
+// is flushed when a client disconnects. Trims are accepted but leave the data as it was, which
+// the protocol allows; the space only comes back when the locker is next locked.
+
+pub const DEFAULT_PORT: u16 = 10809;
+pub const EXPORT_NAME: &str = "locker";
+// Bigger requests than this are refused rather than buffered
+const MAX_REQUEST: u32 = 32 * 1024 * 1024;
+
+const NBDMAGIC: u64 = 0x4e42_444d_4147_4943;
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const OPTION_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1;
+const FLAG_NO_ZEROES: u16 = 2;
+const CLIENT_NO_ZEROES: u32 = 2;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_ABORT: u32 = 2;
+const OPT_LIST: u32 = 3;
+const OPT_INFO: u32 = 6;
+const OPT_GO: u32 = 7;
+
+const REP_ACK: u32 = 1;
+const REP_SERVER: u32 = 2;
+const REP_INFO: u32 = 3;
+const REP_ERR_UNSUP: u32 = 0x8000_0001;
+const REP_ERR_INVALID: u32 = 0x8000_0003;
+const REP_ERR_UNKNOWN: u32 = 0x8000_0006;
+
+const INFO_EXPORT: u16 = 0;
+const INFO_BLOCK_SIZE: u16 = 3;
+
+const TRANSMISSION_HAS_FLAGS: u16 = 1;
+const TRANSMISSION_READ_ONLY: u16 = 2;
+const TRANSMISSION_SEND_FLUSH: u16 = 4;
+const TRANSMISSION_SEND_TRIM: u16 = 32;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+const CMD_TRIM: u16 = 4;
+
+const EPERM: u32 = 1;
+const EIO: u32 = 5;
+const EINVAL: u32 = 22;
+const ENOSPC: u32 = 28;
+
+pub enum Address {
+    Tcp(u16),
+    Unix(PathBuf),
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+// A connection, whichever kind of socket it came in on
+trait Connection: Read + Write + Send {
+    fn try_clone_box(&self) -> io::Result<Box<dyn Connection>>;
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn try_clone_box(&self) -> io::Result<Box<dyn Connection>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+impl Connection for UnixStream {
+    fn try_clone_box(&self) -> io::Result<Box<dyn Connection>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+impl Listener {
+    fn bind(address: &Address) -> io::Result<Self> {
+        match address {
+            // Only local clients: the export is the decrypted locker
+            Address::Tcp(port) => Ok(Listener::Tcp(TcpListener::bind(("127.0.0.1", *port))?)),
+            Address::Unix(path) => {
+                if path.exists() {
+                    // Left behind by a server that didn't get to clean up, unless one is still running
+                    if UnixStream::connect(path).is_ok() {
+                        return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is already being served", path.display())));
+                    }
+                    std::fs::remove_file(path)?;
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            }
+        }
+    }
+
+    fn accept(&self) -> io::Result<Box<dyn Connection>> {
+        match self {
+            Listener::Tcp(listener) => Ok(Box::new(listener.accept()?.0)),
+            Listener::Unix(listener) => Ok(Box::new(listener.accept()?.0)),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map_or_else(|_| "localhost".to_string(), |a| a.to_string()),
+            Listener::Unix(listener) => listener
+                .local_addr()
+                .ok()
+                .and_then(|a| a.as_pathname().map(|p| p.display().to_string()))
+                .unwrap_or_default(),
+        }
+    }
+
+    // Unblocks a pending accept() by connecting to ourselves
+    fn wake(&self) {
+        let _ = match self {
+            Listener::Tcp(listener) => listener.local_addr().and_then(TcpStream::connect).map(drop),
+            Listener::Unix(listener) => listener
+                .local_addr()
+                .and_then(|a| a.as_pathname().map(Path::to_path_buf).ok_or_else(|| io::Error::other("unnamed socket")))
+                .and_then(UnixStream::connect)
+                .map(drop),
+        };
+    }
+}
+
+fn read_u16(stream: &mut dyn Connection) -> io::Result<u16> {
+    let mut bytes = [0u8; 2];
+    stream.read_exact(&mut bytes)?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+fn read_u32(stream: &mut dyn Connection) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    stream.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64(stream: &mut dyn Connection) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    stream.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn option_reply(stream: &mut dyn Connection, option: u32, reply: u32, data: &[u8]) -> io::Result<()> {
+    let mut message = Vec::with_capacity(20 + data.len());
+    message.extend_from_slice(&OPTION_REPLY_MAGIC.to_be_bytes());
+    message.extend_from_slice(&option.to_be_bytes());
+    message.extend_from_slice(&reply.to_be_bytes());
+    message.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    message.extend_from_slice(data);
+    stream.write_all(&message)
+}
+
+fn export_info(size: u64, flags: u16) -> Vec<u8> {
+    let mut info = Vec::with_capacity(12);
+    info.extend_from_slice(&INFO_EXPORT.to_be_bytes());
+    info.extend_from_slice(&size.to_be_bytes());
+    info.extend_from_slice(&flags.to_be_bytes());
+    info
+}
+
+// Runs the handshake. Returns false if the client went away without picking the export.
+fn negotiate(stream: &mut dyn Connection, size: u64, flags: u16) -> io::Result<bool> {
+    let mut greeting = Vec::with_capacity(18);
+    greeting.extend_from_slice(&NBDMAGIC.to_be_bytes());
+    greeting.extend_from_slice(&IHAVEOPT.to_be_bytes());
+    greeting.extend_from_slice(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes());
+    stream.write_all(&greeting)?;
+    let no_zeroes = read_u32(stream)? & CLIENT_NO_ZEROES != 0;
+
+    loop {
+        if read_u64(stream)? != IHAVEOPT {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad option magic from the NBD client"));
+        }
+        let option = read_u32(stream)?;
+        let len = read_u32(stream)?;
+        if len > 4096 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Oversized option from the NBD client"));
+        }
+        let mut data = vec![0u8; len as usize];
+        stream.read_exact(&mut data)?;
+        match option {
+            OPT_EXPORT_NAME => {
+                // No way to refuse here except hanging up; an empty name means the default export
+                if !data.is_empty() && data != EXPORT_NAME.as_bytes() {
+                    return Ok(false);
+                }
+                let mut reply = Vec::with_capacity(134);
+                reply.extend_from_slice(&size.to_be_bytes());
+                reply.extend_from_slice(&flags.to_be_bytes());
+                if !no_zeroes {
+                    reply.extend_from_slice(&[0u8; 124]);
+                }
+                stream.write_all(&reply)?;
+                return Ok(true);
+            }
+            OPT_ABORT => {
+                option_reply(stream, option, REP_ACK, &[])?;
+                return Ok(false);
+            }
+            OPT_LIST => {
+                let mut entry = (EXPORT_NAME.len() as u32).to_be_bytes().to_vec();
+                entry.extend_from_slice(EXPORT_NAME.as_bytes());
+                option_reply(stream, option, REP_SERVER, &entry)?;
+                option_reply(stream, option, REP_ACK, &[])?;
+            }
+            OPT_INFO | OPT_GO => {
+                let name_len = data.get(..4).map(|b| u32::from_be_bytes(b.try_into().expect("4 bytes")) as usize);
+                let Some(name) = name_len.and_then(|n| data.get(4..4 + n)) else {
+                    option_reply(stream, option, REP_ERR_INVALID, &[])?;
+                    continue;
+                };
+                if !name.is_empty() && name != EXPORT_NAME.as_bytes() {
+                    option_reply(stream, option, REP_ERR_UNKNOWN, b"No such export")?;
+                    continue;
+                }
+                option_reply(stream, option, REP_INFO, &export_info(size, flags))?;
+                let mut block_size = INFO_BLOCK_SIZE.to_be_bytes().to_vec();
+                for value in [1u32, 4096, MAX_REQUEST] {
+                    block_size.extend_from_slice(&value.to_be_bytes());
+                }
+                option_reply(stream, option, REP_INFO, &block_size)?;
+                option_reply(stream, option, REP_ACK, &[])?;
+                if option == OPT_GO {
+                    return Ok(true);
+                }
+            }
+            _ => option_reply(stream, option, REP_ERR_UNSUP, &[])?,
+        }
+    }
+}
+
+fn errno_for(error: &io::Error) -> u32 {
+    match error.kind() {
+        io::ErrorKind::PermissionDenied => EPERM,
+        io::ErrorKind::StorageFull => ENOSPC,
+        io::ErrorKind::InvalidInput | io::ErrorKind::UnexpectedEof => EINVAL,
+        _ => EIO,
+    }
+}
+
+fn simple_reply(stream: &mut dyn Connection, handle: u64, result: io::Result<Vec<u8>>) -> io::Result<()> {
+    let (error, data) = match result {
+        Ok(data) => (0, data),
+        Err(e) => {
+            eprintln!("NBD request failed: {}", e);
+            (errno_for(&e), Vec::new())
+        }
+    };
+    let mut reply = Vec::with_capacity(16 + data.len());
+    reply.extend_from_slice(&SIMPLE_REPLY_MAGIC.to_be_bytes());
+    reply.extend_from_slice(&error.to_be_bytes());
+    reply.extend_from_slice(&handle.to_be_bytes());
+    reply.extend_from_slice(&data);
+    stream.write_all(&reply)
+}
+
+fn transmit(stream: &mut dyn Connection, device: &mut dyn BlockDevice, read_only: bool) -> io::Result<()> {
+    loop {
+        if read_u32(stream)? != REQUEST_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad request magic from the NBD client"));
+        }
+        let _command_flags = read_u16(stream)?;
+        let command = read_u16(stream)?;
+        let handle = read_u64(stream)?;
+        let offset = read_u64(stream)?;
+        let len = read_u32(stream)?;
+        match command {
+            CMD_READ => {
+                let result = if len > MAX_REQUEST {
+                    Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Read of {} bytes is too big", len)))
+                } else {
+                    let mut data = vec![0u8; len as usize];
+                    device.read_at(offset, &mut data).map(|_| data)
+                };
+                simple_reply(stream, handle, result)?;
+            }
+            CMD_WRITE => {
+                if len > MAX_REQUEST {
+                    // The payload can't be skipped safely, so the connection has to go
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Write of {} bytes is too big", len)));
+                }
+                let mut data = vec![0u8; len as usize];
+                stream.read_exact(&mut data)?;
+                let result = if read_only {
+                    Err(io::Error::new(io::ErrorKind::PermissionDenied, "The export is read-only"))
+                } else {
+                    device.write_at(offset, &data)
+                };
+                simple_reply(stream, handle, result.map(|_| Vec::new()))?;
+            }
+            CMD_FLUSH => {
+                let result = device.flush();
+                simple_reply(stream, handle, result.map(|_| Vec::new()))?;
+            }
+            CMD_TRIM => {
+                let result = if read_only {
+                    Err(io::Error::new(io::ErrorKind::PermissionDenied, "The export is read-only"))
+                } else {
+                    blockdev::check_range(offset, len as usize, device.size())
+                };
+                simple_reply(stream, handle, result.map(|_| Vec::new()))?;
+            }
+            CMD_DISC => return Ok(()),
+            _ => simple_reply(stream, handle, Err(io::Error::new(io::ErrorKind::InvalidInput, "Unsupported NBD command")))?,
+        }
+    }
+}
+
+fn transmission_flags(read_only: bool) -> u16 {
+    if read_only {
+        TRANSMISSION_HAS_FLAGS | TRANSMISSION_SEND_FLUSH | TRANSMISSION_READ_ONLY
+    } else {
+        TRANSMISSION_HAS_FLAGS | TRANSMISSION_SEND_FLUSH | TRANSMISSION_SEND_TRIM
+    }
+}
+
+// Handshake and transmission with one client. Returns false if the client went away without
+// picking the export.
+fn session(stream: &mut dyn Connection, device: &mut dyn BlockDevice, read_only: bool) -> io::Result<bool> {
+    if !negotiate(stream, device.size(), transmission_flags(read_only))? {
+        return Ok(false);
+    }
+    println!("Client connected.");
+    transmit(stream, device, read_only)?;
+    Ok(true)
+}
+
+// Serves `device` at `address` until Ctrl-C or SIGTERM. Pending writes are flushed whenever a
+// client disconnects and once more before returning.
+pub fn serve(device: &mut dyn BlockDevice, address: &Address, read_only: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = Arc::new(Listener::bind(address)?);
+
+    // Block the signals in every thread and pick them up in one that only waits for them
+    let signals = unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        set
+    };
+    let stopping = Arc::new(AtomicBool::new(false));
+    let client: Arc<Mutex<Option<Box<dyn Connection>>>> = Arc::new(Mutex::new(None));
+    {
+        let (listener, stopping, client) = (listener.clone(), stopping.clone(), client.clone());
+        std::thread::spawn(move || {
+            let mut signal = 0;
+            unsafe { libc::sigwait(&signals, &mut signal) };
+            stopping.store(true, Ordering::SeqCst);
+            if let Some(connection) = client.lock().expect("client lock").as_ref() {
+                let _ = connection.shutdown();
+            }
+            listener.wake();
+        });
+    }
+
+    println!(
+        "Serving the locker{} as NBD export \"{}\" on {}. Press Ctrl-C to stop.",
+        if read_only { " read-only" } else { "" },
+        EXPORT_NAME,
+        listener.describe()
+    );
+
+    let mut result = Ok(());
+    while !stopping.load(Ordering::SeqCst) {
+        let mut stream = match listener.accept() {
+            Ok(stream) => stream,
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        };
+        if stopping.load(Ordering::SeqCst) {
+            break;
+        }
+        *client.lock().expect("client lock") = Some(stream.try_clone_box()?);
+        let session = session(stream.as_mut(), device, read_only);
+        *client.lock().expect("client lock") = None;
+        match session {
+            Ok(true) => println!("Client disconnected."),
+            Ok(false) => {}
+            // Clients that just hang up aren't worth reporting
+            Err(e) if matches!(e.kind(), io::ErrorKind::UnexpectedEof | io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset) => {}
+            Err(_) if stopping.load(Ordering::SeqCst) => {}
+            Err(e) => eprintln!("NBD connection failed: {}", e),
+        }
+        if let Err(e) = device.flush() {
+            eprintln!("{}", e);
+        }
+    }
+
+    if let Address::Unix(path) = address {
+        let _ = std::fs::remove_file(path);
+    }
+    device.flush()?;
+    result?;
+    println!("NBD server stopped.");
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    struct MemoryDisk {
+        data: Vec<u8>,
+        flushes: usize,
+    }
+
+    impl BlockDevice for MemoryDisk {
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+            blockdev::check_range(offset, buf.len(), self.size())?;
+            buf.copy_from_slice(&self.data[offset as usize..offset as usize + buf.len()]);
+            Ok(())
+        }
+
+        fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+            blockdev::check_range(offset, buf.len(), self.size())?;
+            self.data[offset as usize..offset as usize + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn size(&self) -> u64 {
+            self.data.len() as u64
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    // Just enough of a client: fixed newstyle handshake with NBD_OPT_GO, then simple requests
+    struct Client {
+        stream: UnixStream,
+        size: u64,
+        flags: u16,
+        handle: u64,
+    }
+
+    impl Client {
+        fn connect(mut stream: UnixStream) -> Self {
+            assert_eq!(read_u64(&mut stream).unwrap(), NBDMAGIC);
+            assert_eq!(read_u64(&mut stream).unwrap(), IHAVEOPT);
+            assert_ne!(read_u16(&mut stream).unwrap() & FLAG_FIXED_NEWSTYLE, 0);
+            stream.write_all(&(FLAG_FIXED_NEWSTYLE as u32 | CLIENT_NO_ZEROES).to_be_bytes()).unwrap();
+
+            let mut data = (EXPORT_NAME.len() as u32).to_be_bytes().to_vec();
+            data.extend_from_slice(EXPORT_NAME.as_bytes());
+            data.extend_from_slice(&0u16.to_be_bytes());
+            let mut option = IHAVEOPT.to_be_bytes().to_vec();
+            option.extend_from_slice(&OPT_GO.to_be_bytes());
+            option.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            option.extend_from_slice(&data);
+            stream.write_all(&option).unwrap();
+
+            let (mut size, mut flags) = (0, 0);
+            loop {
+                assert_eq!(read_u64(&mut stream).unwrap(), OPTION_REPLY_MAGIC);
+                assert_eq!(read_u32(&mut stream).unwrap(), OPT_GO);
+                let reply = read_u32(&mut stream).unwrap();
+                let mut body = vec![0u8; read_u32(&mut stream).unwrap() as usize];
+                stream.read_exact(&mut body).unwrap();
+                match reply {
+                    REP_ACK => break,
+                    REP_INFO if body[..2] == INFO_EXPORT.to_be_bytes() => {
+                        size = u64::from_be_bytes(body[2..10].try_into().unwrap());
+                        flags = u16::from_be_bytes(body[10..12].try_into().unwrap());
+                    }
+                    REP_INFO => {}
+                    other => panic!("unexpected option reply {:#x}", other),
+                }
+            }
+            Self { stream, size, flags, handle: 0 }
+        }
+
+        // Sends a request and returns the error from the reply, and the data for a read
+        fn request(&mut self, command: u16, offset: u64, len: u32, payload: &[u8]) -> (u32, Vec<u8>) {
+            self.handle += 1;
+            let mut request = REQUEST_MAGIC.to_be_bytes().to_vec();
+            request.extend_from_slice(&0u16.to_be_bytes());
+            request.extend_from_slice(&command.to_be_bytes());
+            request.extend_from_slice(&self.handle.to_be_bytes());
+            request.extend_from_slice(&offset.to_be_bytes());
+            request.extend_from_slice(&len.to_be_bytes());
+            request.extend_from_slice(payload);
+            self.stream.write_all(&request).unwrap();
+            assert_eq!(read_u32(&mut self.stream).unwrap(), SIMPLE_REPLY_MAGIC);
+            let error = read_u32(&mut self.stream).unwrap();
+            assert_eq!(read_u64(&mut self.stream).unwrap(), self.handle);
+            let mut data = vec![0u8; if command == CMD_READ && error == 0 { len as usize } else { 0 }];
+            self.stream.read_exact(&mut data).unwrap();
+            (error, data)
+        }
+
+        fn disconnect(mut self) {
+            self.handle += 1;
+            let mut request = REQUEST_MAGIC.to_be_bytes().to_vec();
+            request.extend_from_slice(&0u16.to_be_bytes());
+            request.extend_from_slice(&CMD_DISC.to_be_bytes());
+            request.extend_from_slice(&[0u8; 20]);
+            self.stream.write_all(&request).unwrap();
+        }
+    }
+
+    // Runs one session against an in-memory disk holding `data`, over a socket pair
+    fn serve_one(data: Vec<u8>, read_only: bool) -> (Client, thread::JoinHandle<MemoryDisk>) {
+        let (server, client) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            let mut disk = MemoryDisk { data, flushes: 0 };
+            let mut stream: Box<dyn Connection> = Box::new(server);
+            assert!(session(stream.as_mut(), &mut disk, read_only).unwrap());
+            disk
+        });
+        (Client::connect(client), server)
+    }
+
+    #[test]
+    fn read_write_flush_trim() {
+        let image: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let (mut client, server) = serve_one(image.clone(), false);
+        assert_eq!(client.size, image.len() as u64);
+        assert_eq!(client.flags & TRANSMISSION_READ_ONLY, 0);
+        assert_ne!(client.flags & TRANSMISSION_SEND_FLUSH, 0);
+        assert_ne!(client.flags & TRANSMISSION_SEND_TRIM, 0);
+
+        assert_eq!(client.request(CMD_READ, 1000, 300, &[]), (0, image[1000..1300].to_vec()));
+        assert_eq!(client.request(CMD_WRITE, 5000, 4, b"sdfs").0, 0);
+        assert_eq!(client.request(CMD_READ, 4998, 8, &[]).1, [image[4998], image[4999], b's', b'd', b'f', b's', image[5004], image[5005]]);
+        assert_eq!(client.request(CMD_FLUSH, 0, 0, &[]).0, 0);
+        assert_eq!(client.request(CMD_TRIM, 8192, 4096, &[]).0, 0);
+        // Past the end of the disk
+        assert_eq!(client.request(CMD_READ, image.len() as u64 - 10, 20, &[]).0, EINVAL);
+        assert_eq!(client.request(CMD_WRITE, image.len() as u64, 1, b"x").0, EINVAL);
+        assert_eq!(client.request(CMD_TRIM, image.len() as u64 - 10, 20, &[]).0, EINVAL);
+        assert_eq!(client.request(99, 0, 0, &[]).0, EINVAL);
+        client.disconnect();
+
+        let disk = server.join().unwrap();
+        assert_eq!(disk.flushes, 1);
+        assert_eq!(&disk.data[5000..5004], b"sdfs");
+        assert_eq!(disk.data[..5000], image[..5000]);
+        assert_eq!(disk.data[5004..], image[5004..]);
+    }
+
+    #[test]
+    fn read_only_export_refuses_changes() {
+        let (mut client, server) = serve_one(vec![7u8; 4096], true);
+        assert_ne!(client.flags & TRANSMISSION_READ_ONLY, 0);
+        assert_eq!(client.request(CMD_WRITE, 0, 4, b"sdfs").0, EPERM);
+        assert_eq!(client.request(CMD_TRIM, 0, 4096, &[]).0, EPERM);
+        assert_eq!(client.request(CMD_READ, 0, 4, &[]), (0, vec![7u8; 4]));
+        client.disconnect();
+        assert_eq!(server.join().unwrap().data, vec![7u8; 4096]);
+    }
+}
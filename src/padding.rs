@@ -0,0 +1,173 @@
+use std::io::{self, Read};
+use hmac::{Hmac, Mac};
+use rand::{CryptoRng, Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Padding after the chunks of every fragment, so fragment sizes stop giving away how many
+// chunks each one holds. With a spread of 0 every fragment is padded to the size of the
+// biggest; otherwise each one is padded to a size drawn evenly from the biggest up to
+// `spread_percent` more than that. The padding bytes come from ChaCha20 keyed through
+// HMAC-SHA256 with `secret`, the lock counter and the fragment's position, so they look like
+// the ciphertext around them, and verify can regenerate them to check nothing was changed.
+// Reassembly never reads past the chunks.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Padding {
+    pub secret: String,
+    pub spread_percent: u64,
+}
+
+// Random bytes, as many as the padding or decoy should hold
+pub struct Filler {
+    rng: ChaCha20Rng,
+    remaining: u64,
+}
+
+impl Filler {
+    pub fn new(rng: ChaCha20Rng, len: u64) -> Self {
+        Self { rng, remaining: len }
+    }
+
+    // Bytes still to come
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    // No bytes at all, for fragments that aren't padded
+    pub fn empty() -> Self {
+        Self::new(ChaCha20Rng::from_seed([0; 32]), 0)
+    }
+}
+
+impl Read for Filler {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.remaining.min(buf.len() as u64) as usize;
+        self.rng.fill_bytes(&mut buf[..n]);
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+impl Padding {
+    // The spread as entered during setup: 0 for equal sizes, or a percentage up to 100
+    pub fn parse(spread: &str, rng: &mut (impl RngCore + CryptoRng + ?Sized)) -> Result<Self, String> {
+        let spread_percent: u64 = spread
+            .trim()
+            .trim_end_matches('%')
+            .parse()
+            .map_err(|_| format!("Expected 0 or a percentage for the padding, not {}", spread))?;
+        if spread_percent > 100 {
+            return Err("Fragments can be padded to at most 100% more than the largest".to_string());
+        }
+        let mut secret = [0u8; 32];
+        rng.fill_bytes(&mut secret);
+        Ok(Self { secret: hex::encode(secret), spread_percent })
+    }
+
+    fn prf(&self, label: &[u8], generation: u64, index: u64) -> ChaCha20Rng {
+        let mut mac = HmacSha256::new_from_slice(self.secret.as_bytes()).expect("HMAC can take key of any size");
+        mac.update(label);
+        mac.update(&generation.to_le_bytes());
+        mac.update(&index.to_le_bytes());
+        ChaCha20Rng::from_seed(mac.finalize().into_bytes().into())
+    }
+
+    // A padded size for a fragment when the biggest holds `largest` bytes of chunks
+    pub fn target(&self, largest: u64, rng: &mut (impl RngCore + ?Sized)) -> u64 {
+        let extra = (largest as u128 * self.spread_percent as u128 / 100) as u64;
+        rng.gen_range(largest..=largest + extra)
+    }
+
+    // Bytes of padding for each fragment holding `chunk_bytes`, on lock `generation`
+    pub fn lengths(&self, chunk_bytes: &[u64], generation: u64) -> Vec<u64> {
+        let largest = chunk_bytes.iter().copied().max().unwrap_or(0);
+        let mut rng = self.prf(b"lengths", generation, 0);
+        chunk_bytes.iter().map(|&bytes| self.target(largest, &mut rng) - bytes).collect()
+    }
+
+    // The padding of fragment `index` on lock `generation`
+    pub fn filler(&self, generation: u64, index: usize, len: u64) -> Filler {
+        Filler::new(self.prf(b"padding", generation, index as u64), len)
+    }
+
+    // Roughly how much padding adds to the fragments, as a percentage of the chunk data, when
+    // they hold `chunk_counts` chunks of about equal length
+    pub fn overhead_percent(&self, chunk_counts: &[usize]) -> u64 {
+        let total: u64 = chunk_counts.iter().map(|&count| count as u64).sum();
+        let largest = chunk_counts.iter().copied().max().unwrap_or(0) as u64;
+        if total == 0 {
+            return 0;
+        }
+        // On average a fragment is padded to half the spread above the largest
+        let padded = chunk_counts.len() as u64 * largest * (200 + self.spread_percent);
+        (padded - total * 200) * 100 / (total * 200)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    fn padding(spread: &str) -> Padding {
+        Padding::parse(spread, &mut OsRng).unwrap()
+    }
+
+    fn fill(mut filler: Filler) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        filler.read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn filler_depends_on_lock_and_fragment_only() {
+        let padding = padding("20");
+        let bytes = fill(padding.filler(3, 1, 5000));
+        assert_eq!(bytes.len(), 5000);
+        assert!(bytes == fill(padding.filler(3, 1, 5000)));
+        // A shorter filler is the start of a longer one
+        assert!(bytes[..100] == fill(padding.filler(3, 1, 100)));
+        for other in [padding.filler(4, 1, 5000), padding.filler(3, 2, 5000), self::padding("20").filler(3, 1, 5000)] {
+            assert!(fill(other) != bytes);
+        }
+        assert!(fill(Filler::empty()).is_empty());
+    }
+
+    #[test]
+    fn lengths_depend_on_lock_only() {
+        let padding = padding("50");
+        let chunk_bytes = [1000, 3000, 2000, 0];
+        let lengths = padding.lengths(&chunk_bytes, 7);
+        assert_eq!(lengths, padding.lengths(&chunk_bytes, 7));
+        assert!((8..40).any(|generation| padding.lengths(&chunk_bytes, generation) != lengths));
+    }
+
+    #[test]
+    fn padded_sizes_stay_within_the_spread() {
+        let chunk_bytes = [1000, 3000, 2000, 0];
+        let equal = padding("0");
+        for generation in 0..20 {
+            let lengths = equal.lengths(&chunk_bytes, generation);
+            assert_eq!(lengths, vec![2000, 0, 1000, 3000]);
+        }
+        let spread = padding("50%");
+        for generation in 0..200 {
+            for (bytes, padding) in chunk_bytes.iter().zip(spread.lengths(&chunk_bytes, generation)) {
+                assert!((3000..=4500).contains(&(bytes + padding)));
+            }
+        }
+        assert!(Padding::parse("101", &mut OsRng).is_err());
+        assert!(Padding::parse("some", &mut OsRng).is_err());
+    }
+
+    #[test]
+    fn overhead_follows_the_spread() {
+        assert_eq!(padding("0").overhead_percent(&[4, 4, 4]), 0);
+        assert_eq!(padding("0").overhead_percent(&[2, 4]), 33);
+        assert_eq!(padding("100").overhead_percent(&[4, 4]), 50);
+        assert_eq!(padding("100").overhead_percent(&[]), 0);
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::HashSet;
+use rand::{CryptoRng, RngCore};
+use crate::filesys;
+use crate::metadata::VaultMetadata;
+use crate::naming::Namer;
+use crate::store;
+
+// Picks a fresh local directory for every selected fragment, avoiding the directory the
+// fragment already lives in and any directory that would clash with an existing file of the
+// same name. Fragments kept on a remote store are not moved and keep their location.
+pub fn pick_new_locations(metadata: &VaultMetadata, selected: &[usize], base_path: &str, rng: &mut (impl RngCore + CryptoRng + ?Sized)) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    // Ask for extra candidates since some are filtered out below
+    let local_count = selected.iter().filter(|&&i| store::is_local(&metadata.fragments[i].location)).count();
+    let candidates = filesys::get_random_directories(local_count * 3 + 3, base_path, rng);
+    let mut used = HashSet::new();
+    let mut picked = Vec::with_capacity(selected.len());
+
+    for &index in selected {
+        let fragment = &metadata.fragments[index];
+        if !store::is_local(&fragment.location) {
+            picked.push(fragment.location.clone());
+            continue;
+        }
+        let choice = candidates.iter().find(|dir| {
+            dir.as_str() != fragment.location
+                && !used.contains(dir.as_str())
+                && !std::path::Path::new(dir).join(&fragment.filename).exists()
+        });
+        match choice {
+            Some(dir) => {
+                used.insert(dir.as_str());
+                picked.push(dir.clone());
+            }
+            None => return Err(format!("Could not find a new directory for fragment {}", fragment.filename).into()),
+        }
+    }
+    Ok(picked)
+}
+
+// Moves the selected fragments (all of them if `selected` is empty) to new locations, renamed
+// to suit them under the vault's naming policy.
+// Every fragment is copied and checked first, then the metadata is rewritten through `save`,
+// and only after that are the originals securely deleted. A failure part way through leaves
+// the old fragments and metadata in place.
+pub fn relocate_fragments(
+    metadata: &mut VaultMetadata,
+    selected: &[usize],
+    base_path: &str,
+    rng: &mut (impl RngCore + CryptoRng + ?Sized),
+    save: impl FnOnce(&VaultMetadata) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let selected: Vec<usize> = if selected.is_empty() {
+        (0..metadata.fragments.len()).collect()
+    } else {
+        selected.to_vec()
+    };
+    let new_locations = pick_new_locations(metadata, &selected, base_path, rng)?;
+
+    // (fragment, old location, new location, old name, new name) of every fragment that actually moves
+    let mut namer = Namer::new(metadata.naming, metadata.carrier);
+    let mut moves: Vec<(usize, String, String, String, String)> = Vec::new();
+    for (&index, new_location) in selected.iter().zip(&new_locations) {
+        let fragment = &metadata.fragments[index];
+        if fragment.location != *new_location {
+            let new_name = namer.name_moved(fragment, new_location, rng)?;
+            moves.push((index, fragment.location.clone(), new_location.clone(), fragment.filename.clone(), new_name));
+        }
+    }
+
+    let mut copied: Vec<&(usize, String, String, String, String)> = Vec::new();
+    for entry in &moves {
+        let (_, old, new, name, new_name) = entry;
+        match store::copy_verified(old, new, name, new_name) {
+            Ok(()) => {
+                println!("  {} -> {}", store::describe(old, name), store::describe(new, new_name));
+                copied.push(entry);
+            }
+            Err(e) => {
+                for (_, _, new, _, new_name) in &copied {
+                    let _ = store::open_store(new).and_then(|s| s.delete(new_name));
+                }
+                return Err(e.into());
+            }
+        }
+    }
+
+    let mut updated = metadata.clone();
+    for (index, _, new_location, _, new_name) in &moves {
+        updated.fragments[*index].location = new_location.clone();
+        updated.fragments[*index].filename = new_name.clone();
+    }
+    updated.dirs = updated.fragments.iter().map(|f| f.location.clone()).collect();
+    if let Err(e) = save(&updated) {
+        for (_, _, new, _, new_name) in &copied {
+            let _ = store::open_store(new).and_then(|s| s.delete(new_name));
+        }
+        return Err(e);
+    }
+    *metadata = updated;
+
+    for (_, old, _, name, _) in &moves {
+        if let Err(e) = store::open_store(old).and_then(|s| s.delete(name)) {
+            eprintln!("Warning: failed to securely delete {}: {}", store::describe(old, name), e);
+        }
+    }
+    Ok(())
+}
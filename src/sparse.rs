@@ -0,0 +1,249 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+// Sparse-aware packing of the locker image before it is encrypted. The image is read in fixed
+// blocks; blocks that are entirely zero (never written, or unallocated in a sparse file) are
+// only recorded here, and the packed file holds just the remaining blocks back to back. Fragment
+// sizes then follow the data actually in use instead of the size of the disk.
+
+pub const BLOCK_SIZE: u64 = 64 * 1024;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SparseMap {
+    pub block_size: u64,
+    // Size of the original image in bytes
+    pub image_size: u64,
+    // Runs of zero blocks as [first block, block count], in ascending order
+    pub holes: Vec<[u64; 2]>,
+}
+
+impl SparseMap {
+    pub fn hole_bytes(&self) -> u64 {
+        // The last block can be short, so clamp each run to the end of the image
+        self.holes
+            .iter()
+            .map(|&[start, count]| ((start + count) * self.block_size).min(self.image_size).saturating_sub(start * self.block_size))
+            .sum()
+    }
+
+    pub fn is_well_formed(&self) -> bool {
+        let blocks = self.image_size.div_ceil(self.block_size.max(1));
+        let mut next_free = 0;
+        self.block_size > 0
+            && self.holes.iter().all(|&[start, count]| {
+                let ok = count > 0 && start >= next_free && start.checked_add(count).is_some_and(|end| end <= blocks);
+                next_free = start + count;
+                ok
+            })
+    }
+
+    // Stretches of the image that hold data, as [offset in the image, offset in the packed
+    // file, length], in ascending order. Everything between them is zeros.
+    pub fn data_runs(&self) -> Vec<[u64; 3]> {
+        let mut runs = Vec::new();
+        let mut image_offset = 0;
+        let mut packed_offset = 0;
+        for &[start, count] in &self.holes {
+            let hole_start = (start * self.block_size).min(self.image_size);
+            if hole_start > image_offset {
+                runs.push([image_offset, packed_offset, hole_start - image_offset]);
+                packed_offset += hole_start - image_offset;
+            }
+            image_offset = ((start + count) * self.block_size).min(self.image_size);
+        }
+        if image_offset < self.image_size {
+            runs.push([image_offset, packed_offset, self.image_size - image_offset]);
+        }
+        runs
+    }
+}
+
+// The parts of `runs` (as returned by data_runs) that fall within `len` bytes at `offset` of
+// the image, clipped to that range
+pub fn runs_in(runs: &[[u64; 3]], offset: u64, len: u64) -> Vec<[u64; 3]> {
+    let end = offset + len;
+    runs.iter()
+        .skip(runs.partition_point(|&[start, _, len]| start + len <= offset))
+        .take_while(|&&[start, _, _]| start < end)
+        .map(|&[start, packed, len]| {
+            let from = start.max(offset);
+            let to = (start + len).min(end);
+            [from, packed + (from - start), to - from]
+        })
+        .collect()
+}
+
+fn read_block(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+// Finds the zero blocks of `image_path` and returns the map needed to pack and later rebuild it
+pub fn scan(image_path: &Path) -> io::Result<SparseMap> {
+    let image = File::open(image_path)?;
+    let image_size = image.metadata()?.len();
+    let mut reader = BufReader::with_capacity(BLOCK_SIZE as usize, image);
+    let mut map = SparseMap { block_size: BLOCK_SIZE, image_size, holes: Vec::new() };
+    let mut buffer = vec![0u8; BLOCK_SIZE as usize];
+
+    let mut block = 0u64;
+    loop {
+        let n = read_block(&mut reader, &mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        if buffer[..n].iter().all(|&b| b == 0) {
+            match map.holes.last_mut() {
+                Some(last) if last[0] + last[1] == block => last[1] += 1,
+                _ => map.holes.push([block, 1]),
+            }
+        }
+        block += 1;
+    }
+    Ok(map)
+}
+
+// Writes the data blocks of `image_path` listed by `map` to `out` back to back. The image must
+// not have changed size since it was scanned.
+pub fn write_packed(image_path: &Path, map: &SparseMap, out: &mut dyn Write) -> io::Result<()> {
+    let mut image = File::open(image_path)?;
+    if image.metadata()?.len() != map.image_size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "The locker image changed size while it was being locked"));
+    }
+    for [offset, _, len] in map.data_runs() {
+        image.seek(SeekFrom::Start(offset))?;
+        let copied = io::copy(&mut BufReader::with_capacity(BLOCK_SIZE as usize, (&mut image).take(len)), out)?;
+        if copied != len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The locker image changed size while it was being locked"));
+        }
+    }
+    Ok(())
+}
+
+// Rebuilds the original image from the packed data in `packed`, which is read to the end.
+// Holes are skipped with a seek rather than written, so the rebuilt image is sparse again where
+// the filesystem supports it.
+pub fn unpack(packed: &mut dyn Read, map: &SparseMap, image_path: &Path) -> io::Result<()> {
+    if !map.is_well_formed() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Sparse map in the fragment info is corrupt"));
+    }
+    let image = File::create(image_path)?;
+    image.set_len(map.image_size)?;
+    let mut writer = BufWriter::new(image);
+    let mut buffer = vec![0u8; map.block_size as usize];
+    for [offset, _, len] in map.data_runs() {
+        writer.seek(SeekFrom::Start(offset))?;
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(map.block_size) as usize;
+            packed.read_exact(&mut buffer[..n]).map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => io::Error::new(io::ErrorKind::InvalidData, "Packed image is shorter than the sparse map expects"),
+                _ => e,
+            })?;
+            writer.write_all(&buffer[..n])?;
+            done += n as u64;
+        }
+    }
+    if packed.read(&mut buffer[..1])? != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Packed image is longer than the sparse map expects"));
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_path(what: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("sdfs_sparse_test_{}_{:016x}", what, rand::random::<u64>()))
+    }
+
+    // Image made of whole blocks that are zero or not, plus `tail` bytes of a last, short block
+    fn image(blocks: &[bool], tail: Option<bool>, tail_len: usize) -> Vec<u8> {
+        let block = |data: bool, len: usize| (0..len).map(move |i| if data { (i % 251) as u8 | 1 } else { 0 });
+        let mut image: Vec<u8> = blocks.iter().flat_map(|&data| block(data, BLOCK_SIZE as usize)).collect();
+        if let Some(data) = tail {
+            image.extend(block(data, tail_len));
+        }
+        image
+    }
+
+    // Packs `image` and rebuilds it, returning the map and the packed bytes
+    fn roundtrip(image: &[u8]) -> (SparseMap, Vec<u8>) {
+        let source = temp_path("image");
+        let rebuilt = temp_path("rebuilt");
+        fs::write(&source, image).unwrap();
+        let map = scan(&source).unwrap();
+        assert!(map.is_well_formed());
+        let mut packed = Vec::new();
+        write_packed(&source, &map, &mut packed).unwrap();
+        assert_eq!(packed.len() as u64, map.image_size - map.hole_bytes());
+        unpack(&mut packed.as_slice(), &map, &rebuilt).unwrap();
+        assert!(fs::read(&rebuilt).unwrap() == image);
+        fs::remove_file(&source).unwrap();
+        fs::remove_file(&rebuilt).unwrap();
+        (map, packed)
+    }
+
+    #[test]
+    fn leading_and_trailing_zero_blocks() {
+        let (map, packed) = roundtrip(&image(&[false, false, true, false, true, false, false], None, 0));
+        assert_eq!(map.holes, vec![[0, 2], [3, 1], [5, 2]]);
+        assert_eq!(packed.len() as u64, 2 * BLOCK_SIZE);
+        assert_eq!(map.data_runs(), vec![[2 * BLOCK_SIZE, 0, BLOCK_SIZE], [4 * BLOCK_SIZE, BLOCK_SIZE, BLOCK_SIZE]]);
+    }
+
+    #[test]
+    fn short_last_block() {
+        // A zero tail is a hole that ends with the image, a data tail is packed as it is
+        let (map, packed) = roundtrip(&image(&[true, false], Some(false), 1000));
+        assert_eq!(map.holes, vec![[1, 2]]);
+        assert_eq!(map.hole_bytes(), BLOCK_SIZE + 1000);
+        assert_eq!(packed.len() as u64, BLOCK_SIZE);
+        let (map, packed) = roundtrip(&image(&[false, true], Some(true), 1000));
+        assert_eq!(map.holes, vec![[0, 1]]);
+        assert_eq!(packed.len() as u64, BLOCK_SIZE + 1000);
+    }
+
+    #[test]
+    fn all_zero_and_empty_images() {
+        let (map, packed) = roundtrip(&image(&[false; 4], Some(false), 10));
+        assert_eq!(map.holes, vec![[0, 5]]);
+        assert!(packed.is_empty() && map.data_runs().is_empty());
+        let (map, packed) = roundtrip(&[]);
+        assert!(map.holes.is_empty() && packed.is_empty());
+        let (map, _) = roundtrip(&image(&[true; 3], None, 0));
+        assert!(map.holes.is_empty());
+    }
+
+    #[test]
+    fn packed_data_must_fit_the_map() {
+        let map = SparseMap { block_size: BLOCK_SIZE, image_size: 3 * BLOCK_SIZE, holes: vec![[1, 1]] };
+        let target = temp_path("mismatch");
+        let fits = vec![1u8; 2 * BLOCK_SIZE as usize];
+        assert!(unpack(&mut &fits[1..], &map, &target).is_err());
+        assert!(unpack(&mut [fits.as_slice(), &[1]].concat().as_slice(), &map, &target).is_err());
+        let overlapping = SparseMap { holes: vec![[0, 2], [1, 1]], ..map };
+        assert!(unpack(&mut fits.as_slice(), &overlapping, &target).is_err());
+        let _ = fs::remove_file(&target);
+    }
+
+    #[test]
+    fn runs_are_clipped_to_the_range() {
+        let runs = vec![[0, 0, 100], [200, 100, 100]];
+        assert_eq!(runs_in(&runs, 50, 200), vec![[50, 50, 50], [200, 100, 50]]);
+        assert!(runs_in(&runs, 100, 100).is_empty());
+        assert_eq!(runs_in(&runs, 250, 1000), vec![[250, 150, 50]]);
+    }
+}
@@ -0,0 +1,1071 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use crate::filesys;
+
+// Where fragment files live. A fragment's location is either a plain directory (the original
+// format) or a URI naming one of the backends below:
+//
+//   file:///srv/fragments
+//   s3://bucket/prefix?endpoint=http://127.0.0.1:9000&region=us-east-1
+//   sftp://user@host:22/home/user/fragments
+//   dav://host/remote.php/webdav/fragments      (davs:// for HTTPS)
+//
+// S3 credentials come from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, WebDAV credentials from
+// SDFS_WEBDAV_USER and SDFS_WEBDAV_PASSWORD, and SFTP uses the normal ssh keys and agent, so no
+// secrets end up in the fragment info. The remote backends shell out to curl and sftp.
+pub trait FragmentStore {
+    // Stores the `len` bytes `data` holds as `name`, replacing any existing object; readers
+    // never see a partial object. Fails if `data` holds more or fewer bytes.
+    fn put(&self, name: &str, data: &mut dyn Read, len: u64) -> io::Result<()>;
+    fn get(&self, name: &str) -> io::Result<Box<dyn Read>>;
+    // `len` bytes of `name` starting at `offset`. Stores that can fetch a range directly
+    // override this; the fallback reads the object from the start and discards the prefix.
+    fn get_range(&self, name: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        read_range(self.get(name)?, &self.describe(name), offset, len)
+    }
+    fn delete(&self, name: &str) -> io::Result<()>;
+    // Moves `from` to `to`, replacing anything already called `to`
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+    fn exists(&self, name: &str) -> io::Result<bool>;
+    // Size of `name` in bytes
+    fn size(&self, name: &str) -> io::Result<u64>;
+    fn list(&self) -> io::Result<Vec<String>>;
+    // Human-readable location of `name`, for messages
+    fn describe(&self, name: &str) -> String;
+}
+
+pub fn is_local(location: &str) -> bool {
+    !location.contains("://") || location.starts_with("file://")
+}
+
+pub fn open_store(location: &str) -> io::Result<Box<dyn FragmentStore>> {
+    if is_local(location) {
+        let dir = location.strip_prefix("file://").unwrap_or(location);
+        return Ok(Box::new(LocalStore { dir: PathBuf::from(dir) }));
+    }
+    let (scheme, rest) = location.split_once("://").unwrap_or(("", location));
+    match scheme {
+        "s3" => Ok(Box::new(S3Store::parse(rest)?)),
+        "sftp" => Ok(Box::new(SftpStore::parse(rest)?)),
+        "dav" => Ok(Box::new(WebDavStore::new(format!("http://{}", rest.trim_end_matches('/'))))),
+        "davs" => Ok(Box::new(WebDavStore::new(format!("https://{}", rest.trim_end_matches('/'))))),
+        _ => Err(invalid(format!("Unsupported fragment store: {}", location))),
+    }
+}
+
+pub fn describe(location: &str, name: &str) -> String {
+    match open_store(location) {
+        Ok(store) => store.describe(name),
+        Err(_) => format!("{}/{}", location, name),
+    }
+}
+
+// Passes data through while hashing it
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+// Copies `name` from one location to another, where it is stored as `new_name`, and reads the
+// copy back to check it against what was sent. A copy that does not match is removed again.
+pub fn copy_verified(from: &str, to: &str, name: &str, new_name: &str) -> io::Result<()> {
+    let source = open_store(from)?;
+    let dest = open_store(to)?;
+    if dest.exists(new_name)? {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", dest.describe(new_name))));
+    }
+
+    let len = source.size(name)?;
+    let mut reader = HashingReader { inner: source.get(name)?, hasher: Sha256::new() };
+    dest.put(new_name, &mut reader, len)?;
+    let sent = reader.hasher.finalize();
+
+    let mut check = HashingReader { inner: dest.get(new_name)?, hasher: Sha256::new() };
+    io::copy(&mut check, &mut io::sink())?;
+    if check.hasher.finalize() != sent {
+        let _ = dest.delete(new_name);
+        return Err(io::Error::other(format!("Copy of {} did not match the original", source.describe(name))));
+    }
+    Ok(())
+}
+
+// Skips to `offset` in a stream and reads `len` bytes from there
+fn read_range(mut reader: Box<dyn Read>, what: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let skipped = io::copy(&mut (&mut reader).take(offset), &mut io::sink())?;
+    let mut data = vec![0u8; len];
+    if skipped < offset {
+        return Err(short_range(what, offset, len));
+    }
+    reader.read_exact(&mut data).map_err(|_| short_range(what, offset, len))?;
+    Ok(data)
+}
+
+// Copies exactly `len` bytes from `data` to `writer`, and checks that `data` ends there
+fn copy_exact(data: &mut dyn Read, writer: &mut dyn Write, len: u64, what: &str) -> io::Result<()> {
+    let copied = io::copy(&mut data.take(len), writer)?;
+    if copied < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{} ended after {} of {} bytes", what, copied, len)));
+    }
+    if data.read(&mut [0u8; 1])? != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} holds more than {} bytes", what, len)));
+    }
+    Ok(())
+}
+
+fn short_range(what: &str, offset: u64, len: usize) -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, format!("{} ends before byte {}", what, offset + len as u64))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+pub fn temp_path(tag: &str) -> PathBuf {
+    let suffix: u64 = rand::thread_rng().gen();
+    env::temp_dir().join(format!("sdfs_{}_{:016x}", tag, suffix))
+}
+
+// Creates a file in the shared temp directory that only the current user can read, failing
+// rather than reusing anything already there
+pub fn create_private(path: &Path) -> io::Result<File> {
+    let mut options = fs::OpenOptions::new();
+    options.read(true).write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(path)
+}
+
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    let mut file = create_private(path)?;
+    file.write_all(contents.as_bytes())
+}
+
+pub struct LocalStore {
+    dir: PathBuf,
+}
+
+impl FragmentStore for LocalStore {
+    fn put(&self, name: &str, data: &mut dyn Read, len: u64) -> io::Result<()> {
+        let partial = self.dir.join(format!("{}.part", name));
+        let written = File::create(&partial).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            copy_exact(data, &mut writer, len, &self.describe(name))?;
+            writer.flush()?;
+            writer.get_ref().sync_all()
+        });
+        // A source that fails part-way (e.g. a cancelled FragmentWriter) leaves nothing behind
+        if let Err(e) = written {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+        fs::rename(&partial, self.dir.join(name))
+    }
+
+    fn get(&self, name: &str) -> io::Result<Box<dyn Read>> {
+        Ok(Box::new(BufReader::new(File::open(self.dir.join(name))?)))
+    }
+
+    fn get_range(&self, name: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut file = File::open(self.dir.join(name))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0u8; len];
+        file.read_exact(&mut data).map_err(|_| short_range(&self.describe(name), offset, len))?;
+        Ok(data)
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        filesys::secure_delete(&self.dir.join(name))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(self.dir.join(from), self.dir.join(to))
+    }
+
+    fn exists(&self, name: &str) -> io::Result<bool> {
+        Ok(self.dir.join(name).exists())
+    }
+
+    fn size(&self, name: &str) -> io::Result<u64> {
+        Ok(fs::metadata(self.dir.join(name))?.len())
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        Ok(names)
+    }
+
+    fn describe(&self, name: &str) -> String {
+        self.dir.join(name).display().to_string()
+    }
+}
+
+// Stdout of a child process; reaching EOF waits for the child and turns a failed exit into an
+// error, so a download that dies half way is never mistaken for a short file
+struct ChildReader {
+    child: Child,
+    stdout: ChildStdout,
+    what: String,
+    // Keeps curl's config file around for as long as curl is running
+    _curl: Curl,
+}
+
+impl Read for ChildReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!("{} failed ({})", self.what, status)));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for ChildReader {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Credentials are handed to curl through a temporary config file rather than on the command
+// line, so they don't show up in the process list
+struct Curl {
+    args: Vec<String>,
+    config: Option<PathBuf>,
+}
+
+impl Curl {
+    fn new(url: &str, user: Option<String>, extra_config: &[String]) -> io::Result<Self> {
+        let mut config_lines: Vec<String> = extra_config.to_vec();
+        if let Some(user) = user {
+            config_lines.push(format!("user = \"{}\"", user.replace('\\', "\\\\").replace('"', "\\\"")));
+        }
+        let config = if config_lines.is_empty() {
+            None
+        } else {
+            let path = temp_path("curl");
+            write_private(&path, &config_lines.join("\n"))?;
+            Some(path)
+        };
+        let mut args = vec!["--silent".to_string(), "--show-error".to_string(), url.to_string()];
+        if let Some(path) = &config {
+            args.push("--config".to_string());
+            args.push(path.to_string_lossy().into_owned());
+        }
+        Ok(Self { args, config })
+    }
+
+    fn arg(mut self, arg: &str) -> Self {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new("curl");
+        command.args(&self.args);
+        command
+    }
+
+    // Runs the request and returns (HTTP status, body)
+    fn status(self) -> io::Result<(u16, Vec<u8>)> {
+        let output = self.arg("--write-out").arg("\n%{http_code}").command().output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!("curl failed: {}", String::from_utf8_lossy(&output.stderr).trim())));
+        }
+        let mut body = output.stdout;
+        let split = body.iter().rposition(|&b| b == b'\n').unwrap_or(0);
+        let code = String::from_utf8_lossy(&body[split..]).trim().parse().unwrap_or(0);
+        body.truncate(split);
+        Ok((code, body))
+    }
+
+    // Sends `body` as the request body and returns (HTTP status, ETag header, response body)
+    fn send(self, body: &[u8]) -> io::Result<(u16, String, Vec<u8>)> {
+        // Kept until curl exits, since dropping it removes the config file
+        let curl = self
+            .arg("--data-binary").arg("@-")
+            .arg("--header").arg("Content-Type: application/octet-stream")
+            .arg("--write-out").arg("\n%header{etag}\n%{http_code}");
+        let mut child = curl
+            .command()
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // curl reads the whole body before it sends anything back
+        let written = child.stdin.take().expect("stdin is piped").write_all(body);
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!("curl failed: {}", String::from_utf8_lossy(&output.stderr).trim())));
+        }
+        written?;
+        let mut response = output.stdout;
+        let split = response.iter().rposition(|&b| b == b'\n').unwrap_or(0);
+        let code = String::from_utf8_lossy(&response[split..]).trim().parse().unwrap_or(0);
+        response.truncate(split);
+        let split = response.iter().rposition(|&b| b == b'\n').unwrap_or(0);
+        let etag = String::from_utf8_lossy(&response[split..]).trim().to_string();
+        response.truncate(split);
+        Ok((code, etag, response))
+    }
+
+    // Streams `len` bytes from `data` as the request body. The length is sent up front rather
+    // than chunked, which not every server takes.
+    fn upload(self, data: &mut dyn Read, len: u64, what: &str) -> io::Result<()> {
+        let curl = self
+            .arg("--fail")
+            .arg("--upload-file").arg("-")
+            .arg("--header").arg(&format!("Content-Length: {}", len))
+            .arg("--header").arg("Transfer-Encoding:");
+        let mut child = curl
+            .command()
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()?;
+        let copied = copy_exact(data, child.stdin.as_mut().expect("stdin is piped"), len, what);
+        drop(child.stdin.take());
+        let status = child.wait()?;
+        copied?;
+        if !status.success() {
+            return Err(io::Error::other(format!("upload failed ({})", status)));
+        }
+        Ok(())
+    }
+
+    // HTTP range request; both S3 and WebDAV servers answer with 206 and just those bytes
+    fn download_range(self, what: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let range = format!("{}-{}", offset, offset + len as u64 - 1);
+        let (code, body) = self.arg("--range").arg(&range).status()?;
+        expect_status(code, &[206], &format!("Range request for {}", what))?;
+        if body.len() != len {
+            return Err(short_range(what, offset, len));
+        }
+        Ok(body)
+    }
+
+    fn download(self, what: String) -> io::Result<Box<dyn Read>> {
+        let curl = self.arg("--fail");
+        let mut child = curl.command().stdout(Stdio::piped()).spawn()?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        Ok(Box::new(ChildReader { child, stdout, what, _curl: curl }))
+    }
+}
+
+impl Drop for Curl {
+    fn drop(&mut self) {
+        if let Some(path) = &self.config {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// Value of header `name` in the headers curl prints for --head
+fn header_value(headers: &[u8], name: &str) -> Option<String> {
+    String::from_utf8_lossy(headers).lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim().to_string())
+    })
+}
+
+fn content_length(headers: &[u8], what: &str) -> io::Result<u64> {
+    header_value(headers, "Content-Length")
+        .and_then(|len| len.parse().ok())
+        .ok_or_else(|| io::Error::other(format!("{} did not report its size", what)))
+}
+
+fn expect_status(code: u16, allowed: &[u16], what: &str) -> io::Result<()> {
+    if allowed.contains(&code) {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("{} returned HTTP {}", what, code)))
+    }
+}
+
+// Pulls the text of every <tag>...</tag> (with any namespace prefix) out of an XML listing
+fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let Some(end) = rest.find('>') else { break };
+        let name = &rest[..end];
+        let local = name.rsplit(':').next().unwrap_or(name);
+        rest = &rest[end + 1..];
+        if local.eq_ignore_ascii_case(tag) {
+            if let Some(close) = rest.find("</") {
+                values.push(rest[..close].trim().to_string());
+            }
+        }
+    }
+    values
+}
+
+// Encodes everything but the unreserved characters of RFC 3986, which is also how SigV4 wants
+// every path segment and query value encoded
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => char::from(b).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// Encodes each segment of a slash-separated path
+fn percent_encode_path(path: &str) -> String {
+    path.split('/').map(percent_encode).collect::<Vec<_>>().join("/")
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Ok(b) = u8::from_str_radix(&value[i + 1..i + 3], 16) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// Objects bigger than this are uploaded in parts of this size, so every request carries its
+// length without the whole object being held in memory
+const S3_PART_SIZE: u64 = 8 * 1024 * 1024;
+// Largest object S3 copies in a single request
+const S3_MAX_COPY: u64 = 5 * 1024 * 1024 * 1024;
+
+// S3-compatible object store (AWS, MinIO, ...) using path-style URLs and curl's SigV4 signing.
+// Query parameters are written in sorted order, the way SigV4 signs them.
+pub struct S3Store {
+    endpoint: String,
+    region: String,
+    bucket: String,
+    prefix: String,
+}
+
+impl S3Store {
+    fn parse(rest: &str) -> io::Result<Self> {
+        let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() {
+            return Err(invalid("S3 location is missing a bucket".to_string()));
+        }
+        let mut endpoint = "https://s3.amazonaws.com".to_string();
+        let mut region = "us-east-1".to_string();
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            match pair.split_once('=') {
+                Some(("endpoint", value)) => endpoint = value.trim_end_matches('/').to_string(),
+                Some(("region", value)) => region = value.to_string(),
+                _ => return Err(invalid(format!("Unknown S3 option: {}", pair))),
+            }
+        }
+        let prefix = prefix.trim_matches('/');
+        Ok(Self {
+            endpoint,
+            region,
+            bucket: bucket.to_string(),
+            prefix: if prefix.is_empty() { String::new() } else { format!("{}/", prefix) },
+        })
+    }
+
+    fn curl(&self, url: &str) -> io::Result<Curl> {
+        let id = env::var("AWS_ACCESS_KEY_ID").map_err(|_| invalid("AWS_ACCESS_KEY_ID is not set".to_string()))?;
+        let secret = env::var("AWS_SECRET_ACCESS_KEY").map_err(|_| invalid("AWS_SECRET_ACCESS_KEY is not set".to_string()))?;
+        let signing = format!("aws-sigv4 = \"aws:amz:{}:s3\"", self.region);
+        // S3 wants the payload hash in a header, which curl only fills in on request
+        let payload_hash = "header = \"x-amz-content-sha256: UNSIGNED-PAYLOAD\"".to_string();
+        Curl::new(url, Some(format!("{}:{}", id, secret)), &[signing, payload_hash])
+    }
+
+    fn object_url(&self, name: &str) -> String {
+        format!("{}/{}/{}", self.endpoint, self.bucket, percent_encode_path(&format!("{}{}", self.prefix, name)))
+    }
+
+    fn upload_parts(&self, url: &str, upload_id: &str, data: &mut dyn Read, len: u64, what: &str) -> io::Result<()> {
+        let mut etags = Vec::new();
+        let mut part = Vec::new();
+        let mut sent = 0;
+        while sent < len {
+            let want = (len - sent).min(S3_PART_SIZE);
+            part.clear();
+            (&mut *data).take(want).read_to_end(&mut part)?;
+            if (part.len() as u64) < want {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{} ended after {} of {} bytes", what, sent + part.len() as u64, len)));
+            }
+            let part_url = format!("{}?partNumber={}&uploadId={}", url, etags.len() + 1, percent_encode(upload_id));
+            let (code, etag, _) = self.curl(&part_url)?.arg("--request").arg("PUT").send(&part)?;
+            expect_status(code, &[200], "S3 part upload")?;
+            etags.push(etag);
+            sent += want;
+        }
+        if data.read(&mut [0u8; 1])? != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} holds more than {} bytes", what, len)));
+        }
+        let parts: String = etags
+            .iter()
+            .enumerate()
+            .map(|(i, etag)| format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", i + 1, etag))
+            .collect();
+        let complete = format!("<CompleteMultipartUpload>{}</CompleteMultipartUpload>", parts);
+        let (code, _, body) = self.curl(&format!("{}?uploadId={}", url, percent_encode(upload_id)))?
+            .arg("--request").arg("POST")
+            .send(complete.as_bytes())?;
+        expect_status(code, &[200], "S3 complete multipart upload")?;
+        // Completing can still fail after the 200 has been sent
+        if String::from_utf8_lossy(&body).contains("<Error>") {
+            return Err(io::Error::other(format!("S3 could not complete the upload of {}", what)));
+        }
+        Ok(())
+    }
+
+    // Every object name under the prefix, `max_keys` per request if set
+    fn list_pages(&self, max_keys: Option<usize>) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut url = format!("{}/{}?", self.endpoint, self.bucket);
+            if let Some(token) = &token {
+                url.push_str(&format!("continuation-token={}&", percent_encode(token)));
+            }
+            url.push_str("list-type=2");
+            if let Some(max_keys) = max_keys {
+                url.push_str(&format!("&max-keys={}", max_keys));
+            }
+            url.push_str(&format!("&prefix={}", percent_encode(&self.prefix)));
+            let (code, body) = self.curl(&url)?.status()?;
+            expect_status(code, &[200], "S3 list")?;
+            let body = String::from_utf8_lossy(&body);
+            names.extend(
+                xml_values(&body, "Key")
+                    .into_iter()
+                    .filter_map(|key| key.strip_prefix(&self.prefix).map(str::to_string))
+                    .filter(|name| !name.contains('/')),
+            );
+            let truncated = xml_values(&body, "IsTruncated").first().is_some_and(|value| value == "true");
+            token = xml_values(&body, "NextContinuationToken").into_iter().next();
+            if !truncated || token.is_none() {
+                return Ok(names);
+            }
+        }
+    }
+}
+
+impl FragmentStore for S3Store {
+    fn put(&self, name: &str, data: &mut dyn Read, len: u64) -> io::Result<()> {
+        let url = self.object_url(name);
+        let what = self.describe(name);
+        if len <= S3_PART_SIZE {
+            let mut body = Vec::with_capacity(len as usize);
+            copy_exact(data, &mut body, len, &what)?;
+            let (code, _, _) = self.curl(&url)?.arg("--request").arg("PUT").send(&body)?;
+            return expect_status(code, &[200], "S3 PUT");
+        }
+        let (code, body) = self.curl(&format!("{}?uploads=", url))?.arg("--request").arg("POST").status()?;
+        expect_status(code, &[200], "S3 multipart upload")?;
+        let upload_id = xml_values(&String::from_utf8_lossy(&body), "UploadId")
+            .into_iter()
+            .next()
+            .ok_or_else(|| io::Error::other("S3 did not return an upload id"))?;
+        let result = self.upload_parts(&url, &upload_id, data, len, &what);
+        if result.is_err() {
+            // Parts of an abandoned upload are kept, and billed, until it is aborted
+            let abort = format!("{}?uploadId={}", url, percent_encode(&upload_id));
+            let _ = self.curl(&abort).and_then(|curl| curl.arg("--request").arg("DELETE").status());
+        }
+        result
+    }
+
+    fn get(&self, name: &str) -> io::Result<Box<dyn Read>> {
+        self.curl(&self.object_url(name))?.download(self.describe(name))
+    }
+
+    fn get_range(&self, name: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        self.curl(&self.object_url(name))?.download_range(&self.describe(name), offset, len)
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        let (code, _) = self.curl(&self.object_url(name))?.arg("--request").arg("DELETE").status()?;
+        expect_status(code, &[200, 204], "S3 DELETE")
+    }
+
+    // S3 has no rename, so the object is copied on the server and the original deleted. Objects
+    // too big for a server-side copy go through here instead.
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let size = self.size(from)?;
+        if size > S3_MAX_COPY {
+            self.put(to, &mut self.get(from)?, size)?;
+        } else {
+            let source = format!("x-amz-copy-source: /{}/{}", self.bucket, percent_encode_path(&format!("{}{}", self.prefix, from)));
+            let (code, _, body) = self.curl(&self.object_url(to))?
+                .arg("--request").arg("PUT")
+                .arg("--header").arg(&source)
+                .send(&[])?;
+            expect_status(code, &[200], "S3 copy")?;
+            // Like completing a multipart upload, a copy can fail after the 200
+            if String::from_utf8_lossy(&body).contains("<Error>") {
+                return Err(io::Error::other(format!("S3 could not copy {} to {}", self.describe(from), self.describe(to))));
+            }
+        }
+        self.delete(from)
+    }
+
+    fn exists(&self, name: &str) -> io::Result<bool> {
+        let (code, _) = self.curl(&self.object_url(name))?.arg("--head").status()?;
+        match code {
+            200 => Ok(true),
+            404 => Ok(false),
+            _ => Err(io::Error::other(format!("S3 HEAD returned HTTP {}", code))),
+        }
+    }
+
+    fn size(&self, name: &str) -> io::Result<u64> {
+        let (code, headers) = self.curl(&self.object_url(name))?.arg("--head").status()?;
+        expect_status(code, &[200], "S3 HEAD")?;
+        content_length(&headers, &self.describe(name))
+    }
+
+    // S3 returns at most 1000 keys per request and a token to ask for the next ones
+    fn list(&self) -> io::Result<Vec<String>> {
+        self.list_pages(None)
+    }
+
+    fn describe(&self, name: &str) -> String {
+        format!("s3://{}/{}{}", self.bucket, self.prefix, name)
+    }
+}
+
+pub struct WebDavStore {
+    base: String,
+}
+
+impl WebDavStore {
+    fn new(base: String) -> Self {
+        Self { base }
+    }
+
+    fn curl(&self, url: &str) -> io::Result<Curl> {
+        let user = match (env::var("SDFS_WEBDAV_USER"), env::var("SDFS_WEBDAV_PASSWORD")) {
+            (Ok(user), Ok(password)) => Some(format!("{}:{}", user, password)),
+            _ => None,
+        };
+        Curl::new(url, user, &[])
+    }
+
+    fn url(&self, name: &str) -> String {
+        format!("{}/{}", self.base, percent_encode(name))
+    }
+}
+
+impl FragmentStore for WebDavStore {
+    fn put(&self, name: &str, data: &mut dyn Read, len: u64) -> io::Result<()> {
+        // Upload under a temporary name and MOVE it into place so the real name is never partial
+        let partial = format!("{}.part", name);
+        self.curl(&self.url(&partial))?.upload(data, len, &self.describe(name))?;
+        self.rename(&partial, name)
+    }
+
+    fn get(&self, name: &str) -> io::Result<Box<dyn Read>> {
+        self.curl(&self.url(name))?.download(self.describe(name))
+    }
+
+    fn get_range(&self, name: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        self.curl(&self.url(name))?.download_range(&self.describe(name), offset, len)
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        let (code, _) = self.curl(&self.url(name))?.arg("--request").arg("DELETE").status()?;
+        expect_status(code, &[200, 204], "WebDAV DELETE")
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let (code, _) = self.curl(&self.url(from))?
+            .arg("--request").arg("MOVE")
+            .arg("--header").arg(&format!("Destination: {}", self.url(to)))
+            .arg("--header").arg("Overwrite: T")
+            .status()?;
+        expect_status(code, &[201, 204], "WebDAV MOVE")
+    }
+
+    fn exists(&self, name: &str) -> io::Result<bool> {
+        let (code, _) = self.curl(&self.url(name))?.arg("--head").status()?;
+        match code {
+            200 => Ok(true),
+            404 => Ok(false),
+            _ => Err(io::Error::other(format!("WebDAV HEAD returned HTTP {}", code))),
+        }
+    }
+
+    fn size(&self, name: &str) -> io::Result<u64> {
+        let (code, headers) = self.curl(&self.url(name))?.arg("--head").status()?;
+        expect_status(code, &[200], "WebDAV HEAD")?;
+        content_length(&headers, &self.describe(name))
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        let (code, body) = self.curl(&format!("{}/", self.base))?
+            .arg("--request").arg("PROPFIND")
+            .arg("--header").arg("Depth: 1")
+            .status()?;
+        expect_status(code, &[207], "WebDAV PROPFIND")?;
+        Ok(xml_values(&String::from_utf8_lossy(&body), "href")
+            .into_iter()
+            .filter(|href| !href.ends_with('/'))
+            .filter_map(|href| href.rsplit('/').next().map(percent_decode))
+            .collect())
+    }
+
+    fn describe(&self, name: &str) -> String {
+        format!("{}/{}", self.base, name)
+    }
+}
+
+// Characters a name can hold and still be used in every store. sftp batch scripts put paths in
+// double quotes, inside which a quote or backslash would end the path or escape the next
+// character, a line break would start a new command, and the wildcards still match other files.
+pub fn is_portable_name(name: &str) -> bool {
+    !name.chars().any(|c| matches!(c, '"' | '\\' | '*' | '?' | '[' | ']') || c.is_control())
+}
+
+fn check_quotable(path: &str) -> io::Result<()> {
+    if is_portable_name(path) {
+        Ok(())
+    } else {
+        Err(invalid(format!("{:?} can't be used with SFTP", path)))
+    }
+}
+
+// SFTP through the OpenSSH sftp client in batch mode, the same way filesys drives diskpart with
+// a script file. Transfers are staged through a temporary file since sftp cannot stream stdin.
+pub struct SftpStore {
+    target: String,
+    port: Option<String>,
+    dir: String,
+}
+
+impl SftpStore {
+    fn parse(rest: &str) -> io::Result<Self> {
+        let (authority, dir) = rest.split_once('/').unwrap_or((rest, ""));
+        if authority.is_empty() {
+            return Err(invalid("SFTP location is missing a host".to_string()));
+        }
+        let (target, port) = match authority.rsplit_once(':') {
+            Some((target, port)) => (target.to_string(), Some(port.to_string())),
+            None => (authority.to_string(), None),
+        };
+        check_quotable(dir)?;
+        Ok(Self { target, port, dir: format!("/{}", dir.trim_end_matches('/')) })
+    }
+
+    fn remote(&self, name: &str) -> String {
+        format!("{}/{}", self.dir.trim_end_matches('/'), name)
+    }
+
+    // Path of `name` on the server as it goes between the quotes of a batch script
+    fn quoted(&self, name: &str) -> io::Result<String> {
+        check_quotable(name)?;
+        Ok(self.remote(name))
+    }
+
+    // Runs a batch script; commands prefixed with '@' are not echoed back
+    fn batch(&self, script: &str) -> io::Result<std::process::Output> {
+        let script_path = temp_path("sftp_batch");
+        write_private(&script_path, script)?;
+        let mut command = Command::new("sftp");
+        command.arg("-q").arg("-b").arg(&script_path);
+        if let Some(port) = &self.port {
+            command.arg("-P").arg(port);
+        }
+        let output = command.arg(&self.target).output();
+        let _ = fs::remove_file(&script_path);
+        output
+    }
+
+    fn run(&self, script: &str) -> io::Result<String> {
+        let output = self.batch(script)?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!("sftp failed: {}", String::from_utf8_lossy(&output.stderr).trim())));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    // Reads a byte range on the server through ssh, so only the range crosses the network.
+    // sftp has no ranged get, and the server may not give a shell, so None means "fall back".
+    fn ssh_range(&self, name: &str, offset: u64, len: usize) -> Option<Vec<u8>> {
+        let mut command = Command::new("ssh");
+        command.arg("-o").arg("BatchMode=yes");
+        if let Some(port) = &self.port {
+            command.arg("-p").arg(port);
+        }
+        let path = self.remote(name).replace('\'', "'\\''");
+        command
+            .arg(&self.target)
+            .arg(format!("tail -c +{} '{}' | head -c {}", offset + 1, path, len))
+            .stdin(Stdio::null())
+            .stderr(Stdio::null());
+        let output = command.output().ok()?;
+        (output.status.success() && output.stdout.len() == len).then_some(output.stdout)
+    }
+}
+
+// A staged download that removes itself once the reader is dropped
+struct StagedFile {
+    reader: BufReader<File>,
+    path: PathBuf,
+}
+
+impl Read for StagedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        let _ = filesys::secure_delete(&self.path);
+    }
+}
+
+impl FragmentStore for SftpStore {
+    fn put(&self, name: &str, data: &mut dyn Read, len: u64) -> io::Result<()> {
+        let staged = temp_path("sftp_put");
+        let result = (|| {
+            let mut writer = BufWriter::new(create_private(&staged)?);
+            copy_exact(data, &mut writer, len, &self.describe(name))?;
+            writer.flush()?;
+            drop(writer);
+            let partial = self.quoted(&format!("{}.part", name))?;
+            self.run(&format!(
+                "@put \"{}\" \"{}\"\n-@rm \"{}\"\n@rename \"{}\" \"{}\"\n",
+                staged.display(), partial, self.quoted(name)?, partial, self.quoted(name)?
+            ))
+        })();
+        let _ = filesys::secure_delete(&staged);
+        result.map(|_| ())
+    }
+
+    fn get(&self, name: &str) -> io::Result<Box<dyn Read>> {
+        let staged = temp_path("sftp_get");
+        // sftp keeps the mode of a file it overwrites, so the download is never readable by others
+        drop(create_private(&staged)?);
+        if let Err(e) = self.run(&format!("@get \"{}\" \"{}\"\n", self.quoted(name)?, staged.display())) {
+            let _ = fs::remove_file(&staged);
+            return Err(e);
+        }
+        Ok(Box::new(StagedFile { reader: BufReader::new(File::open(&staged)?), path: staged }))
+    }
+
+    fn get_range(&self, name: &str, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        match self.ssh_range(name, offset, len) {
+            Some(data) => Ok(data),
+            None => read_range(self.get(name)?, &self.describe(name), offset, len),
+        }
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        self.run(&format!("@rm \"{}\"\n", self.quoted(name)?)).map(|_| ())
+    }
+
+    // sftp's rename won't replace a file, so whatever is at `to` goes first
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.run(&format!("-@rm \"{}\"\n@rename \"{}\" \"{}\"\n", self.quoted(to)?, self.quoted(from)?, self.quoted(to)?)).map(|_| ())
+    }
+
+    fn exists(&self, name: &str) -> io::Result<bool> {
+        Ok(self.batch(&format!("@ls \"{}\"\n", self.quoted(name)?))?.status.success())
+    }
+
+    fn size(&self, name: &str) -> io::Result<u64> {
+        let listing = self.run(&format!("@ls -ln \"{}\"\n", self.quoted(name)?))?;
+        listing
+            .split_whitespace()
+            .nth(4)
+            .and_then(|size| size.parse().ok())
+            .ok_or_else(|| io::Error::other(format!("sftp did not report the size of {}", self.describe(name))))
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        let listing = self.run(&format!("@ls -1 \"{}\"\n", self.dir))?;
+        Ok(listing
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| Path::new(line).file_name().map(|n| n.to_string_lossy().into_owned()))
+            .collect())
+    }
+
+    fn describe(&self, name: &str) -> String {
+        format!("sftp://{}{}", self.target, self.remote(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    // Fragments are named after the files next to them, so the name has the spaces, parentheses
+    // and percent signs those tend to have
+    const SIBLING_NAME: &str = "holiday (2) 100%.jpg";
+
+    fn roundtrip(store: &dyn FragmentStore, len: usize) {
+        let data = pattern(len);
+        store.put(SIBLING_NAME, &mut data.as_slice(), len as u64).unwrap();
+        assert!(store.exists(SIBLING_NAME).unwrap());
+        assert_eq!(store.size(SIBLING_NAME).unwrap(), len as u64);
+        let mut read = Vec::new();
+        store.get(SIBLING_NAME).unwrap().read_to_end(&mut read).unwrap();
+        assert!(read == data);
+        assert_eq!(store.get_range(SIBLING_NAME, 5, 10).unwrap(), &data[5..15]);
+        assert!(store.list().unwrap().contains(&SIBLING_NAME.to_string()));
+        store.put("renamed.bin", &mut [1u8, 2, 3].as_slice(), 3).unwrap();
+        store.rename(SIBLING_NAME, "renamed.bin").unwrap();
+        assert!(!store.exists(SIBLING_NAME).unwrap());
+        assert_eq!(store.size("renamed.bin").unwrap(), len as u64);
+        store.delete("renamed.bin").unwrap();
+        assert!(!store.exists("renamed.bin").unwrap());
+    }
+
+    // Remote stores are only tested when a location to use is given, e.g.
+    // SDFS_TEST_S3=s3://bucket/prefix?endpoint=http://127.0.0.1:9000 cargo test -- --ignored
+    fn remote_store(var: &str) -> Option<Box<dyn FragmentStore>> {
+        let location = env::var(var).ok()?;
+        Some(open_store(&location).unwrap())
+    }
+
+    #[test]
+    fn local_roundtrip() {
+        let dir = temp_path("store_test");
+        fs::create_dir(&dir).unwrap();
+        let store = open_store(&dir.to_string_lossy()).unwrap();
+        roundtrip(store.as_ref(), 1000);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn put_checks_length() {
+        let dir = temp_path("store_test");
+        fs::create_dir(&dir).unwrap();
+        let store = open_store(&dir.to_string_lossy()).unwrap();
+        assert!(store.put("short", &mut [0u8; 10].as_slice(), 11).is_err());
+        assert!(store.put("long", &mut [0u8; 10].as_slice(), 9).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn curl_config_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let curl = Curl::new("http://127.0.0.1/", Some("id:secret".to_string()), &[]).unwrap();
+        let config = curl.config.clone().unwrap();
+        assert_eq!(fs::metadata(&config).unwrap().permissions().mode() & 0o777, 0o600);
+        drop(curl);
+        assert!(!config.exists());
+    }
+
+    #[test]
+    fn encodes_query_values() {
+        assert_eq!(percent_encode("a/b c+d~e.f_g-h"), "a%2Fb%20c%2Bd~e.f_g-h");
+        assert_eq!(percent_decode(&percent_encode("fragment 1/ä")), "fragment 1/ä");
+    }
+
+    #[test]
+    fn encodes_every_path_segment() {
+        let s3 = S3Store::parse("bkt/my vault?endpoint=http://127.0.0.1:9000").unwrap();
+        assert_eq!(s3.object_url("a (1).bin"), "http://127.0.0.1:9000/bkt/my%20vault/a%20%281%29.bin");
+        let webdav = WebDavStore::new("https://dav.example/dir".to_string());
+        assert_eq!(webdav.url("100% \"done\".bin"), "https://dav.example/dir/100%25%20%22done%22.bin");
+    }
+
+    #[test]
+    fn keeps_unquotable_names_out_of_sftp_scripts() {
+        let sftp = SftpStore::parse("host/dir").unwrap();
+        assert_eq!(sftp.quoted(SIBLING_NAME).unwrap(), format!("/dir/{}", SIBLING_NAME));
+        for name in ["a\"b", "a\\b", "a\nrm b", "*.bin", "a[1].bin"] {
+            assert!(sftp.quoted(name).is_err(), "{:?}", name);
+        }
+        assert!(SftpStore::parse("host/di\"r").is_err());
+    }
+
+    #[test]
+    fn reads_headers() {
+        let headers = b"HTTP/1.1 200 OK\r\ncontent-length: 1234\r\nETag: \"abc\"\r\n\r\n";
+        assert_eq!(content_length(headers, "object").unwrap(), 1234);
+        assert_eq!(header_value(headers, "etag").unwrap(), "\"abc\"");
+        assert!(content_length(b"HTTP/1.1 200 OK\r\n", "object").is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn s3_roundtrip() {
+        let Some(store) = remote_store("SDFS_TEST_S3") else { return };
+        roundtrip(store.as_ref(), 1000);
+        // Big enough to go up in parts
+        roundtrip(store.as_ref(), S3_PART_SIZE as usize * 2 + 1000);
+    }
+
+    #[test]
+    #[ignore]
+    fn s3_list_follows_pages() {
+        let Ok(location) = env::var("SDFS_TEST_S3") else { return };
+        let store = S3Store::parse(location.strip_prefix("s3://").unwrap()).unwrap();
+        let names: Vec<String> = (0..5).map(|i| format!("page{}.bin", i)).collect();
+        for name in &names {
+            store.put(name, &mut [1u8, 2, 3].as_slice(), 3).unwrap();
+        }
+        let listed = store.list_pages(Some(2)).unwrap();
+        for name in &names {
+            assert!(listed.contains(name));
+            store.delete(name).unwrap();
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn webdav_roundtrip() {
+        let Some(store) = remote_store("SDFS_TEST_WEBDAV") else { return };
+        roundtrip(store.as_ref(), 1000);
+    }
+
+    #[test]
+    #[ignore]
+    fn sftp_roundtrip() {
+        let Some(store) = remote_store("SDFS_TEST_SFTP") else { return };
+        roundtrip(store.as_ref(), 1000);
+    }
+}
@@ -0,0 +1,303 @@
+use std::io::Read;
+use serde::Serialize;
+use crate::auth;
+use crate::carrier::Carrier;
+use crate::keysetup::FragmentInfo;
+use crate::merkle;
+use crate::metadata::VaultMetadata;
+use crate::padding::Filler;
+use crate::store;
+
+#[derive(Serialize)]
+pub struct FileCheck {
+    pub path: String,
+    pub ok: bool,
+    pub detail: String,
+}
+
+#[derive(Serialize)]
+pub struct FragmentCheck {
+    pub filename: String,
+    pub location: String,
+    pub exists: bool,
+    pub expected_size: u64,
+    pub actual_size: Option<u64>,
+    pub bad_chunks: Vec<usize>,
+    pub bad_padding: bool,
+    pub ok: bool,
+}
+
+#[derive(Serialize)]
+pub struct VerifyReport {
+    pub ok: bool,
+    pub pass_file: FileCheck,
+    pub attempts_file: FileCheck,
+    pub metadata: FileCheck,
+    pub fragments: Vec<FragmentCheck>,
+    pub fragments_ok: usize,
+    pub fragments_total: usize,
+}
+
+fn check_file(path: &str, result: Result<String, String>) -> FileCheck {
+    match result {
+        Ok(detail) => FileCheck { path: path.to_string(), ok: true, detail },
+        Err(detail) => FileCheck { path: path.to_string(), ok: false, detail },
+    }
+}
+
+// Recomputes the Merkle leaves of the chunks stored in one fragment while streaming it, so the
+// whole fragment is never held in memory, and compares them with `leaves`, which the caller has
+// already checked against the root. The padding after the chunks is regenerated and compared byte for byte.
+// Returns the bad chunks, whether the padding differs and the number of bytes the fragment
+// really holds.
+fn check_fragment_chunks(
+    reader: &mut dyn Read,
+    chunk_indices: &[usize],
+    lengths: &[usize],
+    generation: u64,
+    leaves: &[merkle::Hash],
+    padding: &mut Filler,
+) -> std::io::Result<(Vec<usize>, bool, u64)> {
+    let mut bad_chunks = Vec::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut total_read = 0u64;
+
+    for &chunk_index in chunk_indices {
+        let mut remaining = lengths.get(chunk_index).copied().unwrap_or(0);
+        let mut hasher = merkle::LeafHasher::new(generation, chunk_index);
+        let mut truncated = false;
+        while remaining > 0 {
+            let to_read = remaining.min(buffer.len());
+            let n = reader.read(&mut buffer[..to_read])?;
+            if n == 0 {
+                truncated = true;
+                break;
+            }
+            hasher.update(&buffer[..n]);
+            remaining -= n;
+            total_read += n as u64;
+        }
+        if truncated || leaves.get(chunk_index) != Some(&hasher.finalize()) {
+            bad_chunks.push(chunk_index);
+        }
+    }
+    let mut bad_padding = false;
+    let mut expected = vec![0u8; buffer.len()];
+    while padding.remaining() > 0 {
+        let to_read = (padding.remaining() as usize).min(buffer.len());
+        let n = reader.read(&mut buffer[..to_read])?;
+        if n == 0 {
+            // A short fragment already shows in its size
+            break;
+        }
+        padding.read_exact(&mut expected[..n])?;
+        bad_padding |= buffer[..n] != expected[..n];
+        total_read += n as u64;
+    }
+    total_read += std::io::copy(reader, &mut std::io::sink())?;
+    Ok((bad_chunks, bad_padding, total_read))
+}
+
+fn check_fragment(
+    fragment: &FragmentInfo,
+    carrier: Carrier,
+    lengths: &[usize],
+    generation: u64,
+    leaves: Option<&[merkle::Hash]>,
+    mut padding: Filler,
+) -> FragmentCheck {
+    let expected_size: u64 = fragment.chunk_indices
+        .iter()
+        .filter_map(|&i| lengths.get(i))
+        .map(|&len| len as u64)
+        .sum::<u64>()
+        + padding.remaining();
+    let fragment_store = store::open_store(&fragment.location);
+    let exists = fragment_store.as_ref().is_ok_and(|s| s.exists(&fragment.filename).unwrap_or(false));
+
+    let mut bad_chunks = Vec::new();
+    let mut bad_padding = false;
+    let mut actual_size = None;
+    if let (true, Ok(fragment_store)) = (exists, &fragment_store) {
+        let no_leaves: &[merkle::Hash] = &[];
+        let result = fragment_store.get(&fragment.filename).and_then(|reader| {
+            check_fragment_chunks(&mut carrier.unwrap(reader, expected_size), &fragment.chunk_indices, lengths, generation, leaves.unwrap_or(no_leaves), &mut padding)
+        });
+        match result {
+            Ok((bad, padding_differs, size)) => {
+                bad_padding = padding_differs;
+                // Without a manifest there is nothing to check the chunk contents against
+                if leaves.is_some() {
+                    bad_chunks = bad;
+                }
+                actual_size = Some(size);
+            }
+            Err(_) => bad_chunks = fragment.chunk_indices.clone(),
+        }
+    }
+
+    FragmentCheck {
+        filename: fragment.filename.clone(),
+        location: fragment.location.clone(),
+        exists,
+        expected_size,
+        actual_size,
+        ok: exists && actual_size == Some(expected_size) && bad_chunks.is_empty() && !bad_padding,
+        bad_chunks,
+        bad_padding,
+    }
+}
+
+// `only` restricts the check to the fragment with that filename; every other fragment file is
+// left untouched, since the leaves alone are checked against the root
+fn check_fragments(metadata: &VaultMetadata, leaves: Option<&[merkle::Hash]>, only: Option<&str>) -> Vec<FragmentCheck> {
+    let lengths: Vec<usize> = metadata.chunk_sizes().into_iter().map(|len| len as usize).collect();
+    metadata.fragments
+        .iter()
+        .zip(metadata.padding_fillers(metadata.merkle.generation))
+        .filter(|(fragment, _)| only.is_none_or(|name| fragment.filename == name))
+        .map(|(fragment, padding)| check_fragment(fragment, metadata.carrier, &lengths, metadata.merkle.generation, leaves, padding))
+        .collect()
+}
+
+// Reads back every fragment of a freshly written layout and checks it against the manifest
+pub fn check_layout(metadata: &VaultMetadata) -> Result<(), String> {
+    let leaves = metadata.merkle.checked_leaves().map_err(|e| e.to_string())?;
+    let failed: Vec<String> = check_fragments(metadata, Some(&leaves), None)
+        .into_iter()
+        .filter(|f| !f.ok)
+        .map(|f| f.filename)
+        .collect();
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("fragments failed verification: {}", failed.join(", ")))
+    }
+}
+
+// Checks the vault without reassembling or attaching anything. `metadata` is the decrypted
+// fragment info, or the error message if it could not be decrypted.
+pub fn verify_vault(pass_file: &str, attempts_file: &str, metadata_file: &str, metadata: Result<&VaultMetadata, String>, only: Option<&str>) -> VerifyReport {
+    let pass_check = check_file(pass_file, auth::check_pass_file(pass_file).map(|_| "password hash intact".to_string()));
+    let attempts_check = check_file(attempts_file, auth::check_attempts_file(attempts_file)
+        .map(|attempts| format!("MAC valid, {} failed attempt(s)", attempts)));
+
+    let swap_pending = metadata.as_ref().is_ok_and(|metadata| metadata.swap_pending);
+    let (metadata_check, fragments) = match metadata {
+        Ok(metadata) if metadata.image_size == 0 => (
+            check_file(metadata_file, Err("decrypted, but the vault has never been locked so there are no fragments".to_string())),
+            Vec::new(),
+        ),
+        Ok(metadata) if metadata.merkle.is_empty() => (
+            check_file(metadata_file, Err("decrypted, but no Merkle manifest is recorded; lock the vault again to add one".to_string())),
+            check_fragments(metadata, None, only),
+        ),
+        Ok(metadata) => match metadata.merkle.checked_leaves() {
+            Ok(leaves) if leaves.len() == metadata.total_chunks() => (
+                check_file(metadata_file, Ok(format!("decrypted, {} fragments, {} chunks, Merkle root {}", metadata.fragments.len(), metadata.total_chunks(), metadata.merkle.root))),
+                check_fragments(metadata, Some(&leaves), only),
+            ),
+            Ok(leaves) => (
+                check_file(metadata_file, Err(format!("Merkle manifest covers {} chunks but the key has {}", leaves.len(), metadata.total_chunks()))),
+                check_fragments(metadata, None, only),
+            ),
+            Err(e) => (check_file(metadata_file, Err(e.to_string())), check_fragments(metadata, None, only)),
+        },
+        Err(e) => (check_file(metadata_file, Err(e)), Vec::new()),
+    };
+
+    let metadata_check = match only {
+        Some(name) if fragments.is_empty() && metadata_check.ok => check_file(metadata_file, Err(format!("no fragment named {}", name))),
+        // Verifying only looks, so the fragments of an interrupted lock stay where they are
+        _ if swap_pending => check_file(metadata_file, Err("the last lock didn't finish moving its fragments into place; unlock the vault to finish it".to_string())),
+        _ => metadata_check,
+    };
+
+    let fragments_ok = fragments.iter().filter(|f| f.ok).count();
+    let fragments_total = fragments.len();
+    VerifyReport {
+        ok: pass_check.ok && attempts_check.ok && metadata_check.ok && fragments_ok == fragments_total,
+        pass_file: pass_check,
+        attempts_file: attempts_check,
+        metadata: metadata_check,
+        fragments,
+        fragments_ok,
+        fragments_total,
+    }
+}
+
+pub fn print_report(report: &VerifyReport) {
+    let status = |ok: bool| if ok { "OK  " } else { "FAIL" };
+
+    println!("Vault integrity report");
+    for check in [&report.pass_file, &report.attempts_file, &report.metadata] {
+        println!("  [{}] {}: {}", status(check.ok), check.path, check.detail);
+    }
+    println!();
+    for (i, fragment) in report.fragments.iter().enumerate() {
+        let path = store::describe(&fragment.location, &fragment.filename);
+        let detail = if !fragment.exists {
+            "missing".to_string()
+        } else if fragment.actual_size != Some(fragment.expected_size) {
+            format!("size {} bytes, expected {}", fragment.actual_size.unwrap_or(0), fragment.expected_size)
+        } else if !fragment.bad_chunks.is_empty() {
+            format!("corrupted chunks {:?}", fragment.bad_chunks)
+        } else if fragment.bad_padding {
+            "padding has been tampered with".to_string()
+        } else {
+            format!("{} bytes", fragment.expected_size)
+        };
+        println!("  [{}] Fragment {}: {} ({})", status(fragment.ok), i, path, detail);
+    }
+    println!();
+    println!("{}/{} fragments healthy. Vault is {}.", report.fragments_ok, report.fragments_total, if report.ok { "healthy" } else { "NOT healthy" });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::{Seek, SeekFrom, Write};
+    use rand::rngs::OsRng;
+    use crate::fragwriter::{self, FragmentWriter};
+    use crate::keysetup;
+    use crate::padding::Padding;
+
+    #[test]
+    fn padding_is_regenerated_and_compared() {
+        let dir = std::env::temp_dir().join(format!("sdfs_verify_test_{:016x}", rand::random::<u64>()));
+        fs::create_dir(&dir).unwrap();
+        let dirs = vec![dir.to_string_lossy().into_owned(); 3];
+        let (key, fragments) = keysetup::generate_key_and_fragments(dirs.clone(), 4, &mut OsRng);
+        let mut metadata = VaultMetadata::new(4, dirs, key, fragments);
+        metadata.padding = Some(Padding::parse("50", &mut OsRng).unwrap());
+        metadata.image_size = 300_000;
+        metadata.cut_chunks(1);
+        let mut writer = FragmentWriter::for_vault(&metadata, 1).unwrap();
+        writer.write_all(&vec![7u8; 300_000]).unwrap();
+        metadata.merkle = writer.finish().unwrap();
+        fragwriter::swap_in(&metadata.fragments).unwrap();
+        check_layout(&metadata).unwrap();
+
+        // The last byte of the most padded fragment is padding, not chunk data
+        let (padded, _) = metadata.fragment_padding.iter().enumerate().max_by_key(|&(_, &len)| len).unwrap();
+        assert!(metadata.fragment_padding[padded] > 0);
+        let path = dir.join(&metadata.fragments[padded].filename);
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let mut last = [0u8];
+        file.seek(SeekFrom::End(-1)).unwrap();
+        file.read_exact(&mut last).unwrap();
+        file.seek(SeekFrom::End(-1)).unwrap();
+        file.write_all(&[last[0] ^ 1]).unwrap();
+        drop(file);
+
+        let leaves = metadata.merkle.checked_leaves().unwrap();
+        let checks = check_fragments(&metadata, Some(&leaves), None);
+        for (i, check) in checks.iter().enumerate() {
+            assert!(check.bad_chunks.is_empty());
+            assert_eq!(check.bad_padding, i == padded);
+            assert_eq!(check.ok, i != padded);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,563 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use rand::RngCore;
+
+// Native reading and writing of the VHD footer (Microsoft Virtual Hard Disk Image Format
+// Specification, version 1.0). A fixed VHD is just the raw disk followed by this 512-byte
+// footer, so nothing here depends on Windows.
+
+pub const FOOTER_SIZE: usize = 512;
+const COOKIE: &[u8; 8] = b"conectix";
+const FEATURES_RESERVED: u32 = 0x0000_0002;
+const FORMAT_VERSION: u32 = 0x0001_0000;
+const FIXED_DATA_OFFSET: u64 = u64::MAX;
+const CREATOR_APP: &[u8; 4] = b"sdfs";
+const CREATOR_VERSION: u32 = 0x0001_0000;
+const CREATOR_HOST_WINDOWS: u32 = 0x5769_326B; // "Wi2k"
+// VHD timestamps count seconds from 2000-01-01 00:00:00 UTC
+const VHD_EPOCH: u64 = 946_684_800;
+const SPARSE_COOKIE: &[u8; 8] = b"cxsparse";
+const DYNAMIC_HEADER_SIZE: usize = 1024;
+// Dynamic disks allocate space in 2 MB blocks, each preceded by a one-sector bitmap
+pub const DYNAMIC_BLOCK_SIZE: u32 = 2 * 1024 * 1024;
+const UNUSED_BAT_ENTRY: u32 = 0xFFFF_FFFF;
+const VHDX_SIGNATURE: &[u8; 8] = b"vhdxfile";
+// The format caps disks at 2040 GB
+pub const MAX_SIZE: u64 = 2040 * 1024 * 1024 * 1024;
+
+pub const DISK_TYPE_FIXED: u32 = 2;
+pub const DISK_TYPE_DYNAMIC: u32 = 3;
+pub const DISK_TYPE_DIFFERENCING: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Geometry {
+    pub cylinders: u16,
+    pub heads: u8,
+    pub sectors: u8,
+}
+
+#[derive(Clone, Debug)]
+pub struct Footer {
+    pub data_offset: u64,
+    pub timestamp: u32,
+    pub original_size: u64,
+    pub current_size: u64,
+    pub geometry: Geometry,
+    pub disk_type: u32,
+    pub unique_id: [u8; 16],
+}
+
+// CHS geometry algorithm from appendix of the specification
+pub fn geometry(size: u64) -> Geometry {
+    let mut total_sectors = size / 512;
+    if total_sectors > 65535 * 16 * 255 {
+        total_sectors = 65535 * 16 * 255;
+    }
+    let (sectors, heads, cylinder_times_heads);
+    if total_sectors >= 65535 * 16 * 63 {
+        sectors = 255;
+        heads = 16;
+        cylinder_times_heads = total_sectors / sectors;
+    } else {
+        let mut s = 17;
+        let mut cth = total_sectors / s;
+        let mut h = cth.div_ceil(1024).max(4);
+        if cth >= h * 1024 || h > 16 {
+            s = 31;
+            h = 16;
+            cth = total_sectors / s;
+        }
+        if cth >= h * 1024 {
+            s = 63;
+            h = 16;
+            cth = total_sectors / s;
+        }
+        sectors = s;
+        heads = h;
+        cylinder_times_heads = cth;
+    }
+    Geometry {
+        cylinders: (cylinder_times_heads / heads).min(u16::MAX as u64) as u16,
+        heads: heads as u8,
+        sectors: sectors as u8,
+    }
+}
+
+// One's complement of the byte sum with the checksum field left out. The footer and the dynamic
+// disk header both keep their checksum at `at`.
+fn checksum_at(bytes: &[u8], at: usize) -> u32 {
+    let sum: u32 = bytes
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !(at..at + 4).contains(i))
+        .map(|(_, &b)| b as u32)
+        .fold(0u32, u32::wrapping_add);
+    !sum
+}
+
+fn checksum(bytes: &[u8; FOOTER_SIZE]) -> u32 {
+    checksum_at(bytes, 64)
+}
+
+fn new_unique_id() -> [u8; 16] {
+    let mut id = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut id);
+    // Random (version 4) UUID
+    id[6] = (id[6] & 0x0f) | 0x40;
+    id[8] = (id[8] & 0x3f) | 0x80;
+    id
+}
+
+fn vhd_timestamp() -> u32 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    now.saturating_sub(VHD_EPOCH) as u32
+}
+
+impl Footer {
+    pub fn new(disk_type: u32, size: u64, data_offset: u64) -> Self {
+        Self {
+            data_offset,
+            timestamp: vhd_timestamp(),
+            original_size: size,
+            current_size: size,
+            geometry: geometry(size),
+            disk_type,
+            unique_id: new_unique_id(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; FOOTER_SIZE] {
+        let mut bytes = [0u8; FOOTER_SIZE];
+        bytes[0..8].copy_from_slice(COOKIE);
+        bytes[8..12].copy_from_slice(&FEATURES_RESERVED.to_be_bytes());
+        bytes[12..16].copy_from_slice(&FORMAT_VERSION.to_be_bytes());
+        bytes[16..24].copy_from_slice(&self.data_offset.to_be_bytes());
+        bytes[24..28].copy_from_slice(&self.timestamp.to_be_bytes());
+        bytes[28..32].copy_from_slice(CREATOR_APP);
+        bytes[32..36].copy_from_slice(&CREATOR_VERSION.to_be_bytes());
+        bytes[36..40].copy_from_slice(&CREATOR_HOST_WINDOWS.to_be_bytes());
+        bytes[40..48].copy_from_slice(&self.original_size.to_be_bytes());
+        bytes[48..56].copy_from_slice(&self.current_size.to_be_bytes());
+        bytes[56..58].copy_from_slice(&self.geometry.cylinders.to_be_bytes());
+        bytes[58] = self.geometry.heads;
+        bytes[59] = self.geometry.sectors;
+        bytes[60..64].copy_from_slice(&self.disk_type.to_be_bytes());
+        bytes[68..84].copy_from_slice(&self.unique_id);
+        let sum = checksum(&bytes);
+        bytes[64..68].copy_from_slice(&sum.to_be_bytes());
+        bytes
+    }
+
+    pub fn parse(bytes: &[u8; FOOTER_SIZE]) -> Result<Self, String> {
+        if &bytes[0..8] != COOKIE {
+            return Err("VHD footer cookie is missing".to_string());
+        }
+        let be32 = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+        let be64 = |at: usize| u64::from_be_bytes(bytes[at..at + 8].try_into().unwrap());
+        let stored = be32(64);
+        let expected = checksum(bytes);
+        if stored != expected {
+            return Err(format!("VHD footer checksum is {:#010x}, expected {:#010x}", stored, expected));
+        }
+        if be32(12) >> 16 != 1 {
+            return Err(format!("Unsupported VHD format version {:#010x}", be32(12)));
+        }
+        let footer = Self {
+            data_offset: be64(16),
+            timestamp: be32(24),
+            original_size: be64(40),
+            current_size: be64(48),
+            geometry: Geometry {
+                cylinders: u16::from_be_bytes([bytes[56], bytes[57]]),
+                heads: bytes[58],
+                sectors: bytes[59],
+            },
+            disk_type: be32(60),
+            unique_id: bytes[68..84].try_into().unwrap(),
+        };
+        if ![DISK_TYPE_FIXED, DISK_TYPE_DYNAMIC, DISK_TYPE_DIFFERENCING].contains(&footer.disk_type) {
+            return Err(format!("Unknown VHD disk type {}", footer.disk_type));
+        }
+        if footer.current_size == 0 || !footer.current_size.is_multiple_of(512) || footer.current_size > MAX_SIZE {
+            return Err(format!("VHD reports an invalid disk size of {} bytes", footer.current_size));
+        }
+        let g = footer.geometry;
+        if g.cylinders == 0 || g.heads == 0 || g.sectors == 0 {
+            return Err("VHD footer has an empty disk geometry".to_string());
+        }
+        Ok(footer)
+    }
+}
+
+// Writes a fixed VHD of `size` bytes (rounded up to a whole sector). The data area is left
+// sparse where the filesystem supports it, so even large disks are created instantly.
+pub fn create_fixed(path: &Path, size: u64) -> Result<Footer, Box<dyn std::error::Error>> {
+    let size = size.div_ceil(512) * 512;
+    if size == 0 || size > MAX_SIZE {
+        return Err(format!("A VHD must be between 512 bytes and {} bytes", MAX_SIZE).into());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let footer = Footer::new(DISK_TYPE_FIXED, size, FIXED_DATA_OFFSET);
+    let mut file = File::create(path)?;
+    file.set_len(size)?;
+    file.seek(SeekFrom::Start(size))?;
+    file.write_all(&footer.to_bytes())?;
+    file.sync_all()?;
+    Ok(footer)
+}
+
+pub fn read_footer(file: &mut File) -> Result<Footer, Box<dyn std::error::Error>> {
+    let len = file.metadata()?.len();
+    if len < FOOTER_SIZE as u64 {
+        return Err("File is too small to be a VHD".into());
+    }
+    let mut bytes = [0u8; FOOTER_SIZE];
+    file.seek(SeekFrom::Start(len - FOOTER_SIZE as u64))?;
+    file.read_exact(&mut bytes)?;
+    Ok(Footer::parse(&bytes)?)
+}
+
+// Writes an empty dynamically expanding VHD of `size` bytes: a copy of the footer, the dynamic
+// disk header, a block allocation table with every block unallocated, then the footer. The
+// file starts out a few kilobytes long and only grows as Windows writes data into it.
+pub fn create_dynamic(path: &Path, size: u64) -> Result<Footer, Box<dyn std::error::Error>> {
+    let size = size.div_ceil(512) * 512;
+    if size == 0 || size > MAX_SIZE {
+        return Err(format!("A VHD must be between 512 bytes and {} bytes", MAX_SIZE).into());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let header_offset = FOOTER_SIZE as u64;
+    let table_offset = header_offset + DYNAMIC_HEADER_SIZE as u64;
+    let entries = size.div_ceil(DYNAMIC_BLOCK_SIZE as u64) as u32;
+    let table_bytes = (entries as usize * 4).div_ceil(512) * 512;
+
+    let footer = Footer::new(DISK_TYPE_DYNAMIC, size, header_offset);
+    let footer_bytes = footer.to_bytes();
+
+    let mut header = [0u8; DYNAMIC_HEADER_SIZE];
+    header[0..8].copy_from_slice(SPARSE_COOKIE);
+    header[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+    header[16..24].copy_from_slice(&table_offset.to_be_bytes());
+    header[24..28].copy_from_slice(&FORMAT_VERSION.to_be_bytes());
+    header[28..32].copy_from_slice(&entries.to_be_bytes());
+    header[32..36].copy_from_slice(&DYNAMIC_BLOCK_SIZE.to_be_bytes());
+    let sum = checksum_at(&header, 36);
+    header[36..40].copy_from_slice(&sum.to_be_bytes());
+
+    let mut table = vec![0u8; table_bytes];
+    for entry in table.chunks_mut(4) {
+        entry.copy_from_slice(&UNUSED_BAT_ENTRY.to_be_bytes());
+    }
+
+    let mut file = File::create(path)?;
+    file.write_all(&footer_bytes)?;
+    file.write_all(&header)?;
+    file.write_all(&table)?;
+    file.write_all(&footer_bytes)?;
+    file.sync_all()?;
+    Ok(footer)
+}
+
+// Header, block table and allocated blocks of a dynamic VHD must all sit between the leading
+// footer copy and the trailing footer
+fn validate_dynamic(file: &mut File, footer: &Footer, len: u64) -> Result<(), String> {
+    let data_end = len - FOOTER_SIZE as u64;
+    let mut copy = [0u8; FOOTER_SIZE];
+    let mut trailing = [0u8; FOOTER_SIZE];
+    file.seek(SeekFrom::Start(0)).and_then(|_| file.read_exact(&mut copy)).map_err(|e| e.to_string())?;
+    file.seek(SeekFrom::Start(data_end)).and_then(|_| file.read_exact(&mut trailing)).map_err(|e| e.to_string())?;
+    if copy != trailing {
+        return Err("footer copy at the start of the file does not match the footer".to_string());
+    }
+    if footer.data_offset.checked_add(DYNAMIC_HEADER_SIZE as u64).is_none_or(|end| end > data_end) {
+        return Err("dynamic disk header lies outside the file".to_string());
+    }
+
+    let mut header = [0u8; DYNAMIC_HEADER_SIZE];
+    file.seek(SeekFrom::Start(footer.data_offset)).and_then(|_| file.read_exact(&mut header)).map_err(|e| e.to_string())?;
+    if &header[0..8] != SPARSE_COOKIE {
+        return Err("dynamic disk header cookie is missing".to_string());
+    }
+    let be32 = |at: usize| u32::from_be_bytes(header[at..at + 4].try_into().unwrap());
+    let stored = be32(36);
+    if stored != checksum_at(&header, 36) {
+        return Err(format!("dynamic disk header checksum is {:#010x}, expected {:#010x}", stored, checksum_at(&header, 36)));
+    }
+    let table_offset = u64::from_be_bytes(header[16..24].try_into().unwrap());
+    let entries = be32(28) as u64;
+    let block_size = be32(32) as u64;
+    if block_size == 0 || !block_size.is_multiple_of(512) || entries < footer.current_size.div_ceil(block_size) {
+        return Err("dynamic disk header describes an impossible block layout".to_string());
+    }
+    if table_offset.checked_add(entries * 4).is_none_or(|end| end > data_end) {
+        return Err("block allocation table lies outside the file".to_string());
+    }
+
+    let mut table = vec![0u8; (entries * 4) as usize];
+    file.seek(SeekFrom::Start(table_offset)).and_then(|_| file.read_exact(&mut table)).map_err(|e| e.to_string())?;
+    // Every block is preceded by a bitmap with one bit per sector, padded to whole sectors
+    let bitmap_size = (block_size / 512).div_ceil(8).div_ceil(512) * 512;
+    for (block, entry) in table.chunks(4).enumerate() {
+        let sector = u32::from_be_bytes(entry.try_into().unwrap());
+        if sector == UNUSED_BAT_ENTRY {
+            continue;
+        }
+        let start = sector as u64 * 512;
+        if start < table_offset + entries * 4 || start + bitmap_size + block_size > data_end {
+            return Err(format!("block {} points outside the data area", block));
+        }
+    }
+    Ok(())
+}
+
+// VHDX has no footer; check the file type identifier and that both headers are present.
+// diskpart does the full validation when it opens the disk.
+fn validate_vhdx(file: &mut File, len: u64) -> Result<(), String> {
+    const HEADER_OFFSETS: [u64; 2] = [64 * 1024, 128 * 1024];
+    if len < 1024 * 1024 || !len.is_multiple_of(1024 * 1024) {
+        return Err(format!("{} bytes is not a valid VHDX size", len));
+    }
+    let mut signature = [0u8; 8];
+    file.seek(SeekFrom::Start(0)).and_then(|_| file.read_exact(&mut signature)).map_err(|e| e.to_string())?;
+    if &signature != VHDX_SIGNATURE {
+        return Err("VHDX file type identifier is missing".to_string());
+    }
+    for offset in HEADER_OFFSETS {
+        let mut header = [0u8; 4];
+        file.seek(SeekFrom::Start(offset)).and_then(|_| file.read_exact(&mut header)).map_err(|e| e.to_string())?;
+        if &header != b"head" {
+            return Err(format!("VHDX header at offset {} is missing", offset));
+        }
+    }
+    Ok(())
+}
+
+pub fn is_vhdx(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("vhdx"))
+}
+
+// Checks that the file is a well-formed VHD whose layout matches its footer (or, for .vhdx
+// files, a VHDX with intact headers). Run on a reassembled image before it is handed to
+// Windows, so a bad reassembly gives a clear error. Returns the footer for VHD files.
+pub fn validate(path: &Path) -> Result<Option<Footer>, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if is_vhdx(path) {
+        validate_vhdx(&mut file, len).map_err(|e| format!("{} is not a valid VHDX: {}", path.display(), e))?;
+        return Ok(None);
+    }
+    let footer = read_footer(&mut file).map_err(|e| format!("{} is not a valid VHD: {}", path.display(), e))?;
+    match footer.disk_type {
+        DISK_TYPE_FIXED => {
+            if footer.data_offset != FIXED_DATA_OFFSET {
+                return Err(format!("{} is a fixed VHD with a data offset set", path.display()).into());
+            }
+            if len != footer.current_size + FOOTER_SIZE as u64 {
+                return Err(format!(
+                    "{} is {} bytes but its footer describes a {} byte disk",
+                    path.display(),
+                    len,
+                    footer.current_size
+                ).into());
+            }
+        }
+        DISK_TYPE_DYNAMIC => {
+            validate_dynamic(&mut file, &footer, len).map_err(|e| format!("{} is not a valid dynamic VHD: {}", path.display(), e))?;
+        }
+        other => return Err(format!("{} has unsupported VHD disk type {}", path.display(), other).into()),
+    }
+    Ok(Some(footer))
+}
+
+// Read/write access to the disk inside a dynamic VHD. Unallocated blocks read as zeros; writing
+// to one appends a new block where the footer was and moves the footer to the new end.
+// Writes of all-zero data to an unallocated block are dropped, so the image stays sparse.
+pub struct DynamicDisk {
+    file: File,
+    size: u64,
+    block_size: u64,
+    bitmap_size: u64,
+    table_offset: u64,
+    table: Vec<u32>,
+    data_end: u64,
+    footer: [u8; FOOTER_SIZE],
+}
+
+impl DynamicDisk {
+    // Expects a footer that `validate` has already accepted
+    pub fn open(mut file: File, footer: Footer) -> io::Result<Self> {
+        let data_end = file.metadata()?.len() - FOOTER_SIZE as u64;
+        let mut footer_bytes = [0u8; FOOTER_SIZE];
+        file.seek(SeekFrom::Start(data_end))?;
+        file.read_exact(&mut footer_bytes)?;
+
+        let mut header = [0u8; DYNAMIC_HEADER_SIZE];
+        file.seek(SeekFrom::Start(footer.data_offset))?;
+        file.read_exact(&mut header)?;
+        let table_offset = u64::from_be_bytes(header[16..24].try_into().unwrap());
+        let entries = u32::from_be_bytes(header[28..32].try_into().unwrap()) as usize;
+        let block_size = u32::from_be_bytes(header[32..36].try_into().unwrap()) as u64;
+
+        let mut raw_table = vec![0u8; entries * 4];
+        file.seek(SeekFrom::Start(table_offset))?;
+        file.read_exact(&mut raw_table)?;
+        let table = raw_table.chunks(4).map(|e| u32::from_be_bytes(e.try_into().unwrap())).collect();
+
+        Ok(Self {
+            file,
+            size: footer.current_size,
+            block_size,
+            bitmap_size: (block_size / 512).div_ceil(8).div_ceil(512) * 512,
+            table_offset,
+            table,
+            data_end,
+            footer: footer_bytes,
+        })
+    }
+
+    fn allocate(&mut self, block: usize) -> io::Result<u64> {
+        let start = self.data_end;
+        let sector = u32::try_from(start / 512).map_err(|_| io::Error::other("VHD has grown past its addressable size"))?;
+        // Bitmap with every sector marked present, then the zeroed block, then the footer
+        self.file.seek(SeekFrom::Start(start))?;
+        self.file.write_all(&vec![0xFF; self.bitmap_size as usize])?;
+        self.data_end = start + self.bitmap_size + self.block_size;
+        self.file.set_len(self.data_end)?;
+        self.file.seek(SeekFrom::Start(self.data_end))?;
+        self.file.write_all(&self.footer)?;
+        self.file.seek(SeekFrom::Start(self.table_offset + block as u64 * 4))?;
+        self.file.write_all(&sector.to_be_bytes())?;
+        self.table[block] = sector;
+        Ok(start)
+    }
+
+    // Splits a disk range into (block, offset in block, length) pieces
+    fn pieces(&self, offset: u64, len: usize) -> Vec<(usize, u64, usize)> {
+        let mut pieces = Vec::new();
+        let mut position = offset;
+        let end = offset + len as u64;
+        while position < end {
+            let block = (position / self.block_size) as usize;
+            let within = position % self.block_size;
+            let n = (self.block_size - within).min(end - position) as usize;
+            pieces.push((block, within, n));
+            position += n as u64;
+        }
+        pieces
+    }
+}
+
+impl crate::blockdev::BlockDevice for DynamicDisk {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        crate::blockdev::check_range(offset, buf.len(), self.size)?;
+        let mut done = 0;
+        for (block, within, n) in self.pieces(offset, buf.len()) {
+            let target = &mut buf[done..done + n];
+            match self.table[block] {
+                UNUSED_BAT_ENTRY => target.fill(0),
+                sector => {
+                    self.file.seek(SeekFrom::Start(sector as u64 * 512 + self.bitmap_size + within))?;
+                    self.file.read_exact(target)?;
+                }
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        crate::blockdev::check_range(offset, buf.len(), self.size)?;
+        let mut done = 0;
+        for (block, within, n) in self.pieces(offset, buf.len()) {
+            let source = &buf[done..done + n];
+            done += n;
+            let start = match self.table[block] {
+                UNUSED_BAT_ENTRY if source.iter().all(|&b| b == 0) => continue,
+                UNUSED_BAT_ENTRY => self.allocate(block)?,
+                sector => sector as u64 * 512,
+            };
+            self.file.seek(SeekFrom::Start(start + self.bitmap_size + within))?;
+            self.file.write_all(source)?;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_vhd() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("sdfs_vhd_test_{:016x}.vhd", rand::random::<u64>()))
+    }
+
+    #[test]
+    fn footer_round_trips() {
+        let footer = Footer::new(DISK_TYPE_FIXED, 16 * 1024 * 1024, FIXED_DATA_OFFSET);
+        let bytes = footer.to_bytes();
+        assert_eq!(&bytes[0..8], COOKIE);
+        let parsed = Footer::parse(&bytes).unwrap();
+        assert_eq!(parsed.data_offset, FIXED_DATA_OFFSET);
+        assert_eq!(parsed.timestamp, footer.timestamp);
+        assert_eq!(parsed.current_size, 16 * 1024 * 1024);
+        assert_eq!(parsed.original_size, 16 * 1024 * 1024);
+        assert_eq!(parsed.geometry, Geometry { cylinders: 481, heads: 4, sectors: 17 });
+        assert_eq!(parsed.disk_type, DISK_TYPE_FIXED);
+        assert_eq!(parsed.unique_id, footer.unique_id);
+        assert_eq!(parsed.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn checksum_is_ones_complement_of_byte_sum() {
+        let bytes = Footer::new(DISK_TYPE_DYNAMIC, 1024 * 1024, FOOTER_SIZE as u64).to_bytes();
+        let sum: u32 = bytes.iter().enumerate().filter(|(i, _)| !(64..68).contains(i)).map(|(_, &b)| b as u32).sum();
+        assert_eq!(u32::from_be_bytes(bytes[64..68].try_into().unwrap()), !sum);
+        assert_eq!(checksum(&bytes), !sum);
+    }
+
+    #[test]
+    fn rejects_corrupted_footer() {
+        let bytes = Footer::new(DISK_TYPE_FIXED, 1024 * 1024, FIXED_DATA_OFFSET).to_bytes();
+        let mut flipped = bytes;
+        flipped[48] ^= 0x01;
+        assert!(Footer::parse(&flipped).unwrap_err().contains("checksum"));
+        let mut cookie = bytes;
+        cookie[0] = b'x';
+        assert!(Footer::parse(&cookie).unwrap_err().contains("cookie"));
+        // A consistent checksum doesn't make an unknown disk type acceptable
+        let mut disk_type = bytes;
+        disk_type[60..64].copy_from_slice(&7u32.to_be_bytes());
+        let sum = checksum(&disk_type);
+        disk_type[64..68].copy_from_slice(&sum.to_be_bytes());
+        assert!(Footer::parse(&disk_type).unwrap_err().contains("disk type"));
+    }
+
+    #[test]
+    fn validates_created_images() {
+        let path = temp_vhd();
+        create_fixed(&path, 1024 * 1024).unwrap();
+        assert_eq!(validate(&path).unwrap().unwrap().disk_type, DISK_TYPE_FIXED);
+        // Losing a sector of the disk no longer matches the footer
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[512..]).unwrap();
+        assert!(validate(&path).is_err());
+        create_dynamic(&path.with_extension("dyn.vhd"), 8 * 1024 * 1024).unwrap();
+        assert_eq!(validate(&path.with_extension("dyn.vhd")).unwrap().unwrap().disk_type, DISK_TYPE_DYNAMIC);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(path.with_extension("dyn.vhd")).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+
+// Keeping the unlocked locker in memory. With `ram_only` set in config.json the image (or the
+// working directory of the directory backend) lives in a tmpfs directory instead of under
+// files/, so the plaintext never reaches persistent storage, and it is wiped there on lock.
+// Whether a directory is really in memory is checked against /proc/mounts every time the
+// locker is unlocked. Other systems have no such check, so the mode is Linux only: there it
+// can't be picked during setup, and a vault that has it can only be unlocked with --allow-disk.
+
+// Filesystems that only ever live in memory (unless swapped out)
+#[cfg(target_os = "linux")]
+const VOLATILE_FILESYSTEMS: [&str; 2] = ["tmpfs", "ramfs"];
+
+// The filesystem type of the mount holding `path`, from the longest mount point that contains it
+#[cfg(target_os = "linux")]
+fn filesystem_of(path: &Path) -> Option<String> {
+    // The directory may not exist yet (e.g. after a reboot), so resolve its nearest ancestor
+    let existing = path.ancestors().find(|p| p.exists())?.canonicalize().ok()?;
+    let mounts = fs::read_to_string("/proc/mounts").ok()?;
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let mount_point = fields.nth(1)?.replace("\\040", " ");
+            let fs_type = fields.next()?;
+            existing.starts_with(&mount_point).then(|| (mount_point.len(), fs_type.to_string()))
+        })
+        // Later mounts over the same point hide earlier ones, so the last longest match wins
+        .max_by_key(|(len, _)| *len)
+        .map(|(_, fs_type)| fs_type)
+}
+
+#[cfg(target_os = "linux")]
+pub fn is_volatile(path: &Path) -> Result<bool, String> {
+    Ok(filesystem_of(path).is_some_and(|fs_type| VOLATILE_FILESYSTEMS.contains(&fs_type.as_str())))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_volatile(_path: &Path) -> Result<bool, String> {
+    Err("Keeping the unlocked locker in memory (ram_only) is only supported on Linux".to_string())
+}
+
+// A per-vault directory in the first in-memory location available: $XDG_RUNTIME_DIR (per user,
+// cleared at logout) or /dev/shm
+pub fn default_dir(vault_dir: &Path) -> Result<PathBuf, String> {
+    let digest = Sha256::digest(vault_dir.to_string_lossy().as_bytes());
+    let name = format!("sdfs-{}", hex::encode(&digest[..6]));
+    let candidates = std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from).into_iter().chain([PathBuf::from("/dev/shm")]);
+    for dir in candidates {
+        if dir.is_dir() && is_volatile(&dir)? {
+            return Ok(dir.join(name));
+        }
+    }
+    Err("No in-memory filesystem (tmpfs) found for the locker".to_string())
+}
+
+// Creates `dir` readable by the owner only
+pub fn prepare(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+// Overwrites the stretches of `path` that hold data (as [offset, _, length] runs from the sparse
+// map) with zeros and deletes it. In memory that is enough to destroy them, and writing only the
+// data runs keeps a mostly empty sparse image from being filled out to its full size first.
+pub fn wipe(path: &Path, runs: &[[u64; 3]]) -> io::Result<()> {
+    {
+        let mut file: File = OpenOptions::new().write(true).open(path)?;
+        let zeros = vec![0u8; 64 * 1024];
+        for &[offset, _, len] in runs {
+            file.seek(SeekFrom::Start(offset))?;
+            let mut done = 0;
+            while done < len {
+                let n = (len - done).min(zeros.len() as u64) as usize;
+                file.write_all(&zeros[..n])?;
+                done += n as u64;
+            }
+        }
+        file.sync_all()?;
+        file.set_len(0)?;
+    }
+    fs::remove_file(path)
+}
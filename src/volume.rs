@@ -0,0 +1,253 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+#[cfg(target_os = "linux")]
+use std::process::Command;
+use crate::archive;
+use crate::config::VaultConfig;
+use crate::filesys;
+
+// How the decrypted locker image is turned into something the user can open. Everything else
+// (encrypting, fragmenting, reassembling) only ever sees the image file, so it works the same
+// whichever backend is in use.
+pub trait VolumeBackend {
+    // Creates and formats a new image, prompting for its size
+    fn create(&self, image: &str) -> Result<(), Box<dyn std::error::Error>>;
+    fn attach(&self, image: &str) -> Result<(), Box<dyn std::error::Error>>;
+    fn detach(&self, image: &str) -> Result<(), Box<dyn std::error::Error>>;
+    fn is_attached(&self, image: &str) -> bool;
+    // Where the contents can be reached while attached
+    fn mount_point(&self, image: &str) -> Option<String>;
+}
+
+pub fn backend_for(config: &VaultConfig) -> Result<Box<dyn VolumeBackend>, Box<dyn std::error::Error>> {
+    match config.backend.as_str() {
+        "directory" => Ok(Box::new(DirectoryBackend { work_dir: config.mount_point.clone() })),
+        #[cfg(windows)]
+        "vhd" => Ok(Box::new(WindowsVhdBackend {
+            letter: config.mount_point.clone(),
+            disk_format: config.disk_format.clone(),
+            filesystem: config.filesystem.clone(),
+        })),
+        #[cfg(target_os = "linux")]
+        "loop" => Ok(Box::new(LoopBackend {
+            mount_dir: config.mount_point.clone(),
+            filesystem: config.filesystem.clone(),
+        })),
+        other => Err(format!("Volume backend \"{}\" is not available on this platform", other).into()),
+    }
+}
+
+fn prompt_size_mb() -> Result<u64, Box<dyn std::error::Error>> {
+    let mut input = String::new();
+    print!("Enter disk size in MB: ");
+    io::stdout().flush()?;
+    io::stdin().read_line(&mut input)?;
+    Ok(input.trim().parse()?)
+}
+
+// No disk at all: the locker is a plain working directory, packed into a deterministic archive
+// on detach (which the caller then encrypts and fragments like any image) and unpacked again
+// on attach. Needs no admin or root, so it also works inside containers.
+pub struct DirectoryBackend {
+    work_dir: String,
+}
+
+impl VolumeBackend for DirectoryBackend {
+    fn create(&self, _image: &str) -> Result<(), Box<dyn std::error::Error>> {
+        fs::create_dir_all(&self.work_dir)?;
+        Ok(())
+    }
+
+    fn attach(&self, image: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if Path::new(image).exists() {
+            archive::unpack(Path::new(image), Path::new(&self.work_dir))?;
+            filesys::secure_delete(Path::new(image))?;
+        } else {
+            fs::create_dir_all(&self.work_dir)?;
+        }
+        println!("Locker unpacked to {}", self.work_dir);
+        Ok(())
+    }
+
+    fn detach(&self, image: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let work_dir = Path::new(&self.work_dir);
+        if let Err(e) = archive::pack(work_dir, Path::new(image)) {
+            let _ = fs::remove_file(image);
+            return Err(e.into());
+        }
+        filesys::secure_delete_dir(work_dir)?;
+        Ok(())
+    }
+
+    fn is_attached(&self, _image: &str) -> bool {
+        Path::new(&self.work_dir).is_dir()
+    }
+
+    fn mount_point(&self, _image: &str) -> Option<String> {
+        Some(self.work_dir.clone())
+    }
+}
+
+#[cfg(windows)]
+pub struct WindowsVhdBackend {
+    letter: String,
+    disk_format: String,
+    filesystem: String,
+}
+
+#[cfg(windows)]
+impl VolumeBackend for WindowsVhdBackend {
+    fn create(&self, image: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let disk_mb = prompt_size_mb()?;
+        crate::filesys::create_drive(image, disk_mb, &self.letter, &self.disk_format, &self.filesystem)
+    }
+
+    fn attach(&self, image: &str) -> Result<(), Box<dyn std::error::Error>> {
+        // Catch a bad reassembly here rather than letting Windows reject the disk
+        crate::vhd::validate(Path::new(image))?;
+        crate::filesys::attach_drive(image)
+    }
+
+    fn detach(&self, image: &str) -> Result<(), Box<dyn std::error::Error>> {
+        crate::filesys::detach_drive(image)
+    }
+
+    fn is_attached(&self, image: &str) -> bool {
+        crate::filesys::is_vhd_attached(image)
+    }
+
+    fn mount_point(&self, _image: &str) -> Option<String> {
+        if self.letter.is_empty() {
+            None
+        } else {
+            Some(format!("{}:\\", self.letter))
+        }
+    }
+}
+
+// Raw image attached with losetup and mounted with mount, which needs root. Without root it
+// falls back to udisksctl, which lets a desktop session user do the same through polkit.
+#[cfg(target_os = "linux")]
+pub struct LoopBackend {
+    mount_dir: String,
+    filesystem: String,
+}
+
+#[cfg(target_os = "linux")]
+fn run(command: &mut Command) -> Result<String, Box<dyn std::error::Error>> {
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "{:?} failed: {}",
+            command.get_program(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(target_os = "linux")]
+fn is_root() -> bool {
+    run(Command::new("id").arg("-u")).map(|uid| uid.trim() == "0").unwrap_or(false)
+}
+
+#[cfg(target_os = "linux")]
+impl LoopBackend {
+    // Loop device currently backed by the image, from `losetup -j`
+    fn loop_device(&self, image: &str) -> Option<String> {
+        let output = run(Command::new("losetup").arg("-j").arg(image)).ok()?;
+        output.lines().next().and_then(|line| line.split(':').next()).map(str::to_string)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl VolumeBackend for LoopBackend {
+    fn create(&self, image: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(parent) = Path::new(image).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let disk_mb = prompt_size_mb()?;
+        let file = fs::File::create(image)?;
+        file.set_len(disk_mb * 1024 * 1024)?;
+        drop(file);
+
+        // No partition table, so the loop device can be mounted as it is
+        if self.filesystem == "fat32" {
+            return crate::fat::format_image(Path::new(image), false);
+        }
+        let mut mkfs = Command::new(format!("mkfs.{}", self.filesystem));
+        if self.filesystem.starts_with("ext") {
+            // mke2fs asks before formatting a regular file otherwise
+            mkfs.arg("-F").arg("-L").arg("Locker");
+        }
+        run(mkfs.arg(image))?;
+        Ok(())
+    }
+
+    fn attach(&self, image: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if is_root() {
+            let device = run(Command::new("losetup").arg("--find").arg("--show").arg(image))?;
+            let device = device.trim();
+            fs::create_dir_all(&self.mount_dir)?;
+            if let Err(e) = run(Command::new("mount").arg(device).arg(&self.mount_dir)) {
+                let _ = run(Command::new("losetup").arg("-d").arg(device));
+                return Err(e);
+            }
+        } else {
+            let output = run(Command::new("udisksctl").arg("loop-setup").arg("--no-user-interaction").arg("-f").arg(image))?;
+            // "Mapped file locker.img as /dev/loop0."
+            let device = output
+                .split_whitespace()
+                .last()
+                .map(|d| d.trim_end_matches('.').to_string())
+                .ok_or("Could not read the loop device from udisksctl")?;
+            // udisks mounts new loop devices by itself on most desktops
+            if mount_point_of(&device).is_none() {
+                run(Command::new("udisksctl").arg("mount").arg("--no-user-interaction").arg("-b").arg(&device))?;
+            }
+        }
+        if let Some(mount_point) = self.mount_point(image) {
+            println!("Locker mounted at {}", mount_point);
+        }
+        Ok(())
+    }
+
+    fn detach(&self, image: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let device = self.loop_device(image).ok_or("Image is not attached to a loop device")?;
+        if is_root() {
+            if mount_point_of(&device).is_some() {
+                run(Command::new("umount").arg(&device))?;
+            }
+            run(Command::new("losetup").arg("-d").arg(&device))?;
+        } else {
+            if mount_point_of(&device).is_some() {
+                run(Command::new("udisksctl").arg("unmount").arg("--no-user-interaction").arg("-b").arg(&device))?;
+            }
+            run(Command::new("udisksctl").arg("loop-delete").arg("--no-user-interaction").arg("-b").arg(&device))?;
+        }
+        Ok(())
+    }
+
+    fn is_attached(&self, image: &str) -> bool {
+        self.loop_device(image).is_some()
+    }
+
+    fn mount_point(&self, image: &str) -> Option<String> {
+        mount_point_of(&self.loop_device(image)?)
+    }
+}
+
+// Looks the device up in /proc/mounts; mount points there escape spaces as \040
+#[cfg(target_os = "linux")]
+fn mount_point_of(device: &str) -> Option<String> {
+    let mounts = fs::read_to_string("/proc/mounts").ok()?;
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some((fields.next()?, fields.next()?))
+        })
+        .find(|(dev, _)| *dev == device)
+        .map(|(_, mount_point)| mount_point.replace("\\040", " "))
+}